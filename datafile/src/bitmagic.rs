@@ -10,6 +10,10 @@ pub use libtw2_common::relative_size_of_mult;
 pub use libtw2_common::slice::transmute as transmute_slice;
 pub use libtw2_common::slice::transmute_mut as transmute_mut_slice;
 
+pub fn as_i32_slice<T: OnlyI32>(x: &[T]) -> &[i32] {
+    unsafe { transmute_slice(x) }
+}
+
 pub fn as_mut_i32_slice<T: OnlyI32>(x: &mut [T]) -> &mut [i32] {
     unsafe { transmute_mut_slice(x) }
 }
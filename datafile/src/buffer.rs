@@ -1,6 +1,10 @@
+use crate::format;
 use crate::format::ItemView;
+use crate::writer;
 use libtw2_common::MapIterator;
+use std::io::Write;
 use std::ops;
+use uuid::Uuid;
 
 #[derive(Clone, Copy, Debug)]
 struct ItemType {
@@ -189,10 +193,38 @@ impl Buffer {
         Ok(())
     }
 
+    /// Returns the item type ID for the UUID item type, registering it if
+    /// necessary.
+    pub fn add_uuid_item_type(&mut self, uuid: Uuid) -> u16 {
+        let data = format::uuid_to_item_data(uuid);
+        if let Some(item) = self
+            .item_type_items(format::ITEMTYPE_EX)
+            .find(|i| i.data == data)
+        {
+            return item.id;
+        }
+        // Like the reference implementation, allocate item type IDs from the
+        // top.
+        let type_id = (0..format::ITEMTYPE_EX)
+            .rev()
+            .find(|&t| {
+                self.item_type_indices(t).is_empty()
+                    && self.item_type_items(format::ITEMTYPE_EX).all(|i| i.id != t)
+            })
+            .expect("no free item type IDs");
+        self.add_item(format::ITEMTYPE_EX, type_id, &data).unwrap();
+        type_id
+    }
+
     pub fn add_data(&mut self, data: Vec<u8>) -> usize {
         // add the data
         self.data.push(data);
         // return the index
         self.data.len() - 1
     }
+
+    /// Writes the buffer as a version 4 datafile.
+    pub fn write<W: Write>(&self, file: W) -> Result<(), writer::Error> {
+        writer::write(file, self.items(), self.data_iter())
+    }
 }
@@ -0,0 +1,66 @@
+/// Least-recently-used cache of decompressed data, bounded by the total size
+/// of the cached data in bytes.
+///
+/// The number of data blocks in a datafile is small, a linear search over the
+/// cached entries is fine.
+#[derive(Clone, Debug)]
+pub(crate) struct DataCache {
+    capacity: usize,
+    size: usize,
+    // Ordered from least recently to most recently used.
+    entries: Vec<(usize, Vec<u8>)>,
+}
+
+impl DataCache {
+    pub fn new(capacity: usize) -> DataCache {
+        DataCache {
+            capacity: capacity,
+            size: 0,
+            entries: Vec::new(),
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn get(&mut self, index: usize) -> Option<&[u8]> {
+        let pos = self.entries.iter().position(|&(i, _)| i == index)?;
+        let entry = self.entries.remove(pos);
+        self.entries.push(entry);
+        Some(&self.entries.last().unwrap().1)
+    }
+    pub fn insert(&mut self, index: usize, data: Vec<u8>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        if let Some(pos) = self.entries.iter().position(|&(i, _)| i == index) {
+            self.size -= self.entries.remove(pos).1.len();
+        }
+        while self.size + data.len() > self.capacity {
+            self.size -= self.entries.remove(0).1.len();
+        }
+        self.size += data.len();
+        self.entries.push((index, data));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DataCache;
+
+    #[test]
+    fn eviction() {
+        let mut cache = DataCache::new(10);
+        cache.insert(0, vec![0; 4]);
+        cache.insert(1, vec![1; 4]);
+        assert_eq!(cache.get(0), Some(&[0; 4][..]));
+        // Evicts 1, the least recently used entry.
+        cache.insert(2, vec![2; 4]);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(0), Some(&[0; 4][..]));
+        assert_eq!(cache.get(2), Some(&[2; 4][..]));
+        // Too large to be cached at all.
+        cache.insert(3, vec![3; 11]);
+        assert_eq!(cache.get(3), None);
+        assert_eq!(cache.get(2), Some(&[2; 4][..]));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::cache::DataCache;
 use crate::format;
 use crate::format::ItemView;
 use crate::raw;
@@ -5,17 +6,18 @@ use crate::raw::CallbackError;
 use crate::raw::CallbackNew;
 use crate::raw::CallbackReadData;
 use libtw2_common::io::seek_overflow;
-use libtw2_common::io::FileExt;
 use libtw2_common::io::ReadExt;
 use libtw2_common::num::Cast;
 use libtw2_common::MapIterator;
 use std::fs::File;
 use std::io;
 use std::io::BufReader;
+use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::ops;
 use std::path::Path;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub enum Error {
@@ -35,24 +37,26 @@ impl From<io::Error> for Error {
     }
 }
 
-struct CallbackDataNew {
-    file: BufReader<File>,
+struct CallbackDataNew<R> {
+    file: BufReader<R>,
     datafile_start: u64,
     cur_datafile_offset: u64,
     seek_base: Option<u64>,
     error: Option<io::Error>,
 }
 
-struct CallbackData {
-    file: File,
+struct CallbackData<R> {
+    file: R,
     seek_base: u64,
     buffer: Option<Vec<u8>>,
     error: Option<io::Error>,
 }
 
-pub struct Reader {
-    callback_data: CallbackData,
+/// A datafile reader over any seekable byte stream, by default a file.
+pub struct Reader<R = File> {
+    callback_data: CallbackData<R>,
     raw: raw::Reader,
+    cache: Option<DataCache>,
 }
 
 trait ResultExt {
@@ -70,8 +74,17 @@ impl<T> ResultExt for Result<T, raw::Error> {
     }
 }
 
-impl Reader {
-    fn new_impl(file: File, check_initial_offset: bool) -> Result<Reader, Error> {
+impl Reader<File> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
+        fn inner(path: &Path) -> Result<Reader, Error> {
+            Reader::new_impl(File::open(path)?, false)
+        }
+        inner(path.as_ref())
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    fn new_impl(file: R, check_initial_offset: bool) -> Result<Reader<R>, Error> {
         let mut file = file;
         let datafile_start = if check_initial_offset {
             file.seek(SeekFrom::Current(0))?
@@ -89,23 +102,23 @@ impl Reader {
             raw::Reader::new(&mut callback_data_new).retrieve(&mut callback_data_new.error)?;
         let callback_data = CallbackData {
             file: callback_data_new.file.into_inner(),
-            seek_base: callback_data_new.seek_base.unwrap(),
+            seek_base: datafile_start + callback_data_new.seek_base.unwrap(),
             buffer: None,
             error: None,
         };
         Ok(Reader {
             callback_data: callback_data,
             raw: raw,
+            cache: None,
         })
     }
-    pub fn new(file: File) -> Result<Reader, Error> {
+    /// Reads a datafile starting at the current position of `file`.
+    pub fn new(file: R) -> Result<Reader<R>, Error> {
         Reader::new_impl(file, true)
     }
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
-        fn inner(path: &Path) -> Result<Reader, Error> {
-            Reader::new_impl(File::open(path)?, false)
-        }
-        inner(path.as_ref())
+    /// Returns the underlying byte stream.
+    pub fn into_inner(self) -> R {
+        self.callback_data.file
     }
     pub fn debug_dump(&mut self) -> Result<(), Error> {
         Ok(self
@@ -116,11 +129,45 @@ impl Reader {
     pub fn version(&self) -> raw::Version {
         self.raw.version()
     }
+    pub fn limits(&self) -> raw::Limits {
+        self.raw.limits()
+    }
+    /// Sets limits on the decompressed data size, `read_data` fails with
+    /// `format::Error::DataTooLarge` for data exceeding them.
+    pub fn set_limits(&mut self, limits: raw::Limits) {
+        self.raw.set_limits(limits);
+    }
+    /// Returns the maximum total size of the cached decompressed data, `None`
+    /// if caching is disabled.
+    pub fn cache_capacity(&self) -> Option<usize> {
+        self.cache.as_ref().map(|c| c.capacity())
+    }
+    /// Enables caching of up to `capacity` bytes of decompressed data,
+    /// evicting the least recently used data first. `None` disables the
+    /// cache.
+    pub fn set_cache_capacity(&mut self, capacity: Option<usize>) {
+        self.cache = capacity.map(DataCache::new);
+    }
+    /// Returns the decompressed size of the data, without decompressing it.
+    pub fn data_size(&self, index: usize) -> usize {
+        self.raw.data_size(index)
+    }
+    /// Returns the sum of the decompressed sizes of all data.
+    pub fn total_data_size(&self) -> u64 {
+        self.raw.total_data_size()
+    }
     pub fn read_data(&mut self, index: usize) -> Result<Vec<u8>, Error> {
+        if let Some(data) = self.cache.as_mut().and_then(|c| c.get(index)) {
+            return Ok(data.to_vec());
+        }
         self.raw
             .read_data(&mut self.callback_data, index)
             .retrieve(&mut self.callback_data.error)?;
-        Ok(self.callback_data.buffer.take().unwrap())
+        let data = self.callback_data.buffer.take().unwrap();
+        if let Some(ref mut cache) = self.cache {
+            cache.insert(index, data.clone());
+        }
+        Ok(data)
     }
     pub fn item(&self, index: usize) -> ItemView {
         self.raw.item(index)
@@ -144,6 +191,12 @@ impl Reader {
     pub fn find_item(&self, type_id: u16, item_id: u16) -> Option<ItemView> {
         self.raw.find_item(type_id, item_id)
     }
+    pub fn find_uuid_item_type(&self, uuid: Uuid) -> Option<u16> {
+        self.raw.find_uuid_item_type(uuid)
+    }
+    pub fn item_type_uuid(&self, type_id: u16) -> Option<Uuid> {
+        self.raw.item_type_uuid(type_id)
+    }
 
     pub fn items(&self) -> raw::Items {
         self.raw.items()
@@ -154,8 +207,8 @@ impl Reader {
     pub fn item_type_items(&self, type_id: u16) -> raw::ItemTypeItems {
         self.raw.item_type_items(type_id)
     }
-    pub fn data_iter(&mut self) -> DataIter {
-        fn map_fn(i: usize, self_: &mut &mut Reader) -> Result<Vec<u8>, Error> {
+    pub fn data_iter(&mut self) -> DataIter<R> {
+        fn map_fn<R: Read + Seek>(i: usize, self_: &mut &mut Reader<R>) -> Result<Vec<u8>, Error> {
             self_.read_data(i)
         }
         let num_data = self.num_data();
@@ -163,16 +216,17 @@ impl Reader {
     }
 }
 
-pub type DataIter<'a> = MapIterator<Result<Vec<u8>, Error>, &'a mut Reader, ops::Range<usize>>;
+pub type DataIter<'a, R = File> =
+    MapIterator<Result<Vec<u8>, Error>, &'a mut Reader<R>, ops::Range<usize>>;
 
 // "SeekOverflow"
 fn so(o: Option<u64>) -> io::Result<u64> {
     o.ok_or_else(seek_overflow)
 }
 
-impl CallbackNew for CallbackDataNew {
+impl<R: Read + Seek> CallbackNew for CallbackDataNew<R> {
     fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError> {
-        fn inner(self_: &mut CallbackDataNew, buffer: &mut [u8]) -> io::Result<usize> {
+        fn inner<R: Read>(self_: &mut CallbackDataNew<R>, buffer: &mut [u8]) -> io::Result<usize> {
             let r = self_.file.read_retry(buffer)?;
             self_.cur_datafile_offset = so(self_.cur_datafile_offset.checked_add(r.u64()))?;
             Ok(r)
@@ -187,8 +241,12 @@ impl CallbackNew for CallbackDataNew {
         Ok(())
     }
     fn ensure_filesize(&mut self, filesize: u32) -> Result<Result<(), ()>, CallbackError> {
-        fn inner(self_: &mut CallbackDataNew, filesize: u32) -> io::Result<Result<(), ()>> {
-            let actual = self_.file.get_ref().metadata()?.len();
+        fn inner<R: Read + Seek>(
+            self_: &mut CallbackDataNew<R>,
+            filesize: u32,
+        ) -> io::Result<Result<(), ()>> {
+            // All reads through the `BufReader` are done at this point.
+            let actual = self_.file.seek(SeekFrom::End(0))?;
             Ok(
                 if actual.checked_sub(self_.datafile_start).unwrap() >= filesize.u64() {
                     Ok(())
@@ -203,11 +261,16 @@ impl CallbackNew for CallbackDataNew {
         })
     }
 }
-impl CallbackReadData for CallbackData {
+impl<R: Read + Seek> CallbackReadData for CallbackData<R> {
     fn seek_read(&mut self, start: u32, buffer: &mut [u8]) -> Result<usize, CallbackError> {
-        fn inner(self_: &mut CallbackData, start: u32, buffer: &mut [u8]) -> io::Result<usize> {
+        fn inner<R: Read + Seek>(
+            self_: &mut CallbackData<R>,
+            start: u32,
+            buffer: &mut [u8],
+        ) -> io::Result<usize> {
             let offset = so(self_.seek_base.checked_add(start.u64()))?;
-            self_.file.read_offset_retry(buffer, offset)
+            self_.file.seek(SeekFrom::Start(offset))?;
+            self_.file.read_retry(buffer)
         }
         inner(self, start, buffer).map_err(|e| {
             self.error = Some(e);
@@ -226,3 +289,74 @@ impl CallbackReadData for CallbackData {
         self.buffer.as_mut().unwrap()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::buffer::Buffer;
+    use crate::Reader;
+    use std::cell::Cell;
+    use std::io;
+    use std::io::Cursor;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::rc::Rc;
+
+    /// Counts the reads from the underlying byte stream.
+    struct Counting {
+        inner: Cursor<Vec<u8>>,
+        reads: Rc<Cell<u32>>,
+    }
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let mut buffer = Buffer::new();
+        buffer.add_data(vec![0; 4]);
+        buffer.add_data(vec![1; 4]);
+        buffer.add_data(vec![2; 8]);
+        let mut file = Vec::new();
+        buffer.write(&mut file).unwrap();
+        let reads = Rc::new(Cell::new(0));
+        let mut reader = Reader::new(Counting {
+            inner: Cursor::new(file),
+            reads: reads.clone(),
+        })
+        .unwrap();
+        assert_eq!(reader.cache_capacity(), None);
+
+        // Returns whether the data had to be read from the file.
+        let read = |reader: &mut Reader<Counting>, index: usize, expected: &[u8]| {
+            let before = reads.get();
+            assert_eq!(reader.read_data(index).unwrap(), expected);
+            reads.get() != before
+        };
+
+        reader.set_cache_capacity(Some(10));
+        assert_eq!(reader.cache_capacity(), Some(10));
+        assert!(read(&mut reader, 0, &[0; 4]));
+        assert!(!read(&mut reader, 0, &[0; 4]));
+        assert!(read(&mut reader, 1, &[1; 4]));
+        assert!(!read(&mut reader, 1, &[1; 4]));
+        // Evicts both of the previously read data.
+        assert!(read(&mut reader, 2, &[2; 8]));
+        assert!(!read(&mut reader, 2, &[2; 8]));
+        assert!(read(&mut reader, 0, &[0; 4]));
+
+        reader.set_cache_capacity(None);
+        assert!(read(&mut reader, 2, &[2; 8]));
+        assert!(read(&mut reader, 2, &[2; 8]));
+    }
+}
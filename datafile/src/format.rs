@@ -6,6 +6,7 @@ use crate::raw::CallbackNew;
 use libtw2_common::num::Cast;
 use std::mem;
 use std::slice;
+use uuid::Uuid;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Error {
@@ -15,6 +16,8 @@ pub enum Error {
     Malformed,
     CompressionWrongSize,
     CompressionError(libtw2_zlib::Error),
+    /// The data's decompressed size exceeds the reader's `raw::Limits`.
+    DataTooLarge,
     TooShort,
     TooShortHeaderVersion,
     TooShortHeader,
@@ -76,6 +79,28 @@ pub static MAGIC_BIGENDIAN: [u8; 4] = *b"ATAD";
 pub static VERSION3: i32 = 3;
 pub static VERSION4: i32 = 4;
 pub static ITEMTYPE_ID_RANGE: i32 = 0x10000;
+/// Item type of the items registering UUID item types. The item ID is the
+/// item type ID assigned to the UUID, the item data is the UUID.
+pub const ITEMTYPE_EX: u16 = 0xffff;
+
+pub fn uuid_from_item_data(data: &[i32]) -> Option<Uuid> {
+    if data.len() != 4 {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    for (chunk, &i) in bytes.chunks_mut(4).zip(data) {
+        chunk.copy_from_slice(&(i as u32).to_be_bytes());
+    }
+    Some(Uuid::from_bytes(bytes))
+}
+
+pub fn uuid_to_item_data(uuid: Uuid) -> [i32; 4] {
+    let mut result = [0; 4];
+    for (r, chunk) in result.iter_mut().zip(uuid.as_bytes().chunks(4)) {
+        *r = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as i32;
+    }
+    result
+}
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub struct ItemView<'a> {
@@ -138,7 +163,7 @@ impl Header {
         }
         Err(Error::MalformedHeader)
     }
-    fn calculate_size_field(&self, total_size: i32, crude_version: bool) -> i32 {
+    pub(crate) fn calculate_size_field(&self, total_size: i32, crude_version: bool) -> i32 {
         // The first four i32 fields are not accounted for in the size field.
         let result = total_size - mem::size_of::<i32>().assert_i32() * 4;
         if crude_version {
@@ -149,10 +174,10 @@ impl Header {
             result
         }
     }
-    fn calculate_swaplen_field(&self, total_size: i32, crude_version: bool) -> i32 {
+    pub(crate) fn calculate_swaplen_field(&self, total_size: i32, crude_version: bool) -> i32 {
         self.calculate_size_field(total_size, crude_version) - self.hr.size_data
     }
-    fn calculate_total_size(&self) -> Result<i32, Error> {
+    pub(crate) fn calculate_total_size(&self) -> Result<i32, Error> {
         // These two functions are just used to make the lines in this function
         // shorter. `u` converts an `i32` to an `u64`, and `s` returns the size
         // of the type as `u64`.
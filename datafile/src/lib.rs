@@ -9,11 +9,16 @@ pub use self::format::OnlyI32;
 pub use self::raw::ItemTypeItems;
 pub use self::raw::ItemTypes;
 pub use self::raw::Items;
+pub use self::raw::Limits;
 pub use self::raw::Version;
+pub use self::slice::SliceDataIter;
+pub use self::slice::SliceReader;
 
 mod bitmagic;
 pub mod buffer;
+mod cache;
 mod file;
 pub mod format;
 pub mod raw;
-mod writer;
+mod slice;
+pub mod writer;
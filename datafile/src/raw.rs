@@ -13,6 +13,7 @@ use libtw2_common::num::Cast;
 use libtw2_common::MapIterator;
 use std::mem;
 use std::ops;
+use uuid::Uuid;
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub enum Version {
@@ -32,6 +33,17 @@ impl Version {
 
 pub struct CallbackError;
 
+/// Limits on the decompressed size of data, checked before decompressing.
+///
+/// `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Limits {
+    /// Maximum decompressed size of a single data block.
+    pub max_data_size: Option<usize>,
+    /// Maximum sum of the decompressed sizes of all data blocks of a file.
+    pub max_total_data_size: Option<u64>,
+}
+
 pub trait CallbackNew {
     fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError>;
     fn set_seek_base(&mut self) -> Result<(), CallbackError>;
@@ -90,6 +102,7 @@ pub struct Reader {
     uncomp_data_sizes: Option<Vec<i32>>,
     items_raw: Vec<i32>,
     version: Version,
+    limits: Limits,
 }
 
 impl Reader {
@@ -149,6 +162,7 @@ impl Reader {
             uncomp_data_sizes: uncomp_data_sizes,
             items_raw: items_raw,
             version: version,
+            limits: Limits::default(),
         };
         result.check()?;
         Ok(result)
@@ -306,22 +320,78 @@ impl Reader {
     pub fn version(&self) -> Version {
         self.version
     }
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /// Returns the decompressed size of the data, without decompressing it.
+    pub fn data_size(&self, index: usize) -> usize {
+        if let Some(ref uds) = self.uncomp_data_sizes {
+            uds[index].assert_usize()
+        } else {
+            self.data_size_file(index)
+        }
+    }
+    /// Returns the sum of the decompressed sizes of all data.
+    pub fn total_data_size(&self) -> u64 {
+        (0..self.num_data()).map(|i| self.data_size(i).u64()).sum()
+    }
+    fn check_limits(&self, index: usize) -> Result<(), format::Error> {
+        let size = self.data_size(index);
+        if let Some(max) = self.limits.max_data_size {
+            if size > max {
+                error!(
+                    "data exceeds size limit, data={} size={} max={}",
+                    index, size, max
+                );
+                return Err(format::Error::DataTooLarge);
+            }
+        }
+        if let Some(max) = self.limits.max_total_data_size {
+            let total = self.total_data_size();
+            if total > max {
+                error!("data exceeds total size limit, total={} max={}", total, max);
+                return Err(format::Error::DataTooLarge);
+            }
+        }
+        Ok(())
+    }
+    /// Returns the byte range of the data in the file, relative to the end
+    /// of the items.
+    pub fn data_file_range(&self, index: usize) -> ops::Range<u32> {
+        let start = self.data_offsets[index] as u32;
+        // Overflow check was in Reader::check().
+        start..start + self.data_size_file(index) as u32
+    }
     pub fn read_data<'a>(
         &self,
         mut cb: &'a mut dyn CallbackReadData,
         index: usize,
     ) -> Result<(), Error> {
-        let raw_data_len = self.data_size_file(index);
+        let range = self.data_file_range(index);
         let raw_data = cb
-            .seek_read_exact_owned(self.data_offsets[index] as u32, raw_data_len)
+            .seek_read_exact_owned(range.start, (range.end - range.start) as usize)
             .map_err(|e| e.on_eof(format::Error::TooShort))?;
-
+        self.decode_data(cb, index, &raw_data)
+    }
+    /// Decompresses the data, given the bytes of `data_file_range` of the
+    /// file.
+    pub fn decode_data(
+        &self,
+        cb: &mut dyn CallbackReadData,
+        index: usize,
+        raw_data: &[u8],
+    ) -> Result<(), Error> {
+        assert!(raw_data.len() == self.data_size_file(index));
+        self.check_limits(index)?;
         if let Some(ref uds) = self.uncomp_data_sizes {
             let data_len = uds[index] as usize;
             cb.alloc_data_buffer(data_len)?;
             let data = cb.data_buffer();
 
-            match libtw2_zlib::uncompress(data, &raw_data) {
+            match libtw2_zlib::uncompress(data, raw_data) {
                 Ok(len) if len == data_len => Ok(()),
                 Ok(len) => {
                     error!(
@@ -336,7 +406,7 @@ impl Reader {
                 }
             }
         } else {
-            let data_len = raw_data_len;
+            let data_len = raw_data.len();
             cb.alloc_data_buffer(data_len)?;
             let data = cb.data_buffer();
             data.iter_mut().set_from(raw_data.iter().cloned());
@@ -388,6 +458,18 @@ impl Reader {
         None
     }
 
+    /// Returns the item type ID the UUID item type is stored under.
+    pub fn find_uuid_item_type(&self, uuid: Uuid) -> Option<u16> {
+        self.item_type_items(format::ITEMTYPE_EX)
+            .find(|i| format::uuid_from_item_data(i.data) == Some(uuid))
+            .map(|i| i.id)
+    }
+    /// Returns the UUID of an item type ID, if it's a UUID item type.
+    pub fn item_type_uuid(&self, type_id: u16) -> Option<Uuid> {
+        self.find_item(format::ITEMTYPE_EX, type_id)
+            .and_then(|i| format::uuid_from_item_data(i.data))
+    }
+
     pub fn debug_dump(&self, cb: &mut dyn CallbackReadData) -> Result<(), Error> {
         if !log_enabled!(log::LogLevel::Debug) {
             return Ok(());
@@ -0,0 +1,246 @@
+use crate::format;
+use crate::format::ItemView;
+use crate::raw;
+use crate::raw::CallbackError;
+use crate::raw::CallbackNew;
+use crate::raw::CallbackReadData;
+use libtw2_common::num::Cast;
+use libtw2_common::MapIterator;
+use std::cmp;
+use std::ops;
+use uuid::Uuid;
+
+struct CallbackDataNew<'a> {
+    data: &'a [u8],
+    offset: usize,
+    seek_base: Option<usize>,
+}
+
+struct CallbackData<'a> {
+    data: &'a [u8],
+    buffer: Option<Vec<u8>>,
+}
+
+/// A datafile reader over bytes in memory, e.g. a memory-mapped file.
+///
+/// Compressed data is decompressed directly from the slice, without copying
+/// it first.
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+    raw: raw::Reader,
+}
+
+fn retrieve<T>(result: Result<T, raw::Error>) -> Result<T, format::Error> {
+    result.map_err(|e| match e {
+        raw::Error::Df(e) => e,
+        // Reading from a slice never fails.
+        raw::Error::Callback => unreachable!(),
+    })
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<SliceReader<'a>, format::Error> {
+        let mut callback_data_new = CallbackDataNew {
+            data: data,
+            offset: 0,
+            seek_base: None,
+        };
+        let raw = retrieve(raw::Reader::new(&mut callback_data_new))?;
+        Ok(SliceReader {
+            data: &data[callback_data_new.seek_base.unwrap()..],
+            raw: raw,
+        })
+    }
+    pub fn debug_dump(&self) -> Result<(), format::Error> {
+        let mut callback_data = CallbackData {
+            data: self.data,
+            buffer: None,
+        };
+        retrieve(self.raw.debug_dump(&mut callback_data))
+    }
+    pub fn version(&self) -> raw::Version {
+        self.raw.version()
+    }
+    pub fn limits(&self) -> raw::Limits {
+        self.raw.limits()
+    }
+    /// Sets limits on the decompressed data size, `read_data` fails with
+    /// `format::Error::DataTooLarge` for data exceeding them.
+    pub fn set_limits(&mut self, limits: raw::Limits) {
+        self.raw.set_limits(limits);
+    }
+    /// Returns the decompressed size of the data, without decompressing it.
+    pub fn data_size(&self, index: usize) -> usize {
+        self.raw.data_size(index)
+    }
+    /// Returns the sum of the decompressed sizes of all data.
+    pub fn total_data_size(&self) -> u64 {
+        self.raw.total_data_size()
+    }
+    pub fn read_data(&self, index: usize) -> Result<Vec<u8>, format::Error> {
+        let range = self.raw.data_file_range(index);
+        // The file size was checked in `raw::Reader::new`.
+        let raw_data = &self.data[range.start.usize()..range.end.usize()];
+        let mut callback_data = CallbackData {
+            data: self.data,
+            buffer: None,
+        };
+        retrieve(self.raw.decode_data(&mut callback_data, index, raw_data))?;
+        Ok(callback_data.buffer.unwrap())
+    }
+    pub fn item(&self, index: usize) -> ItemView {
+        self.raw.item(index)
+    }
+    pub fn num_items(&self) -> usize {
+        self.raw.num_items()
+    }
+    pub fn num_data(&self) -> usize {
+        self.raw.num_data()
+    }
+    pub fn item_type_indices(&self, type_id: u16) -> ops::Range<usize> {
+        self.raw.item_type_indices(type_id)
+    }
+    pub fn item_type(&self, index: usize) -> u16 {
+        self.raw.item_type(index)
+    }
+    pub fn num_item_types(&self) -> usize {
+        self.raw.num_item_types()
+    }
+
+    pub fn find_item(&self, type_id: u16, item_id: u16) -> Option<ItemView> {
+        self.raw.find_item(type_id, item_id)
+    }
+    pub fn find_uuid_item_type(&self, uuid: Uuid) -> Option<u16> {
+        self.raw.find_uuid_item_type(uuid)
+    }
+    pub fn item_type_uuid(&self, type_id: u16) -> Option<Uuid> {
+        self.raw.item_type_uuid(type_id)
+    }
+
+    pub fn items(&self) -> raw::Items {
+        self.raw.items()
+    }
+    pub fn item_types(&self) -> raw::ItemTypes {
+        self.raw.item_types()
+    }
+    pub fn item_type_items(&self, type_id: u16) -> raw::ItemTypeItems {
+        self.raw.item_type_items(type_id)
+    }
+    pub fn data_iter(&self) -> SliceDataIter<'_, 'a> {
+        fn map_fn<'a>(i: usize, self_: &mut &SliceReader<'a>) -> Result<Vec<u8>, format::Error> {
+            self_.read_data(i)
+        }
+        MapIterator::new(self, 0..self.num_data(), map_fn)
+    }
+}
+
+pub type SliceDataIter<'r, 'a> =
+    MapIterator<Result<Vec<u8>, format::Error>, &'r SliceReader<'a>, ops::Range<usize>>;
+
+impl<'a> CallbackNew for CallbackDataNew<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let remaining = &self.data[self.offset..];
+        let len = cmp::min(buffer.len(), remaining.len());
+        buffer[..len].copy_from_slice(&remaining[..len]);
+        self.offset += len;
+        Ok(len)
+    }
+    fn set_seek_base(&mut self) -> Result<(), CallbackError> {
+        self.seek_base = Some(self.offset);
+        Ok(())
+    }
+    fn ensure_filesize(&mut self, filesize: u32) -> Result<Result<(), ()>, CallbackError> {
+        Ok(if self.data.len().u64() >= filesize.u64() {
+            Ok(())
+        } else {
+            Err(())
+        })
+    }
+}
+
+impl<'a> CallbackReadData for CallbackData<'a> {
+    fn seek_read(&mut self, start: u32, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let remaining = self.data.get(start.usize()..).unwrap_or(&[]);
+        let len = cmp::min(buffer.len(), remaining.len());
+        buffer[..len].copy_from_slice(&remaining[..len]);
+        Ok(len)
+    }
+    fn alloc_data_buffer(&mut self, length: usize) -> Result<(), CallbackError> {
+        self.buffer = Some(vec![0; length]);
+        Ok(())
+    }
+    fn data_buffer(&mut self) -> &mut [u8] {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SliceReader;
+    use crate::buffer::Buffer;
+    use crate::format;
+    use crate::Limits;
+    use crate::Reader;
+    use std::io::Cursor;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use uuid::Uuid;
+
+    #[test]
+    fn in_memory() {
+        let mut buffer = Buffer::new();
+        buffer.add_item(1, 0, &[1, 2]).unwrap();
+        buffer.add_item(3, 4, &[5]).unwrap();
+        let uuid = Uuid::from_u128(0x3e1b2716_178c_3978_9bd9_b11ae0410dd8);
+        let uuid_type = buffer.add_uuid_item_type(uuid);
+        assert_eq!(buffer.add_uuid_item_type(uuid), uuid_type);
+        buffer.add_item(uuid_type, 0, &[1]).unwrap();
+        buffer.add_data(b"first".to_vec());
+        buffer.add_data(b"second".to_vec());
+        let mut bytes = b"garbage".to_vec();
+        buffer.write(&mut bytes).unwrap();
+
+        let slice = SliceReader::new(&bytes[7..]).unwrap();
+        assert_eq!(
+            slice.items().collect::<Vec<_>>(),
+            buffer.items().collect::<Vec<_>>(),
+        );
+        assert_eq!(slice.find_uuid_item_type(uuid), Some(uuid_type));
+        assert_eq!(slice.item_type_uuid(uuid_type), Some(uuid));
+        assert_eq!(slice.find_uuid_item_type(Uuid::nil()), None);
+        let data: Vec<_> = slice.data_iter().map(Result::unwrap).collect();
+        assert_eq!(data, [&b"first"[..], &b"second"[..]]);
+
+        let mut cursor = Cursor::new(&bytes[..]);
+        cursor.seek(SeekFrom::Start(7)).unwrap();
+        let mut reader = Reader::new(cursor).unwrap();
+        assert_eq!(reader.find_item(3, 4).unwrap().data, &[5]);
+        assert_eq!(reader.read_data(1).unwrap(), b"second");
+
+        assert!(SliceReader::new(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let mut buffer = Buffer::new();
+        buffer.add_data(vec![0; 100]);
+        buffer.add_data(vec![0; 1000]);
+        let mut bytes = Vec::new();
+        buffer.write(&mut bytes).unwrap();
+
+        let mut reader = SliceReader::new(&bytes).unwrap();
+        assert_eq!(reader.data_size(1), 1000);
+        assert_eq!(reader.total_data_size(), 1100);
+        reader.set_limits(Limits {
+            max_data_size: Some(500),
+            max_total_data_size: None,
+        });
+        assert_eq!(reader.read_data(0).unwrap().len(), 100);
+        assert_eq!(reader.read_data(1), Err(format::Error::DataTooLarge));
+        reader.set_limits(Limits {
+            max_data_size: None,
+            max_total_data_size: Some(1000),
+        });
+        assert_eq!(reader.read_data(0), Err(format::Error::DataTooLarge));
+    }
+}
@@ -1,52 +1,189 @@
-/*
-pub fn write_datafile<T:Datafile,W:Writer>(df: &T, writer: &mut W) -> Result<IoResult<()>,()> {
-    let compressed_data: Vec<Vec<u8>> = try!(result::collect(df.data_iter().map(|maybe_x| maybe_x.map(|x| {
-        zlib::compress_vec(x).unwrap()
-        (item_type.start, item_type.num)
-    }
-}
-
-/*
-pub fn write_datafile<T:Datafile,W:Writer>(df: &T, writer: &mut W) -> Result<IoResult<()>,()> {
-    let compressed_data: Vec<Vec<u8>> = result::collect(df.data_iter().map(|maybe_x| maybe_x.map(|x| {
-        zlib::compress_vec(x).unwrap()
-    })))?;
-
-    let size_items = df.items().fold(0, |s, i| {
-        s + i.data.len() * mem::size_of::<i32>() + mem::size_of::<DatafileItemHeader>()
-    });
-
-    let size_data = compressed_data.iter().fold(0, |s, d| s + d.len());
-
-    DatafileHeaderVersion {
-        magic: DATAFILE_MAGIC,
-        version: 4,
-    }.write(writer)?;
-
-    DatafileHeader {
-        _size: unimplemented!(),
-        _swaplen: unimplemented!(),
-        num_item_types: df.item_types().len(),
-        num_items: df.items().len(),
-        num_data: df.data_iter().len(),
-        size_items: size_items,
-        size_data: size_data,
-    }.write(writer)?;
-
-    for &type_id in df.item_types() {
-        let (start, num) = df.item_type_indexes_start_num(type_id);
-        DatafileItemType {
-            type_id: type_id.as_i32().unwrap(),
-            start: start.as_i32().unwrap(),
-            num: num.as_i32().unwrap(),
-        }.write(writer)?;
-    }
-
-    for DatafileItem { type_id, id, data } in df.items() {
-        DatafileItemHeader::new(type_id, id, data.len()).write(writer)?;
-    }
-    unimplemented!();
-    Ok(Ok(()))
-}
-*/
-*/
+use crate::bitmagic::as_i32_slice;
+use crate::format;
+use crate::format::ItemView;
+use crate::format::OnlyI32;
+use libtw2_common::num::Cast;
+use std::io;
+use std::io::Write;
+use std::mem;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Compression(libtw2_zlib::Error),
+    /// The datafile would exceed the sizes representable in its header.
+    TooLarge,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<libtw2_zlib::Error> for Error {
+    fn from(err: libtw2_zlib::Error) -> Error {
+        Error::Compression(err)
+    }
+}
+
+struct Item<'a> {
+    type_id: u16,
+    id: u16,
+    data: &'a [i32],
+}
+
+fn too_large<T>(o: Option<T>) -> Result<T, Error> {
+    o.ok_or(Error::TooLarge)
+}
+
+fn extend_le_i32s<T: OnlyI32>(buffer: &mut Vec<u8>, values: &[T]) {
+    for &v in as_i32_slice(values) {
+        buffer.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+/// Writes a version 4 datafile consisting of the given items and data.
+///
+/// Items are sorted by type ID, the relative order of items of the same type
+/// is preserved. Data is zlib-compressed.
+pub fn write<'a, W, I, D>(file: W, items: I, data: D) -> Result<(), Error>
+where
+    W: Write,
+    I: IntoIterator<Item = ItemView<'a>>,
+    D: IntoIterator,
+    D::Item: AsRef<[u8]>,
+{
+    let mut items: Vec<Item> = items
+        .into_iter()
+        .map(|i| Item {
+            type_id: i.type_id,
+            id: i.id,
+            data: i.data,
+        })
+        .collect();
+    // Stable sort, keeps the order of items with the same type ID.
+    items.sort_by_key(|i| i.type_id);
+
+    let mut item_types: Vec<format::ItemType> = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let i = too_large(i.try_i32())?;
+        match item_types.last_mut() {
+            Some(t) if t.type_id == item.type_id.i32() => t.num += 1,
+            _ => item_types.push(format::ItemType {
+                type_id: item.type_id.i32(),
+                start: i,
+                num: 1,
+            }),
+        }
+    }
+
+    let mut item_offsets = Vec::with_capacity(items.len());
+    let mut size_items: usize = 0;
+    for item in &items {
+        item_offsets.push(too_large(size_items.try_i32())?);
+        size_items = too_large(
+            size_items
+                .checked_add(mem::size_of::<format::ItemHeader>())
+                .and_then(|s| s.checked_add(item.data.len().checked_mul(4)?)),
+        )?;
+    }
+
+    let mut compressed_data = Vec::new();
+    let mut data_offsets = Vec::new();
+    let mut uncomp_data_sizes = Vec::new();
+    let mut size_data: usize = 0;
+    for d in data {
+        let d = d.as_ref();
+        let compressed = libtw2_zlib::compress_vec(d)?;
+        data_offsets.push(too_large(size_data.try_i32())?);
+        uncomp_data_sizes.push(too_large(d.len().try_i32())?);
+        size_data = too_large(size_data.checked_add(compressed.len()))?;
+        compressed_data.push(compressed);
+    }
+
+    let mut header = format::Header {
+        hv: format::HeaderVersion {
+            magic: format::MAGIC,
+            version: format::VERSION4,
+        },
+        hr: format::HeaderRest {
+            size: 0,
+            swaplen: 0,
+            num_item_types: too_large(item_types.len().try_i32())?,
+            num_items: too_large(items.len().try_i32())?,
+            num_data: too_large(data_offsets.len().try_i32())?,
+            size_items: too_large(size_items.try_i32())?,
+            size_data: too_large(size_data.try_i32())?,
+        },
+    };
+    let total_size = header.calculate_total_size().map_err(|_| Error::TooLarge)?;
+    header.hr.size = header.calculate_size_field(total_size, false);
+    header.hr.swaplen = header.calculate_swaplen_field(total_size, false);
+
+    // Everything except the data part is written as little-endian `i32`s.
+    let mut buffer = Vec::with_capacity(total_size.assert_usize() - size_data);
+    buffer.extend_from_slice(&header.hv.magic);
+    extend_le_i32s(&mut buffer, &[header.hv.version]);
+    extend_le_i32s(&mut buffer, &[header.hr]);
+    extend_le_i32s(&mut buffer, &item_types);
+    extend_le_i32s(&mut buffer, &item_offsets);
+    extend_le_i32s(&mut buffer, &data_offsets);
+    extend_le_i32s(&mut buffer, &uncomp_data_sizes);
+    for item in &items {
+        // `size_items` fit into an `i32`, so does every item's size.
+        let size = (item.data.len() * 4).assert_i32();
+        extend_le_i32s(
+            &mut buffer,
+            &[format::ItemHeader::new(item.type_id, item.id, size)],
+        );
+        extend_le_i32s(&mut buffer, item.data);
+    }
+
+    let mut file = file;
+    file.write_all(&buffer)?;
+    for d in &compressed_data {
+        file.write_all(d)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::buffer::Buffer;
+    use crate::format::ItemView;
+    use crate::Reader;
+    use std::io::Cursor;
+
+    #[test]
+    fn roundtrip() {
+        let mut buffer = Buffer::new();
+        buffer.add_item(5, 1, &[1, 2, 3]).unwrap();
+        buffer.add_item(2, 0, &[]).unwrap();
+        buffer.add_item(5, 0, &[-1]).unwrap();
+        buffer.add_item(0xffff, 7, &[i32::MAX]).unwrap();
+        buffer.add_data(b"hello".to_vec());
+        buffer.add_data(Vec::new());
+        buffer.add_data(vec![0; 4096]);
+
+        let mut file = Vec::new();
+        buffer.write(&mut file).unwrap();
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+
+        assert_eq!(reader.item_types().collect::<Vec<_>>(), [2, 5, 0xffff]);
+        assert_eq!(
+            reader.items().collect::<Vec<_>>(),
+            buffer.items().collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            reader.find_item(5, 1),
+            Some(ItemView {
+                type_id: 5,
+                id: 1,
+                data: &[1, 2, 3],
+            }),
+        );
+        let data: Vec<_> = reader.data_iter().map(Result::unwrap).collect();
+        assert_eq!(data, buffer.data_iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,169 @@
+//! Converting demos between demo versions.
+
+use std::io;
+use thiserror::Error;
+use warn::wrap;
+use warn::Warn;
+
+use crate::format;
+use crate::format::RawChunk;
+use crate::format::Version;
+use crate::ReadError;
+use crate::Reader;
+use crate::WriteError;
+use crate::Writer;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error(transparent)]
+    Write(#[from] WriteError),
+    #[error("Version 6 demos require the SHA256 of the map")]
+    MissingMapSha256,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Warning {
+    Demo(format::Warning),
+    /// Version 3 demos have no timeline markers.
+    DroppedTimelineMarkers,
+    /// Only version 6 demos store the SHA256 of the map.
+    DroppedMapSha256,
+    DroppedUnknownChunk,
+}
+
+impl From<format::Warning> for Warning {
+    fn from(w: format::Warning) -> Warning {
+        Warning::Demo(w)
+    }
+}
+
+/// Writes the demo of `reader` as a demo of version `version` to `file`.
+///
+/// Tick markers are re-encoded for the target version. Timeline markers and
+/// the map SHA256 are dropped with a warning if the target version cannot
+/// store them. Converting to version 6 requires the source demo to have a
+/// map SHA256.
+pub fn convert<T, W>(
+    reader: &mut Reader,
+    version: Version,
+    file: T,
+    warn: &mut W,
+) -> Result<(), Error>
+where
+    T: io::Write + io::Seek,
+    W: Warn<Warning>,
+{
+    let map_sha256 = reader.map_sha256();
+    match (version == Version::V6Ddnet, map_sha256.is_some()) {
+        (true, false) => return Err(Error::MissingMapSha256),
+        (false, true) => warn.warn(Warning::DroppedMapSha256),
+        _ => {}
+    }
+    reader.rewind()?;
+    let mut writer = Writer::with_version(
+        file,
+        version,
+        reader.net_version(),
+        reader.map_name(),
+        map_sha256,
+        reader.map_crc(),
+        reader.kind(),
+        reader.length(),
+        reader.timestamp(),
+        reader.map_data(),
+    )?;
+    if version < Version::V4 && !reader.timeline_markers().is_empty() {
+        warn.warn(Warning::DroppedTimelineMarkers);
+    }
+    for &marker in reader.timeline_markers() {
+        writer.add_timeline_marker(marker, wrap(warn));
+    }
+    while let Some(chunk) = reader.read_chunk(wrap(warn))? {
+        match chunk {
+            RawChunk::Unknown => warn.warn(Warning::DroppedUnknownChunk),
+            chunk => writer.write_chunk(chunk)?,
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::convert;
+    use super::Warning;
+    use crate::format::RawChunk;
+    use crate::DemoKind;
+    use crate::Reader;
+    use crate::Version;
+    use crate::Writer;
+    use libtw2_common::digest::Sha256;
+    use std::io::Cursor;
+
+    const TICKS: [i32; 6] = [1, 2, 40, 90, 200, 201];
+
+    fn ticks(reader: &mut Reader) -> Vec<i32> {
+        let mut result = Vec::new();
+        while let Some(chunk) = reader.read_chunk(&mut warn::Panic).unwrap() {
+            match chunk {
+                RawChunk::Tick { tick, .. } => result.push(tick),
+                RawChunk::Message(msg) => assert_eq!(msg, [1, 0, 0, 0]),
+                _ => panic!(),
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn versions() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = Writer::new(
+            &mut file,
+            b"0.6 626fce9a778df4d4",
+            b"dm1",
+            Some(Sha256([1; 32])),
+            0xf2159e6e,
+            DemoKind::Client,
+            0,
+            b"2024-01-01_00-00-00",
+            b"map",
+        )
+        .unwrap();
+        for (i, &tick) in TICKS.iter().enumerate() {
+            writer.write_tick(i == 0, tick).unwrap();
+            writer.write_message(&[1, 0, 0, 0]).unwrap();
+        }
+        writer.add_timeline_marker(40, &mut warn::Panic);
+        writer.finish().unwrap();
+        file.set_position(0);
+        let mut v6 = Reader::new(file, &mut warn::Panic).unwrap();
+
+        for &(version, ref expected) in &[
+            (
+                Version::V3,
+                &[Warning::DroppedMapSha256, Warning::DroppedTimelineMarkers][..],
+            ),
+            (Version::V4, &[Warning::DroppedMapSha256]),
+            (Version::V5, &[Warning::DroppedMapSha256]),
+            (Version::V6Ddnet, &[]),
+        ] {
+            let mut warnings = Vec::new();
+            let mut file = Cursor::new(Vec::new());
+            convert(&mut v6, version, &mut file, &mut warnings).unwrap();
+            assert_eq!(warnings, *expected);
+
+            file.set_position(0);
+            let mut reader = Reader::new(file, &mut warn::Panic).unwrap();
+            assert_eq!(reader.version(), version);
+            assert_eq!(reader.map_data(), b"map");
+            assert_eq!(reader.length(), 4);
+            let markers: &[i32] = if version == Version::V3 { &[] } else { &[40] };
+            assert_eq!(reader.timeline_markers(), markers);
+            let sha256 = reader.map_sha256().map(|s| s.0);
+            assert_eq!(sha256.is_some(), version == Version::V6Ddnet);
+            assert_eq!(ticks(&mut reader), TICKS);
+        }
+    }
+}
@@ -1,6 +1,8 @@
 mod reader;
 mod writer;
 
+pub(crate) use self::reader::read_snapshot;
+pub(crate) use self::reader::read_snapshot_delta;
 pub use self::reader::Chunk;
 pub use self::reader::DemoReader;
 pub use self::reader::ReadError;
@@ -37,6 +37,10 @@ pub enum ReadError {
     ChunkOrder,
 }
 
+/// Demo reader decoding snapshots and game messages with the protocol `P`.
+///
+/// Works for DDNet and 0.6 demos as well as Teeworlds 0.7 demos, see
+/// [`crate::Reader::is_teeworlds_0_7`].
 pub struct DemoReader<'a, P: for<'p> Protocol<'p>> {
     raw: reader::Reader<'a>,
     delta: Delta,
@@ -121,36 +125,112 @@ impl<'a, P: for<'p> Protocol<'p>> DemoReader<'a, P> {
                 }
             }
             Some(RawChunk::Snapshot(snap)) => {
-                let mut unpacker = Unpacker::new(snap);
-                let mut swap = Snap::empty();
-                mem::swap(&mut self.snap, &mut swap);
-                self.snap = self
-                    .snap_reader
-                    .read(wrap(warn), swap, &mut unpacker)
-                    .unwrap();
+                read_snapshot(warn, &mut self.snap_reader, &mut self.snap, snap)
+                    .map_err(ReadError::Snap)?;
                 self.snapshot.build::<P, _>(warn, &self.snap)?;
                 Ok(Some(Chunk::Snapshot(self.snapshot.objects.iter())))
             }
             Some(RawChunk::SnapshotDelta(dt)) => {
-                let mut unpacker = Unpacker::new(dt);
-                self.delta
-                    .read(wrap(warn), P::obj_size, &mut unpacker)
-                    .map_err(ReadError::Snap)?;
-                self.old_snap
-                    .read_with_delta(wrap(warn), &self.snap, &self.delta)
-                    .map_err(ReadError::Snap)?;
-                mem::swap(&mut self.old_snap, &mut self.snap);
+                read_snapshot_delta::<P, _>(
+                    warn,
+                    &mut self.delta,
+                    &mut self.snap,
+                    &mut self.old_snap,
+                    dt,
+                )
+                .map_err(ReadError::Snap)?;
                 self.snapshot.build::<P, _>(warn, &self.snap)?;
                 Ok(Some(Chunk::Snapshot(self.snapshot.objects.iter())))
             }
         }
     }
 
+    /// Moves the reader to `tick`, restoring the snapshot from the last
+    /// keyframe at or before it and applying the snapshot deltas up to it.
+    ///
+    /// Returns the last tick at or before `tick` and its snapshot, or `None`
+    /// if there is no keyframe before `tick`. Messages in between are
+    /// skipped. The next chunk read is the first one after that tick.
+    pub fn seek_to_tick<W: Warn<Warning>>(
+        &mut self,
+        tick: i32,
+        warn: &mut W,
+    ) -> Result<Option<(i32, slice::Iter<(P::SnapObj, u16)>)>, ReadError> {
+        if self.raw.seek_to_keyframe(tick, wrap(warn))?.is_none() {
+            return Ok(None);
+        }
+        self.snap = Snap::empty();
+        let mut current_tick = None;
+        loop {
+            let position = self.raw.position()?;
+            match self.raw.read_chunk(wrap(warn))? {
+                None => break,
+                Some(RawChunk::Tick { tick: t, .. }) => {
+                    if t > tick {
+                        self.raw.set_position(position)?;
+                        break;
+                    }
+                    current_tick = Some(t);
+                }
+                Some(RawChunk::Snapshot(snap)) => {
+                    read_snapshot(warn, &mut self.snap_reader, &mut self.snap, snap)
+                        .map_err(ReadError::Snap)?;
+                }
+                Some(RawChunk::SnapshotDelta(dt)) => {
+                    read_snapshot_delta::<P, _>(
+                        warn,
+                        &mut self.delta,
+                        &mut self.snap,
+                        &mut self.old_snap,
+                        dt,
+                    )
+                    .map_err(ReadError::Snap)?;
+                }
+                Some(RawChunk::Message(_)) | Some(RawChunk::Unknown) => {}
+            }
+        }
+        self.snapshot.build::<P, _>(warn, &self.snap)?;
+        Ok(current_tick.map(|t| (t, self.snapshot.objects.iter())))
+    }
+
     pub fn inner(&'a self) -> &'a reader::Reader {
         &self.raw
     }
 }
 
+/// Replaces `snap` with the snapshot in `data`.
+pub(crate) fn read_snapshot<W: Warn<Warning>>(
+    warn: &mut W,
+    snap_reader: &mut SnapReader,
+    snap: &mut Snap,
+    data: &[u8],
+) -> Result<(), snap::Error> {
+    let mut unpacker = Unpacker::new(data);
+    let old = mem::replace(snap, Snap::empty());
+    *snap = snap_reader.read(wrap(warn), old, &mut unpacker)?;
+    Ok(())
+}
+
+/// Applies the snapshot delta in `data` to `snap`, using `old_snap` as
+/// scratch space.
+pub(crate) fn read_snapshot_delta<P, W>(
+    warn: &mut W,
+    delta: &mut Delta,
+    snap: &mut Snap,
+    old_snap: &mut Snap,
+    data: &[u8],
+) -> Result<(), snap::Error>
+where
+    P: ProtocolStatic,
+    W: Warn<Warning>,
+{
+    let mut unpacker = Unpacker::new(data);
+    delta.read(wrap(warn), P::obj_size, &mut unpacker)?;
+    old_snap.read_with_delta(wrap(warn), snap, delta)?;
+    mem::swap(old_snap, snap);
+    Ok(())
+}
+
 struct Snapshot<T> {
     uuid_index: HashMap<u16, Uuid>,
     pub objects: Vec<(T, u16)>,
@@ -208,3 +288,95 @@ impl<T> Snapshot<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Chunk;
+    use super::DemoReader;
+    use crate::ddnet::DemoWriter;
+    use crate::DemoKind;
+    use libtw2_gamenet_ddnet::snap_obj::Flag;
+    use libtw2_gamenet_ddnet::Protocol;
+    use libtw2_gamenet_ddnet::SnapObj;
+    use std::io::Cursor;
+
+    fn flag(x: i32) -> SnapObj {
+        SnapObj::Flag(Flag {
+            x: x,
+            y: 0,
+            team: 0,
+        })
+    }
+
+    /// Returns the x coordinates of the flags, sorted by ID.
+    fn flags<'a>(objects: impl Iterator<Item = &'a (SnapObj, u16)>) -> Vec<(u16, i32)> {
+        let mut result: Vec<_> = objects
+            .map(|&(ref obj, id)| match *obj {
+                SnapObj::Flag(ref f) => (id, f.x),
+                _ => panic!(),
+            })
+            .collect();
+        result.sort_unstable();
+        result
+    }
+
+    #[test]
+    fn seek_to_tick() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = DemoWriter::<Protocol>::new(
+            &mut file,
+            b"0.6",
+            b"dm1",
+            None,
+            0,
+            DemoKind::Server,
+            0,
+            b"",
+            b"",
+        )
+        .unwrap();
+        // Keyframes are written at ticks 0, 300 and 600, the ticks in
+        // between are delta-encoded. Flag 0 changes every tick, flag 1 never
+        // changes and flag 2 is removed after tick 360.
+        for tick in (0..=600).step_by(60) {
+            let mut objects = vec![(flag(tick), 0), (flag(-1), 1)];
+            if tick <= 360 {
+                objects.push((flag(tick * 2), 2));
+            }
+            writer
+                .write_snap(tick, objects.iter().map(|&(ref o, id)| (o, id)))
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        file.set_position(0);
+        let mut reader = DemoReader::<Protocol>::new(file, &mut warn::Panic).unwrap();
+        let keyframes: Vec<i32> = reader
+            .raw
+            .keyframes(&mut warn::Panic)
+            .unwrap()
+            .iter()
+            .map(|k| k.tick)
+            .collect();
+        assert_eq!(keyframes, [0, 300, 600]);
+
+        assert!(reader.seek_to_tick(-1, &mut warn::Panic).unwrap().is_none());
+        let (tick, objects) = reader.seek_to_tick(450, &mut warn::Panic).unwrap().unwrap();
+        assert_eq!(tick, 420);
+        assert_eq!(flags(objects), [(0, 420), (1, -1)]);
+        // The next chunk is the one after the tick.
+        assert!(matches!(
+            reader.next_chunk(&mut warn::Panic).unwrap(),
+            Some(Chunk::Tick(480))
+        ));
+        match reader.next_chunk(&mut warn::Panic).unwrap() {
+            Some(Chunk::Snapshot(objects)) => assert_eq!(flags(objects), [(0, 480), (1, -1)]),
+            _ => panic!(),
+        }
+
+        // Seeking backwards into the first delta-encoded run.
+        let (tick, objects) = reader.seek_to_tick(180, &mut warn::Panic).unwrap().unwrap();
+        assert_eq!(tick, 180);
+        assert_eq!(flags(objects), [(0, 180), (1, -1), (2, 360)]);
+    }
+}
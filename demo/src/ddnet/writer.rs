@@ -14,6 +14,7 @@ use std::io;
 use std::marker::PhantomData;
 use std::mem;
 use thiserror::Error;
+use warn::Warn;
 
 #[derive(Debug, Error)]
 pub enum WriteError {
@@ -41,9 +42,11 @@ impl From<snap::BuilderError> for WriteError {
     }
 }
 
-/// DDNet demo writer.
+/// Demo writer encoding snapshots and game messages with the protocol `P`,
+/// e.g. DDNet or Teeworlds 0.7.
 ///
-/// Automatically writes snapshot deltas.
+/// Automatically writes snapshot deltas. The net version passed to
+/// [`DemoWriter::new`] should match the protocol.
 pub struct DemoWriter<'a, P: for<'p> Protocol<'p>> {
     inner: crate::Writer<'a>,
     // To verify the monotonic increase
@@ -54,7 +57,7 @@ pub struct DemoWriter<'a, P: for<'p> Protocol<'p>> {
     snap: Snap,
     builder: snap::Builder,
     delta: Delta,
-    buf: arrayvec::ArrayVec<[u8; format::MAX_SNAPSHOT_SIZE]>,
+    buf: Vec<u8>,
     i32_buf: Vec<i32>,
     protocol: PhantomData<P>,
 }
@@ -126,12 +129,21 @@ impl<'a, P: for<'p> Protocol<'p>> DemoWriter<'a, P> {
             snap: Snap::default(),
             delta: Delta::default(),
             builder: snap::Builder::default(),
-            buf: arrayvec::ArrayVec::new(),
+            buf: Vec::with_capacity(format::MAX_SNAPSHOT_SIZE),
             i32_buf: Vec::new(),
             protocol: PhantomData,
         })
     }
 
+    /// The demo readers can't handle chunks larger than
+    /// [`format::MAX_SNAPSHOT_SIZE`].
+    fn check_buf_size(&mut self, error: WriteError) -> Result<(), WriteError> {
+        if self.buf.len() > format::MAX_SNAPSHOT_SIZE {
+            self.buf.clear();
+            return Err(error);
+        }
+        Ok(())
+    }
     pub fn write_snap<'b, T: Iterator<Item = (&'b P::SnapObj, u16)>>(
         &mut self,
         tick: i32,
@@ -166,12 +178,14 @@ impl<'a, P: for<'p> Protocol<'p>> DemoWriter<'a, P> {
             let keys = &mut self.i32_buf;
             with_packer(&mut self.buf, |p| new_snap.write(keys, p))
                 .map_err(|_| WriteError::TooLargeSnap)?;
+            self.check_buf_size(WriteError::TooLargeSnap)?;
             self.inner.write_snapshot(&self.buf)?;
         } else {
             self.delta.create(&old_snap, &new_snap);
             let delta = &self.delta;
             with_packer(&mut self.buf, |p| delta.write(P::obj_size, p))
                 .map_err(|_| WriteError::TooLargeSnap)?;
+            self.check_buf_size(WriteError::TooLargeSnap)?;
             self.inner.write_snapshot_delta(&self.buf)?;
         }
 
@@ -189,8 +203,120 @@ impl<'a, P: for<'p> Protocol<'p>> DemoWriter<'a, P> {
     }
     pub fn write_msg(&mut self, msg: &<P as Protocol<'_>>::Game) -> Result<(), WriteError> {
         with_packer(&mut self.buf, |p| msg.encode(p)).map_err(|_| WriteError::TooLongNetMsg)?;
+        self.check_buf_size(WriteError::TooLongNetMsg)?;
         self.inner.write_message(self.buf.as_slice())?;
         self.buf.clear();
         Ok(())
     }
+    /// See [`crate::Writer::add_timeline_marker`].
+    pub fn add_timeline_marker<W: Warn<format::Warning>>(&mut self, tick: i32, warn: &mut W) {
+        self.inner.add_timeline_marker(tick, warn)
+    }
+    /// See [`crate::Writer::finish`].
+    pub fn finish(self) -> Result<(), WriteError> {
+        Ok(self.inner.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DemoWriter;
+    use super::WriteError;
+    use crate::ddnet::Chunk;
+    use crate::ddnet::DemoReader;
+    use crate::DemoKind;
+    use libtw2_gamenet_teeworlds_0_7::enums::Chat;
+    use libtw2_gamenet_teeworlds_0_7::enums::VERSION;
+    use libtw2_gamenet_teeworlds_0_7::msg::game::SvChat;
+    use libtw2_gamenet_teeworlds_0_7::msg::Game;
+    use libtw2_gamenet_teeworlds_0_7::snap_obj::DeGameInfo;
+    use libtw2_gamenet_teeworlds_0_7::Protocol;
+    use libtw2_gamenet_teeworlds_0_7::SnapObj;
+    use std::io::Cursor;
+
+    #[test]
+    fn teeworlds_0_7() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = DemoWriter::<Protocol>::new(
+            &mut file,
+            VERSION.as_bytes(),
+            b"ctf1",
+            None,
+            0,
+            DemoKind::Client,
+            0,
+            b"",
+            b"",
+        )
+        .unwrap();
+        let info = SnapObj::DeGameInfo(DeGameInfo {
+            game_flags: 1,
+            score_limit: 500,
+            time_limit: 0,
+            match_num: 0,
+            match_current: 1,
+        });
+        writer.write_snap(1, [(&info, 0)].iter().cloned()).unwrap();
+        let chat = Game::SvChat(SvChat {
+            mode: Chat::Whisper,
+            client_id: 3,
+            target_id: 5,
+            message: b"hello",
+        });
+        writer.write_msg(&chat).unwrap();
+        writer.finish().unwrap();
+
+        file.set_position(0);
+        let mut reader = DemoReader::<Protocol>::new(file, &mut warn::Panic).unwrap();
+        assert!(matches!(
+            reader.next_chunk(&mut warn::Panic).unwrap(),
+            Some(Chunk::Tick(1))
+        ));
+        match reader.next_chunk(&mut warn::Panic).unwrap() {
+            Some(Chunk::Snapshot(mut objects)) => match objects.next() {
+                Some(&(SnapObj::DeGameInfo(ref i), 0)) => assert_eq!(i.score_limit, 500),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+        match reader.next_chunk(&mut warn::Panic).unwrap() {
+            Some(Chunk::Message(Game::SvChat(c))) => {
+                assert_eq!((c.client_id, c.target_id), (3, 5));
+                assert_eq!(c.message, b"hello");
+            }
+            _ => panic!(),
+        }
+        assert!(reader.next_chunk(&mut warn::Panic).unwrap().is_none());
+        assert!(reader.inner().is_teeworlds_0_7());
+    }
+
+    #[test]
+    fn too_large_snap() {
+        use libtw2_gamenet_ddnet::snap_obj::Flag;
+        use libtw2_gamenet_ddnet::Protocol;
+        use libtw2_gamenet_ddnet::SnapObj;
+
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = DemoWriter::<Protocol>::new(
+            &mut file,
+            b"0.6",
+            b"dm1",
+            None,
+            0,
+            DemoKind::Server,
+            0,
+            b"",
+            b"",
+        )
+        .unwrap();
+        // Fits into the snapshot builder, but not into a demo chunk once
+        // packed.
+        let flag = SnapObj::Flag(Flag {
+            x: i32::MAX,
+            y: i32::MAX,
+            team: 0,
+        });
+        let result = writer.write_snap(0, (0..10000).map(|id| (&flag, id)));
+        assert!(matches!(result, Err(WriteError::TooLargeSnap)));
+    }
 }
@@ -0,0 +1,332 @@
+//! Cutting and concatenating demos.
+
+use libtw2_gamenet_common::traits::ProtocolStatic;
+use libtw2_packer::with_packer;
+use libtw2_snapshot::snap;
+use libtw2_snapshot::Delta;
+use libtw2_snapshot::Snap;
+use libtw2_snapshot::SnapReader;
+use std::marker::PhantomData;
+use thiserror::Error;
+use warn::wrap;
+use warn::Warn;
+
+use crate::ddnet;
+use crate::ddnet::Warning;
+use crate::format::RawChunk;
+use crate::format::MAX_SNAPSHOT_SIZE;
+use crate::ReadError;
+use crate::Reader;
+use crate::WriteError;
+use crate::Writer;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error(transparent)]
+    Write(#[from] WriteError),
+    #[error("Snap parsing - {0:?}")]
+    Snap(snap::Error),
+    #[error("Snap data does not fit into buffer")]
+    TooLargeSnap,
+    #[error("Demos were recorded on different maps")]
+    DifferentMap,
+}
+
+impl From<snap::Error> for Error {
+    fn from(err: snap::Error) -> Error {
+        Error::Snap(err)
+    }
+}
+
+/// First and last tick copied by `Copier::copy`, and the offset added to
+/// their tick numbers.
+#[derive(Clone, Copy)]
+struct Copied {
+    first: i32,
+    last: i32,
+    offset: i32,
+}
+
+struct Copier<P: ProtocolStatic> {
+    snap_reader: SnapReader,
+    delta: Delta,
+    snap: Snap,
+    old_snap: Snap,
+    buf: Vec<u8>,
+    keys: Vec<i32>,
+    protocol: PhantomData<P>,
+}
+
+impl<P: ProtocolStatic> Copier<P> {
+    fn new() -> Copier<P> {
+        Copier {
+            snap_reader: SnapReader::new(),
+            delta: Delta::new(),
+            snap: Snap::empty(),
+            old_snap: Snap::empty(),
+            buf: Vec::with_capacity(MAX_SNAPSHOT_SIZE),
+            keys: Vec::new(),
+            protocol: PhantomData,
+        }
+    }
+    /// Copies the ticks from `start` to `end` (inclusive) from the current
+    /// position of `reader` to `writer`.
+    ///
+    /// The first tick copied is written as a keyframe with a full snapshot.
+    /// If `prev_tick` is given, the ticks are renumbered to follow it.
+    fn copy<W: Warn<Warning>>(
+        &mut self,
+        warn: &mut W,
+        reader: &mut Reader,
+        writer: &mut Writer,
+        start: i32,
+        end: i32,
+        prev_tick: Option<i32>,
+    ) -> Result<Option<Copied>, Error> {
+        self.snap = Snap::empty();
+        let mut copied: Option<Copied> = None;
+        let mut in_range = false;
+        let mut need_snapshot = true;
+        while let Some(chunk) = reader.read_chunk(wrap(warn))? {
+            match chunk {
+                RawChunk::Tick { tick, keyframe } => {
+                    if tick > end {
+                        break;
+                    }
+                    in_range = tick >= start;
+                    if in_range {
+                        let c = copied.get_or_insert(Copied {
+                            first: tick,
+                            last: tick,
+                            offset: prev_tick.map(|p| p + 1 - tick).unwrap_or(0),
+                        });
+                        c.last = tick;
+                        writer.write_tick(keyframe || need_snapshot, tick + c.offset)?;
+                    }
+                }
+                RawChunk::Snapshot(data) => {
+                    ddnet::read_snapshot(warn, &mut self.snap_reader, &mut self.snap, data)?;
+                    if in_range {
+                        writer.write_snapshot(data)?;
+                        need_snapshot = false;
+                    }
+                }
+                RawChunk::SnapshotDelta(data) => {
+                    ddnet::read_snapshot_delta::<P, _>(
+                        warn,
+                        &mut self.delta,
+                        &mut self.snap,
+                        &mut self.old_snap,
+                        data,
+                    )?;
+                    if in_range {
+                        if need_snapshot {
+                            // Deltas are relative to snapshots that weren't
+                            // copied, so write the full snapshot instead.
+                            self.buf.clear();
+                            let keys = &mut self.keys;
+                            let snap = &self.snap;
+                            with_packer(&mut self.buf, |p| snap.write(keys, p))
+                                .map_err(|_| Error::TooLargeSnap)?;
+                            writer.write_snapshot(&self.buf)?;
+                            need_snapshot = false;
+                        } else {
+                            writer.write_snapshot_delta(data)?;
+                        }
+                    }
+                }
+                RawChunk::Message(msg) => {
+                    if in_range {
+                        writer.write_message(msg)?;
+                    }
+                }
+                RawChunk::Unknown => {}
+            }
+        }
+        Ok(copied)
+    }
+}
+
+/// Writes the ticks from `start_tick` to `end_tick` (inclusive) of `reader`
+/// as a standalone demo to `writer`.
+///
+/// Tick numbers are kept, timeline markers outside of the cut are dropped.
+/// The writer still needs to be finished with [`Writer::finish`].
+pub fn cut<P, W>(
+    reader: &mut Reader,
+    start_tick: i32,
+    end_tick: i32,
+    writer: &mut Writer,
+    warn: &mut W,
+) -> Result<(), Error>
+where
+    P: ProtocolStatic,
+    W: Warn<Warning>,
+{
+    reader.rewind()?;
+    reader.seek_to_keyframe(start_tick, wrap(warn))?;
+    let copied = Copier::<P>::new().copy(warn, reader, writer, start_tick, end_tick, None)?;
+    if let Some(copied) = copied {
+        let markers = reader.timeline_markers().iter().cloned();
+        for marker in markers.filter(|&m| copied.first <= m && m <= copied.last) {
+            writer.add_timeline_marker(marker, wrap(warn));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the demos of `readers` one after another to `writer`.
+///
+/// The demos must have been recorded on the same map. The ticks of each demo
+/// are renumbered to follow the ones of the previous demo, the timeline
+/// markers are moved along with them. The writer still needs to be finished
+/// with [`Writer::finish`].
+pub fn concat<P, W>(readers: &mut [Reader], writer: &mut Writer, warn: &mut W) -> Result<(), Error>
+where
+    P: ProtocolStatic,
+    W: Warn<Warning>,
+{
+    if let Some((first, rest)) = readers.split_first() {
+        for r in rest {
+            if r.map_name() != first.map_name()
+                || r.map_crc() != first.map_crc()
+                || r.map_sha256().map(|s| s.0) != first.map_sha256().map(|s| s.0)
+            {
+                return Err(Error::DifferentMap);
+            }
+        }
+    }
+    let mut copier = Copier::<P>::new();
+    let mut prev_tick = None;
+    for reader in readers {
+        reader.rewind()?;
+        let copied = copier.copy(warn, reader, writer, i32::MIN, i32::MAX, prev_tick)?;
+        if let Some(copied) = copied {
+            let markers = reader.timeline_markers().iter().cloned();
+            for marker in markers.filter(|&m| copied.first <= m && m <= copied.last) {
+                writer.add_timeline_marker(marker + copied.offset, wrap(warn));
+            }
+            prev_tick = Some(copied.last + copied.offset);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::concat;
+    use super::cut;
+    use crate::ddnet::Chunk;
+    use crate::ddnet::DemoReader;
+    use crate::format::MAX_SNAPSHOT_SIZE;
+    use crate::DemoKind;
+    use crate::Reader;
+    use crate::Writer;
+    use libtw2_gamenet_ddnet::snap_obj;
+    use libtw2_gamenet_ddnet::Protocol;
+    use libtw2_gamenet_ddnet::SnapObj;
+    use libtw2_packer::with_packer;
+    use libtw2_snapshot::snap;
+    use libtw2_snapshot::Delta;
+    use libtw2_snapshot::Snap;
+    use std::io::Cursor;
+
+    /// Returns the demo written by `f`.
+    fn edit<F: FnOnce(&mut Writer)>(f: F) -> Cursor<Vec<u8>> {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = Writer::new(
+            &mut file,
+            b"0.6",
+            b"dm1",
+            None,
+            0,
+            DemoKind::Server,
+            0,
+            b"",
+            b"",
+        )
+        .unwrap();
+        f(&mut writer);
+        writer.finish().unwrap();
+        file.set_position(0);
+        file
+    }
+
+    /// Writes a demo with a flag snapshot at `ticks`, whose x coordinate
+    /// is the tick. Only the first tick is a keyframe.
+    fn record(ticks: impl Iterator<Item = i32>, markers: &[i32]) -> Cursor<Vec<u8>> {
+        edit(|writer| {
+            let mut prev = Snap::empty();
+            let mut delta = Delta::new();
+            let mut buf = Vec::with_capacity(MAX_SNAPSHOT_SIZE);
+            let mut keys = Vec::new();
+            for (i, tick) in ticks.enumerate() {
+                let mut builder = snap::Builder::new();
+                builder.add_item(snap_obj::FLAG, 0, &[tick, 0, 0]).unwrap();
+                let snap = builder.finish();
+                buf.clear();
+                writer.write_tick(i == 0, tick).unwrap();
+                if i == 0 {
+                    with_packer(&mut buf, |p| snap.write(&mut keys, p)).unwrap();
+                    writer.write_snapshot(&buf).unwrap();
+                } else {
+                    delta.create(&prev, &snap);
+                    with_packer(&mut buf, |p| delta.write(snap_obj::obj_size, p)).unwrap();
+                    writer.write_snapshot_delta(&buf).unwrap();
+                }
+                prev = snap;
+            }
+            for &marker in markers {
+                writer.add_timeline_marker(marker, &mut warn::Panic);
+            }
+        })
+    }
+
+    /// Returns the ticks of the demo with the x coordinate of the flag.
+    fn snapshots(file: Cursor<Vec<u8>>) -> (Vec<(i32, i32)>, Vec<i32>) {
+        let mut reader = DemoReader::<Protocol>::new(file, &mut warn::Panic).unwrap();
+        let mut result = Vec::new();
+        let mut tick = None;
+        while let Some(chunk) = reader.next_chunk(&mut warn::Panic).unwrap() {
+            match chunk {
+                Chunk::Tick(t) => tick = Some(t),
+                Chunk::Snapshot(mut objects) => match objects.next() {
+                    Some(&(SnapObj::Flag(ref f), 0)) => result.push((tick.unwrap(), f.x)),
+                    _ => panic!(),
+                },
+                _ => panic!(),
+            }
+        }
+        (result, reader.inner().timeline_markers().to_vec())
+    }
+
+    #[test]
+    fn cut_ticks() {
+        let file = record(1..21, &[5, 12, 18]);
+        let file = edit(|writer| {
+            let mut reader = Reader::new(file, &mut warn::Panic).unwrap();
+            cut::<Protocol, _>(&mut reader, 10, 15, writer, &mut warn::Panic).unwrap();
+        });
+        let (snapshots, markers) = snapshots(file);
+        assert_eq!(snapshots, (10..16).map(|t| (t, t)).collect::<Vec<_>>());
+        assert_eq!(markers, [12]);
+    }
+
+    #[test]
+    fn concat_demos() {
+        let files = vec![record(1..11, &[5]), record(101..111, &[108])];
+        let file = edit(|writer| {
+            let mut readers = Vec::new();
+            for file in files {
+                readers.push(Reader::new(file, &mut warn::Panic).unwrap());
+            }
+            concat::<Protocol, _>(&mut readers, writer, &mut warn::Panic).unwrap();
+        });
+        let (snapshots, markers) = snapshots(file);
+        let expected: Vec<_> = (1..21).zip((1..11).chain(101..111)).collect();
+        assert_eq!(snapshots, expected);
+        assert_eq!(markers, [5, 18]);
+    }
+}
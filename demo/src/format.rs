@@ -40,6 +40,7 @@ impl From<libtw2_packer::Warning> for Warning {
 pub enum Version {
     V3 = 3,
     V4 = 4,
+    /// Also used by Teeworlds 0.7, distinguishable by the net version.
     V5 = 5,
     V6Ddnet = 6,
 }
@@ -313,7 +314,6 @@ impl ChunkHeader {
     where
         W: io::Write + io::Seek,
     {
-        assert!(version >= Version::V5);
         match *self {
             ChunkHeader::Tick {
                 marker: TickMarker::Delta(dt),
@@ -321,7 +321,13 @@ impl ChunkHeader {
             } => {
                 assert!(dt <= version.max_tick_delta());
                 assert!(!keyframe);
-                let flags: u8 = CHUNKTYPEFLAG_TICKMARKER | CHUNKTICKFLAG_INLINETICK | dt;
+                let flags: u8 = if version >= Version::V5 {
+                    CHUNKTYPEFLAG_TICKMARKER | CHUNKTICKFLAG_INLINETICK | dt
+                } else {
+                    // A zero delta means an absolute tick before V5.
+                    assert!(dt != 0);
+                    CHUNKTYPEFLAG_TICKMARKER | dt
+                };
                 flags.write(file)?;
             }
             ChunkHeader::Tick {
@@ -1,4 +1,6 @@
+pub mod convert;
 pub mod ddnet;
+pub mod edit;
 mod format;
 mod reader;
 mod writer;
@@ -7,6 +9,7 @@ pub use self::format::DemoKind;
 pub use self::format::RawChunk;
 pub use self::format::Version;
 pub use self::format::Warning;
+pub use self::reader::Keyframe;
 pub use self::reader::ReadError;
 pub use self::reader::Reader;
 pub use self::writer::WriteError;
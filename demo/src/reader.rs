@@ -9,6 +9,8 @@ use thiserror::Error;
 use warn::wrap;
 use warn::Warn;
 
+use crate::format::ChunkHeader;
+use crate::format::DataKind;
 use crate::format::TickMarker;
 use crate::format::Warning;
 use crate::format::MAX_SNAPSHOT_SIZE;
@@ -45,9 +47,28 @@ impl ReadError {
 trait SeekableRead: io::Read + io::Seek {}
 impl<T: io::Read + io::Seek> SeekableRead for T {}
 
+/// Position of a keyframe in the demo file.
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe {
+    pub tick: i32,
+    /// Offset of the keyframe's tick chunk in the file.
+    pub offset: u64,
+    // Needed to read a delta-encoded keyframe tick.
+    prev_tick: Option<i32>,
+}
+
+/// Position of the reader, used to return to a chunk.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Position {
+    offset: u64,
+    current_tick: Option<i32>,
+}
+
 pub struct Reader<'a> {
     data: Box<dyn SeekableRead + 'a>,
     start: format::HeaderStart,
+    chunks_start: u64,
+    keyframes: Option<Vec<Keyframe>>,
     current_tick: Option<i32>,
     raw: [u8; MAX_SNAPSHOT_SIZE],
     huffman: ArrayVec<[u8; MAX_SNAPSHOT_SIZE]>,
@@ -62,9 +83,12 @@ impl<'a> Reader<'a> {
         let start = format::HeaderStart::read(&mut data)?;
         start.header.check(warn);
         start.timeline_markers.check(warn);
+        let chunks_start = data.stream_position()?;
         Ok(Self {
             data: Box::new(data),
             start: start,
+            chunks_start: chunks_start,
+            keyframes: None,
             current_tick: None,
             raw: [0; MAX_SNAPSHOT_SIZE],
             huffman: ArrayVec::new(),
@@ -97,6 +121,11 @@ impl<'a> Reader<'a> {
     pub fn timestamp(&self) -> &[u8] {
         self.start.header.timestamp.raw()
     }
+    /// Whether the demo was recorded with the Teeworlds 0.7 protocol,
+    /// according to its net version.
+    pub fn is_teeworlds_0_7(&self) -> bool {
+        self.net_version().starts_with(b"0.7 ")
+    }
     pub fn timeline_markers(&self) -> &[i32] {
         self.start.timeline_markers.markers()
     }
@@ -106,12 +135,131 @@ impl<'a> Reader<'a> {
             .as_ref()
             .map(|sha| Sha256(sha.sha_256))
     }
+    fn advance_tick(&mut self, marker: TickMarker) -> Result<i32, ReadError> {
+        let tick = match marker {
+            TickMarker::Absolute(t) => {
+                if let Some(previous) = self.current_tick {
+                    if previous >= t {
+                        return Err(ReadError::NotIncreasingTick);
+                    }
+                }
+                t
+            }
+            TickMarker::Delta(d) => match self.current_tick {
+                None => return Err(ReadError::StartingDeltaSnapshot),
+                Some(t) => t.checked_add(d.i32()).ok_or(ReadError::TickOverflow)?,
+            },
+        };
+        self.current_tick = Some(tick);
+        Ok(tick)
+    }
+    pub(crate) fn position(&mut self) -> Result<Position, ReadError> {
+        Ok(Position {
+            offset: self.data.stream_position()?,
+            current_tick: self.current_tick,
+        })
+    }
+    pub(crate) fn set_position(&mut self, position: Position) -> Result<(), ReadError> {
+        self.data.seek(io::SeekFrom::Start(position.offset))?;
+        self.current_tick = position.current_tick;
+        Ok(())
+    }
+    /// Moves the reader back to the first chunk.
+    pub(crate) fn rewind(&mut self) -> Result<(), ReadError> {
+        self.set_position(Position {
+            offset: self.chunks_start,
+            current_tick: None,
+        })
+    }
+    /// Returns the keyframes of the demo, sorted by tick.
+    ///
+    /// The first call reads through the whole demo to build the index,
+    /// without decompressing the chunks. A truncated final chunk ends the
+    /// index. Doesn't change the position of the reader.
+    pub fn keyframes<W>(&mut self, warn: &mut W) -> Result<&[Keyframe], ReadError>
+    where
+        W: Warn<Warning>,
+    {
+        if self.keyframes.is_none() {
+            let keyframes = self.index_keyframes(warn)?;
+            self.keyframes = Some(keyframes);
+        }
+        Ok(self.keyframes.as_ref().unwrap())
+    }
+    fn index_keyframes<W>(&mut self, warn: &mut W) -> Result<Vec<Keyframe>, ReadError>
+    where
+        W: Warn<Warning>,
+    {
+        let position = self.position()?;
+        let result = self.index_keyframes_impl(warn);
+        self.set_position(position)?;
+        result
+    }
+    fn index_keyframes_impl<W>(&mut self, warn: &mut W) -> Result<Vec<Keyframe>, ReadError>
+    where
+        W: Warn<Warning>,
+    {
+        let end = self.data.seek(io::SeekFrom::End(0))?;
+        self.rewind()?;
+        let mut keyframes = Vec::new();
+        loop {
+            let offset = self.data.stream_position()?;
+            let chunk_header = match ChunkHeader::read(&mut self.data, self.start.version, warn) {
+                Ok(ch) => ch,
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            match chunk_header {
+                None => break,
+                Some(ChunkHeader::Tick { marker, keyframe }) => {
+                    let prev_tick = self.current_tick;
+                    let tick = self.advance_tick(marker)?;
+                    if keyframe {
+                        keyframes.push(Keyframe {
+                            tick: tick,
+                            offset: offset,
+                            prev_tick: prev_tick,
+                        });
+                    }
+                }
+                // Like `read_chunk`, don't skip the data of unknown chunks.
+                Some(ChunkHeader::Data {
+                    kind: DataKind::Unknown,
+                    ..
+                }) => {}
+                Some(ChunkHeader::Data { size, .. }) => {
+                    if self.data.seek(io::SeekFrom::Current(size.into()))? > end {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(keyframes)
+    }
+    /// Moves the reader to the last keyframe at or before `tick`.
+    ///
+    /// The next chunk read is the tick chunk of that keyframe. Returns the
+    /// tick of the keyframe, or `None` if there is no such keyframe, in which
+    /// case the position of the reader is unchanged.
+    pub fn seek_to_keyframe<W>(&mut self, tick: i32, warn: &mut W) -> Result<Option<i32>, ReadError>
+    where
+        W: Warn<Warning>,
+    {
+        let keyframes = self.keyframes(warn)?;
+        let keyframe = match keyframes.iter().rev().find(|k| k.tick <= tick) {
+            Some(&k) => k,
+            None => return Ok(None),
+        };
+        self.set_position(Position {
+            offset: keyframe.offset,
+            current_tick: keyframe.prev_tick,
+        })?;
+        Ok(Some(keyframe.tick))
+    }
     pub fn read_chunk<W>(&mut self, warn: &mut W) -> Result<Option<format::RawChunk>, ReadError>
     where
         W: Warn<Warning>,
     {
-        use crate::format::ChunkHeader;
-        use crate::format::DataKind;
         use crate::format::RawChunk;
 
         let chunk_header = match ChunkHeader::read(&mut self.data, self.start.version, warn)? {
@@ -119,37 +267,13 @@ impl<'a> Reader<'a> {
             None => return Ok(None),
         };
         match chunk_header {
-            ChunkHeader::Tick {
-                marker: TickMarker::Absolute(t),
-                keyframe,
-            } => {
-                if let Some(previous) = self.current_tick {
-                    if previous >= t {
-                        return Err(ReadError::NotIncreasingTick);
-                    }
-                }
-                self.current_tick = Some(t);
+            ChunkHeader::Tick { marker, keyframe } => {
+                let tick = self.advance_tick(marker)?;
                 Ok(Some(RawChunk::Tick {
-                    tick: t,
+                    tick: tick,
                     keyframe: keyframe,
                 }))
             }
-            ChunkHeader::Tick {
-                marker: TickMarker::Delta(d),
-                keyframe,
-            } => match self.current_tick {
-                None => Err(ReadError::StartingDeltaSnapshot),
-                Some(t) => match t.checked_add(d.i32()) {
-                    None => Err(ReadError::TickOverflow),
-                    Some(new_t) => {
-                        self.current_tick = Some(new_t);
-                        Ok(Some(RawChunk::Tick {
-                            tick: new_t,
-                            keyframe: keyframe,
-                        }))
-                    }
-                },
-            },
             ChunkHeader::Data { kind, size } => {
                 if kind == DataKind::Unknown {
                     return Ok(Some(RawChunk::Unknown));
@@ -183,3 +307,105 @@ impl<'a> Reader<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Reader;
+    use crate::format::RawChunk;
+    use crate::DemoKind;
+    use crate::Writer;
+    use std::io::Cursor;
+
+    /// Writes a demo with ticks 1 to 19, each followed by a message
+    /// containing the tick. Every fifth tick is a keyframe.
+    fn record() -> Cursor<Vec<u8>> {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = Writer::new(
+            &mut file,
+            b"0.6 626fce9a778df4d4",
+            b"dm1",
+            None,
+            0,
+            DemoKind::Server,
+            0,
+            b"",
+            b"",
+        )
+        .unwrap();
+        for tick in 1..20 {
+            writer.write_tick(tick % 5 == 0, tick).unwrap();
+            writer.write_message(&tick.to_le_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        file.set_position(0);
+        file
+    }
+
+    fn keyframe_ticks(reader: &mut Reader) -> Vec<i32> {
+        reader
+            .keyframes(&mut warn::Panic)
+            .unwrap()
+            .iter()
+            .map(|k| k.tick)
+            .collect()
+    }
+
+    #[test]
+    fn seek_to_keyframe() {
+        let mut reader = Reader::new(record(), &mut warn::Panic).unwrap();
+        assert_eq!(keyframe_ticks(&mut reader), [5, 10, 15]);
+        // Building the index doesn't move the reader.
+        assert!(matches!(
+            reader.read_chunk(&mut warn::Panic).unwrap(),
+            Some(RawChunk::Tick { tick: 1, .. })
+        ));
+
+        assert_eq!(reader.seek_to_keyframe(4, &mut warn::Panic).unwrap(), None);
+        assert_eq!(
+            reader.seek_to_keyframe(14, &mut warn::Panic).unwrap(),
+            Some(10)
+        );
+        assert!(matches!(
+            reader.read_chunk(&mut warn::Panic).unwrap(),
+            Some(RawChunk::Tick {
+                tick: 10,
+                keyframe: true
+            })
+        ));
+        match reader.read_chunk(&mut warn::Panic).unwrap() {
+            Some(RawChunk::Message(msg)) => assert_eq!(msg, 10i32.to_le_bytes()),
+            _ => panic!(),
+        }
+        // Delta-encoded ticks after the keyframe still work.
+        assert!(matches!(
+            reader.read_chunk(&mut warn::Panic).unwrap(),
+            Some(RawChunk::Tick { tick: 11, .. })
+        ));
+    }
+
+    #[test]
+    fn keyframes_truncated() {
+        // Cut the demo in the middle of the absolute tick of the last
+        // keyframe.
+        let mut file = record();
+        let mut reader = Reader::new(&mut file, &mut warn::Panic).unwrap();
+        let offset = reader.keyframes(&mut warn::Panic).unwrap()[2].offset;
+        drop(reader);
+        let mut data = file.into_inner();
+        data.truncate(offset as usize + 3);
+        let mut reader = Reader::new(Cursor::new(data), &mut warn::Panic).unwrap();
+        assert!(matches!(
+            reader.read_chunk(&mut warn::Panic).unwrap(),
+            Some(RawChunk::Tick { tick: 1, .. })
+        ));
+        assert_eq!(keyframe_ticks(&mut reader), [5, 10]);
+        match reader.read_chunk(&mut warn::Panic).unwrap() {
+            Some(RawChunk::Message(msg)) => assert_eq!(msg, 1i32.to_le_bytes()),
+            _ => panic!(),
+        }
+        assert_eq!(
+            reader.seek_to_keyframe(19, &mut warn::Panic).unwrap(),
+            Some(10)
+        );
+    }
+}
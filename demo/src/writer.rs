@@ -1,4 +1,3 @@
-use arrayvec::ArrayVec;
 use binrw::BinWrite;
 use libtw2_common::digest::Sha256;
 use libtw2_common::num::Cast;
@@ -6,6 +5,7 @@ use libtw2_huffman::instances::TEEWORLDS as HUFFMAN;
 use libtw2_packer::with_packer;
 use std::io;
 use thiserror::Error;
+use warn::Warn;
 
 use crate::format::CappedString;
 use crate::format::ChunkHeader;
@@ -17,6 +17,7 @@ use crate::format::RawChunk;
 use crate::format::TickMarker;
 use crate::format::TimelineMarkers;
 use crate::format::Version;
+use crate::format::Warning;
 use crate::format::MAX_SNAPSHOT_SIZE;
 
 #[derive(Error, Debug)]
@@ -34,19 +35,33 @@ impl WriteError {
 
 pub struct Writer<'a> {
     file: Box<dyn SeekableWrite + 'a>,
+    start: u64,
+    version: Version,
     header: Header,
+    timeline_markers: TimelineMarkers,
+    first_tick: Option<i32>,
     prev_tick: Option<i32>,
-    huffman: ArrayVec<[u8; MAX_SNAPSHOT_SIZE]>,
-    buffer2: ArrayVec<[u8; MAX_SNAPSHOT_SIZE]>,
+    huffman: Vec<u8>,
+    buffer2: Vec<u8>,
 }
 
 const WRITER_VERSION: Version = Version::V5;
 const WRITER_VERSION_DDNET: Version = Version::V6Ddnet;
 
+const TICKS_PER_SECOND: i32 = 50;
+const MAX_TIMELINE_MARKERS: usize = 64;
+
 pub(crate) trait SeekableWrite: io::Write + io::Seek {}
 impl<T: io::Write + io::Seek> SeekableWrite for T {}
 
 impl<'a> Writer<'a> {
+    /// Starts writing a demo.
+    ///
+    /// The demo is written as version 6 if `map_sha256` is given, as
+    /// version 5 otherwise.
+    ///
+    /// `length` is only kept if the writer isn't finished using
+    /// [`Writer::finish`], which replaces it with the actual length.
     pub fn new<W: io::Write + io::Seek + 'a>(
         file: W,
         net_version: &[u8],
@@ -58,8 +73,46 @@ impl<'a> Writer<'a> {
         timestamp: &[u8],
         map: &[u8],
     ) -> Result<Writer<'a>, WriteError> {
+        let version = if map_sha256.is_some() {
+            WRITER_VERSION_DDNET
+        } else {
+            WRITER_VERSION
+        };
+        Writer::with_version(
+            file,
+            version,
+            net_version,
+            map_name,
+            map_sha256,
+            map_crc,
+            kind,
+            length,
+            timestamp,
+            map,
+        )
+    }
+    /// Starts writing a demo of the given version.
+    ///
+    /// `map_sha256` is only stored in version 6 demos and must be given for
+    /// them. Timeline markers are only stored from version 4 on.
+    pub fn with_version<W: io::Write + io::Seek + 'a>(
+        mut file: W,
+        version: Version,
+        net_version: &[u8],
+        map_name: &[u8],
+        map_sha256: Option<Sha256>,
+        map_crc: u32,
+        kind: DemoKind,
+        length: i32,
+        timestamp: &[u8],
+        map: &[u8],
+    ) -> Result<Writer<'a>, WriteError> {
+        assert!(version != Version::V6Ddnet || map_sha256.is_some());
+        let start = file.stream_position().map_err(binrw::Error::Io)?;
         let mut writer = Writer {
             file: Box::new(file),
+            start: start,
+            version: version,
             header: Header {
                 net_version: CappedString::from_raw(net_version),
                 map_name: CappedString::from_raw(map_name),
@@ -69,30 +122,25 @@ impl<'a> Writer<'a> {
                 length,
                 timestamp: CappedString::from_raw(timestamp),
             },
+            timeline_markers: TimelineMarkers::default(),
+            first_tick: None,
             prev_tick: None,
-            huffman: ArrayVec::new(),
-            buffer2: ArrayVec::new(),
+            huffman: Vec::with_capacity(MAX_SNAPSHOT_SIZE),
+            buffer2: Vec::with_capacity(MAX_SNAPSHOT_SIZE),
         };
-        writer.write_header(map_sha256.is_some())?;
-        TimelineMarkers {
-            amount: 0,
-            markers: [0; 64],
-        }
-        .write(&mut writer.file)?;
-        if let Some(sha256) = map_sha256 {
-            MapSha256::new(sha256).write_le(&mut writer.file)?;
+        writer.write_header()?;
+        if version == Version::V6Ddnet {
+            MapSha256::new(map_sha256.unwrap()).write_le(&mut writer.file)?;
         }
         map.write(&mut writer.file)?;
         Ok(writer)
     }
-    fn write_header(&mut self, ddnet: bool) -> Result<(), WriteError> {
-        let version = if ddnet {
-            WRITER_VERSION_DDNET
-        } else {
-            WRITER_VERSION
-        };
-        version.write(&mut self.file)?;
+    fn write_header(&mut self) -> Result<(), WriteError> {
+        self.version.write(&mut self.file)?;
         self.header.write(&mut self.file)?;
+        if self.version >= Version::V4 {
+            self.timeline_markers.write(&mut self.file)?;
+        }
         Ok(())
     }
     pub fn write_chunk(&mut self, chunk: RawChunk) -> Result<(), WriteError> {
@@ -105,26 +153,53 @@ impl<'a> Writer<'a> {
         }
     }
     pub fn write_tick(&mut self, keyframe: bool, tick: i32) -> Result<(), WriteError> {
-        let tm = TickMarker::new(tick, self.prev_tick, keyframe, WRITER_VERSION);
+        let tm = TickMarker::new(tick, self.prev_tick, keyframe, self.version);
         ChunkHeader::Tick {
             marker: tm,
             keyframe: keyframe,
         }
-        .write(&mut self.file, WRITER_VERSION)?;
+        .write(&mut self.file, self.version)?;
+        self.first_tick.get_or_insert(tick);
         self.prev_tick = Some(tick);
         Ok(())
     }
+    /// Adds a timeline marker at `tick`.
+    ///
+    /// Markers must be strictly increasing, other markers are dropped with
+    /// a warning. Like in the reference implementation, markers beyond the
+    /// 64th are ignored. Version 3 demos don't store any markers.
+    pub fn add_timeline_marker<W: Warn<Warning>>(&mut self, tick: i32, warn: &mut W) {
+        let markers = &mut self.timeline_markers;
+        let amount = markers.amount.assert_usize();
+        if let Some(&last) = markers.markers().last() {
+            if tick <= last {
+                warn.warn(Warning::NonIncreasingTimelineMarkers);
+                return;
+            }
+        }
+        if amount == MAX_TIMELINE_MARKERS {
+            return;
+        }
+        markers.markers[amount] = tick;
+        markers.amount += 1;
+    }
     fn write_chunk_impl(&mut self, kind: DataKind, data: Option<&[u8]>) -> Result<(), WriteError> {
-        let data = data.unwrap_or(&self.buffer2);
+        let data = data.unwrap_or(&self.buffer2[..]);
         self.huffman.clear();
         HUFFMAN
             .compress(data, &mut self.huffman)
             .expect("too long compression");
+        // The readers decompress into buffers of this size.
+        assert!(data.len() <= MAX_SNAPSHOT_SIZE, "too long chunk");
+        assert!(
+            self.huffman.len() <= MAX_SNAPSHOT_SIZE,
+            "too long compression"
+        );
         ChunkHeader::Data {
             kind,
             size: self.huffman.len().assert_u16(),
         }
-        .write(&mut self.file, WRITER_VERSION)?;
+        .write(&mut self.file, self.version)?;
         self.file
             .write_all(&self.huffman)
             .map_err(binrw::Error::Io)?;
@@ -152,8 +227,74 @@ impl<'a> Writer<'a> {
             },
         )
         .expect("overlong message");
+        assert!(self.buffer2.len() <= MAX_SNAPSHOT_SIZE, "overlong message");
         self.write_chunk_impl(DataKind::Message, None)
     }
-    // TODO: Add a `finalize` function that writes the demo length into the
-    // original header.
+    /// Writes the demo length and the timeline markers into the header.
+    ///
+    /// The length is the time in seconds between the first and the last
+    /// tick.
+    pub fn finish(mut self) -> Result<(), WriteError> {
+        self.header.length = match (self.first_tick, self.prev_tick) {
+            (Some(first), Some(last)) => (last - first) / TICKS_PER_SECOND,
+            _ => 0,
+        };
+        self.file
+            .seek(io::SeekFrom::Start(self.start))
+            .map_err(binrw::Error::Io)?;
+        self.write_header()?;
+        self.file
+            .seek(io::SeekFrom::End(0))
+            .map_err(binrw::Error::Io)?;
+        self.file.flush().map_err(binrw::Error::Io)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Writer;
+    use crate::format::Warning;
+    use crate::DemoKind;
+    use crate::Reader;
+    use std::io::Cursor;
+
+    #[test]
+    fn finish() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = Writer::new(
+            &mut file,
+            b"0.6 626fce9a778df4d4",
+            b"dm1",
+            None,
+            0xf2159e6e,
+            DemoKind::Client,
+            0,
+            b"2024-01-01_00-00-00",
+            b"map",
+        )
+        .unwrap();
+        writer.write_tick(true, 100).unwrap();
+        writer.add_timeline_marker(120, &mut warn::Panic);
+        writer.write_message(&[1, 0, 0, 0]).unwrap();
+        writer.add_timeline_marker(300, &mut warn::Panic);
+        let mut warnings = Vec::new();
+        writer.add_timeline_marker(300, &mut warnings);
+        writer.add_timeline_marker(200, &mut warnings);
+        assert_eq!(warnings, [Warning::NonIncreasingTimelineMarkers; 2]);
+        writer.write_tick(false, 101).unwrap();
+        writer.write_tick(true, 400).unwrap();
+        writer.finish().unwrap();
+
+        file.set_position(0);
+        let mut reader = Reader::new(file, &mut warn::Panic).unwrap();
+        assert_eq!(reader.length(), 6);
+        assert_eq!(reader.timeline_markers(), [120, 300]);
+        assert_eq!(reader.map_data(), b"map");
+        let mut chunks = 0;
+        while let Some(_) = reader.read_chunk(&mut warn::Panic).unwrap() {
+            chunks += 1;
+        }
+        assert_eq!(chunks, 4);
+    }
 }
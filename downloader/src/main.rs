@@ -412,7 +412,7 @@ impl Main {
             peers: PeerMap::with_capacity(addresses.len()),
             config: config,
         };
-        let mut loop_ = L::client();
+        let mut loop_ = L::client().unwrap();
         for &addr in addresses {
             let pid = loop_.connect(addr);
             main.peers.insert(pid, Peer::new(&mut loop_));
@@ -0,0 +1,612 @@
+//! Event loop driving [`Net`] from a tokio [`UdpSocket`].
+//!
+//! Unlike [`SocketLoop`](crate::SocketLoop), [`AsyncLoop`] doesn't need a
+//! thread of its own: received chunks and connection events are handed out
+//! as a [`Stream`] of owned [`Event`]s, and [`AsyncLoop::run_application`]
+//! runs an [`Application`] on top of it as a future.
+
+use crate::Application;
+use crate::Chunk;
+use crate::ConnlessChunk;
+use crate::Loop;
+use crate::Protocol;
+use crate::Warn;
+use arrayvec::ArrayVec;
+use futures_core::Stream;
+use libtw2_common::Takeable;
+use libtw2_net::collections::PeerMap;
+use libtw2_net::collections::PeerSet;
+use libtw2_net::net::Callback;
+use libtw2_net::net::ChunkOrEvent;
+use libtw2_net::net::PeerId;
+use libtw2_net::Net;
+use libtw2_net::Timeout;
+use libtw2_net::Timestamp;
+use libtw2_socket::Addr;
+use rand::thread_rng;
+use rand::RngCore as _;
+use std::cmp;
+use std::collections::VecDeque;
+use std::future;
+use std::future::Future as _;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tokio::time::Sleep;
+
+/// Owned version of [`ChunkOrEvent`], as yielded by the [`AsyncLoop`]
+/// stream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    Chunk {
+        pid: PeerId,
+        vital: bool,
+        data: Vec<u8>,
+    },
+    Connless {
+        addr: Addr,
+        protocol: Protocol,
+        data: Vec<u8>,
+    },
+    Connect(PeerId, Protocol),
+    Ready(PeerId),
+    /// `remote` is `false` if the disconnect was initiated locally using
+    /// [`AsyncLoop::disconnect`].
+    Disconnect {
+        pid: PeerId,
+        remote: bool,
+        reason: Vec<u8>,
+    },
+}
+
+impl Event {
+    fn from_chunk(chunk: ChunkOrEvent<Addr>) -> Event {
+        match chunk {
+            ChunkOrEvent::Chunk(c) => Event::Chunk {
+                pid: c.pid,
+                vital: c.vital,
+                data: c.data.to_vec(),
+            },
+            ChunkOrEvent::Connless(c) => Event::Connless {
+                addr: c.addr,
+                protocol: c.protocol,
+                data: c.data.to_vec(),
+            },
+            ChunkOrEvent::Connect(pid, protocol) => Event::Connect(pid, protocol),
+            ChunkOrEvent::Ready(pid) => Event::Ready(pid),
+            ChunkOrEvent::Disconnect(pid, reason) => Event::Disconnect {
+                pid,
+                remote: true,
+                reason: reason.to_vec(),
+            },
+        }
+    }
+}
+
+/// The socket is only registered with the tokio reactor once the loop is
+/// first polled, so that an `AsyncLoop` can be created outside of a runtime,
+/// e.g. for [`Loop::run`].
+enum UdpSock {
+    Std(std::net::UdpSocket),
+    Tokio(UdpSocket),
+}
+
+impl UdpSock {
+    fn register(&mut self) -> io::Result<()> {
+        if let UdpSock::Std(ref socket) = *self {
+            *self = UdpSock::Tokio(UdpSocket::from_std(socket.try_clone()?)?);
+        }
+        Ok(())
+    }
+    fn registered(&self) -> &UdpSocket {
+        match *self {
+            UdpSock::Std(_) => panic!("socket not registered"),
+            UdpSock::Tokio(ref socket) => socket,
+        }
+    }
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match *self {
+            UdpSock::Std(ref socket) => socket.send_to(data, addr),
+            UdpSock::Tokio(ref socket) => socket.try_send_to(data, addr),
+        }
+    }
+}
+
+struct SocketCallback<'a> {
+    socket: &'a UdpSock,
+    start: Instant,
+}
+
+fn cb(socket: &UdpSock, start: Instant) -> SocketCallback {
+    SocketCallback {
+        socket: socket,
+        start: start,
+    }
+}
+
+impl<'a> Callback<Addr> for SocketCallback<'a> {
+    type Error = io::Error;
+    fn secure_random(&mut self, buffer: &mut [u8]) {
+        thread_rng().fill_bytes(buffer)
+    }
+    fn send(&mut self, addr: Addr, data: &[u8]) -> Result<(), io::Error> {
+        let size = self
+            .socket
+            .send_to(data, SocketAddr::new(addr.ip, addr.port))?;
+        assert!(data.len() == size);
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        Timestamp::from_secs_since_epoch(0) + self.start.elapsed()
+    }
+}
+
+fn net_error(e: libtw2_net::net::Error<io::Error>) -> io::Error {
+    match e {
+        libtw2_net::net::Error::TooLongData => {
+            io::Error::new(io::ErrorKind::InvalidInput, "too long data")
+        }
+        libtw2_net::net::Error::Callback(e) => e,
+    }
+}
+
+pub struct AsyncLoop {
+    socket: UdpSock,
+    start: Instant,
+    net: Net<Addr>,
+    want_to_flush: PeerSet,
+    disconnected: Takeable<PeerMap<ArrayVec<[u8; 1024]>>>,
+    events: VecDeque<Event>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    buf: Vec<u8>,
+    server: bool,
+}
+
+impl AsyncLoop {
+    fn new(socket: UdpSock, server: bool) -> AsyncLoop {
+        AsyncLoop {
+            socket: socket,
+            start: Instant::now(),
+            net: if server { Net::server() } else { Net::client() },
+            want_to_flush: PeerSet::new(),
+            disconnected: Default::default(),
+            events: VecDeque::new(),
+            sleep: None,
+            buf: vec![0; 4096],
+            server: server,
+        }
+    }
+    fn bind_std(addr: SocketAddr, server: bool) -> io::Result<AsyncLoop> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(AsyncLoop::new(UdpSock::Std(socket), server))
+    }
+    /// Creates a loop accepting incoming connections on `addr`.
+    pub fn server(addr: SocketAddr) -> io::Result<AsyncLoop> {
+        AsyncLoop::bind_std(addr, true)
+    }
+    /// Creates a loop for outgoing connections, bound to an ephemeral IPv4
+    /// port.
+    pub fn client() -> io::Result<AsyncLoop> {
+        AsyncLoop::bind_std(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0), false)
+    }
+    /// Creates a loop from an already bound socket.
+    pub fn from_socket(socket: UdpSocket, server: bool) -> AsyncLoop {
+        AsyncLoop::new(UdpSock::Tokio(socket), server)
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.socket {
+            UdpSock::Std(ref socket) => socket.local_addr(),
+            UdpSock::Tokio(ref socket) => socket.local_addr(),
+        }
+    }
+    pub fn net(&self) -> &Net<Addr> {
+        &self.net
+    }
+    pub fn net_mut(&mut self) -> &mut Net<Addr> {
+        &mut self.net
+    }
+    pub fn time(&self) -> Timestamp {
+        cb(&self.socket, self.start).time()
+    }
+    pub fn connect(&mut self, addr: Addr) -> io::Result<PeerId> {
+        let mut cb = cb(&self.socket, self.start);
+        let (pid, res) = self.net.connect(&mut cb, addr);
+        res.map(|()| pid)
+    }
+    pub fn connect7(&mut self, addr: Addr) -> io::Result<PeerId> {
+        let mut cb = cb(&self.socket, self.start);
+        let (pid, res) = self.net.connect7(&mut cb, addr);
+        res.map(|()| pid)
+    }
+    /// Disconnects the peer, an [`Event::Disconnect`] with `remote: false`
+    /// is yielded for it afterwards.
+    pub fn disconnect(&mut self, pid: PeerId, reason: &[u8]) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        if self.want_to_flush.contains(pid) {
+            self.want_to_flush.remove(pid);
+            self.net.flush(&mut cb, pid)?;
+        }
+        self.disconnected
+            .insert(pid, reason.iter().cloned().collect());
+        self.net.disconnect(&mut cb, pid, reason)
+    }
+    pub fn send_connless(&mut self, addr: Addr, data: &[u8]) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        self.net
+            .send_connless(&mut cb, addr, data)
+            .map_err(net_error)
+    }
+    pub fn send_connless7(&mut self, addr: Addr, data: &[u8]) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        self.net
+            .send_connless7(&mut cb, addr, data)
+            .map_err(net_error)
+    }
+    pub fn send(&mut self, chunk: Chunk) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        self.net.send(&mut cb, chunk).map_err(net_error)
+    }
+    /// Flushes the peer's queued chunks right away.
+    pub fn force_flush(&mut self, pid: PeerId) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        self.want_to_flush.remove(pid);
+        self.net.flush(&mut cb, pid)
+    }
+    /// Flushes the peer's queued chunks the next time the loop is polled.
+    pub fn flush(&mut self, pid: PeerId) {
+        self.want_to_flush.insert(pid);
+    }
+    pub fn ignore(&mut self, pid: PeerId) {
+        self.net.ignore(pid);
+    }
+    pub fn accept(&mut self, pid: PeerId) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        self.net.accept(&mut cb, pid)
+    }
+    pub fn reject(&mut self, pid: PeerId, reason: &[u8]) -> io::Result<()> {
+        let mut cb = cb(&self.socket, self.start);
+        self.net.reject(&mut cb, pid, reason)
+    }
+    fn queue_disconnected(&mut self) {
+        let mut disconnected = self.disconnected.take();
+        for (pid, reason) in disconnected.drain() {
+            self.events.push_back(Event::Disconnect {
+                pid,
+                remote: false,
+                reason: reason.to_vec(),
+            });
+        }
+        self.disconnected.restore(disconnected);
+    }
+    fn pop_event(&mut self) -> Option<Event> {
+        while let Some(event) = self.events.pop_front() {
+            // The peer might have been disconnected since the chunk was
+            // received.
+            if let Event::Chunk {
+                pid,
+                vital,
+                ref data,
+            } = event
+            {
+                let mut chunk = ChunkOrEvent::Chunk(Chunk { pid, vital, data });
+                if !self.net.is_receive_chunk_still_valid(&mut chunk) {
+                    continue;
+                }
+            }
+            return Some(event);
+        }
+        None
+    }
+    fn poll_event(&mut self, cx: &mut Context) -> Poll<io::Result<Event>> {
+        loop {
+            if let Some(event) = self.pop_event() {
+                return Poll::Ready(Ok(event));
+            }
+            self.socket.register()?;
+            {
+                let mut cb = cb(&self.socket, self.start);
+                for event in self.net.tick(&mut cb, &mut warn::Log) {
+                    match event? {
+                        // Exceeded the queue limit, reported like a local
+                        // disconnect.
+                        ChunkOrEvent::Disconnect(pid, reason) => {
+                            self.want_to_flush.remove(pid);
+                            self.disconnected
+                                .insert(pid, reason.iter().cloned().collect());
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                for pid in self.want_to_flush.drain() {
+                    self.net.flush(&mut cb, pid)?;
+                }
+            }
+            self.queue_disconnected();
+            if !self.events.is_empty() {
+                continue;
+            }
+
+            let mut cb = cb(&self.socket, self.start);
+            let mut read_buf = ReadBuf::new(&mut self.buf);
+            match self.socket.registered().poll_recv_from(cx, &mut read_buf) {
+                Poll::Ready(Ok(addr)) => {
+                    let addr = Addr::from(addr);
+                    let data = read_buf.filled();
+                    let mut buf: ArrayVec<[u8; 4096]> = ArrayVec::new();
+                    let (iter, res) =
+                        self.net
+                            .feed(&mut cb, &mut Warn(addr, data), addr, data, &mut buf);
+                    self.events.extend(iter.map(Event::from_chunk));
+                    res?;
+                    continue;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+
+            let deadline = match self.net.needs_tick().to_opt() {
+                Some(time) => self.start + Duration::from_micros(time.as_usecs_since_epoch()),
+                None => {
+                    self.sleep = None;
+                    return Poll::Pending;
+                }
+            };
+            match self.sleep {
+                Some(ref mut sleep) => sleep.as_mut().reset(deadline),
+                None => self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline))),
+            }
+            if self.sleep.as_mut().unwrap().as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+    /// Waits for the next event.
+    pub async fn next_event(&mut self) -> io::Result<Event> {
+        future::poll_fn(|cx| self.poll_event(cx)).await
+    }
+    /// Runs `application` until the loop has nothing left to do, which only
+    /// happens for client loops.
+    pub async fn run_application<A: Application<AsyncLoop>>(
+        mut self,
+        mut application: A,
+    ) -> io::Result<()> {
+        let mut app_sleep: Option<Pin<Box<Sleep>>> = None;
+        loop {
+            application.on_tick(&mut self);
+            let app_timeout = application.needs_tick();
+            if !self.server && cmp::min(self.net.needs_tick(), app_timeout) == Timeout::inactive() {
+                return Ok(());
+            }
+            if let Some(time) = app_timeout.to_opt() {
+                let deadline = self.start + Duration::from_micros(time.as_usecs_since_epoch());
+                match app_sleep {
+                    Some(ref mut sleep) => sleep.as_mut().reset(deadline),
+                    None => app_sleep = Some(Box::pin(tokio::time::sleep_until(deadline))),
+                }
+            } else {
+                app_sleep = None;
+            }
+            let event = future::poll_fn(|cx| {
+                if let Poll::Ready(event) = self.poll_event(cx) {
+                    return Poll::Ready(Some(event));
+                }
+                match app_sleep {
+                    Some(ref mut sleep) => sleep.as_mut().poll(cx).map(|()| None),
+                    None => Poll::Pending,
+                }
+            })
+            .await;
+            let event = match event {
+                Some(event) => event?,
+                None => continue,
+            };
+            match event {
+                Event::Chunk { pid, vital, data } => application.on_packet(
+                    &mut self,
+                    Chunk {
+                        pid,
+                        vital,
+                        data: &data,
+                    },
+                ),
+                Event::Connless {
+                    addr,
+                    protocol,
+                    data,
+                } => application.on_connless_packet(
+                    &mut self,
+                    ConnlessChunk {
+                        addr,
+                        pid: None,
+                        protocol,
+                        data: &data,
+                    },
+                ),
+                Event::Connect(pid, _) => application.on_connect(&mut self, pid),
+                Event::Ready(pid) => application.on_ready(&mut self, pid),
+                Event::Disconnect {
+                    pid,
+                    remote,
+                    reason,
+                } => application.on_disconnect(&mut self, pid, remote, &reason),
+            }
+        }
+    }
+}
+
+impl Stream for AsyncLoop {
+    type Item = io::Result<Event>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Event>>> {
+        self.get_mut().poll_event(cx).map(Some)
+    }
+}
+
+/// Panics on socket errors after binding, like [`SocketLoop`](crate::SocketLoop).
+///
+/// `AsyncLoop` only drives a single socket, binding the IPv4 wildcard address
+/// in [`Loop::accept_connections_on_port`] and a single address in
+/// [`Loop::accept_connections_on`].
+///
+/// [`Loop::run`] blocks the current thread on a new single-threaded runtime,
+/// use [`AsyncLoop::run_application`] from within a runtime instead.
+impl Loop for AsyncLoop {
+    fn accept_connections_on_port(port: u16) -> io::Result<AsyncLoop> {
+        AsyncLoop::server(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+    }
+    fn accept_connections_on(addrs: &[SocketAddr]) -> io::Result<AsyncLoop> {
+        match *addrs {
+            [addr] => AsyncLoop::server(addr),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AsyncLoop can only bind a single address",
+            )),
+        }
+    }
+    fn client() -> io::Result<AsyncLoop> {
+        AsyncLoop::client()
+    }
+    fn run<A: Application<AsyncLoop>>(self, application: A) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(self.run_application(application))
+            .unwrap();
+    }
+    fn time(&mut self) -> Timestamp {
+        AsyncLoop::time(self)
+    }
+    fn connect(&mut self, addr: Addr) -> PeerId {
+        AsyncLoop::connect(self, addr).unwrap()
+    }
+    fn connect7(&mut self, addr: Addr) -> PeerId {
+        AsyncLoop::connect7(self, addr).unwrap()
+    }
+    fn disconnect(&mut self, pid: PeerId, reason: &[u8]) {
+        AsyncLoop::disconnect(self, pid, reason).unwrap()
+    }
+    fn send_connless(&mut self, addr: Addr, data: &[u8]) {
+        AsyncLoop::send_connless(self, addr, data).unwrap()
+    }
+    fn send_connless7(&mut self, addr: Addr, data: &[u8]) {
+        AsyncLoop::send_connless7(self, addr, data).unwrap()
+    }
+    fn send(&mut self, chunk: Chunk) {
+        AsyncLoop::send(self, chunk).unwrap()
+    }
+    fn force_flush(&mut self, pid: PeerId) {
+        AsyncLoop::force_flush(self, pid).unwrap()
+    }
+    fn flush(&mut self, pid: PeerId) {
+        AsyncLoop::flush(self, pid)
+    }
+    fn ignore(&mut self, pid: PeerId) {
+        AsyncLoop::ignore(self, pid)
+    }
+    fn accept(&mut self, pid: PeerId) {
+        AsyncLoop::accept(self, pid).unwrap()
+    }
+    fn reject(&mut self, pid: PeerId, reason: &[u8]) {
+        AsyncLoop::reject(self, pid, reason).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncLoop;
+    use super::Event;
+    use crate::Addr;
+    use crate::Chunk;
+    use crate::Protocol;
+    use std::future;
+    use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
+    use std::task::Poll;
+
+    /// Waits for the next event of either loop, `false` denoting `a` and
+    /// `true` denoting `b`.
+    async fn next_event(a: &mut AsyncLoop, b: &mut AsyncLoop) -> (bool, Event) {
+        future::poll_fn(|cx| {
+            if let Poll::Ready(e) = a.poll_event(cx) {
+                return Poll::Ready((false, e.unwrap()));
+            }
+            b.poll_event(cx).map(|e| (true, e.unwrap()))
+        })
+        .await
+    }
+
+    #[test]
+    fn loopback() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let localhost = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+            let mut server = AsyncLoop::server(localhost).unwrap();
+            let mut client = AsyncLoop::client().unwrap();
+            let server_addr = Addr::from(server.local_addr().unwrap());
+
+            let cpid = client.connect(server_addr).unwrap();
+            let c = &mut client;
+            let s = &mut server;
+            assert_eq!(next_event(c, s).await, (false, Event::Ready(cpid)));
+            let spid = match next_event(c, s).await {
+                (true, Event::Connect(pid, Protocol::V6)) => pid,
+                e => panic!("unexpected event {:?}", e),
+            };
+            s.accept(spid).unwrap();
+
+            let chunk = Chunk {
+                pid: cpid,
+                vital: true,
+                data: b"hello",
+            };
+            c.send(chunk).unwrap();
+            c.flush(cpid);
+            assert_eq!(
+                next_event(c, s).await,
+                (
+                    true,
+                    Event::Chunk {
+                        pid: spid,
+                        vital: true,
+                        data: b"hello".to_vec(),
+                    }
+                )
+            );
+
+            c.disconnect(cpid, b"bye").unwrap();
+            assert_eq!(
+                next_event(c, s).await,
+                (
+                    false,
+                    Event::Disconnect {
+                        pid: cpid,
+                        remote: false,
+                        reason: b"bye".to_vec(),
+                    }
+                )
+            );
+            assert_eq!(
+                next_event(c, s).await,
+                (
+                    true,
+                    Event::Disconnect {
+                        pid: spid,
+                        remote: true,
+                        reason: b"bye".to_vec(),
+                    }
+                )
+            );
+        });
+    }
+}
@@ -8,32 +8,59 @@ use libtw2_common::Takeable;
 use libtw2_net::collections::PeerMap;
 use libtw2_net::collections::PeerSet;
 use libtw2_net::net::Callback;
+use libtw2_net::net::ChunkOrEvent;
 use libtw2_net::Net;
 use libtw2_socket::Socket;
 use log::LogLevel;
 use std::cmp;
 use std::fmt;
+use std::io;
+use std::net::SocketAddr;
 
+#[cfg(feature = "tokio")]
+mod async_loop;
+mod replay;
+
+#[cfg(feature = "tokio")]
+pub use self::async_loop::AsyncLoop;
+#[cfg(feature = "tokio")]
+pub use self::async_loop::Event;
+pub use self::replay::ReplayLoop;
 pub use libtw2_net::collections;
 pub use libtw2_net::net::PeerId;
+pub use libtw2_net::net::Protocol;
 pub use libtw2_net::Timeout;
 pub use libtw2_net::Timestamp;
+pub use libtw2_socket::pcap;
 pub use libtw2_socket::Addr;
+pub use libtw2_socket::BindError;
+pub use libtw2_socket::BindOptions;
 
 pub type Chunk<'a> = libtw2_net::net::Chunk<'a>;
 pub type ConnlessChunk<'a> = libtw2_net::net::ConnlessChunk<'a, Addr>;
 
 pub trait Loop {
-    fn accept_connections_on_port(port: u16) -> Self;
-    fn client() -> Self;
+    /// Accepts connections on the IPv4 and IPv6 wildcard addresses.
+    fn accept_connections_on_port(port: u16) -> io::Result<Self>
+    where
+        Self: Sized;
+    /// Accepts connections on each of the given addresses.
+    fn accept_connections_on(addrs: &[SocketAddr]) -> io::Result<Self>
+    where
+        Self: Sized;
+    fn client() -> io::Result<Self>
+    where
+        Self: Sized;
     fn run<A: Application<Self>>(self, application: A)
     where
         Self: Sized;
 
     fn time(&mut self) -> Timestamp;
     fn connect(&mut self, addr: Addr) -> PeerId;
+    fn connect7(&mut self, addr: Addr) -> PeerId;
     fn disconnect(&mut self, pid: PeerId, reason: &[u8]);
     fn send_connless(&mut self, addr: Addr, data: &[u8]);
+    fn send_connless7(&mut self, addr: Addr, data: &[u8]);
     fn send(&mut self, chunk: Chunk);
     fn force_flush(&mut self, pid: PeerId);
     fn flush(&mut self, pid: PeerId);
@@ -60,44 +87,86 @@ pub struct SocketLoop {
     server: bool,
 }
 
-impl Loop for SocketLoop {
-    fn accept_connections_on_port(port: u16) -> SocketLoop {
+impl SocketLoop {
+    fn new(socket: Socket, server: bool) -> SocketLoop {
         SocketLoop {
-            socket: Socket::bound(port).unwrap(),
-            net: Net::server(),
+            socket: socket,
+            net: if server { Net::server() } else { Net::client() },
             want_to_flush: PeerSet::new(),
             disconnected: Default::default(),
-            server: true,
+            server: server,
         }
     }
-    fn client() -> SocketLoop {
-        SocketLoop {
-            socket: Socket::new().unwrap(),
-            net: Net::client(),
-            want_to_flush: PeerSet::new(),
-            disconnected: Default::default(),
-            server: false,
+    /// Accepts connections on each of the given addresses, with control
+    /// over `IPV6_V6ONLY`.
+    pub fn accept_connections_with_options(
+        addrs: &[SocketAddr],
+        options: BindOptions,
+    ) -> Result<SocketLoop, BindError> {
+        Ok(SocketLoop::new(
+            Socket::bind_with_options(addrs, options)?,
+            true,
+        ))
+    }
+    pub fn net(&self) -> &Net<Addr> {
+        &self.net
+    }
+    /// Allows configuring the `Net`, e.g. installing an admission policy
+    /// using `Net::set_admission`.
+    pub fn net_mut(&mut self) -> &mut Net<Addr> {
+        &mut self.net
+    }
+    /// Records all datagrams to `writer` in the pcap format, see
+    /// [`Socket::capture_to`].
+    pub fn capture_to<W: io::Write + Send + 'static>(&mut self, writer: W) -> io::Result<()> {
+        self.socket.capture_to(writer)
+    }
+    fn tick(&mut self) {
+        for event in self.net.tick(&mut self.socket, &mut warn::Log) {
+            match event.unwrap() {
+                // Exceeded the queue limit, reported like a local disconnect.
+                ChunkOrEvent::Disconnect(pid, reason) => {
+                    self.want_to_flush.remove(pid);
+                    self.disconnected
+                        .insert(pid, reason.iter().cloned().collect());
+                }
+                _ => unreachable!(),
+            }
         }
     }
+    fn report_disconnected<A: Application<SocketLoop>>(&mut self, application: &mut A) {
+        let mut disconnected = self.disconnected.take();
+        for (pid, reason) in disconnected.drain() {
+            application.on_disconnect(self, pid, false, &reason);
+        }
+        self.disconnected.restore(disconnected);
+    }
+}
+
+impl Loop for SocketLoop {
+    fn accept_connections_on_port(port: u16) -> io::Result<SocketLoop> {
+        Ok(SocketLoop::new(Socket::bound(port)?, true))
+    }
+    fn accept_connections_on(addrs: &[SocketAddr]) -> io::Result<SocketLoop> {
+        Ok(SocketLoop::new(Socket::bind(addrs)?, true))
+    }
+    fn client() -> io::Result<SocketLoop> {
+        Ok(SocketLoop::new(Socket::new()?, false))
+    }
     fn run<A: Application<SocketLoop>>(mut self, mut application: A) {
         let mut buf1: ArrayVec<[u8; 4096]> = ArrayVec::new();
         let mut buf2: ArrayVec<[u8; 4096]> = ArrayVec::new();
 
         loop {
-            self.net
-                .tick(&mut self.socket)
-                .foreach(|e| panic!("{:?}", e));
+            self.tick();
+            self.report_disconnected(&mut application);
             application.on_tick(&mut self);
 
             for pid in self.want_to_flush.drain() {
                 self.net.flush(&mut self.socket, pid).unwrap();
             }
 
-            let mut disconnected = self.disconnected.take();
-            for (pid, reason) in disconnected.drain() {
-                application.on_disconnect(&mut self, pid, false, &reason);
-            }
-            self.disconnected.restore(disconnected);
+            self.report_disconnected(&mut application);
 
             let sleep_timeout = cmp::min(self.net.needs_tick(), application.needs_tick());
             let sleep_duration = sleep_timeout.time_from(self.socket.time());
@@ -128,18 +197,14 @@ impl Loop for SocketLoop {
                     match chunk {
                         Chunk(c) => application.on_packet(&mut self, c),
                         Connless(c) => application.on_connless_packet(&mut self, c),
-                        Connect(pid) => application.on_connect(&mut self, pid),
+                        Connect(pid, _) => application.on_connect(&mut self, pid),
                         Ready(pid) => application.on_ready(&mut self, pid),
                         Disconnect(pid, r) => application.on_disconnect(&mut self, pid, true, r),
                     }
                 }
             }
 
-            let mut disconnected = self.disconnected.take();
-            for (pid, reason) in disconnected.drain() {
-                application.on_disconnect(&mut self, pid, false, &reason);
-            }
-            self.disconnected.restore(disconnected);
+            self.report_disconnected(&mut application);
         }
     }
     fn time(&mut self) -> Timestamp {
@@ -150,6 +215,11 @@ impl Loop for SocketLoop {
         res.unwrap();
         pid
     }
+    fn connect7(&mut self, addr: Addr) -> PeerId {
+        let (pid, res) = self.net.connect7(&mut self.socket, addr);
+        res.unwrap();
+        pid
+    }
     fn disconnect(&mut self, pid: PeerId, reason: &[u8]) {
         if self.want_to_flush.contains(pid) {
             self.net.flush(&mut self.socket, pid).unwrap();
@@ -164,6 +234,11 @@ impl Loop for SocketLoop {
             .send_connless(&mut self.socket, addr, data)
             .unwrap();
     }
+    fn send_connless7(&mut self, addr: Addr, data: &[u8]) {
+        self.net
+            .send_connless7(&mut self.socket, addr, data)
+            .unwrap();
+    }
     fn send(&mut self, chunk: Chunk) {
         self.net.send(&mut self.socket, chunk).unwrap();
     }
@@ -0,0 +1,603 @@
+//! Replaying packet captures against an [`Application`].
+
+use crate::hexdump;
+use crate::Application;
+use crate::Chunk;
+use crate::Loop;
+use crate::Warn;
+use arrayvec::ArrayVec;
+use libtw2_common::Takeable;
+use libtw2_net::collections::PeerMap;
+use libtw2_net::collections::PeerSet;
+use libtw2_net::net::Callback;
+use libtw2_net::net::ChunkOrEvent;
+use libtw2_net::net::PeerId;
+use libtw2_net::protocol;
+use libtw2_net::protocol7;
+use libtw2_net::protocol7::Token;
+use libtw2_net::Net;
+use libtw2_net::Timestamp;
+use libtw2_socket::pcap;
+use libtw2_socket::unmap;
+use libtw2_socket::Addr;
+use log::LogLevel;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use void::ResultVoidExt;
+use void::Void;
+use warn::Ignore;
+
+struct ReplayCallback {
+    time: Timestamp,
+    random: u64,
+    // Handed out instead of the next random 0.7 connection token.
+    token: Option<Token>,
+}
+
+impl Callback<Addr> for ReplayCallback {
+    type Error = Void;
+    fn secure_random(&mut self, buffer: &mut [u8]) {
+        if buffer.len() == 4 {
+            if let Some(token) = self.token.take() {
+                buffer.copy_from_slice(&token.0);
+                return;
+            }
+        }
+        // Deterministic, so that replaying the same capture twice behaves
+        // the same.
+        for b in buffer {
+            self.random = self
+                .random
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            *b = (self.random >> 56) as u8;
+        }
+    }
+    fn send(&mut self, addr: Addr, data: &[u8]) -> Result<(), Void> {
+        debug!("-> {} (dropped, replaying)", addr);
+        hexdump(LogLevel::Debug, data);
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        self.time
+    }
+}
+
+fn is_local(local: &[SocketAddr], addr: SocketAddr) -> bool {
+    local.iter().any(|&l| {
+        let l = unmap(l);
+        l.port() == addr.port() && (l.ip().is_unspecified() || l.ip() == addr.ip())
+    })
+}
+
+struct Incoming {
+    time: Timestamp,
+    addr: Addr,
+    data: Vec<u8>,
+}
+
+/// A [`Loop`] feeding recorded datagrams into [`Net`] instead of receiving
+/// them from a socket.
+///
+/// Time is virtual: it starts at zero at the first packet of the capture
+/// and jumps directly to the next recorded packet or timeout, so replays
+/// run as fast as possible while seeing the recorded timing. Packets sent
+/// by the application are dropped. The replay ends once all recorded
+/// packets have been fed.
+///
+/// Random values differ from the recorded ones. Connection tokens are
+/// taken from the capture instead: a replayed server hands out the tokens
+/// the clients echo, and 0.7 clients reuse the token the server echoed.
+///
+/// Only the classic pcap format is supported, not pcapng.
+pub struct ReplayLoop {
+    cb: ReplayCallback,
+    packets: VecDeque<Incoming>,
+    net: Net<Addr>,
+    want_to_flush: PeerSet,
+    disconnected: Takeable<PeerMap<ArrayVec<[u8; 1024]>>>,
+}
+
+impl ReplayLoop {
+    /// Replays the datagrams of the capture that were sent to one of the
+    /// `local` addresses. A `local` address with an unspecified IP address
+    /// matches all datagrams sent to its port.
+    pub fn from_pcap<R: Read>(
+        reader: R,
+        local: &[SocketAddr],
+        server: bool,
+    ) -> io::Result<ReplayLoop> {
+        let packets: io::Result<Vec<_>> = pcap::Reader::new(reader)?.collect();
+        Ok(ReplayLoop::from_packets(packets?, local, server))
+    }
+    pub fn from_packets(
+        packets: Vec<pcap::Packet>,
+        local: &[SocketAddr],
+        server: bool,
+    ) -> ReplayLoop {
+        let start = packets.first().map(|p| p.time).unwrap_or_default();
+        let mut last = Timestamp::from_secs_since_epoch(0);
+        let packets = packets
+            .into_iter()
+            .filter_map(|p| {
+                // Keep time monotonic even if the capture isn't sorted.
+                let time = Timestamp::from_secs_since_epoch(0) + p.time.saturating_sub(start);
+                last = cmp::max(last, time);
+                if !is_local(local, unmap(p.dst)) {
+                    return None;
+                }
+                Some(Incoming {
+                    time: last,
+                    addr: Addr::from(unmap(p.src)),
+                    data: p.data,
+                })
+            })
+            .collect();
+        let mut result = ReplayLoop {
+            cb: ReplayCallback {
+                time: Timestamp::from_secs_since_epoch(0),
+                random: 0,
+                token: None,
+            },
+            packets: packets,
+            net: if server { Net::server() } else { Net::client() },
+            want_to_flush: PeerSet::new(),
+            disconnected: Default::default(),
+        };
+        if server {
+            result.set_recorded_server_tokens();
+        }
+        result
+    }
+    pub fn net(&self) -> &Net<Addr> {
+        &self.net
+    }
+    pub fn net_mut(&mut self) -> &mut Net<Addr> {
+        &mut self.net
+    }
+    /// Returns the token the 0.7 client used for its connection to `addr` in
+    /// the capture, the server echoes it in every connected packet.
+    fn recorded_token(&self, addr: Addr) -> Option<Token> {
+        let mut buf: ArrayVec<[u8; 4096]> = ArrayVec::new();
+        self.packets
+            .iter()
+            .filter(|p| p.addr == addr)
+            .find_map(|p| {
+                buf.clear();
+                match protocol7::Packet::read(&mut Ignore, &p.data, &mut buf) {
+                    Ok(protocol7::Packet::Connected(c)) => Some(c.token),
+                    _ => None,
+                }
+            })
+    }
+    /// Makes the server hand out the tokens the clients echoed in the
+    /// capture, in their DDNet `Accept`, 0.7 `Connect` or 0.7 connless
+    /// packets.
+    fn set_recorded_server_tokens(&mut self) {
+        let mut buf: ArrayVec<[u8; 4096]> = ArrayVec::new();
+        let mut tokens = HashMap::new();
+        for p in &self.packets {
+            buf.clear();
+            // Same distinction as in `Net::feed`.
+            let token = if protocol7::Packet::is_connless(&p.data)
+                || !protocol::Packet::is_initial(&p.data)
+            {
+                use libtw2_net::protocol7::ConnectedPacket;
+                use libtw2_net::protocol7::ConnectedPacketType;
+                use libtw2_net::protocol7::ControlPacket;
+                use libtw2_net::protocol7::Packet;
+                if let Some(header) = Packet::connless_header(&p.data) {
+                    tokens.insert(p.addr, header.token.0);
+                    continue;
+                }
+                match Packet::read(&mut Ignore, &p.data, &mut buf) {
+                    Ok(Packet::Connected(ConnectedPacket {
+                        token,
+                        type_: ConnectedPacketType::Control(ControlPacket::Connect(_)),
+                        ..
+                    })) => token.0,
+                    _ => continue,
+                }
+            } else {
+                use libtw2_net::protocol::ConnectedPacket;
+                use libtw2_net::protocol::ConnectedPacketType;
+                use libtw2_net::protocol::ControlPacket;
+                use libtw2_net::protocol::Packet;
+                match Packet::read(&mut Ignore, &p.data, None, &mut buf) {
+                    Ok(Packet::Connected(ConnectedPacket {
+                        token: Some(token),
+                        type_: ConnectedPacketType::Control(ControlPacket::Accept),
+                        ..
+                    })) => token.0,
+                    _ => continue,
+                }
+            };
+            tokens.insert(p.addr, token);
+        }
+        self.net.set_server_tokens(tokens);
+    }
+    fn tick(&mut self) {
+        for event in self.net.tick(&mut self.cb, &mut warn::Log) {
+            match event.void_unwrap() {
+                // Exceeded the queue limit, reported like a local disconnect.
+                ChunkOrEvent::Disconnect(pid, reason) => {
+                    self.want_to_flush.remove(pid);
+                    self.disconnected
+                        .insert(pid, reason.iter().cloned().collect());
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+    fn report_disconnected<A: Application<ReplayLoop>>(&mut self, application: &mut A) {
+        let mut disconnected = self.disconnected.take();
+        for (pid, reason) in disconnected.drain() {
+            application.on_disconnect(self, pid, false, &reason);
+        }
+        self.disconnected.restore(disconnected);
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ReplayLoop must be created from a capture",
+    )
+}
+
+/// The constructors of [`Loop`] fail, use [`ReplayLoop::from_pcap`] or
+/// [`ReplayLoop::from_packets`] instead.
+impl Loop for ReplayLoop {
+    fn accept_connections_on_port(_port: u16) -> io::Result<ReplayLoop> {
+        Err(unsupported())
+    }
+    fn accept_connections_on(_addrs: &[SocketAddr]) -> io::Result<ReplayLoop> {
+        Err(unsupported())
+    }
+    fn client() -> io::Result<ReplayLoop> {
+        Err(unsupported())
+    }
+    fn run<A: Application<ReplayLoop>>(mut self, mut application: A) {
+        let mut buf: ArrayVec<[u8; 4096]> = ArrayVec::new();
+
+        loop {
+            self.tick();
+            self.report_disconnected(&mut application);
+            application.on_tick(&mut self);
+
+            for pid in self.want_to_flush.drain() {
+                self.net.flush(&mut self.cb, pid).void_unwrap();
+            }
+            self.report_disconnected(&mut application);
+
+            let packet_time = match self.packets.front() {
+                Some(p) => p.time,
+                None => break,
+            };
+            let timeout = cmp::min(self.net.needs_tick(), application.needs_tick());
+            if let Some(time) = timeout.to_opt() {
+                if self.cb.time < time && time < packet_time {
+                    self.cb.time = time;
+                    continue;
+                }
+            }
+            let packet = self.packets.pop_front().unwrap();
+            self.cb.time = cmp::max(self.cb.time, packet.time);
+
+            buf.clear();
+            let (iter, res) = self.net.feed(
+                &mut self.cb,
+                &mut Warn(packet.addr, &packet.data),
+                packet.addr,
+                &packet.data,
+                &mut buf,
+            );
+            res.void_unwrap();
+            for mut chunk in iter {
+                if !self.net.is_receive_chunk_still_valid(&mut chunk) {
+                    continue;
+                }
+                use libtw2_net::net::ChunkOrEvent::*;
+                match chunk {
+                    Chunk(c) => application.on_packet(&mut self, c),
+                    Connless(c) => application.on_connless_packet(&mut self, c),
+                    Connect(pid, _) => application.on_connect(&mut self, pid),
+                    Ready(pid) => application.on_ready(&mut self, pid),
+                    Disconnect(pid, r) => application.on_disconnect(&mut self, pid, true, r),
+                }
+            }
+            self.report_disconnected(&mut application);
+        }
+    }
+    fn time(&mut self) -> Timestamp {
+        self.cb.time
+    }
+    fn connect(&mut self, addr: Addr) -> PeerId {
+        let (pid, res) = self.net.connect(&mut self.cb, addr);
+        res.void_unwrap();
+        pid
+    }
+    fn connect7(&mut self, addr: Addr) -> PeerId {
+        self.cb.token = self.recorded_token(addr);
+        let (pid, res) = self.net.connect7(&mut self.cb, addr);
+        res.void_unwrap();
+        pid
+    }
+    fn disconnect(&mut self, pid: PeerId, reason: &[u8]) {
+        if self.want_to_flush.contains(pid) {
+            self.net.flush(&mut self.cb, pid).void_unwrap();
+            self.want_to_flush.remove(pid);
+        }
+        self.disconnected
+            .insert(pid, reason.iter().cloned().collect());
+        self.net.disconnect(&mut self.cb, pid, reason).void_unwrap();
+    }
+    fn send_connless(&mut self, addr: Addr, data: &[u8]) {
+        self.net.send_connless(&mut self.cb, addr, data).unwrap();
+    }
+    fn send_connless7(&mut self, addr: Addr, data: &[u8]) {
+        self.net.send_connless7(&mut self.cb, addr, data).unwrap();
+    }
+    fn send(&mut self, chunk: Chunk) {
+        self.net.send(&mut self.cb, chunk).unwrap();
+    }
+    fn force_flush(&mut self, pid: PeerId) {
+        if self.want_to_flush.contains(pid) {
+            self.want_to_flush.remove(pid);
+        }
+        self.net.flush(&mut self.cb, pid).void_unwrap();
+    }
+    fn flush(&mut self, pid: PeerId) {
+        self.want_to_flush.insert(pid);
+    }
+    fn ignore(&mut self, pid: PeerId) {
+        self.net.ignore(pid);
+    }
+    fn accept(&mut self, pid: PeerId) {
+        self.net.accept(&mut self.cb, pid).void_unwrap();
+    }
+    fn reject(&mut self, pid: PeerId, reason: &[u8]) {
+        self.net.reject(&mut self.cb, pid, reason).void_unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReplayLoop;
+    use crate::Addr;
+    use crate::Application;
+    use crate::Chunk;
+    use crate::ConnlessChunk;
+    use crate::Loop;
+    use crate::PeerId;
+    use crate::Protocol;
+    use crate::Timeout;
+    use libtw2_net::net::Callback;
+    use libtw2_net::net::ChunkOrEvent;
+    use libtw2_net::Net;
+    use libtw2_net::Timestamp;
+    use libtw2_socket::pcap;
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use void::ResultVoidExt;
+    use void::Void;
+
+    struct Recorder<'a> {
+        addr: Addr,
+        time: Timestamp,
+        queue: &'a mut VecDeque<(Addr, Addr, Vec<u8>)>,
+    }
+
+    impl<'a> Callback<Addr> for Recorder<'a> {
+        type Error = Void;
+        fn secure_random(&mut self, buffer: &mut [u8]) {
+            buffer.iter_mut().for_each(|b| *b = 0x55);
+        }
+        fn send(&mut self, addr: Addr, data: &[u8]) -> Result<(), Void> {
+            self.queue.push_back((self.addr, addr, data.to_vec()));
+            Ok(())
+        }
+        fn time(&mut self) -> Timestamp {
+            self.time
+        }
+    }
+
+    fn cb(addr: Addr, time: Timestamp, queue: &mut VecDeque<(Addr, Addr, Vec<u8>)>) -> Recorder {
+        Recorder { addr, time, queue }
+    }
+
+    fn socket_addr(addr: Addr) -> SocketAddr {
+        SocketAddr::new(addr.ip, addr.port)
+    }
+
+    /// Records a connection in which the server sends `hello`.
+    fn record(server_addr: Addr, client_addr: Addr, protocol: Protocol) -> Vec<u8> {
+        let mut pcap = pcap::Writer::new(Vec::new()).unwrap();
+        let mut server = Net::server();
+        let mut client = Net::client();
+        let mut queue = VecDeque::new();
+        let mut time = Timestamp::from_secs_since_epoch(0);
+        let epoch = Duration::from_secs(1_600_000_000);
+
+        let mut client_cb = cb(client_addr, time, &mut queue);
+        let (_, res) = match protocol {
+            Protocol::V6 => client.connect(&mut client_cb, server_addr),
+            Protocol::V7 => client.connect7(&mut client_cb, server_addr),
+        };
+        res.void_unwrap();
+        while let Some((from, to, data)) = queue.pop_front() {
+            time = time + Duration::from_millis(10);
+            let pcap_time = epoch + Duration::from_micros(time.as_usecs_since_epoch());
+            pcap.write_packet(pcap_time, socket_addr(from), socket_addr(to), &data)
+                .unwrap();
+            let net = if to == server_addr {
+                &mut server
+            } else {
+                &mut client
+            };
+            let mut buf = Vec::with_capacity(4096);
+            let mut warn = Vec::new();
+            let (iter, res) = net.feed(
+                &mut cb(to, time, &mut queue),
+                &mut warn,
+                from,
+                &data,
+                &mut buf,
+            );
+            let events: Vec<_> = iter.collect();
+            res.void_unwrap();
+            for event in events {
+                if let ChunkOrEvent::Connect(pid, _) = event {
+                    let mut cb = cb(server_addr, time, &mut queue);
+                    server.accept(&mut cb, pid).void_unwrap();
+                    let chunk = Chunk {
+                        pid,
+                        vital: true,
+                        data: b"hello",
+                    };
+                    server.send(&mut cb, chunk).unwrap();
+                    server.flush(&mut cb, pid).void_unwrap();
+                }
+            }
+        }
+        pcap.into_inner()
+    }
+
+    struct Client<'a> {
+        server: Addr,
+        protocol: Protocol,
+        connected: bool,
+        events: &'a mut Vec<String>,
+    }
+
+    impl<'a> Application<ReplayLoop> for Client<'a> {
+        fn needs_tick(&mut self) -> Timeout {
+            Timeout::inactive()
+        }
+        fn on_tick(&mut self, loop_: &mut ReplayLoop) {
+            if !self.connected {
+                self.connected = true;
+                match self.protocol {
+                    Protocol::V6 => loop_.connect(self.server),
+                    Protocol::V7 => loop_.connect7(self.server),
+                };
+            }
+        }
+        fn on_packet(&mut self, loop_: &mut ReplayLoop, chunk: Chunk) {
+            self.events.push(format!(
+                "{} {:?} {}",
+                loop_.time().as_usecs_since_epoch(),
+                chunk.data,
+                chunk.vital
+            ));
+        }
+        fn on_connless_packet(&mut self, _: &mut ReplayLoop, _: ConnlessChunk) {
+            unreachable!();
+        }
+        fn on_connect(&mut self, _: &mut ReplayLoop, _: PeerId) {
+            unreachable!();
+        }
+        fn on_ready(&mut self, loop_: &mut ReplayLoop, _: PeerId) {
+            let time = loop_.time().as_usecs_since_epoch();
+            self.events.push(format!("{} ready", time));
+        }
+        fn on_disconnect(&mut self, _: &mut ReplayLoop, _: PeerId, _: bool, _: &[u8]) {
+            unreachable!();
+        }
+    }
+
+    fn replay_client(protocol: Protocol) -> Vec<String> {
+        let server: Addr = "192.0.2.1:8303".parse().unwrap();
+        let client: Addr = "198.51.100.7:51234".parse().unwrap();
+        let capture = record(server, client, protocol);
+        let local: SocketAddr = "0.0.0.0:51234".parse().unwrap();
+        let loop_ = ReplayLoop::from_pcap(&capture[..], &[local], false).unwrap();
+        let mut events = Vec::new();
+        loop_.run(Client {
+            server,
+            protocol,
+            connected: false,
+            events: &mut events,
+        });
+        events
+    }
+
+    #[test]
+    fn replay_client6() {
+        // The first packet is the recorded connect at 10ms, the server's
+        // answers arrive 10ms and 30ms later.
+        assert_eq!(
+            replay_client(Protocol::V6),
+            ["10000 ready", "30000 [104, 101, 108, 108, 111] true"]
+        );
+    }
+
+    #[test]
+    fn replay_client7() {
+        // The token exchange takes another round trip. The tokens differ
+        // from the ones the replay would pick, see `recorded_token`.
+        assert_eq!(
+            replay_client(Protocol::V7),
+            ["30000 ready", "40000 [104, 101, 108, 108, 111] true"]
+        );
+    }
+
+    struct Server<'a> {
+        events: &'a mut Vec<String>,
+    }
+
+    impl<'a> Application<ReplayLoop> for Server<'a> {
+        fn needs_tick(&mut self) -> Timeout {
+            Timeout::inactive()
+        }
+        fn on_tick(&mut self, _: &mut ReplayLoop) {}
+        fn on_packet(&mut self, _: &mut ReplayLoop, _: Chunk) {
+            unreachable!();
+        }
+        fn on_connless_packet(&mut self, _: &mut ReplayLoop, _: ConnlessChunk) {
+            unreachable!();
+        }
+        fn on_connect(&mut self, loop_: &mut ReplayLoop, pid: PeerId) {
+            let time = loop_.time().as_usecs_since_epoch();
+            self.events.push(format!("{} connect", time));
+            loop_.accept(pid);
+        }
+        fn on_ready(&mut self, loop_: &mut ReplayLoop, _: PeerId) {
+            let time = loop_.time().as_usecs_since_epoch();
+            self.events.push(format!("{} ready", time));
+        }
+        fn on_disconnect(&mut self, _: &mut ReplayLoop, _: PeerId, _: bool, _: &[u8]) {
+            unreachable!();
+        }
+    }
+
+    fn replay_server(protocol: Protocol) -> Vec<String> {
+        let server: Addr = "192.0.2.1:8303".parse().unwrap();
+        let client: Addr = "198.51.100.7:51234".parse().unwrap();
+        let capture = record(server, client, protocol);
+        let local: SocketAddr = "0.0.0.0:8303".parse().unwrap();
+        let loop_ = ReplayLoop::from_pcap(&capture[..], &[local], true).unwrap();
+        let mut events = Vec::new();
+        loop_.run(Server {
+            events: &mut events,
+        });
+        events
+    }
+
+    #[test]
+    fn replay_server6() {
+        // The client echoes the recorded DDNet token in its `Accept`, which
+        // the replayed server must hand out as well.
+        assert_eq!(replay_server(Protocol::V6), ["20000 connect"]);
+    }
+
+    #[test]
+    fn replay_server7() {
+        assert_eq!(replay_server(Protocol::V7), ["20000 connect"]);
+    }
+}
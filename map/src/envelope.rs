@@ -0,0 +1,298 @@
+use crate::format;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CurveType {
+    Step,
+    Linear,
+    Slow,
+    Fast,
+    Smooth,
+    Bezier,
+    /// Evaluated like `Linear`.
+    Unknown(i32),
+}
+
+impl CurveType {
+    pub fn from_raw(raw: i32) -> CurveType {
+        match raw {
+            format::CURVETYPE_STEP => CurveType::Step,
+            format::CURVETYPE_LINEAR => CurveType::Linear,
+            format::CURVETYPE_SLOW => CurveType::Slow,
+            format::CURVETYPE_FAST => CurveType::Fast,
+            format::CURVETYPE_SMOOTH => CurveType::Smooth,
+            format::CURVETYPE_BEZIER => CurveType::Bezier,
+            _ => CurveType::Unknown(raw),
+        }
+    }
+    pub fn to_raw(self) -> i32 {
+        match self {
+            CurveType::Step => format::CURVETYPE_STEP,
+            CurveType::Linear => format::CURVETYPE_LINEAR,
+            CurveType::Slow => format::CURVETYPE_SLOW,
+            CurveType::Fast => format::CURVETYPE_FAST,
+            CurveType::Smooth => format::CURVETYPE_SMOOTH,
+            CurveType::Bezier => format::CURVETYPE_BEZIER,
+            CurveType::Unknown(raw) => raw,
+        }
+    }
+}
+
+/// Tangents of a DDNet bezier envelope point, per channel.
+///
+/// The `dx` values are time deltas in milliseconds, the `dy` values are value
+/// deltas in 22.10 fixed point.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Bezier {
+    pub in_tangent_dx: [i32; 4],
+    pub in_tangent_dy: [i32; 4],
+    pub out_tangent_dx: [i32; 4],
+    pub out_tangent_dy: [i32; 4],
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Envpoint {
+    /// Time in milliseconds.
+    pub time: i32,
+    /// Curve from this point to the next one.
+    pub curve_type: CurveType,
+    /// Values in 22.10 fixed point.
+    pub values: [i32; 4],
+    /// Only present in maps with version 3 envelopes.
+    pub bezier: Option<Bezier>,
+}
+
+fn fixed(values: &[format::Fixed22_10; 4]) -> [i32; 4] {
+    [
+        values[0].value,
+        values[1].value,
+        values[2].value,
+        values[3].value,
+    ]
+}
+
+fn to_fixed(values: &[i32; 4]) -> [format::Fixed22_10; 4] {
+    [
+        format::Fixed22_10 { value: values[0] },
+        format::Fixed22_10 { value: values[1] },
+        format::Fixed22_10 { value: values[2] },
+        format::Fixed22_10 { value: values[3] },
+    ]
+}
+
+impl Envpoint {
+    pub fn from_v1(raw: &format::MapItemEnvpointV1) -> Envpoint {
+        Envpoint {
+            time: raw.time,
+            curve_type: CurveType::from_raw(raw.curve_type),
+            values: fixed(&raw.values),
+            bezier: None,
+        }
+    }
+    pub fn from_v2(raw: &format::MapItemEnvpointV2) -> Envpoint {
+        Envpoint {
+            bezier: Some(Bezier {
+                in_tangent_dx: fixed(&raw.in_tangent_dx),
+                in_tangent_dy: fixed(&raw.in_tangent_dy),
+                out_tangent_dx: fixed(&raw.out_tangent_dx),
+                out_tangent_dy: fixed(&raw.out_tangent_dy),
+            }),
+            ..Envpoint::from_v1(&raw.v1)
+        }
+    }
+    pub fn to_v1(&self) -> format::MapItemEnvpointV1 {
+        format::MapItemEnvpointV1 {
+            time: self.time,
+            curve_type: self.curve_type.to_raw(),
+            values: to_fixed(&self.values),
+        }
+    }
+    /// Points without bezier tangents get zero tangents.
+    pub fn to_v2(&self) -> format::MapItemEnvpointV2 {
+        let bezier = self.bezier.unwrap_or(Bezier {
+            in_tangent_dx: [0; 4],
+            in_tangent_dy: [0; 4],
+            out_tangent_dx: [0; 4],
+            out_tangent_dy: [0; 4],
+        });
+        format::MapItemEnvpointV2 {
+            v1: self.to_v1(),
+            in_tangent_dx: to_fixed(&bezier.in_tangent_dx),
+            in_tangent_dy: to_fixed(&bezier.in_tangent_dy),
+            out_tangent_dx: to_fixed(&bezier.out_tangent_dx),
+            out_tangent_dy: to_fixed(&bezier.out_tangent_dy),
+        }
+    }
+}
+
+fn fx2f(value: i32) -> f64 {
+    value as f64 / 1024.0
+}
+
+fn bezier(p0: f64, p1: f64, p2: f64, p3: f64, a: f64) -> f64 {
+    let b = 1.0 - a;
+    b * b * b * p0 + 3.0 * b * b * a * p1 + 3.0 * b * a * a * p2 + a * a * a * p3
+}
+
+/// Evaluates the bezier curve of one channel between `p0` and `p1` at `time`.
+fn evaluate_bezier(p0: &Envpoint, p1: &Envpoint, channel: usize, time: f64) -> f64 {
+    let t0 = p0.time as f64;
+    let t3 = p1.time as f64;
+    let v0 = fx2f(p0.values[channel]);
+    let v3 = fx2f(p1.values[channel]);
+    let (out_dx, out_dy) = p0
+        .bezier
+        .map(|b| (b.out_tangent_dx[channel], b.out_tangent_dy[channel]))
+        .unwrap_or((0, 0));
+    let (in_dx, in_dy) = p1
+        .bezier
+        .map(|b| (b.in_tangent_dx[channel], b.in_tangent_dy[channel]))
+        .unwrap_or((0, 0));
+    // Like the reference implementation, keep the control points within the
+    // time range of the segment so that there's a solution for every time.
+    let t1 = (t0 + out_dx as f64).max(t0).min(t3);
+    let t2 = (t3 + in_dx as f64).max(t0).min(t3);
+    let v1 = v0 + fx2f(out_dy);
+    let v2 = v3 + fx2f(in_dy);
+
+    // Find the curve parameter for `time` by bisection, x(0) <= time <= x(1).
+    let mut lo = 0.0;
+    let mut hi = 1.0;
+    for _ in 0..48 {
+        let mid = (lo + hi) / 2.0;
+        if bezier(t0, t1, t2, t3, mid) < time {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    bezier(v0, v1, v2, v3, (lo + hi) / 2.0)
+}
+
+/// Evaluates the envelope consisting of `points` at `time`, given in
+/// milliseconds.
+///
+/// The envelope loops after its last point. Returns the values of all four
+/// channels, converted from fixed point, unused channels are meaningless.
+pub fn evaluate(points: &[Envpoint], time: f64) -> [f32; 4] {
+    fn convert(values: &[i32; 4]) -> [f32; 4] {
+        [
+            fx2f(values[0]) as f32,
+            fx2f(values[1]) as f32,
+            fx2f(values[2]) as f32,
+            fx2f(values[3]) as f32,
+        ]
+    }
+    let last = match points.last() {
+        Some(l) => l,
+        None => return [0.0; 4],
+    };
+    let end = last.time as f64;
+    let time = if end > 0.0 { time.rem_euclid(end) } else { 0.0 };
+    for segment in points.windows(2) {
+        let (p0, p1) = (&segment[0], &segment[1]);
+        let t0 = p0.time as f64;
+        let t1 = p1.time as f64;
+        if !(t0 <= time && time <= t1) {
+            continue;
+        }
+        let a = if t1 > t0 {
+            (time - t0) / (t1 - t0)
+        } else {
+            0.0
+        };
+        let a = match p0.curve_type {
+            CurveType::Step => 0.0,
+            CurveType::Linear | CurveType::Unknown(_) => a,
+            CurveType::Slow => a * a * a,
+            CurveType::Fast => {
+                let b = 1.0 - a;
+                1.0 - b * b * b
+            }
+            CurveType::Smooth => -2.0 * a * a * a + 3.0 * a * a,
+            CurveType::Bezier => {
+                let mut result = [0.0; 4];
+                for (c, r) in result.iter_mut().enumerate() {
+                    *r = evaluate_bezier(p0, p1, c, time) as f32;
+                }
+                return result;
+            }
+        };
+        let mut result = [0.0; 4];
+        for (c, r) in result.iter_mut().enumerate() {
+            let v0 = fx2f(p0.values[c]);
+            let v1 = fx2f(p1.values[c]);
+            *r = (v0 + (v1 - v0) * a) as f32;
+        }
+        return result;
+    }
+    convert(&last.values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::evaluate;
+    use super::Bezier;
+    use super::CurveType;
+    use super::Envpoint;
+
+    fn point(time: i32, curve_type: CurveType, value: i32) -> Envpoint {
+        Envpoint {
+            time: time,
+            curve_type: curve_type,
+            values: [value, 0, 0, 0],
+            bezier: None,
+        }
+    }
+
+    #[test]
+    fn curves() {
+        let linear = [
+            point(0, CurveType::Linear, 0),
+            point(1000, CurveType::Linear, 2048),
+        ];
+        assert_eq!(evaluate(&linear, 500.0)[0], 1.0);
+        // Loops after the last point.
+        assert_eq!(evaluate(&linear, 1250.0)[0], 0.5);
+
+        let step = [
+            point(0, CurveType::Step, 0),
+            point(1000, CurveType::Linear, 1024),
+        ];
+        assert_eq!(evaluate(&step, 999.0)[0], 0.0);
+
+        let smooth = [
+            point(0, CurveType::Smooth, 0),
+            point(1000, CurveType::Linear, 1024),
+        ];
+        assert_eq!(evaluate(&smooth, 500.0)[0], 0.5);
+        assert!(evaluate(&smooth, 100.0)[0] < 0.1);
+
+        assert_eq!(evaluate(&[], 0.0), [0.0; 4]);
+        assert_eq!(evaluate(&linear[1..], 123.0)[0], 2.0);
+    }
+
+    #[test]
+    fn bezier() {
+        let no_tangents = Bezier {
+            in_tangent_dx: [0; 4],
+            in_tangent_dy: [0; 4],
+            out_tangent_dx: [0; 4],
+            out_tangent_dy: [0; 4],
+        };
+        let mut p0 = point(0, CurveType::Bezier, 0);
+        let mut p1 = point(1000, CurveType::Linear, 1024);
+        p0.bezier = Some(no_tangents);
+        p1.bezier = Some(no_tangents);
+        // Without tangents, the curve is symmetric around its middle.
+        let mid = evaluate(&[p0, p1], 500.0)[0];
+        assert!((mid - 0.5).abs() < 1e-4);
+
+        // Tangents that lie on the straight line make the curve linear.
+        p0.bezier.as_mut().unwrap().out_tangent_dx[0] = 250;
+        p0.bezier.as_mut().unwrap().out_tangent_dy[0] = 256;
+        p1.bezier.as_mut().unwrap().in_tangent_dx[0] = -250;
+        p1.bezier.as_mut().unwrap().in_tangent_dy[0] = -256;
+        let quarter = evaluate(&[p0, p1], 250.0)[0];
+        assert!((quarter - 0.25).abs() < 1e-4);
+    }
+}
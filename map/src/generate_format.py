@@ -56,6 +56,7 @@ use libtw2_datafile::OnlyI32;
 use std::fmt;
 use std::mem;
 use std::ops;
+use uuid::Uuid;
 use zerocopy::byteorder::little_endian;
 
 pub trait MapItem: OnlyI32 {
@@ -129,6 +130,16 @@ pub fn i32s_to_bytes(result: &mut [u8], input: &[i32]) {
     }
 }
 
+pub fn bytes_to_i32s(result: &mut [i32], input: &[u8]) {
+    assert!(input.len() == result.len() * mem::size_of::<i32>());
+    for (output, input) in result.iter_mut().zip(input.chunks(mem::size_of::<i32>())) {
+        *output = (((input[0] as i32 + 0x80) & 0xff) << 24)
+            | (((input[1] as i32 + 0x80) & 0xff) << 16)
+            | (((input[2] as i32 + 0x80) & 0xff) <<  8)
+            | (((input[3] as i32 + 0x80) & 0xff) <<  0);
+    }
+}
+
 pub fn bytes_to_string(bytes: &[u8]) -> &[u8] {
     for (i, &b) in bytes.iter().enumerate() {
         if b == 0 {
@@ -140,8 +151,8 @@ pub fn bytes_to_string(bytes: &[u8]) -> &[u8] {
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-struct Fixed22_10 {
-    value: i32,
+pub struct Fixed22_10 {
+    pub value: i32,
 }
 
 unsafe impl OnlyI32 for Fixed22_10 { }
@@ -211,6 +222,51 @@ impl fmt::Debug for MapItemEnvelopeV1Legacy {
     }
 }
 
+pub const MAP_ITEMTYPE_UUID_TEST: Uuid = Uuid::from_u128(0xb1ab2186_5ac3_39d9_9fbc_47cf03efdf1e);
+pub const MAP_ITEMTYPE_UUID_AUTOMAPPER_CONFIG: Uuid = Uuid::from_u128(0x3e1b2716_178c_3978_9bd9_b11ae0410dd8);
+pub const MAP_ITEMTYPE_UUID_GROUP_EX: Uuid = Uuid::from_u128(0x6ec267b7_65b3_3f8f_b467_6f0c9261632d);
+
+pub const AUTOMAPPER_CONFIG_FLAG_AUTOMATIC: i32 = 1;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MapItemAutoMapperConfigV1 {
+    pub group: i32,
+    pub layer: i32,
+    pub config: i32,
+    pub seed: i32,
+    pub flags: i32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MapItemGroupExV1 {
+    pub parallax_zoom: i32,
+}
+
+unsafe impl OnlyI32 for MapItemAutoMapperConfigV1 { }
+unsafe impl OnlyI32 for MapItemGroupExV1 { }
+impl MapItem for MapItemAutoMapperConfigV1 { fn version() -> i32 { 1 } fn offset() -> usize { 1 } fn ignore_version() -> bool { false } }
+impl MapItem for MapItemGroupExV1 { fn version() -> i32 { 1 } fn offset() -> usize { 1 } fn ignore_version() -> bool { false } }
+
+impl fmt::Debug for MapItemAutoMapperConfigV1 {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        write!(_f, "group={:?}", self.group)?;
+        write!(_f, " layer={:?}", self.layer)?;
+        write!(_f, " config={:?}", self.config)?;
+        write!(_f, " seed={:?}", self.seed)?;
+        write!(_f, " flags={:?}", self.flags)?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MapItemGroupExV1 {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        write!(_f, "parallax_zoom={:?}", self.parallax_zoom)?;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct MapItemLayerV1CommonV0 {
@@ -229,19 +285,19 @@ impl fmt::Debug for MapItemLayerV1CommonV0 {
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct MapItemEnvpointV1 {
-    time: i32,
-    curve_type: i32,
-    values: [Fixed22_10; 4],
+    pub time: i32,
+    pub curve_type: i32,
+    pub values: [Fixed22_10; 4],
 }
 
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct MapItemEnvpointV2 {
-    v1: MapItemEnvpointV1,
-    in_tangent_dx: [Fixed22_10; 4],
-    in_tangent_dy: [Fixed22_10; 4],
-    out_tangent_dx: [Fixed22_10; 4],
-    out_tangent_dy: [Fixed22_10; 4],
+    pub v1: MapItemEnvpointV1,
+    pub in_tangent_dx: [Fixed22_10; 4],
+    pub in_tangent_dy: [Fixed22_10; 4],
+    pub out_tangent_dx: [Fixed22_10; 4],
+    pub out_tangent_dy: [Fixed22_10; 4],
 }
 
 unsafe impl OnlyI32 for MapItemEnvpointV1 { }
@@ -330,6 +386,81 @@ pub struct Tile {
     pub reserved: u8,
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(C)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(C)]
+pub struct QuadColor {
+    pub red: i32,
+    pub green: i32,
+    pub blue: i32,
+    pub alpha: i32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(C)]
+pub struct Quad {
+    pub points: [Point; 5],
+    pub colors: [QuadColor; 4],
+    pub texcoords: [Point; 4],
+    pub pos_env: i32,
+    pub pos_env_offset: i32,
+    pub color_env: i32,
+    pub color_env_offset: i32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(C)]
+pub struct SoundShape {
+    pub type_: i32,
+    // Rectangle: width, height. Circle: radius, unused.
+    pub values: [i32; 2],
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(C)]
+pub struct SoundSource {
+    pub position: Point,
+    pub loop_: i32,
+    pub pan: i32,
+    pub time_delay: i32,
+    pub falloff: i32,
+    pub pos_env: i32,
+    pub pos_env_offset: i32,
+    pub sound_env: i32,
+    pub sound_env_offset: i32,
+    pub shape: SoundShape,
+}
+
+// Used by sound layers of type `MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS_LEGACY`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(C)]
+pub struct SoundSourceLegacy {
+    pub position: Point,
+    pub loop_: i32,
+    pub time_delay: i32,
+    pub falloff_distance: i32,
+    pub pos_env: i32,
+    pub pos_env_offset: i32,
+    pub sound_env: i32,
+    pub sound_env_offset: i32,
+}
+
+unsafe impl OnlyI32 for Point { }
+unsafe impl OnlyI32 for QuadColor { }
+unsafe impl OnlyI32 for Quad { }
+unsafe impl OnlyI32 for SoundShape { }
+unsafe impl OnlyI32 for SoundSource { }
+unsafe impl OnlyI32 for SoundSourceLegacy { }
+
+pub const SOUNDSHAPE_RECTANGLE: i32 = 0;
+pub const SOUNDSHAPE_CIRCLE: i32 = 1;
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[repr(C)]
 pub struct TeleTile {
@@ -363,6 +494,13 @@ pub struct TuneTile {
     pub index: u8,
 }
 
+pub const CURVETYPE_STEP: i32 = 0;
+pub const CURVETYPE_LINEAR: i32 = 1;
+pub const CURVETYPE_SLOW: i32 = 2;
+pub const CURVETYPE_FAST: i32 = 3;
+pub const CURVETYPE_SMOOTH: i32 = 4;
+pub const CURVETYPE_BEZIER: i32 = 5;
+
 pub const TILEFLAG_VFLIP: u8 = 1 << 0;
 pub const TILEFLAG_HFLIP: u8 = 1 << 1;
 pub const TILEFLAG_OPAQUE: u8 = 1 << 2;
@@ -398,6 +536,16 @@ pub enum DdraceLayerSoundsError {
     InvalidSoundIndex(i32),
     InvalidNumSources(i32),
     InvalidDataIndex(i32),
+    // InvalidDataLength(length, num_sources)
+    InvalidDataLength(usize, usize),
+    // InvalidFalloff(source, falloff)
+    InvalidFalloff(usize, i32),
+    // InvalidShapeType(source, type)
+    InvalidShapeType(usize, i32),
+    // InvalidPosEnvelopeIndex(source, index)
+    InvalidPosEnvelopeIndex(usize, i32),
+    // InvalidSoundEnvelopeIndex(source, index)
+    InvalidSoundEnvelopeIndex(usize, i32),
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -408,6 +556,14 @@ pub enum LayerQuadsError {
     InvalidImageIndex(i32),
     InvalidNumQuads(i32),
     InvalidDataIndex(i32),
+    // InvalidDataLength(length, num_quads)
+    InvalidDataLength(usize, usize),
+    // InvalidColor(quad, component, value)
+    InvalidColor(usize, ColorComponent, i32),
+    // InvalidPosEnvelopeIndex(quad, index)
+    InvalidPosEnvelopeIndex(usize, i32),
+    // InvalidColorEnvelopeIndex(quad, index)
+    InvalidColorEnvelopeIndex(usize, i32),
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -471,6 +627,17 @@ impl From<DdraceLayerSoundsError> for LayerError {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum EnvelopeError {
+    TooShort(usize),
+    InvalidVersion(i32),
+    InvalidChannels(i32),
+    // InvalidStartPoints(start_points, num_points)
+    InvalidStartPoints(i32, i32),
+    // InvalidNumPoints(start_points, num_points)
+    InvalidNumPoints(i32, i32),
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum ImageError {
     TooShort(usize),
@@ -479,6 +646,34 @@ pub enum ImageError {
     InvalidWidth(i32),
     InvalidHeight(i32),
     InvalidNameIndex(i32),
+    InvalidFormat(i32),
+    // InvalidDataLength(length, expected)
+    InvalidDataLength(usize, usize),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum AutoMapperConfigError {
+    TooShort(usize),
+    InvalidVersion(i32),
+    InvalidGroupIndex(i32),
+    InvalidLayerIndex(i32),
+    InvalidConfig(i32),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum GroupExError {
+    TooShort(usize),
+    InvalidVersion(i32),
+    InvalidGroupIndex(u16),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SoundError {
+    TooShort(usize),
+    InvalidVersion(i32),
+    InvalidNameIndex(i32),
+    InvalidDataIndex(i32),
+    InvalidDataSize(i32),
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -503,13 +698,19 @@ pub enum Error {
     Group(usize, GroupError),
     Layer(usize, LayerError),
     Image(usize, ImageError),
+    Envelope(usize, EnvelopeError),
     Info(InfoError),
+    Sound(usize, SoundError),
+    AutoMapperConfig(usize, AutoMapperConfigError),
+    GroupEx(usize, GroupExError),
 
     InconsistentGameLayerDimensions,
     InvalidTilesLength(usize),
     InvalidTeleTilesLength(usize),
     InvalidTuneTilesLength(usize),
     InvalidVersion(i32),
+    // InvalidEnvpoints(envelope_version, length)
+    InvalidEnvpoints(i32, usize),
     MalformedImageName(usize),
     // InvalidTilesDimensions(length, width, height)
     InvalidTilesDimensions(usize, u32, u32),
@@ -529,6 +730,9 @@ pub enum Error {
 }
 
 pub const MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS_LEGACY: i32 = 9;
+
+pub const IMAGE_FORMAT_RGB: i32 = 0;
+pub const IMAGE_FORMAT_RGBA: i32 = 1;
 """
 
 def make_items(items):
@@ -648,7 +852,12 @@ impl {s} {{
         result[{num_bytes}-1] = 0;
         result
     }}
-}}""".format(s=struct_name(name, i), m=member, num_bytes=size*4))
+    pub fn {m}_set(&mut self, value: &[u8; {num_bytes}]) {{
+        bytes_to_i32s(&mut self.{m}, value);
+        // Like the reference implementation, always null-terminate.
+        self.{m}[{size}-1] &= !0xff;
+    }}
+}}""".format(size=size, s=struct_name(name, i), m=member, num_bytes=size*4))
 
     result.append("")
     return "\n".join(result)
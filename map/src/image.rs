@@ -0,0 +1,232 @@
+use crate::format;
+use crate::reader::ImageFormat;
+use libtw2_common::num::Cast;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Component;
+use std::path::Path;
+use std::str;
+
+/// An image decoded to 8-bit RGBA, stored row by row.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ExternalImageError {
+    Io(io::Error),
+    Png(png::DecodingError),
+}
+
+impl From<io::Error> for ExternalImageError {
+    fn from(err: io::Error) -> ExternalImageError {
+        ExternalImageError::Io(err)
+    }
+}
+
+impl From<png::DecodingError> for ExternalImageError {
+    fn from(err: png::DecodingError) -> ExternalImageError {
+        ExternalImageError::Png(err)
+    }
+}
+
+fn expand(width: u32, height: u32, samples: usize, raw: &[u8]) -> RgbaImage {
+    let data = if samples == 4 {
+        raw.to_vec()
+    } else {
+        let mut data = Vec::with_capacity(raw.len() / samples * 4);
+        for pixel in raw.chunks(samples) {
+            match *pixel {
+                [l] => data.extend_from_slice(&[l, l, l, 255]),
+                [l, a] => data.extend_from_slice(&[l, l, l, a]),
+                [r, g, b] => data.extend_from_slice(&[r, g, b, 255]),
+                _ => unreachable!(),
+            }
+        }
+        data
+    };
+    RgbaImage {
+        width: width,
+        height: height,
+        data: data,
+    }
+}
+
+impl RgbaImage {
+    /// Converts the data of an embedded map image to RGBA.
+    pub fn from_raw(
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+        raw: &[u8],
+    ) -> Result<RgbaImage, format::ImageError> {
+        use format::ImageError::*;
+
+        let samples = match format {
+            ImageFormat::Rgb => 3,
+            ImageFormat::Rgba => 4,
+        };
+        let expected = width
+            .usize()
+            .checked_mul(height.usize())
+            .and_then(|n| n.checked_mul(samples))
+            .ok_or(InvalidDataLength(raw.len(), usize::max_value()))?;
+        if raw.len() != expected {
+            return Err(InvalidDataLength(raw.len(), expected));
+        }
+        Ok(expand(width, height, samples, raw))
+    }
+    /// Decodes a PNG image, converting it to RGBA.
+    pub fn decode_png<R: Read>(r: R) -> Result<RgbaImage, png::DecodingError> {
+        let (info, mut reader) = png::Decoder::new(r).read_info()?;
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf)?;
+        let samples = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => {
+                return Err(png::DecodingError::Format(
+                    "unexpanded indexed color".into(),
+                ))
+            }
+        };
+        if info.bit_depth != png::BitDepth::Eight {
+            return Err(png::DecodingError::Format("unsupported bit depth".into()));
+        }
+        Ok(expand(info.width, info.height, samples, &buf))
+    }
+}
+
+/// Checks that the image name refers to a file directly inside the `mapres`
+/// directory.
+fn is_valid_name(name: &str) -> bool {
+    if name.contains(&['/', '\\', '\0'][..]) || name.contains("..") {
+        return false;
+    }
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+/// Loads the external image `name` from the `mapres` directory.
+///
+/// Returns `Ok(None)` if the image doesn't exist or its name cannot be a file
+/// name in `mapres`, e.g. because it contains path separators.
+pub fn load_external(mapres: &Path, name: &[u8]) -> Result<Option<RgbaImage>, ExternalImageError> {
+    let name = match str::from_utf8(name) {
+        Ok(n) if is_valid_name(n) => n,
+        _ => return Ok(None),
+    };
+    let file = match File::open(mapres.join(format!("{}.png", name))) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(RgbaImage::decode_png(io::BufReader::new(file))?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_valid_name;
+    use super::load_external;
+    use super::ExternalImageError;
+    use super::RgbaImage;
+    use crate::format::ImageError;
+    use crate::reader::ImageFormat;
+    use std::fs;
+
+    // 2x1 RGB image with the pixels (1, 2, 3) and (4, 5, 6).
+    const PNG_RGB: &[u8] = &[
+        137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 2, 0, 0, 0, 1, 8, 2,
+        0, 0, 0, 123, 64, 232, 221, 0, 0, 0, 15, 73, 68, 65, 84, 120, 156, 99, 96, 100, 98, 102,
+        97, 101, 3, 0, 0, 63, 0, 22, 33, 186, 212, 84, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96,
+        130,
+    ];
+    // 1x1 grayscale image with alpha and the pixel (7, 8).
+    const PNG_GRAYSCALE_ALPHA: &[u8] = &[
+        137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 1, 0, 0, 0, 1, 8, 4,
+        0, 0, 0, 181, 28, 12, 2, 0, 0, 0, 11, 73, 68, 65, 84, 120, 156, 99, 96, 231, 0, 0, 0, 25,
+        0, 16, 142, 29, 232, 75, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130,
+    ];
+
+    #[test]
+    fn from_raw() {
+        let rgb = RgbaImage::from_raw(2, 1, ImageFormat::Rgb, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            rgb,
+            Ok(RgbaImage {
+                width: 2,
+                height: 1,
+                data: vec![1, 2, 3, 255, 4, 5, 6, 255],
+            })
+        );
+        let rgba = RgbaImage::from_raw(1, 1, ImageFormat::Rgba, &[1, 2, 3, 4]);
+        assert_eq!(rgba.unwrap().data, [1, 2, 3, 4]);
+        assert_eq!(
+            RgbaImage::from_raw(2, 2, ImageFormat::Rgba, &[0; 15]),
+            Err(ImageError::InvalidDataLength(15, 16))
+        );
+    }
+
+    #[test]
+    fn decode_png() {
+        assert_eq!(
+            RgbaImage::decode_png(PNG_RGB).unwrap(),
+            RgbaImage {
+                width: 2,
+                height: 1,
+                data: vec![1, 2, 3, 255, 4, 5, 6, 255],
+            }
+        );
+        let gray = RgbaImage::decode_png(PNG_GRAYSCALE_ALPHA).unwrap();
+        assert_eq!(gray.data, [7, 7, 7, 8]);
+        assert!(RgbaImage::decode_png(&PNG_RGB[..40]).is_err());
+    }
+
+    #[test]
+    fn valid_name() {
+        assert!(is_valid_name("grass_main"));
+        assert!(is_valid_name("bg cloud1"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("."));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("../grass_main"));
+        assert!(!is_valid_name("a..b"));
+        assert!(!is_valid_name("dir/grass_main"));
+        assert!(!is_valid_name("dir\\grass_main"));
+        assert!(!is_valid_name("/etc/passwd"));
+        assert!(!is_valid_name("grass\0main"));
+    }
+
+    #[test]
+    fn external() {
+        let dir = tempfile::tempdir().unwrap();
+        let mapres = dir.path().join("mapres");
+        fs::create_dir(&mapres).unwrap();
+        fs::write(mapres.join("rgb.png"), PNG_RGB).unwrap();
+        fs::write(mapres.join("broken.png"), &PNG_RGB[..40]).unwrap();
+        fs::write(dir.path().join("outside.png"), PNG_RGB).unwrap();
+
+        let rgb = load_external(&mapres, b"rgb").unwrap().unwrap();
+        assert_eq!(rgb.data, [1, 2, 3, 255, 4, 5, 6, 255]);
+        assert!(load_external(&mapres, b"missing").unwrap().is_none());
+        assert!(load_external(&mapres, b"\xff").unwrap().is_none());
+        assert!(matches!(
+            load_external(&mapres, b"broken"),
+            Err(ExternalImageError::Png(_))
+        ));
+        // Names must not escape the `mapres` directory.
+        assert!(load_external(&mapres, b"../outside").unwrap().is_none());
+        let absolute = dir.path().join("outside");
+        let absolute = absolute.to_str().unwrap().as_bytes();
+        assert!(load_external(&mapres, absolute).unwrap().is_none());
+    }
+}
@@ -1,6 +1,11 @@
+pub use self::model::Map;
 pub use self::reader::Error;
 pub use self::reader::Reader;
 
+pub mod envelope;
 #[rustfmt::skip]
 pub mod format;
+pub mod image;
+pub mod model;
 pub mod reader;
+pub mod validate;
@@ -0,0 +1,926 @@
+use crate::envelope::Envpoint;
+use crate::format;
+use crate::format::Error as MapError;
+use crate::reader;
+use crate::reader::Clipping;
+use crate::reader::Color;
+use crate::reader::Error;
+use crate::reader::Quad;
+use crate::reader::Reader;
+use crate::reader::SoundShape;
+use crate::reader::SoundSource;
+use libtw2_common::num::Cast;
+use libtw2_common::slice;
+use libtw2_datafile as df;
+use libtw2_datafile::buffer::Buffer;
+use libtw2_datafile::OnlyI32;
+use ndarray::Array2;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+
+/// An owned, editable map.
+///
+/// References between parts of the map, e.g. from a layer to its image, are
+/// indices into the corresponding `Vec`s of the `Map`. They are converted to
+/// item and data indices when saving.
+///
+/// Items of UUID item types, e.g. auto-mapper configs, are not preserved.
+#[derive(Clone)]
+pub struct Map {
+    pub info: Info,
+    pub images: Vec<Image>,
+    pub envelopes: Vec<Envelope>,
+    pub groups: Vec<Group>,
+    pub sounds: Vec<Sound>,
+}
+
+#[derive(Clone, Default)]
+pub struct Info {
+    pub author: Option<Vec<u8>>,
+    pub version: Option<Vec<u8>>,
+    pub credits: Option<Vec<u8>>,
+    pub license: Option<Vec<u8>>,
+    /// Server commands, without null termination.
+    pub settings: Vec<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub struct Image {
+    pub name: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// RGBA pixel data, `None` for external images.
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub struct Sound {
+    pub name: Vec<u8>,
+    /// Opus data in an Ogg container, `None` for external sounds.
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub struct Envelope {
+    pub channels: u32,
+    pub points: Vec<Envpoint>,
+    pub synchronized: bool,
+    pub name: [u8; 32],
+}
+
+#[derive(Clone)]
+pub struct Group {
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub parallax_x: i32,
+    pub parallax_y: i32,
+    pub clipping: Option<Clipping>,
+    pub name: [u8; 12],
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Clone)]
+pub struct Layer {
+    pub detail: bool,
+    pub name: [u8; 12],
+    pub t: LayerType,
+}
+
+#[derive(Clone)]
+pub enum LayerType {
+    Tilemap(Tilemap),
+    Quads(Quads),
+    Sounds(Sounds),
+}
+
+#[derive(Clone)]
+pub struct Tilemap {
+    /// Color, color envelope and image are only used by `Tiles::Normal`
+    /// layers.
+    pub color: Color,
+    pub color_env_and_offset: Option<(usize, i32)>,
+    pub image: Option<usize>,
+    pub tiles: Tiles,
+}
+
+#[derive(Clone)]
+pub enum Tiles {
+    Normal(Array2<format::Tile>),
+    Game(Array2<format::Tile>),
+    Teleport(Array2<format::TeleTile>),
+    Speedup(Array2<format::SpeedupTile>),
+    Front(Array2<format::Tile>),
+    Switch(Array2<format::SwitchTile>),
+    Tune(Array2<format::TuneTile>),
+}
+
+impl Tiles {
+    /// Returns `(height, width)`.
+    pub fn dim(&self) -> (usize, usize) {
+        match *self {
+            Tiles::Normal(ref t) => t.dim(),
+            Tiles::Game(ref t) => t.dim(),
+            Tiles::Teleport(ref t) => t.dim(),
+            Tiles::Speedup(ref t) => t.dim(),
+            Tiles::Front(ref t) => t.dim(),
+            Tiles::Switch(ref t) => t.dim(),
+            Tiles::Tune(ref t) => t.dim(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Quads {
+    pub image: Option<usize>,
+    /// Envelope indices are indices into `Map::envelopes`.
+    pub quads: Vec<Quad>,
+}
+
+#[derive(Clone)]
+pub struct Sounds {
+    pub sound: Option<usize>,
+    /// Envelope indices are indices into `Map::envelopes`.
+    pub sources: Vec<SoundSource>,
+}
+
+const WHITE: Color = Color {
+    red: 255,
+    green: 255,
+    blue: 255,
+    alpha: 255,
+};
+
+fn rebase(index: Option<usize>, base: usize) -> Option<usize> {
+    index.map(|i| i - base)
+}
+
+fn rebase_env(env: Option<(usize, i32)>, base: usize) -> Option<(usize, i32)> {
+    env.map(|(i, offset)| (i - base, offset))
+}
+
+impl Map {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Map, Error> {
+        fn inner(path: &Path) -> Result<Map, Error> {
+            Map::from_reader(&mut Reader::open(path)?)
+        }
+        inner(path.as_ref())
+    }
+    pub fn from_reader<R: Read + Seek>(reader: &mut Reader<R>) -> Result<Map, Error> {
+        reader.check_version()?;
+        let image_base = reader
+            .reader
+            .item_type_indices(format::MAP_ITEMTYPE_IMAGE)
+            .start;
+        let envelope_base = reader.envelope_indices().start;
+        let sound_base = reader.sound_indices().start;
+
+        let info = match reader.info() {
+            Ok(info) => Info {
+                author: info.author.map(|i| reader.string(i)).transpose()?,
+                version: info.version.map(|i| reader.string(i)).transpose()?,
+                credits: info.credits.map(|i| reader.string(i)).transpose()?,
+                license: info.license.map(|i| reader.string(i)).transpose()?,
+                settings: match info.settings {
+                    Some(i) => reader.settings(i)?.iter().map(|s| s.to_vec()).collect(),
+                    None => Vec::new(),
+                },
+            },
+            Err(MapError::MissingInfo) => Info::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut images = Vec::new();
+        for i in reader.reader.item_type_indices(format::MAP_ITEMTYPE_IMAGE) {
+            let image = reader.image(i)?;
+            images.push(Image {
+                name: reader.image_name(image.name)?,
+                width: image.width,
+                height: image.height,
+                data: match image.data {
+                    Some(_) => reader.image_rgba(i, None)?.map(|i| i.data),
+                    None => None,
+                },
+            });
+        }
+
+        let points = reader.envelope_points()?;
+        let mut envelopes = Vec::new();
+        for i in reader.envelope_indices() {
+            let envelope = reader.envelope(i)?;
+            envelopes.push(Envelope {
+                channels: envelope.channels,
+                // Doesn't fail if envelope is from Reader::envelope().
+                points: points[envelope.points].to_vec(),
+                synchronized: envelope.synchronized,
+                name: envelope.name,
+            });
+        }
+
+        let mut sounds = Vec::new();
+        for i in reader.sound_indices() {
+            let sound = reader.sound(i)?;
+            sounds.push(Sound {
+                name: reader.string(sound.name)?,
+                data: sound.data.map(|d| reader.sound_data(d)).transpose()?,
+            });
+        }
+
+        let mut groups = Vec::new();
+        for i in reader.group_indices() {
+            let group = reader.group(i)?;
+            let mut layers = Vec::new();
+            for k in group.layer_indices.clone() {
+                let layer = reader.layer(k)?;
+                let (name, t) = match layer.t {
+                    reader::LayerType::Tilemap(tilemap) => {
+                        use reader::LayerTilemapType::*;
+
+                        // Special layers don't use color, envelope and image.
+                        let special = |tiles| Tilemap {
+                            color: WHITE,
+                            color_env_and_offset: None,
+                            image: None,
+                            tiles: tiles,
+                        };
+                        let result = match tilemap.type_ {
+                            Normal(n) => Tilemap {
+                                color: n.color,
+                                color_env_and_offset: rebase_env(
+                                    n.color_env_and_offset,
+                                    envelope_base,
+                                ),
+                                image: rebase(n.image, image_base),
+                                tiles: Tiles::Normal(reader.layer_tiles(tilemap.tiles(n.data))?),
+                            },
+                            Game(d) => special(Tiles::Game(reader.layer_tiles(tilemap.tiles(d))?)),
+                            RaceTeleport(d, _) => {
+                                special(Tiles::Teleport(reader.tele_layer_tiles(tilemap.tiles(d))?))
+                            }
+                            RaceSpeedup(d, _) => special(Tiles::Speedup(
+                                reader.speedup_layer_tiles(tilemap.tiles(d))?,
+                            )),
+                            DdraceFront(d, _) => {
+                                special(Tiles::Front(reader.layer_tiles(tilemap.tiles(d))?))
+                            }
+                            DdraceSwitch(d, _) => {
+                                special(Tiles::Switch(reader.switch_layer_tiles(tilemap.tiles(d))?))
+                            }
+                            DdraceTune(d, _) => {
+                                special(Tiles::Tune(reader.tune_layer_tiles(tilemap.tiles(d))?))
+                            }
+                        };
+                        (tilemap.name, LayerType::Tilemap(result))
+                    }
+                    reader::LayerType::Quads(quads) => {
+                        let mut result = reader.layer_quads(&quads)?;
+                        for q in &mut result {
+                            q.pos_env_and_offset = rebase_env(q.pos_env_and_offset, envelope_base);
+                            q.color_env_and_offset =
+                                rebase_env(q.color_env_and_offset, envelope_base);
+                        }
+                        let quads_ = Quads {
+                            image: rebase(quads.image, image_base),
+                            quads: result,
+                        };
+                        (quads.name, LayerType::Quads(quads_))
+                    }
+                    reader::LayerType::DdraceSounds(sounds) => {
+                        let mut result = reader.layer_sound_sources(&sounds)?;
+                        for s in &mut result {
+                            s.pos_env_and_offset = rebase_env(s.pos_env_and_offset, envelope_base);
+                            s.sound_env_and_offset =
+                                rebase_env(s.sound_env_and_offset, envelope_base);
+                        }
+                        let sounds_ = Sounds {
+                            sound: rebase(sounds.sound, sound_base),
+                            sources: result,
+                        };
+                        (sounds.name, LayerType::Sounds(sounds_))
+                    }
+                };
+                layers.push(Layer {
+                    detail: layer.detail,
+                    name: name,
+                    t: t,
+                });
+            }
+            groups.push(Group {
+                offset_x: group.offset_x,
+                offset_y: group.offset_y,
+                parallax_x: group.parallax_x,
+                parallax_y: group.parallax_y,
+                clipping: group.clipping,
+                name: group.name,
+                layers: layers,
+            });
+        }
+
+        Ok(Map {
+            info: info,
+            images: images,
+            envelopes: envelopes,
+            groups: groups,
+            sounds: sounds,
+        })
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), df::writer::Error> {
+        fn inner(map: &Map, path: &Path) -> Result<(), df::writer::Error> {
+            map.write(File::create(path)?)
+        }
+        inner(self, path.as_ref())
+    }
+    pub fn write<W: Write>(&self, file: W) -> Result<(), df::writer::Error> {
+        self.to_datafile().write(file)
+    }
+    /// Converts the map to datafile items and data, numbering items and
+    /// data in order.
+    pub fn to_datafile(&self) -> Buffer {
+        let mut df = Buffer::new();
+        df.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]).unwrap();
+        self.add_info(&mut df);
+        self.add_images(&mut df);
+        self.add_envelopes(&mut df);
+        self.add_groups(&mut df);
+        self.add_sounds(&mut df);
+        df
+    }
+    fn add_info(&self, df: &mut Buffer) {
+        let info = &self.info;
+        let settings = if !info.settings.is_empty() {
+            let mut raw = Vec::new();
+            for s in &info.settings {
+                raw.extend_from_slice(s);
+                raw.push(0);
+            }
+            df.add_data(raw).assert_i32()
+        } else {
+            -1
+        };
+        let mut data = vec![1];
+        push(
+            &mut data,
+            &format::MapItemInfoV1 {
+                author: add_string_opt(df, &info.author),
+                version: add_string_opt(df, &info.version),
+                credits: add_string_opt(df, &info.credits),
+                license: add_string_opt(df, &info.license),
+            },
+        );
+        push(&mut data, &format::MapItemInfoV2 { settings: settings });
+        df.add_item(format::MAP_ITEMTYPE_INFO, 0, &data).unwrap();
+    }
+    fn add_images(&self, df: &mut Buffer) {
+        for (i, image) in self.images.iter().enumerate() {
+            let name = add_string(df, &image.name);
+            let data = image.data.as_ref().map(|d| df.add_data(d.clone()));
+            let mut item = vec![1];
+            push(
+                &mut item,
+                &format::MapItemImageV1 {
+                    width: image.width.assert_i32(),
+                    height: image.height.assert_i32(),
+                    external: data.is_none() as i32,
+                    name: name,
+                    data: index_opt(data),
+                },
+            );
+            df.add_item(format::MAP_ITEMTYPE_IMAGE, i.assert_u16(), &item)
+                .unwrap();
+        }
+    }
+    fn add_envelopes(&self, df: &mut Buffer) {
+        // All points must have the same format, determined by the envelope
+        // version.
+        let bezier = self
+            .envelopes
+            .iter()
+            .any(|e| e.points.iter().any(|p| p.bezier.is_some()));
+        let mut points = Vec::new();
+        let mut num_points: usize = 0;
+        for (i, envelope) in self.envelopes.iter().enumerate() {
+            let mut v1 = format::MapItemEnvelopeV1 {
+                channels: envelope.channels.assert_i32(),
+                start_points: num_points.assert_i32(),
+                num_points: envelope.points.len().assert_i32(),
+                name: [0; 8],
+            };
+            v1.name_set(&envelope.name);
+            let mut item = vec![if bezier { 3 } else { 2 }];
+            push(&mut item, &v1);
+            push(
+                &mut item,
+                &format::MapItemEnvelopeV2 {
+                    synchronized: envelope.synchronized as i32,
+                },
+            );
+            df.add_item(format::MAP_ITEMTYPE_ENVELOPE, i.assert_u16(), &item)
+                .unwrap();
+            for p in &envelope.points {
+                if bezier {
+                    push(&mut points, &p.to_v2());
+                } else {
+                    push(&mut points, &p.to_v1());
+                }
+            }
+            num_points += envelope.points.len();
+        }
+        df.add_item(format::MAP_ITEMTYPE_ENVPOINTS, 0, &points)
+            .unwrap();
+    }
+    fn add_groups(&self, df: &mut Buffer) {
+        let mut num_layers: usize = 0;
+        for (i, group) in self.groups.iter().enumerate() {
+            let clipping = group.clipping.unwrap_or(Clipping {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+            });
+            let mut v3 = format::MapItemGroupV3 { name: [0; 3] };
+            v3.name_set(&group.name);
+            let mut item = vec![3];
+            push(
+                &mut item,
+                &format::MapItemGroupV1 {
+                    offset_x: group.offset_x,
+                    offset_y: group.offset_y,
+                    parallax_x: group.parallax_x,
+                    parallax_y: group.parallax_y,
+                    start_layer: num_layers.assert_i32(),
+                    num_layers: group.layers.len().assert_i32(),
+                },
+            );
+            push(
+                &mut item,
+                &format::MapItemGroupV2 {
+                    use_clipping: group.clipping.is_some() as i32,
+                    clip_x: clipping.x,
+                    clip_y: clipping.y,
+                    clip_w: clipping.width,
+                    clip_h: clipping.height,
+                },
+            );
+            push(&mut item, &v3);
+            df.add_item(format::MAP_ITEMTYPE_GROUP, i.assert_u16(), &item)
+                .unwrap();
+            for layer in &group.layers {
+                let item = layer.to_item(df);
+                df.add_item(format::MAP_ITEMTYPE_LAYER, num_layers.assert_u16(), &item)
+                    .unwrap();
+                num_layers += 1;
+            }
+        }
+    }
+    fn add_sounds(&self, df: &mut Buffer) {
+        for (i, sound) in self.sounds.iter().enumerate() {
+            let name = add_string(df, &sound.name);
+            let data = sound
+                .data
+                .as_ref()
+                .map(|d| (df.add_data(d.clone()), d.len()));
+            let mut item = vec![1];
+            push(
+                &mut item,
+                &format::MapItemDdraceSoundV1 {
+                    external: data.is_none() as i32,
+                    name: name,
+                    data: index_opt(data.map(|(d, _)| d)),
+                    data_size: data.map(|(_, s)| s.assert_i32()).unwrap_or(0),
+                },
+            );
+            df.add_item(format::MAP_ITEMTYPE_DDRACE_SOUND, i.assert_u16(), &item)
+                .unwrap();
+        }
+    }
+}
+
+impl Layer {
+    fn to_item(&self, df: &mut Buffer) -> Vec<i32> {
+        let flags = if self.detail {
+            format::LAYERFLAG_DETAIL
+        } else {
+            0
+        };
+        let (type_, rest) = match self.t {
+            LayerType::Tilemap(ref t) => {
+                (format::MAP_ITEMTYPE_LAYER_V1_TILEMAP, t.to_item(self, df))
+            }
+            LayerType::Quads(ref q) => (format::MAP_ITEMTYPE_LAYER_V1_QUADS, q.to_item(self, df)),
+            LayerType::Sounds(ref s) => (
+                format::MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS,
+                s.to_item(self, df),
+            ),
+        };
+        // The version of `MapItemLayerV1` isn't checked, write what the
+        // reference implementation writes.
+        let mut item = vec![0];
+        push(
+            &mut item,
+            &format::MapItemLayerV1 {
+                type_: type_,
+                flags: flags as i32,
+            },
+        );
+        item.extend_from_slice(&rest);
+        item
+    }
+}
+
+impl Tilemap {
+    fn to_item(&self, layer: &Layer, df: &mut Buffer) -> Vec<i32> {
+        let (height, width) = self.tiles.dim();
+        let (flags, tiles, extra_index) = match self.tiles {
+            Tiles::Normal(ref t) => (0, tiles_data(t), None),
+            Tiles::Game(ref t) => (format::TILELAYERFLAG_GAME, tiles_data(t), None),
+            Tiles::Teleport(ref t) => (format::TILELAYERFLAG_TELEPORT, tiles_data(t), Some(0)),
+            Tiles::Speedup(ref t) => (format::TILELAYERFLAG_SPEEDUP, tiles_data(t), Some(1)),
+            Tiles::Front(ref t) => (format::TILELAYERFLAG_FRONT, tiles_data(t), Some(2)),
+            Tiles::Switch(ref t) => (format::TILELAYERFLAG_SWITCH, tiles_data(t), Some(3)),
+            Tiles::Tune(ref t) => (format::TILELAYERFLAG_TUNE, tiles_data(t), Some(4)),
+        };
+        // Special layers store their tiles in an extra data, along with
+        // zeroed tiles in the regular one.
+        let mut extra = [-1; 5];
+        let data = match extra_index {
+            None => df.add_data(tiles),
+            Some(i) => {
+                extra[i] = df.add_data(tiles).assert_i32();
+                df.add_data(vec![0; width * height * mem::size_of::<format::Tile>()])
+            }
+        };
+        let (color_env, color_env_offset) = env_raw(self.color_env_and_offset);
+        let mut v3 = format::MapItemLayerV1TilemapV3 { name: [0; 3] };
+        v3.name_set(&layer.name);
+        let mut item = vec![3];
+        push(
+            &mut item,
+            &format::MapItemLayerV1TilemapV2 {
+                width: width.assert_i32(),
+                height: height.assert_i32(),
+                flags: flags as i32,
+                color_red: self.color.red.i32(),
+                color_green: self.color.green.i32(),
+                color_blue: self.color.blue.i32(),
+                color_alpha: self.color.alpha.i32(),
+                color_env: color_env,
+                color_env_offset: color_env_offset,
+                image: index_opt(self.image),
+                data: data.assert_i32(),
+            },
+        );
+        push(&mut item, &v3);
+        item.extend_from_slice(&extra);
+        item
+    }
+}
+
+impl Quads {
+    fn to_item(&self, layer: &Layer, df: &mut Buffer) -> Vec<i32> {
+        let quads: Vec<format::Quad> = self.quads.iter().map(quad_raw).collect();
+        let v1 = format::MapItemLayerV1QuadsV1 {
+            num_quads: quads.len().assert_i32(),
+            data: df.add_data(i32s_data(&quads)).assert_i32(),
+            image: index_opt(self.image),
+        };
+        let mut v2 = format::MapItemLayerV1QuadsV2 { name: [0; 3] };
+        v2.name_set(&layer.name);
+        let mut item = vec![2];
+        push(&mut item, &v1);
+        push(&mut item, &v2);
+        item
+    }
+}
+
+impl Sounds {
+    fn to_item(&self, layer: &Layer, df: &mut Buffer) -> Vec<i32> {
+        let sources: Vec<format::SoundSource> = self.sources.iter().map(sound_source_raw).collect();
+        let mut v1 = format::MapItemLayerV1DdraceSoundsV1 {
+            num_sources: sources.len().assert_i32(),
+            data: df.add_data(i32s_data(&sources)).assert_i32(),
+            sound: index_opt(self.sound),
+            name: [0; 3],
+        };
+        v1.name_set(&layer.name);
+        let mut item = vec![2];
+        push(&mut item, &v1);
+        item
+    }
+}
+
+fn quad_raw(quad: &Quad) -> format::Quad {
+    let color = |c: &Color| format::QuadColor {
+        red: c.red.i32(),
+        green: c.green.i32(),
+        blue: c.blue.i32(),
+        alpha: c.alpha.i32(),
+    };
+    let (pos_env, pos_env_offset) = env_raw(quad.pos_env_and_offset);
+    let (color_env, color_env_offset) = env_raw(quad.color_env_and_offset);
+    format::Quad {
+        points: quad.points,
+        colors: [
+            color(&quad.colors[0]),
+            color(&quad.colors[1]),
+            color(&quad.colors[2]),
+            color(&quad.colors[3]),
+        ],
+        texcoords: quad.texcoords,
+        pos_env: pos_env,
+        pos_env_offset: pos_env_offset,
+        color_env: color_env,
+        color_env_offset: color_env_offset,
+    }
+}
+
+fn sound_source_raw(source: &SoundSource) -> format::SoundSource {
+    let shape = match source.shape {
+        SoundShape::Rectangle(width, height) => format::SoundShape {
+            type_: format::SOUNDSHAPE_RECTANGLE,
+            values: [width, height],
+        },
+        SoundShape::Circle(radius) => format::SoundShape {
+            type_: format::SOUNDSHAPE_CIRCLE,
+            values: [radius, 0],
+        },
+    };
+    let (pos_env, pos_env_offset) = env_raw(source.pos_env_and_offset);
+    let (sound_env, sound_env_offset) = env_raw(source.sound_env_and_offset);
+    format::SoundSource {
+        position: source.position,
+        loop_: source.looped as i32,
+        pan: source.pan as i32,
+        time_delay: source.time_delay,
+        falloff: source.falloff.i32(),
+        pos_env: pos_env,
+        pos_env_offset: pos_env_offset,
+        sound_env: sound_env,
+        sound_env_offset: sound_env_offset,
+        shape: shape,
+    }
+}
+
+fn index_opt(index: Option<usize>) -> i32 {
+    index.map(|i| i.assert_i32()).unwrap_or(-1)
+}
+
+fn env_raw(env: Option<(usize, i32)>) -> (i32, i32) {
+    env.map(|(i, offset)| (i.assert_i32(), offset))
+        .unwrap_or((-1, 0))
+}
+
+fn add_string(df: &mut Buffer, string: &[u8]) -> i32 {
+    let mut data = string.to_vec();
+    data.push(0);
+    df.add_data(data).assert_i32()
+}
+
+fn add_string_opt(df: &mut Buffer, string: &Option<Vec<u8>>) -> i32 {
+    string.as_ref().map(|s| add_string(df, s)).unwrap_or(-1)
+}
+
+fn push<T: OnlyI32>(data: &mut Vec<i32>, value: &T) {
+    // `T: OnlyI32` only consists of `i32`s.
+    data.extend_from_slice(unsafe { slice::transmute(std::slice::from_ref(value)) });
+}
+
+pub(crate) fn i32s_data<T: OnlyI32>(values: &[T]) -> Vec<u8> {
+    // `T: OnlyI32` only consists of `i32`s.
+    let ints: &[i32] = unsafe { slice::transmute(values) };
+    ints.iter().flat_map(|i| i.to_le_bytes()).collect()
+}
+
+fn tiles_data<T: Copy>(tiles: &Array2<T>) -> Vec<u8> {
+    let tiles: Vec<T> = tiles.iter().cloned().collect();
+    // The tile types only consist of bytes.
+    unsafe { slice::transmute(&tiles) }.to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Envelope;
+    use super::Group;
+    use super::Image;
+    use super::Info;
+    use super::Layer;
+    use super::LayerType;
+    use super::Map;
+    use super::Quads;
+    use super::Sound;
+    use super::Sounds;
+    use super::Tilemap;
+    use super::Tiles;
+    use super::WHITE;
+    use crate::envelope::CurveType;
+    use crate::envelope::Envpoint;
+    use crate::format;
+    use crate::reader::Quad;
+    use crate::reader::SoundShape;
+    use crate::reader::SoundSource;
+    use crate::Reader;
+    use ndarray::Array2;
+    use std::io::Cursor;
+
+    fn tilemap(tiles: Tiles) -> Layer {
+        Layer {
+            detail: false,
+            name: *b"Tiles\0\0\0\0\0\0\0",
+            t: LayerType::Tilemap(Tilemap {
+                color: WHITE,
+                color_env_and_offset: None,
+                image: None,
+                tiles: tiles,
+            }),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let tile = format::Tile {
+            index: 1,
+            flags: 0,
+            skip: 0,
+            reserved: 0,
+        };
+        let tele = format::TeleTile {
+            number: 3,
+            index: 26,
+        };
+        let point = |time, value| Envpoint {
+            time: time,
+            curve_type: CurveType::Linear,
+            values: [value, 0, 0, 0],
+            bezier: None,
+        };
+        let mut normal = tilemap(Tiles::Normal(Array2::from_elem((1, 3), tile)));
+        if let LayerType::Tilemap(ref mut t) = normal.t {
+            t.image = Some(1);
+            t.color_env_and_offset = Some((0, 100));
+        }
+        let quad = Quad {
+            points: [format::Point { x: 1, y: 2 }; 5],
+            colors: [WHITE; 4],
+            texcoords: [format::Point { x: 0, y: 1024 }; 4],
+            pos_env_and_offset: Some((0, 0)),
+            color_env_and_offset: None,
+        };
+        let source = SoundSource {
+            position: format::Point { x: 3, y: 4 },
+            looped: true,
+            pan: false,
+            time_delay: 2,
+            falloff: 80,
+            pos_env_and_offset: None,
+            sound_env_and_offset: Some((0, 10)),
+            shape: SoundShape::Rectangle(1024, 2048),
+        };
+        let map = Map {
+            info: Info {
+                author: Some(b"author".to_vec()),
+                settings: vec![b"sv_gametype dm".to_vec(), b"sv_scorelimit 0".to_vec()],
+                ..Info::default()
+            },
+            images: vec![
+                Image {
+                    name: b"grass_main".to_vec(),
+                    width: 1024,
+                    height: 1024,
+                    data: None,
+                },
+                Image {
+                    name: b"embedded".to_vec(),
+                    width: 1,
+                    height: 1,
+                    data: Some(vec![255, 0, 0, 255]),
+                },
+            ],
+            envelopes: vec![Envelope {
+                channels: 4,
+                points: vec![point(0, 0), point(1000, 1024)],
+                synchronized: true,
+                name: [0; 32],
+            }],
+            groups: vec![
+                Group {
+                    offset_x: 0,
+                    offset_y: 0,
+                    parallax_x: 100,
+                    parallax_y: 100,
+                    clipping: None,
+                    name: *b"Game\0\0\0\0\0\0\0\0",
+                    layers: vec![
+                        tilemap(Tiles::Game(Array2::from_elem((2, 2), tile))),
+                        tilemap(Tiles::Teleport(Array2::from_elem((2, 2), tele))),
+                    ],
+                },
+                Group {
+                    offset_x: 1,
+                    offset_y: 2,
+                    parallax_x: 50,
+                    parallax_y: 50,
+                    clipping: None,
+                    name: [0; 12],
+                    layers: vec![
+                        normal,
+                        Layer {
+                            detail: true,
+                            name: [0; 12],
+                            t: LayerType::Quads(Quads {
+                                image: None,
+                                quads: vec![quad],
+                            }),
+                        },
+                        Layer {
+                            detail: false,
+                            name: *b"Sounds\0\0\0\0\0\0",
+                            t: LayerType::Sounds(Sounds {
+                                sound: Some(1),
+                                sources: vec![source],
+                            }),
+                        },
+                    ],
+                },
+            ],
+            sounds: vec![
+                Sound {
+                    name: b"external".to_vec(),
+                    data: None,
+                },
+                Sound {
+                    name: b"embedded".to_vec(),
+                    data: Some(b"OggS".to_vec()),
+                },
+            ],
+        };
+
+        let mut file = Vec::new();
+        map.write(&mut file).unwrap();
+        let loaded = Map::from_reader(&mut Reader::new(Cursor::new(file)).unwrap()).unwrap();
+
+        assert_eq!(loaded.info.author.as_deref(), Some(&b"author"[..]));
+        assert_eq!(loaded.info.version, None);
+        assert_eq!(loaded.info.settings, map.info.settings);
+        assert_eq!(loaded.images.len(), 2);
+        assert_eq!(loaded.images[0].data, None);
+        assert_eq!(loaded.images[1].name, b"embedded");
+        assert_eq!(loaded.images[1].data, map.images[1].data);
+        assert_eq!(loaded.envelopes[0].points, map.envelopes[0].points);
+        assert!(loaded.envelopes[0].synchronized);
+        assert_eq!(loaded.groups.len(), 2);
+        assert_eq!(loaded.groups[0].name, map.groups[0].name);
+        assert_eq!(loaded.groups[1].offset_y, 2);
+
+        let layers = &loaded.groups[0].layers;
+        assert_eq!(layers[0].name, *b"Tiles\0\0\0\0\0\0\0");
+        match layers[1].t {
+            LayerType::Tilemap(Tilemap {
+                tiles: Tiles::Teleport(ref t),
+                ..
+            }) => assert!(t.iter().all(|&t| t == tele)),
+            _ => panic!("expected teleport layer"),
+        }
+        let layers = &loaded.groups[1].layers;
+        match layers[0].t {
+            LayerType::Tilemap(ref t) => {
+                assert_eq!(t.image, Some(1));
+                assert_eq!(t.color_env_and_offset, Some((0, 100)));
+                assert_eq!(t.tiles.dim(), (1, 3));
+            }
+            _ => panic!("expected tilemap layer"),
+        }
+        assert!(layers[1].detail);
+        match layers[1].t {
+            LayerType::Quads(ref q) => {
+                assert_eq!(q.quads.len(), 1);
+                assert_eq!(q.quads[0].points, quad.points);
+                assert_eq!(q.quads[0].pos_env_and_offset, Some((0, 0)));
+            }
+            _ => panic!("expected quads layer"),
+        }
+        assert_eq!(layers[2].name, *b"Sounds\0\0\0\0\0\0");
+        match layers[2].t {
+            LayerType::Sounds(ref s) => {
+                assert_eq!(s.sound, Some(1));
+                assert_eq!(s.sources.len(), 1);
+                let loaded = s.sources[0];
+                assert_eq!(loaded.position, source.position);
+                assert!(loaded.looped);
+                assert!(!loaded.pan);
+                assert_eq!(loaded.time_delay, 2);
+                assert_eq!(loaded.falloff, 80);
+                assert_eq!(loaded.pos_env_and_offset, None);
+                assert_eq!(loaded.sound_env_and_offset, Some((0, 10)));
+                assert_eq!(loaded.shape, source.shape);
+            }
+            _ => panic!("expected sounds layer"),
+        }
+        assert_eq!(loaded.sounds.len(), 2);
+        assert_eq!(loaded.sounds[0].name, b"external");
+        assert_eq!(loaded.sounds[0].data, None);
+        assert_eq!(loaded.sounds[1].name, b"embedded");
+        assert_eq!(loaded.sounds[1].data, map.sounds[1].data);
+    }
+}
@@ -1,21 +1,33 @@
+use crate::envelope;
+use crate::envelope::Envpoint;
 use crate::format;
+use crate::format::EnvpointExt;
 use crate::format::Error as MapError;
 use crate::format::MapItem;
 use crate::format::MapItemExt;
+use crate::image;
+use crate::image::RgbaImage;
 use libtw2_common::num::Cast;
+use libtw2_common::slice;
 use libtw2_common::unwrap_or_return;
 use libtw2_common::vec;
 use libtw2_datafile as df;
+use libtw2_datafile::OnlyI32;
 use ndarray::Array2;
+use std::fs::File;
 use std::io;
+use std::io::Read;
+use std::io::Seek;
 use std::mem;
 use std::ops;
 use std::path::Path;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub enum Error {
     Map(MapError),
     Df(df::Error),
+    ExternalImage(image::ExternalImageError),
 }
 
 impl From<MapError> for Error {
@@ -36,6 +48,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<image::ExternalImageError> for Error {
+    fn from(err: image::ExternalImageError) -> Error {
+        Error::ExternalImage(err)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Color {
     pub red: u8,
@@ -155,6 +173,34 @@ impl<T> AugmentResult for Result<T, format::ImageError> {
     }
 }
 
+impl<T> AugmentResult for Result<T, format::AutoMapperConfigError> {
+    type AddIndex = Result<T, MapError>;
+    fn add_index(self, index: usize) -> Result<T, MapError> {
+        self.map_err(|e| MapError::AutoMapperConfig(index, e))
+    }
+}
+
+impl<T> AugmentResult for Result<T, format::GroupExError> {
+    type AddIndex = Result<T, MapError>;
+    fn add_index(self, index: usize) -> Result<T, MapError> {
+        self.map_err(|e| MapError::GroupEx(index, e))
+    }
+}
+
+impl<T> AugmentResult for Result<T, format::SoundError> {
+    type AddIndex = Result<T, MapError>;
+    fn add_index(self, index: usize) -> Result<T, MapError> {
+        self.map_err(|e| MapError::Sound(index, e))
+    }
+}
+
+impl<T> AugmentResult for Result<T, format::EnvelopeError> {
+    type AddIndex = Result<T, MapError>;
+    fn add_index(self, index: usize) -> Result<T, MapError> {
+        self.map_err(|e| MapError::Envelope(index, e))
+    }
+}
+
 pub struct LayerTilesIndex {
     data_index: usize,
     width: u32,
@@ -211,6 +257,8 @@ impl Group {
 
 #[derive(Clone, Copy)]
 pub struct DdraceLayerSounds {
+    /// Item index of the layer, as passed to `Reader::layer`.
+    pub index: usize,
     pub num_sources: usize,
     pub data: usize,
     pub sound: Option<usize>,
@@ -220,6 +268,7 @@ pub struct DdraceLayerSounds {
 
 impl DdraceLayerSounds {
     fn from_raw(
+        index: usize,
         raw: &[i32],
         data_indices: ops::Range<usize>,
         sound_indices: ops::Range<usize>,
@@ -231,6 +280,7 @@ impl DdraceLayerSounds {
             format::MapItemLayerV1DdraceSoundsV2::mandatory(raw, TooShortV2, InvalidVersion)?;
         }
         Ok(DdraceLayerSounds {
+            index: index,
             num_sources: v1
                 .num_sources
                 .try_usize()
@@ -243,8 +293,97 @@ impl DdraceLayerSounds {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SoundShape {
+    /// Width and height in 22.10 fixed point.
+    Rectangle(i32, i32),
+    /// Radius in 22.10 fixed point.
+    Circle(i32),
+}
+
+#[derive(Clone, Copy)]
+pub struct SoundSource {
+    /// Position in 22.10 fixed point.
+    pub position: format::Point,
+    pub looped: bool,
+    pub pan: bool,
+    /// Delay in seconds.
+    pub time_delay: i32,
+    /// 0 means no falloff, 255 full falloff.
+    pub falloff: u8,
+    pub pos_env_and_offset: Option<(usize, i32)>,
+    pub sound_env_and_offset: Option<(usize, i32)>,
+    pub shape: SoundShape,
+}
+
+impl SoundSource {
+    fn from_raw(
+        index: usize,
+        raw: &format::SoundSource,
+        envelope_indices: ops::Range<usize>,
+    ) -> Result<SoundSource, format::DdraceLayerSoundsError> {
+        use format::DdraceLayerSoundsError::*;
+
+        let shape = match raw.shape.type_ {
+            format::SOUNDSHAPE_RECTANGLE => {
+                SoundShape::Rectangle(raw.shape.values[0], raw.shape.values[1])
+            }
+            format::SOUNDSHAPE_CIRCLE => SoundShape::Circle(raw.shape.values[0]),
+            t => return Err(InvalidShapeType(index, t)),
+        };
+        let mut result = SoundSource::from_raw_legacy(
+            index,
+            &format::SoundSourceLegacy {
+                position: raw.position,
+                loop_: raw.loop_,
+                time_delay: raw.time_delay,
+                falloff_distance: 0,
+                pos_env: raw.pos_env,
+                pos_env_offset: raw.pos_env_offset,
+                sound_env: raw.sound_env,
+                sound_env_offset: raw.sound_env_offset,
+            },
+            envelope_indices,
+        )?;
+        result.pan = raw.pan != 0;
+        result.falloff = raw
+            .falloff
+            .try_u8()
+            .ok_or(InvalidFalloff(index, raw.falloff))?;
+        result.shape = shape;
+        Ok(result)
+    }
+    fn from_raw_legacy(
+        index: usize,
+        raw: &format::SoundSourceLegacy,
+        envelope_indices: ops::Range<usize>,
+    ) -> Result<SoundSource, format::DdraceLayerSoundsError> {
+        use format::DdraceLayerSoundsError::*;
+
+        let pos_env = get_index_opt(raw.pos_env, envelope_indices.clone(), |i| {
+            InvalidPosEnvelopeIndex(index, i)
+        })?;
+        let sound_env = get_index_opt(raw.sound_env, envelope_indices, |i| {
+            InvalidSoundEnvelopeIndex(index, i)
+        })?;
+        // Legacy sources are converted like in the reference implementation.
+        Ok(SoundSource {
+            position: raw.position,
+            looped: raw.loop_ != 0,
+            pan: true,
+            time_delay: raw.time_delay,
+            falloff: 0,
+            pos_env_and_offset: pos_env.map(|e| (e, raw.pos_env_offset)),
+            sound_env_and_offset: sound_env.map(|e| (e, raw.sound_env_offset)),
+            shape: SoundShape::Circle(raw.falloff_distance),
+        })
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct LayerQuads {
+    /// Item index of the layer, as passed to `Reader::layer`.
+    pub index: usize,
     pub num_quads: usize,
     pub data: usize,
     pub image: Option<usize>,
@@ -253,6 +392,7 @@ pub struct LayerQuads {
 
 impl LayerQuads {
     fn from_raw(
+        index: usize,
         raw: &[i32],
         data_indices: ops::Range<usize>,
         image_indices: ops::Range<usize>,
@@ -263,6 +403,7 @@ impl LayerQuads {
         let v2 = format::MapItemLayerV1QuadsV2::optional(raw, TooShortV2)?;
         let name = v2.map(|v2| v2.name_get()).unwrap_or([0; 12]);
         Ok(LayerQuads {
+            index: index,
             num_quads: v1
                 .num_quads
                 .try_usize()
@@ -274,6 +415,63 @@ impl LayerQuads {
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct Quad {
+    /// The four corners followed by the pivot, in 22.10 fixed point.
+    pub points: [format::Point; 5],
+    pub colors: [Color; 4],
+    /// Texture coordinates of the corners, in 22.10 fixed point.
+    pub texcoords: [format::Point; 4],
+    pub pos_env_and_offset: Option<(usize, i32)>,
+    pub color_env_and_offset: Option<(usize, i32)>,
+}
+
+impl Quad {
+    fn from_raw(
+        index: usize,
+        raw: &format::Quad,
+        envelope_indices: ops::Range<usize>,
+    ) -> Result<Quad, format::LayerQuadsError> {
+        use format::ColorComponent::*;
+        use format::LayerQuadsError::*;
+
+        fn component(
+            index: usize,
+            c: format::ColorComponent,
+            value: i32,
+        ) -> Result<u8, format::LayerQuadsError> {
+            value.try_u8().ok_or(InvalidColor(index, c, value))
+        }
+        let mut colors = [Color {
+            red: 0,
+            green: 0,
+            blue: 0,
+            alpha: 0,
+        }; 4];
+        for (color, raw_color) in colors.iter_mut().zip(raw.colors.iter()) {
+            *color = Color {
+                red: component(index, Red, raw_color.red)?,
+                green: component(index, Green, raw_color.green)?,
+                blue: component(index, Blue, raw_color.blue)?,
+                alpha: component(index, Alpha, raw_color.alpha)?,
+            };
+        }
+        let pos_env = get_index_opt(raw.pos_env, envelope_indices.clone(), |i| {
+            InvalidPosEnvelopeIndex(index, i)
+        })?;
+        let color_env = get_index_opt(raw.color_env, envelope_indices, |i| {
+            InvalidColorEnvelopeIndex(index, i)
+        })?;
+        Ok(Quad {
+            points: raw.points,
+            colors: colors,
+            texcoords: raw.texcoords,
+            pos_env_and_offset: pos_env.map(|e| (e, raw.pos_env_offset)),
+            color_env_and_offset: color_env.map(|e| (e, raw.color_env_offset)),
+        })
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum LayerTilemapType {
     // Normal(normal)
@@ -486,6 +684,7 @@ pub enum LayerType {
 
 impl Layer {
     fn from_raw(
+        index: usize,
         raw: &[i32],
         data_indices: ops::Range<usize>,
         envelope_indices: ops::Range<usize>,
@@ -510,12 +709,16 @@ impl Layer {
                 format::MAP_ITEMTYPE_LAYER_V1_TILEMAP => LayerType::Tilemap(
                     LayerTilemap::from_raw(rest, data_indices, envelope_indices, image_indices)?,
                 ),
-                format::MAP_ITEMTYPE_LAYER_V1_QUADS => {
-                    LayerType::Quads(LayerQuads::from_raw(rest, data_indices, image_indices)?)
-                }
+                format::MAP_ITEMTYPE_LAYER_V1_QUADS => LayerType::Quads(LayerQuads::from_raw(
+                    index,
+                    rest,
+                    data_indices,
+                    image_indices,
+                )?),
                 format::MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS
                 | format::MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS_LEGACY => {
                     LayerType::DdraceSounds(DdraceLayerSounds::from_raw(
+                        index,
                         rest,
                         data_indices,
                         sound_indices,
@@ -531,11 +734,19 @@ impl Layer {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ImageFormat {
+    Rgb,
+    Rgba,
+}
+
 pub struct Image {
     pub width: u32,
     pub height: u32,
     pub name: usize,
     pub data: Option<usize>,
+    /// Pixel format of the embedded data, images without a format are RGBA.
+    pub format: ImageFormat,
 }
 
 impl Image {
@@ -543,6 +754,12 @@ impl Image {
         use format::ImageError::*;
 
         let v1 = format::MapItemImageV1::mandatory(raw, TooShort, InvalidVersion)?;
+        let v2 = format::MapItemImageV2::optional(raw, TooShort)?;
+        let format = match v2.map(|v2| v2.format) {
+            None | Some(format::IMAGE_FORMAT_RGBA) => ImageFormat::Rgba,
+            Some(format::IMAGE_FORMAT_RGB) => ImageFormat::Rgb,
+            Some(f) => return Err(InvalidFormat(f)),
+        };
         // WARN if external is something other than 0,1
         let data = if v1.external != 0 {
             None
@@ -554,10 +771,160 @@ impl Image {
             height: v1.height.try_u32().ok_or(InvalidHeight(v1.height))?,
             name: get_index(v1.name, data_indices.clone(), InvalidNameIndex)?,
             data: data,
+            format: format,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Envelope {
+    /// 1 for sound volume, 3 for position and rotation, 4 for color.
+    pub channels: u32,
+    /// Indices into `Reader::envelope_points`.
+    pub points: ops::Range<usize>,
+    pub synchronized: bool,
+    pub name: [u8; 32],
+}
+
+impl Envelope {
+    fn from_raw(raw: &[i32], num_envpoints: usize) -> Result<Envelope, format::EnvelopeError> {
+        use format::EnvelopeError::*;
+
+        let (channels, start_points, num_points, name) =
+            match format::MapItemEnvelopeV1::optional(raw, TooShort) {
+                Ok(Some(v1)) => (v1.channels, v1.start_points, v1.num_points, v1.name_get()),
+                Ok(None) => return Err(InvalidVersion(raw[0])),
+                Err(_) => {
+                    // Old maps have envelopes without name.
+                    let l =
+                        format::MapItemEnvelopeV1Legacy::mandatory(raw, TooShort, InvalidVersion)?;
+                    (l.channels, l.start_points, l.num_points, [0; 32])
+                }
+            };
+        let v2 = format::MapItemEnvelopeV2::optional(raw, TooShort)?;
+
+        let sp = InvalidStartPoints(start_points, num_points);
+        let np = InvalidNumPoints(start_points, num_points);
+        let points_start = start_points.try_usize().ok_or(sp)?;
+        if points_start > num_envpoints {
+            return Err(sp);
+        }
+        let points_end = points_start + num_points.try_usize().ok_or(np)?;
+        if points_end > num_envpoints {
+            return Err(np);
+        }
+        Ok(Envelope {
+            channels: match channels {
+                1..=4 => channels.assert_u32(),
+                _ => return Err(InvalidChannels(channels)),
+            },
+            points: points_start..points_end,
+            synchronized: v2.map(|v2| v2.synchronized != 0).unwrap_or(false),
+            name: name,
+        })
+    }
+}
+
+enum RawEnvpoints<'a> {
+    V1(&'a [format::MapItemEnvpointV1]),
+    V2(&'a [format::MapItemEnvpointV2]),
+}
+
+impl<'a> RawEnvpoints<'a> {
+    fn len(&self) -> usize {
+        match *self {
+            RawEnvpoints::V1(p) => p.len(),
+            RawEnvpoints::V2(p) => p.len(),
+        }
+    }
+}
+
+pub struct Sound {
+    pub name: usize,
+    /// `None` for external sounds.
+    pub data: Option<usize>,
+    /// Size of the data as stored in the sound item.
+    pub data_size: Option<usize>,
+}
+
+impl Sound {
+    fn from_raw(raw: &[i32], data_indices: ops::Range<usize>) -> Result<Sound, format::SoundError> {
+        use format::SoundError::*;
+
+        let v1 = format::MapItemDdraceSoundV1::mandatory(raw, TooShort, InvalidVersion)?;
+        let (data, data_size) = if v1.external != 0 {
+            (None, None)
+        } else {
+            (
+                Some(get_index(v1.data, data_indices.clone(), InvalidDataIndex)?),
+                Some(
+                    v1.data_size
+                        .try_usize()
+                        .ok_or(InvalidDataSize(v1.data_size))?,
+                ),
+            )
+        };
+        Ok(Sound {
+            name: get_index(v1.name, data_indices, InvalidNameIndex)?,
+            data: data,
+            data_size: data_size,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AutoMapperConfig {
+    pub group: usize,
+    pub layer: usize,
+    /// Index of the rule set in the automapper file of the layer's image.
+    pub config: Option<u32>,
+    pub seed: u32,
+    pub automatic: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GroupEx {
+    pub group: usize,
+    pub parallax_zoom: i32,
+}
+
+impl AutoMapperConfig {
+    fn from_raw<GL>(
+        raw: &[i32],
+        group_indices: ops::Range<usize>,
+        group_layer_indices: GL,
+    ) -> Result<AutoMapperConfig, format::AutoMapperConfigError>
+    where
+        GL: FnOnce(usize) -> Option<ops::Range<usize>>,
+    {
+        use format::AutoMapperConfigError::*;
+
+        let v1 = format::MapItemAutoMapperConfigV1::mandatory(raw, TooShort, InvalidVersion)?;
+        let group = get_index(v1.group, group_indices, InvalidGroupIndex)?;
+        let layer_indices = group_layer_indices(group).ok_or(InvalidGroupIndex(v1.group))?;
+        let config = if v1.config == -1 {
+            None
+        } else {
+            Some(v1.config.try_u32().ok_or(InvalidConfig(v1.config))?)
+        };
+        Ok(AutoMapperConfig {
+            group: group,
+            layer: get_index(v1.layer, layer_indices, InvalidLayerIndex)?,
+            config: config,
+            seed: v1.seed as u32,
+            automatic: v1.flags & format::AUTOMAPPER_CONFIG_FLAG_AUTOMATIC != 0,
         })
     }
 }
 
+/// An item of a UUID item type, typed if the UUID is known.
+#[derive(Clone, Copy, Debug)]
+pub enum UuidItem<'a> {
+    AutoMapperConfig(AutoMapperConfig),
+    GroupEx(GroupEx),
+    Unknown(Uuid, df::ItemView<'a>),
+}
+
 pub struct GameLayers {
     pub group: Group,
     pub width: u32,
@@ -658,18 +1025,26 @@ impl<'a> Iterator for SettingsIter<'a> {
     }
 }
 
-pub struct Reader {
-    pub reader: df::Reader,
+/// A map reader over any seekable byte stream, by default a file.
+pub struct Reader<R = File> {
+    pub reader: df::Reader<R>,
 }
 
-impl Reader {
+impl Reader<File> {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
         fn inner(path: &Path) -> Result<Reader, Error> {
             Ok(Reader::from_datafile(df::Reader::open(path)?))
         }
         inner(path.as_ref())
     }
-    pub fn from_datafile(reader: df::Reader) -> Reader {
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Reads a map starting at the current position of `file`.
+    pub fn new(file: R) -> Result<Reader<R>, Error> {
+        Ok(Reader::from_datafile(df::Reader::new(file)?))
+    }
+    pub fn from_datafile(reader: df::Reader<R>) -> Reader<R> {
         Reader { reader: reader }
     }
     pub fn check_version(&self) -> Result<(), MapError> {
@@ -721,6 +1096,7 @@ impl Reader {
             .reader
             .item_type_indices(format::MAP_ITEMTYPE_DDRACE_SOUND);
         Layer::from_raw(
+            index,
             raw.data,
             data_indices,
             envelope_indices,
@@ -729,6 +1105,98 @@ impl Reader {
         )
         .add_index(index)
     }
+    pub fn envelope_indices(&self) -> ops::Range<usize> {
+        self.reader.item_type_indices(format::MAP_ITEMTYPE_ENVELOPE)
+    }
+    pub fn envelope(&self, index: usize) -> Result<Envelope, MapError> {
+        // Doesn't fail if index is from Reader::envelope_indices().
+        let raw = self.reader.item(index);
+        assert!(raw.type_id == format::MAP_ITEMTYPE_ENVELOPE);
+        let num_envpoints = self.raw_envpoints()?.len();
+        Envelope::from_raw(raw.data, num_envpoints).add_index(index)
+    }
+    fn raw_envpoints(&self) -> Result<RawEnvpoints, MapError> {
+        let raw = self
+            .reader
+            .find_item(format::MAP_ITEMTYPE_ENVPOINTS, 0)
+            .map(|i| i.data)
+            .unwrap_or(&[]);
+        // The format of the envelope points is determined by the version of
+        // the first envelope.
+        let version = self
+            .envelope_indices()
+            .next()
+            .and_then(|i| self.reader.item(i).data.first().cloned())
+            .unwrap_or(1);
+        if let Some(points) = format::MapItemEnvpointV1::from_slice(raw, version) {
+            Ok(RawEnvpoints::V1(points))
+        } else if let Some(points) = format::MapItemEnvpointV2::from_slice(raw, version) {
+            Ok(RawEnvpoints::V2(points))
+        } else {
+            Err(MapError::InvalidEnvpoints(version, raw.len()))
+        }
+    }
+    /// Returns the points of all envelopes, see `Envelope::points`.
+    pub fn envelope_points(&self) -> Result<Vec<Envpoint>, MapError> {
+        Ok(match self.raw_envpoints()? {
+            RawEnvpoints::V1(p) => p.iter().map(Envpoint::from_v1).collect(),
+            RawEnvpoints::V2(p) => p.iter().map(Envpoint::from_v2).collect(),
+        })
+    }
+    /// Evaluates the envelope at `time`, given in milliseconds.
+    ///
+    /// Reads all envelope points on each call, use `envelope::evaluate` with
+    /// the result of `envelope_points` to evaluate envelopes repeatedly.
+    pub fn evaluate(&self, envelope: &Envelope, time: f64) -> Result<[f32; 4], MapError> {
+        let points = self.envelope_points()?;
+        // Doesn't fail if envelope is from Reader::envelope().
+        Ok(envelope::evaluate(&points[envelope.points.clone()], time))
+    }
+    pub fn automapper_config(&self, index: usize) -> Result<AutoMapperConfig, MapError> {
+        let raw = self.reader.item(index);
+        // The groups themselves are validated by Reader::group().
+        let group_layer_indices = |group| self.group(group).ok().map(|g| g.layer_indices);
+        AutoMapperConfig::from_raw(raw.data, self.group_indices(), group_layer_indices)
+            .add_index(index)
+    }
+    pub fn group_ex(&self, index: usize) -> Result<GroupEx, MapError> {
+        use format::GroupExError::*;
+
+        let raw = self.reader.item(index);
+        let result = format::MapItemGroupExV1::mandatory(raw.data, TooShort, InvalidVersion)
+            .and_then(|v1| {
+                // Group extensions share the ID of the group they extend.
+                let group = self
+                    .group_indices()
+                    .find(|&i| self.reader.item(i).id == raw.id)
+                    .ok_or(InvalidGroupIndex(raw.id))?;
+                Ok(GroupEx {
+                    group: group,
+                    parallax_zoom: v1.parallax_zoom,
+                })
+            });
+        result.add_index(index)
+    }
+    /// Returns all items of UUID item types.
+    pub fn uuid_items(&self) -> Result<Vec<UuidItem>, MapError> {
+        let mut result = Vec::new();
+        for registration in self.reader.item_type_items(df::format::ITEMTYPE_EX) {
+            let uuid = match df::format::uuid_from_item_data(registration.data) {
+                Some(u) => u,
+                None => continue,
+            };
+            for index in self.reader.item_type_indices(registration.id) {
+                result.push(if uuid == format::MAP_ITEMTYPE_UUID_AUTOMAPPER_CONFIG {
+                    UuidItem::AutoMapperConfig(self.automapper_config(index)?)
+                } else if uuid == format::MAP_ITEMTYPE_UUID_GROUP_EX {
+                    UuidItem::GroupEx(self.group_ex(index)?)
+                } else {
+                    UuidItem::Unknown(uuid, self.reader.item(index))
+                });
+            }
+        }
+        Ok(result)
+    }
     pub fn image(&self, index: usize) -> Result<Image, MapError> {
         let raw = self.reader.item(index);
         let data_indices = 0..self.reader.num_data();
@@ -737,6 +1205,47 @@ impl Reader {
     pub fn image_data(&mut self, data_index: usize) -> Result<Vec<u8>, Error> {
         Ok(self.reader.read_data(data_index)?)
     }
+    /// Returns the image decoded to RGBA.
+    ///
+    /// External images are loaded from the `mapres` directory. Returns
+    /// `Ok(None)` for external images if `mapres` is `None` or the image
+    /// can't be found there.
+    pub fn image_rgba(
+        &mut self,
+        index: usize,
+        mapres: Option<&Path>,
+    ) -> Result<Option<RgbaImage>, Error> {
+        let image = self.image(index)?;
+        match image.data {
+            Some(data_index) => {
+                let raw = self.image_data(data_index)?;
+                Ok(Some(
+                    RgbaImage::from_raw(image.width, image.height, image.format, &raw)
+                        .add_index(index)?,
+                ))
+            }
+            None => {
+                let mapres = unwrap_or_return!(mapres, Ok(None));
+                let name = self.image_name(image.name)?;
+                Ok(image::load_external(mapres, &name)?)
+            }
+        }
+    }
+    pub fn sound_indices(&self) -> ops::Range<usize> {
+        self.reader
+            .item_type_indices(format::MAP_ITEMTYPE_DDRACE_SOUND)
+    }
+    pub fn sound(&self, index: usize) -> Result<Sound, MapError> {
+        // Doesn't fail if index is from Reader::sound_indices().
+        let raw = self.reader.item(index);
+        assert!(raw.type_id == format::MAP_ITEMTYPE_DDRACE_SOUND);
+        let data_indices = 0..self.reader.num_data();
+        Sound::from_raw(raw.data, data_indices).add_index(index)
+    }
+    /// Returns the embedded sound, Opus data in an Ogg container.
+    pub fn sound_data(&mut self, data_index: usize) -> Result<Vec<u8>, Error> {
+        Ok(self.reader.read_data(data_index)?)
+    }
     pub fn game_layers(&self) -> Result<GameLayers, MapError> {
         fn put<T>(opt: &mut Option<T>, new: T) -> Result<(), MapError> {
             match mem::replace(opt, Some(new)) {
@@ -946,6 +1455,71 @@ impl Reader {
                 .map_err(|_| MapError::InvalidTilesDimensions(len, height, width))?,
         )
     }
+    fn read_i32_structs<T, IL>(
+        &mut self,
+        data_index: usize,
+        num: usize,
+        invalid_length: IL,
+    ) -> Result<Vec<T>, Error>
+    where
+        T: OnlyI32,
+        IL: FnOnce(usize) -> MapError,
+    {
+        let raw = self.reader.read_data(data_index)?;
+        if num.checked_mul(mem::size_of::<T>()) != Some(raw.len()) {
+            return Err(invalid_length(raw.len()).into());
+        }
+        let ints: Vec<i32> = raw
+            .chunks(mem::size_of::<i32>())
+            .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        // `T: OnlyI32` only consists of `i32`s.
+        Ok(unsafe { slice::transmute(&ints) }.to_vec())
+    }
+    pub fn layer_quads(&mut self, layer: &LayerQuads) -> Result<Vec<Quad>, Error> {
+        use format::LayerQuadsError::*;
+
+        let quads: Vec<format::Quad> = self.read_i32_structs(layer.data, layer.num_quads, |l| {
+            MapError::Layer(layer.index, InvalidDataLength(l, layer.num_quads).into())
+        })?;
+        let envelope_indices = self.envelope_indices();
+        let quads: Result<Vec<Quad>, _> = quads
+            .iter()
+            .enumerate()
+            .map(|(i, q)| Quad::from_raw(i, q, envelope_indices.clone()))
+            .collect();
+        Ok(quads
+            .map_err(format::LayerError::from)
+            .add_index(layer.index)?)
+    }
+    pub fn layer_sound_sources(
+        &mut self,
+        layer: &DdraceLayerSounds,
+    ) -> Result<Vec<SoundSource>, Error> {
+        use format::DdraceLayerSoundsError::*;
+
+        let num = layer.num_sources;
+        let invalid_length = |l| MapError::Layer(layer.index, InvalidDataLength(l, num).into());
+        let envelope_indices = self.envelope_indices();
+        let sources: Result<Vec<SoundSource>, _> = if !layer.legacy {
+            let raw: Vec<format::SoundSource> =
+                self.read_i32_structs(layer.data, num, invalid_length)?;
+            raw.iter()
+                .enumerate()
+                .map(|(i, s)| SoundSource::from_raw(i, s, envelope_indices.clone()))
+                .collect()
+        } else {
+            let raw: Vec<format::SoundSourceLegacy> =
+                self.read_i32_structs(layer.data, num, invalid_length)?;
+            raw.iter()
+                .enumerate()
+                .map(|(i, s)| SoundSource::from_raw_legacy(i, s, envelope_indices.clone()))
+                .collect()
+        };
+        Ok(sources
+            .map_err(format::LayerError::from)
+            .add_index(layer.index)?)
+    }
     pub fn string(&mut self, data_index: usize) -> Result<Vec<u8>, Error> {
         let mut raw = self.reader.read_data(data_index)?;
         if let Some(0) = raw.pop() {
@@ -966,3 +1540,319 @@ impl Reader {
         Ok(Settings { raw: raw })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use super::LayerType;
+    use super::Quad;
+    use super::Reader;
+    use super::SoundShape;
+    use super::SoundSource;
+    use super::UuidItem;
+    use crate::format;
+    use crate::format::Error as MapError;
+    use crate::format::LayerError;
+    use crate::model::i32s_data;
+    use libtw2_datafile::buffer::Buffer;
+    use std::io::Cursor;
+    use std::mem;
+    use uuid::Uuid;
+
+    fn read(df: &Buffer) -> Reader<Cursor<Vec<u8>>> {
+        let mut file = Vec::new();
+        df.write(&mut file).unwrap();
+        Reader::new(Cursor::new(file)).unwrap()
+    }
+
+    fn add_quads_layer(df: &mut Buffer, id: u16, num_quads: i32, data: Vec<u8>) {
+        let data = df.add_data(data) as i32;
+        let item = [
+            0,
+            format::MAP_ITEMTYPE_LAYER_V1_QUADS,
+            0,
+            2,
+            num_quads,
+            data,
+            -1,
+            0,
+            0,
+            0,
+        ];
+        df.add_item(format::MAP_ITEMTYPE_LAYER, id, &item).unwrap();
+    }
+
+    fn add_sounds_layer(df: &mut Buffer, id: u16, type_: i32, num_sources: i32, data: Vec<u8>) {
+        let data = df.add_data(data) as i32;
+        let version = if type_ == format::MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS {
+            2
+        } else {
+            1
+        };
+        let item = [0, type_, 0, version, num_sources, data, 0, 0, 0, 0];
+        df.add_item(format::MAP_ITEMTYPE_LAYER, id, &item).unwrap();
+    }
+
+    fn read_sound_sources(
+        reader: &mut Reader<Cursor<Vec<u8>>>,
+        index: usize,
+    ) -> Result<Vec<SoundSource>, Error> {
+        match reader.layer(index).unwrap().t {
+            LayerType::DdraceSounds(sounds) => {
+                assert_eq!(sounds.sound, Some(reader.sound_indices().start));
+                reader.layer_sound_sources(&sounds)
+            }
+            _ => panic!("expected sounds layer"),
+        }
+    }
+
+    fn read_quads(reader: &mut Reader<Cursor<Vec<u8>>>, index: usize) -> Result<Vec<Quad>, Error> {
+        match reader.layer(index).unwrap().t {
+            LayerType::Quads(quads) => reader.layer_quads(&quads),
+            _ => panic!("expected quads layer"),
+        }
+    }
+
+    #[test]
+    fn layer_quads() {
+        use crate::format::LayerQuadsError::*;
+
+        let quad = format::Quad {
+            points: [format::Point { x: 1, y: 2 }; 5],
+            colors: [format::QuadColor {
+                red: 255,
+                green: 128,
+                blue: 0,
+                alpha: 255,
+            }; 4],
+            texcoords: [format::Point { x: 0, y: 1024 }; 4],
+            pos_env: -1,
+            pos_env_offset: 0,
+            color_env: -1,
+            color_env_offset: 0,
+        };
+        let invalid_env = format::Quad {
+            color_env: 0,
+            ..quad
+        };
+        let mut df = Buffer::new();
+        df.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]).unwrap();
+        add_quads_layer(&mut df, 0, 2, i32s_data(&[quad, quad]));
+        add_quads_layer(&mut df, 1, 2, i32s_data(&[quad]));
+        add_quads_layer(&mut df, 2, 1, i32s_data(&[invalid_env]));
+        let mut reader = read(&df);
+        let layers = reader.reader.item_type_indices(format::MAP_ITEMTYPE_LAYER);
+
+        let quads = read_quads(&mut reader, layers.start).unwrap();
+        assert_eq!(quads.len(), 2);
+        assert_eq!(quads[1].points, quad.points);
+        assert_eq!(quads[1].colors[0].green, 128);
+        assert_eq!(quads[1].texcoords, quad.texcoords);
+        assert!(quads[1].pos_env_and_offset.is_none());
+        assert!(quads[1].color_env_and_offset.is_none());
+
+        // Errors carry the index of the layer they occurred in.
+        let size = mem::size_of::<format::Quad>();
+        let index = layers.start + 1;
+        assert!(matches!(
+            read_quads(&mut reader, index),
+            Err(Error::Map(MapError::Layer(i, LayerError::Quads(InvalidDataLength(l, 2)))))
+                if i == index && l == size
+        ));
+        let index = layers.start + 2;
+        assert!(matches!(
+            read_quads(&mut reader, index),
+            Err(Error::Map(MapError::Layer(
+                i,
+                LayerError::Quads(InvalidColorEnvelopeIndex(0, 0))
+            ))) if i == index
+        ));
+    }
+
+    #[test]
+    fn layer_sound_sources() {
+        use crate::format::DdraceLayerSoundsError::*;
+
+        let source = format::SoundSource {
+            position: format::Point { x: 1024, y: 2048 },
+            loop_: 1,
+            pan: 0,
+            time_delay: 3,
+            falloff: 128,
+            pos_env: -1,
+            pos_env_offset: 0,
+            sound_env: -1,
+            sound_env_offset: 0,
+            shape: format::SoundShape {
+                type_: format::SOUNDSHAPE_RECTANGLE,
+                values: [100, 200],
+            },
+        };
+        let circle = format::SoundSource {
+            shape: format::SoundShape {
+                type_: format::SOUNDSHAPE_CIRCLE,
+                values: [300, 0],
+            },
+            ..source
+        };
+        let invalid_shape = format::SoundSource {
+            shape: format::SoundShape {
+                type_: 2,
+                values: [0, 0],
+            },
+            ..source
+        };
+        let invalid_falloff = format::SoundSource {
+            falloff: 256,
+            ..source
+        };
+        let legacy = format::SoundSourceLegacy {
+            position: format::Point { x: 1, y: 2 },
+            loop_: 0,
+            time_delay: 4,
+            falloff_distance: 1500,
+            pos_env: -1,
+            pos_env_offset: 0,
+            sound_env: -1,
+            sound_env_offset: 0,
+        };
+        let sounds = format::MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS;
+        let sounds_legacy = format::MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS_LEGACY;
+        let mut df = Buffer::new();
+        df.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]).unwrap();
+        let name = df.add_data(b"sound\0".to_vec()) as i32;
+        let data = df.add_data(b"OggS".to_vec()) as i32;
+        df.add_item(format::MAP_ITEMTYPE_DDRACE_SOUND, 0, &[1, 0, name, data, 4])
+            .unwrap();
+        add_sounds_layer(&mut df, 0, sounds, 2, i32s_data(&[source, circle]));
+        add_sounds_layer(&mut df, 1, sounds_legacy, 1, i32s_data(&[legacy]));
+        add_sounds_layer(&mut df, 2, sounds, 2, i32s_data(&[source, invalid_shape]));
+        add_sounds_layer(&mut df, 3, sounds, 1, i32s_data(&[invalid_falloff]));
+        let mut reader = read(&df);
+        let layers = reader.reader.item_type_indices(format::MAP_ITEMTYPE_LAYER);
+
+        let sources = read_sound_sources(&mut reader, layers.start).unwrap();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].position, source.position);
+        assert!(sources[0].looped);
+        assert!(!sources[0].pan);
+        assert_eq!(sources[0].time_delay, 3);
+        assert_eq!(sources[0].falloff, 128);
+        assert_eq!(sources[0].shape, SoundShape::Rectangle(100, 200));
+        assert_eq!(sources[1].shape, SoundShape::Circle(300));
+
+        // Legacy sources are always panned, have no falloff and a circle
+        // shape with the falloff distance as radius.
+        let sources = read_sound_sources(&mut reader, layers.start + 1).unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].position, legacy.position);
+        assert!(!sources[0].looped);
+        assert!(sources[0].pan);
+        assert_eq!(sources[0].time_delay, 4);
+        assert_eq!(sources[0].falloff, 0);
+        assert_eq!(sources[0].shape, SoundShape::Circle(1500));
+
+        let index = layers.start + 2;
+        assert!(matches!(
+            read_sound_sources(&mut reader, index),
+            Err(Error::Map(MapError::Layer(
+                i,
+                LayerError::DdraceSounds(InvalidShapeType(1, 2))
+            ))) if i == index
+        ));
+        let index = layers.start + 3;
+        assert!(matches!(
+            read_sound_sources(&mut reader, index),
+            Err(Error::Map(MapError::Layer(
+                i,
+                LayerError::DdraceSounds(InvalidFalloff(0, 256))
+            ))) if i == index
+        ));
+    }
+
+    #[test]
+    fn sound() {
+        use crate::format::SoundError::*;
+
+        let mut df = Buffer::new();
+        df.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]).unwrap();
+        let name = df.add_data(b"sound\0".to_vec()) as i32;
+        let data = df.add_data(b"OggS".to_vec()) as i32;
+        let sound = format::MAP_ITEMTYPE_DDRACE_SOUND;
+        df.add_item(sound, 0, &[1, 0, name, data, 4]).unwrap();
+        df.add_item(sound, 1, &[1, 1, name, -1, 0]).unwrap();
+        df.add_item(sound, 2, &[1, 0, name, data, -1]).unwrap();
+        df.add_item(sound, 3, &[1, 0, name, 5, 4]).unwrap();
+        let mut reader = read(&df);
+        let sounds = reader.sound_indices();
+
+        let embedded = reader.sound(sounds.start).unwrap();
+        assert_eq!(reader.string(embedded.name).unwrap(), b"sound");
+        assert_eq!(embedded.data, Some(data as usize));
+        assert_eq!(embedded.data_size, Some(4));
+        assert_eq!(reader.sound_data(data as usize).unwrap(), b"OggS");
+        let external = reader.sound(sounds.start + 1).unwrap();
+        assert_eq!(external.name, name as usize);
+        assert_eq!(external.data, None);
+        assert_eq!(external.data_size, None);
+
+        let index = sounds.start + 2;
+        assert!(matches!(
+            reader.sound(index),
+            Err(MapError::Sound(i, InvalidDataSize(-1))) if i == index
+        ));
+        let index = sounds.start + 3;
+        assert!(matches!(
+            reader.sound(index),
+            Err(MapError::Sound(i, InvalidDataIndex(5))) if i == index
+        ));
+    }
+
+    #[test]
+    fn uuid_items() {
+        let mut df = Buffer::new();
+        df.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]).unwrap();
+        df.add_item(format::MAP_ITEMTYPE_GROUP, 0, &[1, 0, 0, 100, 100, 0, 2])
+            .unwrap();
+        add_quads_layer(&mut df, 0, 0, Vec::new());
+        add_quads_layer(&mut df, 1, 0, Vec::new());
+        let automapper = df.add_uuid_item_type(format::MAP_ITEMTYPE_UUID_AUTOMAPPER_CONFIG);
+        df.add_item(automapper, 0, &[1, 0, 1, -1, 42, 1]).unwrap();
+        df.add_item(automapper, 1, &[1, 0, 0, 3, 0, 0]).unwrap();
+        // Spelled out to check against the UUID DDNet writes.
+        let group_ex = Uuid::from_u128(0x6ec267b7_65b3_3f8f_b467_6f0c9261632d);
+        let group_ex = df.add_uuid_item_type(group_ex);
+        df.add_item(group_ex, 0, &[1, 50]).unwrap();
+        let unknown = Uuid::from_u128(0x01234567_89ab_cdef_0123_456789abcdef);
+        let unknown_type = df.add_uuid_item_type(unknown);
+        df.add_item(unknown_type, 0, &[7]).unwrap();
+        let reader = read(&df);
+        let group = reader.group_indices().start;
+        let layers = reader.reader.item_type_indices(format::MAP_ITEMTYPE_LAYER);
+
+        let items = reader.uuid_items().unwrap();
+        assert_eq!(items.len(), 4);
+        let automapper_configs: Vec<_> = items
+            .iter()
+            .filter_map(|i| match *i {
+                UuidItem::AutoMapperConfig(c) => Some(c),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(automapper_configs.len(), 2);
+        let c = automapper_configs[0];
+        assert_eq!((c.group, c.layer), (group, layers.start + 1));
+        assert_eq!((c.config, c.seed, c.automatic), (None, 42, true));
+        let c = automapper_configs[1];
+        assert_eq!((c.group, c.layer), (group, layers.start));
+        assert_eq!((c.config, c.seed, c.automatic), (Some(3), 0, false));
+        assert!(items.iter().any(|i| matches!(
+            *i,
+            UuidItem::GroupEx(g) if g.group == group && g.parallax_zoom == 50
+        )));
+        assert!(items.iter().any(|i| matches!(
+            *i,
+            UuidItem::Unknown(u, item) if u == unknown && item.data == [7]
+        )));
+    }
+}
@@ -0,0 +1,641 @@
+//! Checks whole maps for problems, reporting them as diagnostics.
+//!
+//! Unlike the `Reader`, validation doesn't stop at the first problem. The
+//! diagnostics derive `Serialize`, for machine-readable output.
+
+use crate::format;
+use crate::format::Error as MapError;
+use crate::reader;
+use crate::reader::Error;
+use crate::reader::ImageFormat;
+use crate::reader::LayerTilemapType;
+use crate::reader::LayerType;
+use crate::reader::Reader;
+use libtw2_common::num::Cast;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Seek;
+use std::mem;
+use std::ops;
+
+/// Layers with more tiles than this are reported as `Code::LayerTooLarge`.
+pub const MAX_LAYER_TILES: u64 = 1000 * 1000;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Code {
+    /// Datafile data that can't be read, e.g. due to broken compression.
+    InvalidData,
+    InvalidVersion,
+    InvalidInfo,
+    /// An item that can't be parsed, for reasons not covered by other codes.
+    InvalidItem,
+    InvalidImageIndex,
+    InvalidEnvelopeIndex,
+    InvalidSoundIndex,
+    ImageDataSize,
+    TilesDataSize,
+    NoGameLayer,
+    TooManyGameLayers,
+    TooManyGameGroups,
+    InconsistentGameLayerDimensions,
+    LayerTooLarge,
+    UnusedImage,
+    UnusedEnvelope,
+    UnusedSound,
+    /// A teleporter tile without teleporter number.
+    TeleMissingNumber,
+    /// Teleporter entrances without an exit of the same number.
+    TeleMissingTarget,
+    /// An empty tele or switch tile that has a number set.
+    NumberWithoutTile,
+}
+
+/// Location of a diagnostic, all indices start at zero.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize)]
+pub struct Location {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<usize>,
+    /// Index of the layer in its group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layer: Option<usize>,
+    /// Tile position `(x, y)` in the layer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile: Option<(usize, usize)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sound: Option<usize>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub location: Location,
+    pub message: String,
+}
+
+fn map_error_code(err: &MapError) -> Code {
+    use format::DdraceLayerSoundsError as S;
+    use format::LayerError as L;
+    use format::LayerQuadsError as Q;
+    use format::LayerTilemapError as T;
+
+    match *err {
+        MapError::Layer(_, L::Tilemap(T::InvalidImageIndex(_)))
+        | MapError::Layer(_, L::Quads(Q::InvalidImageIndex(_))) => Code::InvalidImageIndex,
+        MapError::Layer(_, L::Tilemap(T::InvalidColorEnvelopeIndex(_)))
+        | MapError::Layer(_, L::Quads(Q::InvalidPosEnvelopeIndex(..)))
+        | MapError::Layer(_, L::Quads(Q::InvalidColorEnvelopeIndex(..)))
+        | MapError::Layer(_, L::DdraceSounds(S::InvalidPosEnvelopeIndex(..)))
+        | MapError::Layer(_, L::DdraceSounds(S::InvalidSoundEnvelopeIndex(..))) => {
+            Code::InvalidEnvelopeIndex
+        }
+        MapError::Layer(_, L::DdraceSounds(S::InvalidSoundIndex(_))) => Code::InvalidSoundIndex,
+        MapError::InvalidVersion(_) | MapError::EmptyVersion | MapError::MissingVersion => {
+            Code::InvalidVersion
+        }
+        MapError::Info(_) => Code::InvalidInfo,
+        MapError::NoGameLayer => Code::NoGameLayer,
+        MapError::TooManyGameLayers => Code::TooManyGameLayers,
+        MapError::TooManyGameGroups => Code::TooManyGameGroups,
+        MapError::InconsistentGameLayerDimensions => Code::InconsistentGameLayerDimensions,
+        _ => Code::InvalidItem,
+    }
+}
+
+fn error_code(err: &Error) -> Code {
+    match *err {
+        Error::Map(ref e) => map_error_code(e),
+        Error::Df(_) | Error::ExternalImage(_) => Code::InvalidData,
+    }
+}
+
+fn tele_needs_number(index: u8) -> bool {
+    matches!(index, 10 | 14 | 15 | 26 | 27 | 29 | 30)
+}
+
+fn tele_is_entrance(index: u8) -> bool {
+    matches!(index, 10 | 14 | 15 | 26)
+}
+
+const TELEOUT: u8 = 27;
+
+struct Validator<'a, R: 'a> {
+    reader: &'a mut Reader<R>,
+    diagnostics: Vec<Diagnostic>,
+    image_indices: ops::Range<usize>,
+    envelope_indices: ops::Range<usize>,
+    sound_indices: ops::Range<usize>,
+    used_images: Vec<bool>,
+    used_envelopes: Vec<bool>,
+    used_sounds: Vec<bool>,
+    /// Whether a group or layer couldn't be parsed, so the usage of images,
+    /// envelopes and sounds is unknown.
+    unparsed_layers: bool,
+}
+
+/// Checks the map and returns the problems found, in map order.
+pub fn validate<R: Read + Seek>(reader: &mut Reader<R>) -> Vec<Diagnostic> {
+    let image_indices = reader.reader.item_type_indices(format::MAP_ITEMTYPE_IMAGE);
+    let envelope_indices = reader.envelope_indices();
+    let sound_indices = reader.sound_indices();
+    let mut validator = Validator {
+        used_images: vec![false; image_indices.len()],
+        used_envelopes: vec![false; envelope_indices.len()],
+        used_sounds: vec![false; sound_indices.len()],
+        image_indices: image_indices,
+        envelope_indices: envelope_indices,
+        sound_indices: sound_indices,
+        reader: reader,
+        diagnostics: Vec::new(),
+        unparsed_layers: false,
+    };
+    validator.validate();
+    validator.diagnostics
+}
+
+impl<'a, R: Read + Seek> Validator<'a, R> {
+    fn push(&mut self, severity: Severity, code: Code, location: Location, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: severity,
+            code: code,
+            location: location,
+            message: message,
+        });
+    }
+    fn error(&mut self, location: Location, err: &Error) {
+        let message = match *err {
+            Error::Map(ref e) => format!("{:?}", e),
+            Error::Df(ref e) => format!("{:?}", e),
+            Error::ExternalImage(ref e) => format!("{:?}", e),
+        };
+        self.push(Severity::Error, error_code(err), location, message);
+    }
+    fn validate(&mut self) {
+        if let Err(e) = self.reader.check_version() {
+            self.error(Location::default(), &e.into());
+        }
+        match self.reader.info() {
+            Ok(_) | Err(MapError::MissingInfo) => {}
+            Err(e) => self.error(Location::default(), &e.into()),
+        }
+        for i in self.image_indices.clone() {
+            self.validate_image(i);
+        }
+        for i in self.envelope_indices.clone() {
+            if let Err(e) = self.reader.envelope(i) {
+                let location = Location {
+                    envelope: Some(i - self.envelope_indices.start),
+                    ..Location::default()
+                };
+                self.error(location, &e.into());
+            }
+        }
+        for i in self.sound_indices.clone() {
+            if let Err(e) = self.reader.sound(i) {
+                let location = Location {
+                    sound: Some(i - self.sound_indices.start),
+                    ..Location::default()
+                };
+                self.error(location, &e.into());
+            }
+        }
+        let group_indices = self.reader.group_indices();
+        for i in group_indices.clone() {
+            let location = Location {
+                group: Some(i - group_indices.start),
+                ..Location::default()
+            };
+            let group = match self.reader.group(i) {
+                Ok(g) => g,
+                Err(e) => {
+                    self.error(location, &e.into());
+                    self.unparsed_layers = true;
+                    continue;
+                }
+            };
+            for k in group.layer_indices.clone() {
+                let location = Location {
+                    layer: Some(k - group.layer_indices.start),
+                    ..location
+                };
+                if let Err(e) = self.validate_layer(location, k) {
+                    self.error(location, &e);
+                    self.unparsed_layers = true;
+                }
+            }
+        }
+        if let Err(e) = self.reader.game_layers() {
+            // Errors of single layers have already been reported.
+            if !matches!(e, MapError::Group(..) | MapError::Layer(..)) {
+                self.error(Location::default(), &e.into());
+            }
+        }
+        // Don't bury the errors of the layers under spurious warnings.
+        if !self.unparsed_layers {
+            self.validate_unused();
+        }
+    }
+    fn validate_image(&mut self, index: usize) {
+        let location = Location {
+            image: Some(index - self.image_indices.start),
+            ..Location::default()
+        };
+        let image = match self.reader.image(index) {
+            Ok(i) => i,
+            Err(e) => return self.error(location, &e.into()),
+        };
+        let data = match image.data {
+            Some(d) => d,
+            // External image.
+            None => return,
+        };
+        let bytes_per_pixel = match image.format {
+            ImageFormat::Rgb => 3,
+            ImageFormat::Rgba => 4,
+        };
+        let expected = image.width.u64() * image.height.u64() * bytes_per_pixel;
+        let size = self.reader.reader.data_size(data).u64();
+        if size != expected {
+            self.push(
+                Severity::Error,
+                Code::ImageDataSize,
+                location,
+                format!(
+                    "image data has {} bytes, expected {} for {}x{} pixels",
+                    size, expected, image.width, image.height,
+                ),
+            );
+        }
+    }
+    fn use_image(&mut self, image: Option<usize>) {
+        if let Some(i) = image {
+            self.used_images[i - self.image_indices.start] = true;
+        }
+    }
+    fn use_envelope(&mut self, envelope: Option<(usize, i32)>) {
+        if let Some((i, _)) = envelope {
+            self.used_envelopes[i - self.envelope_indices.start] = true;
+        }
+    }
+    fn validate_layer(&mut self, location: Location, index: usize) -> Result<(), Error> {
+        match self.reader.layer(index)?.t {
+            LayerType::Tilemap(tilemap) => self.validate_tilemap(location, &tilemap)?,
+            LayerType::Quads(quads) => {
+                self.use_image(quads.image);
+                for quad in self.reader.layer_quads(&quads)? {
+                    self.use_envelope(quad.pos_env_and_offset);
+                    self.use_envelope(quad.color_env_and_offset);
+                }
+            }
+            LayerType::DdraceSounds(sounds) => {
+                if let Some(i) = sounds.sound {
+                    self.used_sounds[i - self.sound_indices.start] = true;
+                }
+                for source in self.reader.layer_sound_sources(&sounds)? {
+                    self.use_envelope(source.pos_env_and_offset);
+                    self.use_envelope(source.sound_env_and_offset);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn check_tiles_size<T>(
+        &mut self,
+        location: Location,
+        tilemap: &reader::LayerTilemap,
+        data: usize,
+    ) -> bool {
+        let num_tiles = tilemap.width.u64() * tilemap.height.u64();
+        let expected = num_tiles * mem::size_of::<T>().u64();
+        let size = self.reader.reader.data_size(data).u64();
+        if size != expected {
+            self.push(
+                Severity::Error,
+                Code::TilesDataSize,
+                location,
+                format!(
+                    "tile data has {} bytes, expected {} for {}x{} tiles",
+                    size, expected, tilemap.width, tilemap.height,
+                ),
+            );
+            return false;
+        }
+        true
+    }
+    fn validate_tilemap(
+        &mut self,
+        location: Location,
+        tilemap: &reader::LayerTilemap,
+    ) -> Result<(), Error> {
+        use self::LayerTilemapType::*;
+
+        let num_tiles = tilemap.width.u64() * tilemap.height.u64();
+        if num_tiles > MAX_LAYER_TILES {
+            self.push(
+                Severity::Warning,
+                Code::LayerTooLarge,
+                location,
+                format!(
+                    "layer has {}x{} tiles, more than {}",
+                    tilemap.width, tilemap.height, MAX_LAYER_TILES,
+                ),
+            );
+        }
+        match tilemap.type_ {
+            Normal(n) => {
+                self.use_image(n.image);
+                self.use_envelope(n.color_env_and_offset);
+                self.check_tiles_size::<format::Tile>(location, tilemap, n.data);
+            }
+            Game(d) => {
+                self.check_tiles_size::<format::Tile>(location, tilemap, d);
+            }
+            RaceTeleport(d, zeroes) => {
+                self.check_tiles_size::<format::Tile>(location, tilemap, zeroes);
+                if self.check_tiles_size::<format::TeleTile>(location, tilemap, d) {
+                    let tiles = self.reader.tele_layer_tiles(tilemap.tiles(d))?;
+                    self.validate_tele(location, &tiles);
+                }
+            }
+            RaceSpeedup(d, zeroes) => {
+                self.check_tiles_size::<format::Tile>(location, tilemap, zeroes);
+                self.check_tiles_size::<format::SpeedupTile>(location, tilemap, d);
+            }
+            DdraceFront(d, zeroes) => {
+                self.check_tiles_size::<format::Tile>(location, tilemap, zeroes);
+                self.check_tiles_size::<format::Tile>(location, tilemap, d);
+            }
+            DdraceSwitch(d, zeroes) => {
+                self.check_tiles_size::<format::Tile>(location, tilemap, zeroes);
+                if self.check_tiles_size::<format::SwitchTile>(location, tilemap, d) {
+                    let tiles = self.reader.switch_layer_tiles(tilemap.tiles(d))?;
+                    for ((y, x), t) in tiles.indexed_iter() {
+                        if t.index == 0 && t.number != 0 {
+                            self.number_without_tile(location, x, y, t.number);
+                        }
+                    }
+                }
+            }
+            DdraceTune(d, zeroes) => {
+                self.check_tiles_size::<format::Tile>(location, tilemap, zeroes);
+                self.check_tiles_size::<format::TuneTile>(location, tilemap, d);
+            }
+        }
+        Ok(())
+    }
+    fn number_without_tile(&mut self, location: Location, x: usize, y: usize, number: u8) {
+        self.push(
+            Severity::Warning,
+            Code::NumberWithoutTile,
+            Location {
+                tile: Some((x, y)),
+                ..location
+            },
+            format!("empty tile has number {}", number),
+        );
+    }
+    fn validate_tele(&mut self, location: Location, tiles: &ndarray::Array2<format::TeleTile>) {
+        // First entrance of each number, and whether there's an exit.
+        let mut numbers: BTreeMap<u8, (Option<(usize, usize)>, bool)> = BTreeMap::new();
+        for ((y, x), t) in tiles.indexed_iter() {
+            if t.index == 0 {
+                if t.number != 0 {
+                    self.number_without_tile(location, x, y, t.number);
+                }
+                continue;
+            }
+            if t.number == 0 {
+                if tele_needs_number(t.index) {
+                    self.push(
+                        Severity::Warning,
+                        Code::TeleMissingNumber,
+                        Location {
+                            tile: Some((x, y)),
+                            ..location
+                        },
+                        format!("teleporter tile {} has no number", t.index),
+                    );
+                }
+                continue;
+            }
+            let entry = numbers.entry(t.number).or_insert((None, false));
+            if tele_is_entrance(t.index) && entry.0.is_none() {
+                entry.0 = Some((x, y));
+            }
+            if t.index == TELEOUT {
+                entry.1 = true;
+            }
+        }
+        for (number, (entrance, exit)) in numbers {
+            if let (Some(tile), false) = (entrance, exit) {
+                self.push(
+                    Severity::Warning,
+                    Code::TeleMissingTarget,
+                    Location {
+                        tile: Some(tile),
+                        ..location
+                    },
+                    format!("teleporter {} has no exit", number),
+                );
+            }
+        }
+    }
+    fn validate_unused(&mut self) {
+        for i in 0..self.used_images.len() {
+            if !self.used_images[i] {
+                let location = Location {
+                    image: Some(i),
+                    ..Location::default()
+                };
+                let message = "image is not used by any layer".to_owned();
+                self.push(Severity::Warning, Code::UnusedImage, location, message);
+            }
+        }
+        for i in 0..self.used_envelopes.len() {
+            if !self.used_envelopes[i] {
+                let location = Location {
+                    envelope: Some(i),
+                    ..Location::default()
+                };
+                let message = "envelope is not used by any layer".to_owned();
+                self.push(Severity::Warning, Code::UnusedEnvelope, location, message);
+            }
+        }
+        for i in 0..self.used_sounds.len() {
+            if !self.used_sounds[i] {
+                let location = Location {
+                    sound: Some(i),
+                    ..Location::default()
+                };
+                let message = "sound is not used by any layer".to_owned();
+                self.push(Severity::Warning, Code::UnusedSound, location, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate;
+    use super::Code;
+    use super::Location;
+    use crate::format;
+    use crate::model;
+    use crate::model::Map;
+    use crate::Reader;
+    use ndarray::Array2;
+    use std::io::Cursor;
+
+    #[test]
+    fn diagnostics() {
+        let mut tiles = Array2::from_elem(
+            (2, 3),
+            format::TeleTile {
+                number: 0,
+                index: 0,
+            },
+        );
+        tiles[(0, 1)] = format::TeleTile {
+            number: 0,
+            index: 26,
+        };
+        tiles[(1, 2)] = format::TeleTile {
+            number: 5,
+            index: 26,
+        };
+        let map = Map {
+            info: model::Info::default(),
+            images: vec![model::Image {
+                name: b"unused".to_vec(),
+                width: 1,
+                height: 1,
+                data: None,
+            }],
+            envelopes: Vec::new(),
+            groups: vec![model::Group {
+                offset_x: 0,
+                offset_y: 0,
+                parallax_x: 100,
+                parallax_y: 100,
+                clipping: None,
+                name: [0; 12],
+                layers: vec![model::Layer {
+                    detail: false,
+                    name: [0; 12],
+                    t: model::LayerType::Tilemap(model::Tilemap {
+                        color: crate::reader::Color {
+                            red: 255,
+                            green: 255,
+                            blue: 255,
+                            alpha: 255,
+                        },
+                        color_env_and_offset: None,
+                        image: None,
+                        tiles: model::Tiles::Teleport(tiles),
+                    }),
+                }],
+            }],
+            sounds: Vec::new(),
+        };
+        let mut file = Vec::new();
+        map.write(&mut file).unwrap();
+        let diagnostics = validate(&mut Reader::new(Cursor::new(file)).unwrap());
+
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(
+            codes,
+            [
+                Code::TeleMissingNumber,
+                Code::TeleMissingTarget,
+                Code::NoGameLayer,
+                Code::UnusedImage,
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].location,
+            Location {
+                group: Some(0),
+                layer: Some(0),
+                tile: Some((1, 0)),
+                ..Location::default()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&diagnostics[3]).unwrap(),
+            r#"{"severity":"warning","code":"unused_image","location":{"image":0},"message":"image is not used by any layer"}"#,
+        );
+    }
+
+    #[test]
+    fn unparsed_layer() {
+        let tilemap = |image, tiles| model::Layer {
+            detail: false,
+            name: [0; 12],
+            t: model::LayerType::Tilemap(model::Tilemap {
+                color: crate::reader::Color {
+                    red: 255,
+                    green: 255,
+                    blue: 255,
+                    alpha: 255,
+                },
+                color_env_and_offset: None,
+                image: image,
+                tiles: tiles,
+            }),
+        };
+        let tile = format::Tile {
+            index: 0,
+            flags: 0,
+            skip: 0,
+            reserved: 0,
+        };
+        let map = Map {
+            info: model::Info::default(),
+            images: vec![model::Image {
+                name: b"grass_main".to_vec(),
+                width: 1,
+                height: 1,
+                data: None,
+            }],
+            envelopes: Vec::new(),
+            groups: vec![model::Group {
+                offset_x: 0,
+                offset_y: 0,
+                parallax_x: 100,
+                parallax_y: 100,
+                clipping: None,
+                name: [0; 12],
+                layers: vec![
+                    tilemap(None, model::Tiles::Game(Array2::from_elem((2, 2), tile))),
+                    tilemap(
+                        Some(1),
+                        model::Tiles::Normal(Array2::from_elem((2, 2), tile)),
+                    ),
+                ],
+            }],
+            sounds: Vec::new(),
+        };
+        let mut file = Vec::new();
+        map.write(&mut file).unwrap();
+        let diagnostics = validate(&mut Reader::new(Cursor::new(file)).unwrap());
+
+        // The image might be meant for the broken layer, so it isn't
+        // reported as unused.
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Code::InvalidImageIndex]);
+        assert_eq!(diagnostics[0].location.layer, Some(1));
+    }
+}
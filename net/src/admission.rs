@@ -0,0 +1,429 @@
+//! Deciding which packets and connection attempts `Net` processes.
+//!
+//! An [`Admission`] policy installed using `Net::set_admission` is consulted
+//! before any state is created for a packet. Dropped packets are reported
+//! as `net::Warning::Dropped`.
+//!
+//! [`Limiter`] implements the usual measures for public servers: per-IP
+//! connection caps, per-subnet packet rate limits, a ban list and a global
+//! limit on connectionless packets.
+
+use crate::net::Address;
+use crate::Timestamp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Reason for dropping a packet.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Dropped {
+    /// The sender is banned.
+    Banned,
+    /// The sender's subnet exceeded its packet rate.
+    RateLimited,
+    /// The rate of connectionless packets was exceeded.
+    ConnlessRateLimited,
+    /// The sender already has the maximum number of connections.
+    TooManyConnections,
+}
+
+pub trait Admission<A: Address> {
+    /// Called for every received packet before it is parsed.
+    fn allow_packet(&mut self, time: Timestamp, addr: A) -> Result<(), Dropped>;
+    /// Called for every received connectionless packet, after
+    /// `allow_packet`.
+    fn allow_connless(&mut self, time: Timestamp, addr: A) -> Result<(), Dropped> {
+        let _ = (time, addr);
+        Ok(())
+    }
+    /// Called before a peer is created for an incoming connection, `peers`
+    /// yields the addresses of all current peers.
+    fn allow_connect(
+        &mut self,
+        time: Timestamp,
+        addr: A,
+        peers: &mut dyn Iterator<Item = A>,
+    ) -> Result<(), Dropped> {
+        let _ = (time, addr, peers);
+        Ok(())
+    }
+}
+
+/// Addresses consisting of an IP address and possibly more, needed for
+/// [`Limiter`].
+pub trait IpAddress {
+    fn ip(&self) -> IpAddr;
+}
+
+impl IpAddress for IpAddr {
+    fn ip(&self) -> IpAddr {
+        *self
+    }
+}
+
+impl IpAddress for SocketAddr {
+    fn ip(&self) -> IpAddr {
+        SocketAddr::ip(self)
+    }
+}
+
+/// Sustained rate and burst size of a token bucket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rate {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Maximum number of connections from a single IP address.
+    pub connections_per_ip: Option<usize>,
+    /// Packet rate accepted from a single subnet.
+    pub packets_per_subnet: Option<Rate>,
+    /// Prefix length of IPv4 subnets for `packets_per_subnet`.
+    pub subnet_prefix_v4: u8,
+    /// Prefix length of IPv6 subnets for `packets_per_subnet`.
+    pub subnet_prefix_v6: u8,
+    /// Rate of connectionless packets accepted in total.
+    pub connless: Option<Rate>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            connections_per_ip: None,
+            packets_per_subnet: None,
+            subnet_prefix_v4: 24,
+            subnet_prefix_v6: 64,
+            connless: None,
+        }
+    }
+}
+
+const MICROS_PER_SEC: u64 = 1_000_000;
+
+/// Token bucket counting in millionths of a token, so that refilling works
+/// with integer microseconds.
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: u64,
+    last_refill: Timestamp,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, time: Timestamp) -> TokenBucket {
+        TokenBucket {
+            tokens: u64::from(rate.burst) * MICROS_PER_SEC,
+            last_refill: time,
+        }
+    }
+    fn refill(&mut self, rate: Rate, time: Timestamp) {
+        let elapsed = time.duration_since(self.last_refill);
+        let elapsed_us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let capacity = u64::from(rate.burst) * MICROS_PER_SEC;
+        self.tokens = self
+            .tokens
+            .saturating_add(elapsed_us.saturating_mul(u64::from(rate.per_second)))
+            .min(capacity);
+        if time > self.last_refill {
+            self.last_refill = time;
+        }
+    }
+    fn take(&mut self, rate: Rate, time: Timestamp) -> bool {
+        self.refill(rate, time);
+        if self.tokens < MICROS_PER_SEC {
+            return false;
+        }
+        self.tokens -= MICROS_PER_SEC;
+        true
+    }
+    fn is_full(&mut self, rate: Rate, time: Timestamp) -> bool {
+        self.refill(rate, time);
+        self.tokens == u64::from(rate.burst) * MICROS_PER_SEC
+    }
+}
+
+fn subnet(ip: IpAddr, prefix_v4: u8, prefix_v6: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_v4.min(32)));
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask.unwrap_or(0)))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_v6.min(128)));
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask.unwrap_or(0)))
+        }
+    }
+}
+
+/// How often expired bans and idle rate limit state are forgotten.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum number of subnets whose packet rate is tracked at once. Packets
+/// from further subnets are dropped as rate limited until the cleanup
+/// forgets idle ones.
+const MAX_SUBNETS: usize = 65536;
+
+/// Admission policy enforcing [`Limits`] and a ban list.
+#[derive(Clone, Debug)]
+pub struct Limiter {
+    limits: Limits,
+    /// Banned IP addresses, mapped to the time the ban expires.
+    bans: HashMap<IpAddr, Option<Timestamp>>,
+    subnets: HashMap<IpAddr, TokenBucket>,
+    connless: Option<TokenBucket>,
+    next_cleanup: Timestamp,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Limiter {
+        Limiter {
+            limits: limits,
+            bans: HashMap::new(),
+            subnets: HashMap::new(),
+            connless: None,
+            next_cleanup: Timestamp::from_secs_since_epoch(0),
+        }
+    }
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.subnets.clear();
+        self.connless = None;
+    }
+    /// Bans `ip` until `until`, or permanently if it is `None`.
+    pub fn ban(&mut self, ip: IpAddr, until: Option<Timestamp>) {
+        self.bans.insert(ip, until);
+    }
+    pub fn unban(&mut self, ip: IpAddr) {
+        self.bans.remove(&ip);
+    }
+    pub fn is_banned(&self, ip: IpAddr, time: Timestamp) -> bool {
+        match self.bans.get(&ip) {
+            Some(&Some(until)) => time < until,
+            Some(&None) => true,
+            None => false,
+        }
+    }
+    /// Returns the current bans and their expiry times, including expired
+    /// ones that haven't been cleaned up yet.
+    pub fn bans(&self) -> impl Iterator<Item = (IpAddr, Option<Timestamp>)> + '_ {
+        self.bans.iter().map(|(&ip, &until)| (ip, until))
+    }
+    fn cleanup(&mut self, time: Timestamp) {
+        if time < self.next_cleanup {
+            return;
+        }
+        self.next_cleanup = time + CLEANUP_INTERVAL;
+        self.bans
+            .retain(|_, until| until.map(|u| time < u).unwrap_or(true));
+        if let Some(rate) = self.limits.packets_per_subnet {
+            self.subnets.retain(|_, bucket| !bucket.is_full(rate, time));
+        }
+    }
+}
+
+impl<A: Address + IpAddress> Admission<A> for Limiter {
+    fn allow_packet(&mut self, time: Timestamp, addr: A) -> Result<(), Dropped> {
+        self.cleanup(time);
+        let ip = addr.ip();
+        if self.is_banned(ip, time) {
+            return Err(Dropped::Banned);
+        }
+        if let Some(rate) = self.limits.packets_per_subnet {
+            let subnet = subnet(
+                ip,
+                self.limits.subnet_prefix_v4,
+                self.limits.subnet_prefix_v6,
+            );
+            if self.subnets.len() >= MAX_SUBNETS && !self.subnets.contains_key(&subnet) {
+                return Err(Dropped::RateLimited);
+            }
+            let bucket = self
+                .subnets
+                .entry(subnet)
+                .or_insert_with(|| TokenBucket::new(rate, time));
+            if !bucket.take(rate, time) {
+                return Err(Dropped::RateLimited);
+            }
+        }
+        Ok(())
+    }
+    fn allow_connless(&mut self, time: Timestamp, _addr: A) -> Result<(), Dropped> {
+        if let Some(rate) = self.limits.connless {
+            let bucket = self
+                .connless
+                .get_or_insert_with(|| TokenBucket::new(rate, time));
+            if !bucket.take(rate, time) {
+                return Err(Dropped::ConnlessRateLimited);
+            }
+        }
+        Ok(())
+    }
+    fn allow_connect(
+        &mut self,
+        _time: Timestamp,
+        addr: A,
+        peers: &mut dyn Iterator<Item = A>,
+    ) -> Result<(), Dropped> {
+        if let Some(max) = self.limits.connections_per_ip {
+            let ip = addr.ip();
+            if peers.filter(|p| p.ip() == ip).count() >= max {
+                return Err(Dropped::TooManyConnections);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Admission;
+    use super::Dropped;
+    use super::Limiter;
+    use super::Limits;
+    use super::Rate;
+    use super::MAX_SUBNETS;
+    use crate::Timestamp;
+    use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn ms(ms: u64) -> Timestamp {
+        Timestamp::from_secs_since_epoch(0) + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn subnet_rate() {
+        let mut limiter = Limiter::new(Limits {
+            packets_per_subnet: Some(Rate {
+                per_second: 10,
+                burst: 2,
+            }),
+            ..Limits::default()
+        });
+        let a = addr("192.0.2.1:1");
+        let b = addr("192.0.2.200:2");
+        let other = addr("198.51.100.1:3");
+        assert_eq!(limiter.allow_packet(ms(0), a), Ok(()));
+        assert_eq!(limiter.allow_packet(ms(0), b), Ok(()));
+        // Same /24.
+        assert_eq!(limiter.allow_packet(ms(0), a), Err(Dropped::RateLimited));
+        assert_eq!(limiter.allow_packet(ms(0), other), Ok(()));
+        // One token per 100ms.
+        assert_eq!(limiter.allow_packet(ms(50), a), Err(Dropped::RateLimited));
+        assert_eq!(limiter.allow_packet(ms(100), a), Ok(()));
+        assert_eq!(limiter.allow_packet(ms(100), b), Err(Dropped::RateLimited));
+
+        let v6 = addr("[2001:db8::1]:1");
+        let v6_same = addr("[2001:db8::ffff:1]:1");
+        assert_eq!(limiter.allow_packet(ms(0), v6), Ok(()));
+        assert_eq!(limiter.allow_packet(ms(0), v6), Ok(()));
+        assert_eq!(
+            limiter.allow_packet(ms(0), v6_same),
+            Err(Dropped::RateLimited)
+        );
+    }
+
+    #[test]
+    fn subnet_cap() {
+        let mut limiter = Limiter::new(Limits {
+            packets_per_subnet: Some(Rate {
+                per_second: 1,
+                burst: 2,
+            }),
+            ..Limits::default()
+        });
+        let subnet = |i: usize| SocketAddr::new(Ipv4Addr::from((i as u32) << 8).into(), 1);
+        for i in 0..MAX_SUBNETS {
+            assert_eq!(limiter.allow_packet(ms(0), subnet(i)), Ok(()));
+        }
+        assert_eq!(
+            limiter.allow_packet(ms(0), subnet(MAX_SUBNETS)),
+            Err(Dropped::RateLimited)
+        );
+        // Already tracked subnets are unaffected.
+        assert_eq!(limiter.allow_packet(ms(0), subnet(0)), Ok(()));
+        assert_eq!(
+            limiter.allow_packet(ms(1000), subnet(MAX_SUBNETS)),
+            Err(Dropped::RateLimited)
+        );
+        // Idle subnets are forgotten eventually, making room for new ones.
+        assert_eq!(
+            limiter.allow_packet(ms(20_000), subnet(MAX_SUBNETS)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn bans() {
+        let mut limiter = Limiter::new(Limits::default());
+        let a = addr("192.0.2.1:1");
+        limiter.ban(a.ip(), Some(ms(1000)));
+        limiter.ban(addr("192.0.2.2:1").ip(), None);
+        assert_eq!(limiter.allow_packet(ms(0), a), Err(Dropped::Banned));
+        assert_eq!(limiter.allow_packet(ms(999), a), Err(Dropped::Banned));
+        assert_eq!(limiter.allow_packet(ms(1000), a), Ok(()));
+        assert_eq!(
+            limiter.allow_packet(ms(1000), addr("192.0.2.2:5")),
+            Err(Dropped::Banned)
+        );
+        // Expired bans are forgotten eventually.
+        assert_eq!(limiter.allow_packet(ms(20_000), a), Ok(()));
+        assert_eq!(limiter.bans().count(), 1);
+        limiter.unban(addr("192.0.2.2:1").ip());
+        assert_eq!(
+            limiter.allow_packet(ms(20_000), addr("192.0.2.2:5")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn connless_rate() {
+        let mut limiter = Limiter::new(Limits {
+            connless: Some(Rate {
+                per_second: 1,
+                burst: 1,
+            }),
+            ..Limits::default()
+        });
+        let a = addr("192.0.2.1:1");
+        let b = addr("198.51.100.1:1");
+        assert_eq!(limiter.allow_connless(ms(0), a), Ok(()));
+        assert_eq!(
+            limiter.allow_connless(ms(0), b),
+            Err(Dropped::ConnlessRateLimited)
+        );
+        assert_eq!(limiter.allow_connless(ms(1000), b), Ok(()));
+    }
+
+    #[test]
+    fn connections_per_ip() {
+        let mut limiter = Limiter::new(Limits {
+            connections_per_ip: Some(2),
+            ..Limits::default()
+        });
+        let peers = [
+            addr("192.0.2.1:1"),
+            addr("192.0.2.1:2"),
+            addr("192.0.2.2:1"),
+        ];
+        assert_eq!(
+            limiter.allow_connect(ms(0), addr("192.0.2.1:3"), &mut peers.iter().cloned()),
+            Err(Dropped::TooManyConnections)
+        );
+        assert_eq!(
+            limiter.allow_connect(ms(0), addr("192.0.2.2:2"), &mut peers.iter().cloned()),
+            Ok(())
+        );
+    }
+}
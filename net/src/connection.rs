@@ -1,3 +1,4 @@
+use crate::connection7::ReceiveChunks7;
 use crate::protocol;
 use crate::protocol::ChunksIter;
 use crate::protocol::ConnectedPacket;
@@ -8,6 +9,7 @@ use crate::protocol::Token;
 use crate::protocol::MAX_PACKETSIZE;
 use crate::protocol::MAX_PAYLOAD;
 use crate::protocol::TOKEN_NONE;
+use crate::protocol7;
 use crate::Timeout;
 use crate::Timestamp;
 use arrayvec::ArrayVec;
@@ -54,12 +56,76 @@ impl<CE> Error<CE> {
 #[derive(Debug)]
 pub enum Warning {
     Packet(protocol::Warning),
+    Packet7(protocol7::Warning),
     Read(protocol::PacketReadError),
+    Read7(protocol7::PacketReadError),
     TokenMismatch,
     Unexpected,
+    /// The amount of unacknowledged vital data exceeds the configured
+    /// `QueueLimit`, contains the queued number of bytes.
+    QueueLimitExceeded(usize),
 }
 
-trait TimeoutExt {
+/// Traffic statistics of a connection, see `Connection::stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// Number of connected packets received from the peer, not counting
+    /// unreadable ones or ones with the wrong token.
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub vital_chunks_sent: u64,
+    pub nonvital_chunks_sent: u64,
+    pub vital_chunks_received: u64,
+    pub nonvital_chunks_received: u64,
+    /// Number of vital chunks that were resent.
+    pub resends: u64,
+    /// Number of received vital chunks that were dropped because they
+    /// arrived before a preceding vital chunk.
+    pub dropped_out_of_order: u64,
+    /// Number of received vital chunks that were dropped because they had
+    /// already been received, e.g. resends of acknowledged chunks.
+    pub dropped_duplicates: u64,
+    /// Sequence number of the last vital chunk received in order, i.e. the
+    /// one that is acknowledged to the peer.
+    pub ack: u16,
+    /// Sequence number of the last vital chunk sent.
+    pub sequence: u16,
+    /// Smoothed round-trip time, if it has been measured yet.
+    pub rtt: Option<Duration>,
+    /// Time at which the last packet counted in `packets_received` was
+    /// received.
+    pub last_received: Option<Timestamp>,
+}
+
+/// Limit on the amount of vital data that hasn't been acknowledged by the
+/// peer yet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueueLimit {
+    pub max_vital_bytes: usize,
+    pub action: QueueLimitAction,
+}
+
+/// What to do when the `QueueLimit` is exceeded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueueLimitAction {
+    /// Emit `Warning::QueueLimitExceeded` once each time the limit is
+    /// exceeded.
+    Warn,
+    /// Close the connection with `QUEUE_LIMIT_REASON`.
+    Disconnect,
+}
+
+/// Disconnect reason sent when the `QueueLimit` is exceeded.
+pub const QUEUE_LIMIT_REASON: &'static [u8] = b"Too weak connection (out of buffer)";
+
+/// Resend timeout used until the first round-trip time sample is available.
+pub(crate) const INITIAL_RESEND_TIMEOUT: Duration = Duration::from_millis(1_000);
+const MIN_RESEND_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RESEND_TIMEOUT: Duration = Duration::from_millis(10_000);
+
+pub(crate) trait TimeoutExt {
     fn set<CB: Callback>(&mut self, cb: &mut CB, value: Duration);
     fn has_triggered_level<CB: Callback>(&self, cb: &mut CB) -> bool;
     fn has_triggered_edge<CB: Callback>(&mut self, cb: &mut CB) -> bool;
@@ -85,12 +151,21 @@ pub struct Connection {
     state: State,
     send: Timeout,
     builder: PacketBuilder,
+    queue_limit: Option<QueueLimit>,
+    // Set by `send` when the queue limit is exceeded, `tick` reports it.
+    queue_limit_check: Timeout,
+    stats: Stats,
 }
 
+/// Number of connect attempts signalling token support before falling back
+/// to connect attempts without a token, for servers that don't understand
+/// the DDNet token protocol.
+const CONNECT_TOKEN_ATTEMPTS: u32 = 5;
+
 #[derive(Clone, Debug)]
 enum State {
     Unconnected,
-    Connecting,
+    Connecting(ConnectingState),
     Pending(PendingState),
     Online(OnlineState),
     Disconnected,
@@ -106,7 +181,7 @@ impl State {
     pub fn token(&self) -> Option<&Option<Token>> {
         match *self {
             State::Unconnected => None,
-            State::Connecting => None,
+            State::Connecting(_) => None,
             State::Pending(ref pending) => Some(&pending.token),
             State::Online(ref online) => Some(&online.token),
             State::Disconnected => None,
@@ -115,29 +190,189 @@ impl State {
 }
 
 #[derive(Clone, Debug)]
-struct ResendChunk {
-    next_send: Timeout,
-    sequence: Sequence,
-    data: ArrayVec<[u8; 2048]>,
+pub(crate) struct ResendChunk {
+    pub next_send: Timeout,
+    pub sequence: Sequence,
+    pub data: ArrayVec<[u8; 2048]>,
+    // Time of the first transmission, for round-trip time estimation, `None`
+    // until the chunk is flushed.
+    sent: Option<Timestamp>,
+    resent: bool,
 }
 
 impl ResendChunk {
-    fn new<CB: Callback>(cb: &mut CB, sequence: Sequence, data: &[u8]) -> ResendChunk {
+    pub fn new<CB: Callback>(
+        cb: &mut CB,
+        sequence: Sequence,
+        data: &[u8],
+        timeout: Duration,
+    ) -> ResendChunk {
         let mut result = ResendChunk {
             next_send: Timeout::inactive(),
             sequence: sequence,
             data: data.iter().cloned().collect(),
+            sent: None,
+            resent: false,
         };
         assert!(
             result.data.len() == data.len(),
             "overlong resend packet {}",
             data.len()
         );
-        result.start_timeout(cb);
+        result.start_timeout(cb, timeout);
         result
     }
-    fn start_timeout<CB: Callback>(&mut self, cb: &mut CB) {
-        self.next_send.set(cb, Duration::from_millis(1_000));
+    pub fn start_timeout<CB: Callback>(&mut self, cb: &mut CB, timeout: Duration) {
+        self.next_send.set(cb, timeout);
+    }
+}
+
+/// Round-trip time estimator as described in RFC 6298.
+#[derive(Clone, Copy, Debug)]
+struct RttEstimator {
+    // Smoothed round-trip time and its variation, `None` until the first
+    // sample.
+    srtt: Option<(Duration, Duration)>,
+    // Current resend timeout, including backoff.
+    rto: Duration,
+}
+
+impl RttEstimator {
+    fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rto: INITIAL_RESEND_TIMEOUT,
+        }
+    }
+    fn sample(&mut self, rtt: Duration) {
+        let (srtt, rttvar) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some((srtt, rttvar)) => {
+                let diff = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + diff / 4)
+            }
+        };
+        self.srtt = Some((srtt, rttvar));
+        self.rto = cmp::min(
+            cmp::max(srtt + rttvar * 4, MIN_RESEND_TIMEOUT),
+            MAX_RESEND_TIMEOUT,
+        );
+    }
+    fn backoff(&mut self) {
+        self.rto = cmp::min(self.rto * 2, MAX_RESEND_TIMEOUT);
+    }
+}
+
+/// Unacknowledged vital chunks along with the statistics needed to schedule
+/// their resends.
+#[derive(Clone, Debug)]
+pub(crate) struct ResendQueue {
+    // This contains the unacked chunks that we sent, starting from the most
+    // recently sent chunk.
+    chunks: VecDeque<ResendChunk>,
+    rtt: RttEstimator,
+    vital_bytes: usize,
+    resends: u64,
+    limit_exceeded: bool,
+}
+
+impl ResendQueue {
+    pub fn new() -> ResendQueue {
+        ResendQueue {
+            chunks: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            vital_bytes: 0,
+            resends: 0,
+            limit_exceeded: false,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+    /// Returns the `i`-th oldest chunk.
+    pub fn oldest(&self, i: usize) -> &ResendChunk {
+        &self.chunks[self.chunks.len() - i - 1]
+    }
+    pub fn next_send(&self) -> Timeout {
+        self.chunks.back().map(|c| c.next_send).unwrap_or_default()
+    }
+    pub fn push<CB: Callback>(&mut self, cb: &mut CB, sequence: Sequence, data: &[u8]) {
+        self.vital_bytes += data.len();
+        self.chunks
+            .push_front(ResendChunk::new(cb, sequence, data, self.rtt.rto));
+    }
+    /// Removes all chunks up to and including `ack`.
+    pub fn ack<CB: Callback>(&mut self, cb: &mut CB, ack: Sequence) {
+        let index = match self.chunks.iter().position(|c| c.sequence == ack) {
+            Some(i) => i,
+            None => return,
+        };
+        // Only take samples from chunks that weren't resent, it's unclear
+        // which transmission the acknowledgement refers to otherwise.
+        if !self.chunks[index].resent {
+            if let Some(sent) = self.chunks[index].sent {
+                self.rtt.sample(cb.time().duration_since(sent));
+            }
+        }
+        for chunk in self.chunks.drain(index..) {
+            self.vital_bytes -= chunk.data.len();
+        }
+    }
+    /// Records that the chunks pushed since the last call were just sent,
+    /// restarting their resend timeouts.
+    pub fn mark_sent<CB: Callback>(&mut self, cb: &mut CB) {
+        let now = cb.time();
+        let rto = self.rtt.rto;
+        for chunk in self.chunks.iter_mut().take_while(|c| c.sent.is_none()) {
+            chunk.sent = Some(now);
+            chunk.start_timeout(cb, rto);
+        }
+    }
+    /// Prepares all chunks for being resent.
+    ///
+    /// `timed_out` says whether this resend was triggered by the resend
+    /// timeout rather than by a request from the peer, in which case the
+    /// resend timeout is backed off.
+    pub fn start_resend<CB: Callback>(&mut self, cb: &mut CB, timed_out: bool) {
+        if timed_out {
+            self.rtt.backoff();
+        }
+        for chunk in &mut self.chunks {
+            chunk.resent = true;
+            chunk.start_timeout(cb, self.rtt.rto);
+        }
+        self.resends += self.chunks.len() as u64;
+    }
+    pub fn vital_bytes(&self) -> usize {
+        self.vital_bytes
+    }
+    /// Returns whether the queued vital data exceeds `limit`.
+    pub fn exceeds_limit(&self, limit: Option<QueueLimit>) -> bool {
+        limit.map_or(false, |l| self.vital_bytes > l.max_vital_bytes)
+    }
+    pub fn resends(&self) -> u64 {
+        self.resends
+    }
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.srtt.map(|(srtt, _)| srtt)
+    }
+    pub fn resend_timeout(&self) -> Duration {
+        self.rtt.rto
+    }
+    /// Returns the action to take if the limit has just been exceeded.
+    pub fn check_limit(&mut self, limit: Option<QueueLimit>) -> Option<QueueLimitAction> {
+        let limit = limit?;
+        let exceeded = self.vital_bytes > limit.max_vital_bytes;
+        let newly_exceeded = exceeded && !self.limit_exceeded;
+        self.limit_exceeded = exceeded;
+        if newly_exceeded {
+            Some(limit.action)
+        } else {
+            None
+        }
     }
 }
 
@@ -154,17 +389,17 @@ impl<'a> Clone for ReceivePacket<'a> {
 }
 
 impl<'a> ReceivePacket<'a> {
-    fn none() -> ReceivePacket<'a> {
+    pub(crate) fn none() -> ReceivePacket<'a> {
         ReceivePacket {
             type_: ReceivePacketType::None,
         }
     }
-    fn ready() -> ReceivePacket<'a> {
+    pub(crate) fn ready() -> ReceivePacket<'a> {
         ReceivePacket {
             type_: ReceivePacketType::Ready(iter::once(())),
         }
     }
-    fn connless(data: &[u8]) -> ReceivePacket {
+    pub(crate) fn connless(data: &[u8]) -> ReceivePacket {
         ReceivePacket {
             type_: ReceivePacketType::Connless(iter::once(data)),
         }
@@ -172,6 +407,7 @@ impl<'a> ReceivePacket<'a> {
     fn connected<W>(
         warn: &mut W,
         online: &mut OnlineState,
+        stats: &mut Stats,
         num_chunks: u8,
         data: &'a [u8],
     ) -> ReceivePacket<'a>
@@ -184,9 +420,19 @@ impl<'a> ReceivePacket<'a> {
         while let Some(c) = iter.next_warn(&mut w(warn)) {
             if let Some((sequence, resend)) = c.vital {
                 let _ = resend;
-                if online.ack.update(Sequence::from_u16(sequence)) != SequenceOrdering::Current {
-                    online.request_resend = true;
+                match online.ack.update(Sequence::from_u16(sequence)) {
+                    SequenceOrdering::Current => stats.vital_chunks_received += 1,
+                    SequenceOrdering::Past => {
+                        online.request_resend = true;
+                        stats.dropped_duplicates += 1;
+                    }
+                    SequenceOrdering::Future => {
+                        online.request_resend = true;
+                        stats.dropped_out_of_order += 1;
+                    }
                 }
+            } else {
+                stats.nonvital_chunks_received += 1;
             }
         }
         ReceivePacket {
@@ -196,7 +442,12 @@ impl<'a> ReceivePacket<'a> {
             }),
         }
     }
-    fn disconnect(reason: &[u8]) -> ReceivePacket {
+    pub(crate) fn connected7(chunks: ReceiveChunks7<'a>) -> ReceivePacket<'a> {
+        ReceivePacket {
+            type_: ReceivePacketType::Connected7(chunks),
+        }
+    }
+    pub(crate) fn disconnect(reason: &[u8]) -> ReceivePacket {
         ReceivePacket {
             type_: ReceivePacketType::Close(iter::once(reason)),
         }
@@ -208,6 +459,7 @@ enum ReceivePacketType<'a> {
     None,
     Connless(iter::Once<&'a [u8]>),
     Connected(ReceiveChunks<'a>),
+    Connected7(ReceiveChunks7<'a>),
     Ready(iter::Once<()>),
     Close(iter::Once<&'a [u8]>),
 }
@@ -220,6 +472,7 @@ impl<'a> Iterator for ReceivePacket<'a> {
             ReceivePacketType::Ready(ref mut once) => once.next().map(|()| ReceiveChunk::Ready),
             ReceivePacketType::Connless(ref mut once) => once.next().map(ReceiveChunk::Connless),
             ReceivePacketType::Connected(ref mut chunks) => chunks.next(),
+            ReceivePacketType::Connected7(ref mut chunks) => chunks.next(),
             ReceivePacketType::Close(ref mut once) => once.next().map(ReceiveChunk::Disconnect),
         }
     }
@@ -267,6 +520,26 @@ pub enum ReceiveChunk<'a> {
     Disconnect(&'a [u8]),
 }
 
+#[derive(Clone, Debug)]
+struct ConnectingState {
+    // Number of connect packets sent so far.
+    attempts: u32,
+}
+
+impl ConnectingState {
+    fn new() -> ConnectingState {
+        ConnectingState { attempts: 0 }
+    }
+    fn token(&self) -> Option<Token> {
+        if self.attempts < CONNECT_TOKEN_ATTEMPTS {
+            // Signal support for the token protocol.
+            Some(TOKEN_NONE)
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct PendingState {
     // `token`, if present, is included in every message from and to the peer
@@ -294,9 +567,7 @@ struct OnlineState {
     // non-vital ones. This is important for resending.
     packet: PacketContents,
     packet_nonvital: PacketContents,
-    // This contains the unacked chunks that we sent, starting from the most
-    // recently sent chunk.
-    resend_queue: VecDeque<ResendChunk>,
+    resend_queue: ResendQueue,
 }
 
 impl OnlineState {
@@ -306,21 +577,14 @@ impl OnlineState {
             ack: Sequence::new(),
             sequence: Sequence::new(),
             request_resend: false,
-            packet: PacketContents::new(),
-            packet_nonvital: PacketContents::new(),
-            resend_queue: VecDeque::new(),
+            packet: PacketContents::new(protocol::write_chunk_impl),
+            packet_nonvital: PacketContents::new(protocol::write_chunk_impl),
+            resend_queue: ResendQueue::new(),
         }
     }
     fn can_send(&self) -> bool {
         self.packet.num_chunks != 0 || self.request_resend
     }
-    fn ack_chunks(&mut self, ack: Sequence) {
-        let index = self
-            .resend_queue
-            .iter()
-            .position(|chunk| chunk.sequence == ack);
-        index.map(|i| self.resend_queue.truncate(i));
-    }
     fn flush<CB: Callback>(
         &mut self,
         cb: &mut CB,
@@ -343,6 +607,9 @@ impl OnlineState {
                 }),
             )
             .map_err(|e| e.unwrap_callback());
+        if result.is_ok() {
+            self.resend_queue.mark_sent(cb);
+        }
         self.request_resend = false;
         self.packet.clear();
         self.packet_nonvital.clear();
@@ -350,60 +617,75 @@ impl OnlineState {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-struct PacketContents {
-    num_chunks: u8,
-    data: ArrayVec<[u8; 2048]>,
+/// Chunk encoding of a protocol version, `protocol::write_chunk_impl` or
+/// `protocol7::write_chunk_impl`.
+pub(crate) type WriteChunk = for<'d, 's> fn(
+    &[u8],
+    Option<(u16, bool)>,
+    BufferRef<'d, 's>,
+) -> Result<&'d [u8], buffer::CapacityError>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct PacketContents {
+    write_chunk: WriteChunk,
+    pub num_chunks: u8,
+    pub data: ArrayVec<[u8; 2048]>,
 }
 
 impl PacketContents {
-    fn new() -> PacketContents {
+    pub fn new(write_chunk: WriteChunk) -> PacketContents {
         PacketContents {
+            write_chunk: write_chunk,
             num_chunks: 0,
             data: ArrayVec::new(),
         }
     }
-    fn write_chunk(&mut self, data: &[u8], vital: Option<(u16, bool)>) {
-        protocol::write_chunk(data, vital, &mut self.data).unwrap();
+    pub fn write_chunk(&mut self, data: &[u8], vital: Option<(u16, bool)>) {
+        let write_chunk = self.write_chunk;
+        with_buffer(&mut self.data, |b| write_chunk(data, vital, b)).unwrap();
         self.num_chunks += 1;
     }
-    fn can_fit_chunk(&self, data: &[u8], vital: bool) -> bool {
+    pub fn can_fit_chunk(&self, data: &[u8], vital: bool) -> bool {
         // current size + chunk header + chunk length
+        //
+        // Chunk headers and the maximum payload have the same size in 0.6
+        // and 0.7.
         self.data.len() + protocol::chunk_header_size(vital) + data.len() <= MAX_PAYLOAD
     }
-    fn clear(&mut self) {
-        *self = PacketContents::new();
+    pub fn clear(&mut self) {
+        self.num_chunks = 0;
+        self.data.clear();
     }
 }
 
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
-struct Sequence {
+pub(crate) struct Sequence {
     seq: u16, // u10
 }
 
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
-enum SequenceOrdering {
+pub(crate) enum SequenceOrdering {
     Past,
     Current,
     Future,
 }
 
 impl Sequence {
-    fn new() -> Sequence {
+    pub fn new() -> Sequence {
         Default::default()
     }
-    fn from_u16(seq: u16) -> Sequence {
+    pub fn from_u16(seq: u16) -> Sequence {
         assert!(seq < protocol::SEQUENCE_MODULUS);
         Sequence { seq: seq }
     }
-    fn to_u16(self) -> u16 {
+    pub fn to_u16(self) -> u16 {
         self.seq
     }
-    fn next(&mut self) -> Sequence {
+    pub fn next(&mut self) -> Sequence {
         self.seq = (self.seq + 1) % protocol::SEQUENCE_MODULUS;
         *self
     }
-    fn update(&mut self, other: Sequence) -> SequenceOrdering {
+    pub fn update(&mut self, other: Sequence) -> SequenceOrdering {
         let mut next_self = *self;
         next_self.next();
         let result = next_self.compare(other);
@@ -413,7 +695,7 @@ impl Sequence {
         result
     }
     /// Returns what `other` is in relation to `self`.
-    fn compare(self, other: Sequence) -> SequenceOrdering {
+    pub fn compare(self, other: Sequence) -> SequenceOrdering {
         let half = protocol::SEQUENCE_MODULUS / 2;
         let less;
         match self.seq.cmp(&other.seq) {
@@ -429,25 +711,39 @@ impl Sequence {
     }
 }
 
-struct PacketBuilder {
+pub(crate) struct PacketBuilder {
     buffer: [u8; MAX_PACKETSIZE],
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
 }
 
 impl PacketBuilder {
-    fn new() -> PacketBuilder {
+    pub fn new() -> PacketBuilder {
         PacketBuilder {
             buffer: [0; MAX_PACKETSIZE],
+            packets_sent: 0,
+            bytes_sent: 0,
         }
     }
-    fn send<CB: Callback>(&mut self, cb: &mut CB, packet: Packet) -> Result<(), Error<CB::Error>> {
-        let data = match packet.write(&mut self.buffer[..]) {
-            Ok(d) => d,
-            Err(protocol::Error::Capacity(_)) => unreachable!("too short buffer provided"),
-            Err(protocol::Error::TooLongData) => return Err(Error::TooLongData),
-        };
+    /// Sends the packet that `write` writes into the packet buffer.
+    pub fn send_with<CB, F>(&mut self, cb: &mut CB, write: F) -> Result<(), Error<CB::Error>>
+    where
+        CB: Callback,
+        F: for<'b> FnOnce(&'b mut [u8]) -> Result<&'b [u8], Error<CB::Error>>,
+    {
+        let data = write(&mut self.buffer[..])?;
         cb.send(data)?;
+        self.packets_sent += 1;
+        self.bytes_sent += data.len() as u64;
         Ok(())
     }
+    fn send<CB: Callback>(&mut self, cb: &mut CB, packet: Packet) -> Result<(), Error<CB::Error>> {
+        self.send_with(cb, |buffer| match packet.write(buffer) {
+            Ok(d) => Ok(d),
+            Err(protocol::Error::Capacity(_)) => unreachable!("too short buffer provided"),
+            Err(protocol::Error::TooLongData) => Err(Error::TooLongData),
+        })
+    }
 }
 
 struct WarnCallback<'a, W: Warn<Warning> + 'a> {
@@ -470,6 +766,9 @@ impl Connection {
             state: State::Unconnected,
             send: Timeout::inactive(),
             builder: PacketBuilder::new(),
+            queue_limit: None,
+            queue_limit_check: Timeout::inactive(),
+            stats: Stats::default(),
         }
     }
     pub fn new_accept_token<CB: Callback>(cb: &mut CB, token: Token) -> Connection {
@@ -477,13 +776,90 @@ impl Connection {
             state: State::Online(OnlineState::new(Some(token))),
             send: Timeout::inactive(),
             builder: PacketBuilder::new(),
+            queue_limit: None,
+            queue_limit_check: Timeout::inactive(),
+            stats: Stats::default(),
         };
         result.send.set(cb, Duration::from_millis(500));
         result
     }
     pub fn reset(&mut self) {
         assert!(matches!(self.state, State::Disconnected));
+        let queue_limit = self.queue_limit;
         *self = Connection::new();
+        self.queue_limit = queue_limit;
+    }
+    /// Sets the limit on the amount of unacknowledged vital data.
+    ///
+    /// The limit is checked in `tick` and whenever a packet from the peer is
+    /// processed. Queueing vital data beyond the limit makes `needs_tick`
+    /// expire immediately, so the `QueueLimitAction` is taken on the next
+    /// `tick` even if the peer stays silent.
+    pub fn set_queue_limit(&mut self, limit: Option<QueueLimit>) {
+        self.queue_limit = limit;
+    }
+    fn resend_queue(&self) -> Option<&ResendQueue> {
+        match self.state {
+            State::Online(ref online) => Some(&online.resend_queue),
+            _ => None,
+        }
+    }
+    /// Number of bytes of vital chunks that haven't been acknowledged by the
+    /// peer yet.
+    pub fn queued_vital_bytes(&self) -> usize {
+        self.resend_queue().map(|q| q.vital_bytes()).unwrap_or(0)
+    }
+    /// Total number of vital chunks that were resent.
+    pub fn resends(&self) -> u64 {
+        self.resend_queue().map(|q| q.resends()).unwrap_or(0)
+    }
+    /// Smoothed round-trip time, if it has been measured yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.resend_queue().and_then(|q| q.rtt())
+    }
+    /// Current timeout after which unacknowledged vital chunks are resent.
+    pub fn resend_timeout(&self) -> Duration {
+        self.resend_queue()
+            .map(|q| q.resend_timeout())
+            .unwrap_or(INITIAL_RESEND_TIMEOUT)
+    }
+    /// Returns the traffic statistics of this connection.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+        stats.packets_sent = self.builder.packets_sent;
+        stats.bytes_sent = self.builder.bytes_sent;
+        if let State::Online(ref online) = self.state {
+            stats.resends = online.resend_queue.resends();
+            stats.ack = online.ack.to_u16();
+            stats.sequence = online.sequence.to_u16();
+            stats.rtt = online.resend_queue.rtt();
+        }
+        stats
+    }
+    fn check_queue_limit<CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+    ) -> Option<(ReceivePacket<'static>, Result<(), CB::Error>)>
+    where
+        CB: Callback,
+        W: Warn<Warning>,
+    {
+        let online = match self.state {
+            State::Online(ref mut online) => online,
+            _ => return None,
+        };
+        let action = online.resend_queue.check_limit(self.queue_limit)?;
+        warn.warn(Warning::QueueLimitExceeded(
+            online.resend_queue.vital_bytes(),
+        ));
+        match action {
+            QueueLimitAction::Warn => None,
+            QueueLimitAction::Disconnect => {
+                let result = self.disconnect(cb, QUEUE_LIMIT_REASON);
+                Some((ReceivePacket::disconnect(QUEUE_LIMIT_REASON), result))
+            }
+        }
     }
     pub fn is_unconnected(&self) -> bool {
         matches!(self.state, State::Unconnected)
@@ -494,18 +870,14 @@ impl Connection {
             _ => {}
         }
         let resends = match self.state {
-            State::Online(ref online) => online
-                .resend_queue
-                .back()
-                .map(|r| r.next_send)
-                .unwrap_or_default(),
+            State::Online(ref online) => online.resend_queue.next_send(),
             _ => Timeout::inactive(),
         };
-        cmp::min(self.send, resends)
+        cmp::min(cmp::min(self.send, resends), self.queue_limit_check)
     }
     pub fn connect<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
         assert!(matches!(self.state, State::Unconnected));
-        self.state = State::Connecting;
+        self.state = State::Connecting(ConnectingState::new());
         self.tick_action(cb)?;
         Ok(())
     }
@@ -528,20 +900,18 @@ impl Connection {
         self.state = State::Disconnected;
         result
     }
-    fn resend<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+    fn resend<CB: Callback>(&mut self, cb: &mut CB, timed_out: bool) -> Result<(), CB::Error> {
         let online = self.state.assert_online();
         if online.resend_queue.is_empty() {
             return Ok(());
         }
         online.packet = online.packet_nonvital.clone();
         let mut i = 0;
-        for chunk in &mut online.resend_queue {
-            chunk.start_timeout(cb);
-        }
+        online.resend_queue.start_resend(cb, timed_out);
         while i < online.resend_queue.len() {
             let can_fit;
             {
-                let chunk = &online.resend_queue[online.resend_queue.len() - i - 1];
+                let chunk = online.resend_queue.oldest(i);
                 can_fit = online.packet.can_fit_chunk(&chunk.data, true);
                 if can_fit {
                     let vital = (chunk.sequence.to_u16(), true);
@@ -554,6 +924,11 @@ impl Connection {
                 online.flush(cb, &mut self.builder)?;
             }
         }
+        if timed_out {
+            // Nobody else is going to flush the resent chunks soon.
+            self.send.set(cb, Duration::from_millis(500));
+            online.flush(cb, &mut self.builder)?;
+        }
         Ok(())
     }
     pub fn flush<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
@@ -562,11 +937,17 @@ impl Connection {
     }
     fn queue<CB: Callback>(&mut self, cb: &mut CB, buffer: &[u8], vital: bool) {
         let online = self.state.assert_online();
+        if vital {
+            self.stats.vital_chunks_sent += 1;
+        } else {
+            self.stats.nonvital_chunks_sent += 1;
+        }
         let vital = if vital {
             let sequence = online.sequence.next();
-            online
-                .resend_queue
-                .push_front(ResendChunk::new(cb, sequence, buffer));
+            online.resend_queue.push(cb, sequence, buffer);
+            if online.resend_queue.exceeds_limit(self.queue_limit) {
+                self.queue_limit_check.set(cb, Duration::from_secs(0));
+            }
             Some((sequence.to_u16(), false))
         } else {
             None
@@ -617,8 +998,7 @@ impl Connection {
         };
         let token = match self.state {
             State::Unconnected => unreachable!(),
-            // Signal support for the token protocol.
-            State::Connecting => Some(TOKEN_NONE),
+            State::Connecting(ref connecting) => connecting.token(),
             State::Pending(ref pending) => pending.token,
             State::Online(ref online) => online.token,
             State::Disconnected => unreachable!(),
@@ -634,29 +1014,41 @@ impl Connection {
             )
             .map_err(|e| e.unwrap_callback())
     }
-    pub fn tick<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+    /// Resends unacknowledged chunks and sends keepalives as necessary.
+    ///
+    /// Also checks the queue limit, which can't be done by `send`.
+    pub fn tick<CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+    ) -> (ReceivePacket<'static>, Result<(), CB::Error>)
+    where
+        CB: Callback,
+        W: Warn<Warning>,
+    {
+        self.queue_limit_check = Timeout::inactive();
+        if let Some(result) = self.check_queue_limit(cb, warn) {
+            return result;
+        }
         let do_resend = match self.state {
             State::Online(ref online) => {
                 // WARN?
-                online
-                    .resend_queue
-                    .back()
-                    .map(|c| c.next_send.has_triggered_level(cb))
-                    .unwrap_or(false)
+                online.resend_queue.next_send().has_triggered_level(cb)
             }
             _ => false,
         };
-        if do_resend {
-            self.resend(cb)
+        let result = if do_resend {
+            self.resend(cb, true)
         } else if self.send.has_triggered_edge(cb) {
             self.tick_action(cb)
         } else {
             Ok(())
-        }
+        };
+        (ReceivePacket::none(), result)
     }
     fn tick_action<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
         let control = match self.state {
-            State::Connecting => ControlPacket::Connect,
+            State::Connecting(_) => ControlPacket::Connect,
             State::Pending(_) => ControlPacket::ConnectAccept,
             State::Online(ref mut online) => {
                 if online.can_send() {
@@ -669,7 +1061,11 @@ impl Connection {
             _ => return Ok(()),
         };
         self.send.set(cb, Duration::from_millis(500));
-        self.send_control(cb, control)
+        let result = self.send_control(cb, control);
+        if let State::Connecting(ref mut connecting) = self.state {
+            connecting.attempts += 1;
+        }
+        result
     }
     /// Notifies the connection of incoming data.
     ///
@@ -728,10 +1124,16 @@ impl Connection {
                     return none;
                 }
             }
+            self.stats.packets_received += 1;
+            self.stats.bytes_received += data.len() as u64;
+            self.stats.last_received = Some(cb.time());
 
             // TODO: Check ack for sanity.
             if let State::Online(ref mut online) = self.state {
-                online.ack_chunks(Sequence::from_u16(ack));
+                online.resend_queue.ack(cb, Sequence::from_u16(ack));
+            }
+            if let Some(result) = self.check_queue_limit(cb, warn) {
+                return result;
             }
 
             match type_ {
@@ -743,7 +1145,7 @@ impl Connection {
                     let result;
                     if request_resend {
                         if let State::Online(_) = self.state {
-                            result = self.resend(cb);
+                            result = self.resend(cb, false);
                         } else {
                             result = Ok(());
                         }
@@ -753,7 +1155,13 @@ impl Connection {
                     match self.state {
                         State::Online(ref mut online) => {
                             return (
-                                ReceivePacket::connected(warn, online, num_chunks, chunks),
+                                ReceivePacket::connected(
+                                    warn,
+                                    online,
+                                    &mut self.stats,
+                                    num_chunks,
+                                    chunks,
+                                ),
                                 result,
                             );
                         }
@@ -778,7 +1186,7 @@ impl Connection {
                     }
                 }
                 Control(ConnectAccept) => {
-                    if let State::Connecting = self.state {
+                    if let State::Connecting(_) = self.state {
                         self.state = State::Online(OnlineState::new(token));
                         return (
                             ReceivePacket::ready(),
@@ -804,14 +1212,20 @@ impl Connection {
 mod test {
     use super::Callback;
     use super::Connection;
+    use super::QueueLimit;
+    use super::QueueLimitAction;
     use super::ReceiveChunk;
     use super::Sequence;
     use super::SequenceOrdering;
+    use super::Warning;
+    use super::QUEUE_LIMIT_REASON;
     use crate::protocol;
+    use crate::Timeout;
     use crate::Timestamp;
     use hexdump::hexdump;
     use itertools::Itertools;
     use std::collections::VecDeque;
+    use std::time::Duration;
     use void::ResultVoidExt;
     use void::Void;
     use warn::Panic;
@@ -945,6 +1359,258 @@ mod test {
         server.reset();
     }
 
+    struct TimeCb {
+        packets: VecDeque<Vec<u8>>,
+        time: Timestamp,
+    }
+    impl TimeCb {
+        fn new() -> TimeCb {
+            TimeCb {
+                packets: VecDeque::new(),
+                time: Timestamp::from_secs_since_epoch(0),
+            }
+        }
+        fn advance(&mut self, ms: u64) {
+            self.time = self.time + Duration::from_millis(ms);
+        }
+    }
+    impl Callback for TimeCb {
+        type Error = Void;
+        fn secure_random(&mut self, buffer: &mut [u8]) {
+            let _ = buffer;
+            unimplemented!();
+        }
+        fn send(&mut self, data: &[u8]) -> Result<(), Void> {
+            self.packets.push_back(data.to_owned());
+            Ok(())
+        }
+        fn time(&mut self) -> Timestamp {
+            self.time
+        }
+    }
+
+    #[test]
+    fn rtt_and_resend_timeout() {
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let token = protocol::Token([0x12, 0x34, 0x56, 0x78]);
+        let mut client = Connection::new_accept_token(cb, token);
+        let mut server = Connection::new_accept_token(cb, token);
+        assert_eq!(client.rtt(), None);
+        assert_eq!(client.resend_timeout(), Duration::from_millis(1_000));
+
+        client.send(cb, b"\x42", true).unwrap();
+        client.flush(cb).void_unwrap();
+        assert_eq!(client.queued_vital_bytes(), 1);
+        let packet = cb.packets.pop_front().unwrap();
+
+        // The server acknowledges the chunk 100 ms later.
+        cb.advance(100);
+        assert!(
+            server
+                .feed(cb, &mut Panic, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ReceiveChunk::Connected(b"\x42", true)]
+        );
+        server.send(cb, b"\x43", true).unwrap();
+        server.flush(cb).void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        client
+            .feed(cb, &mut Panic, &packet, &mut buffer[..])
+            .0
+            .for_each(drop);
+        assert!(cb.packets.is_empty());
+        assert_eq!(client.queued_vital_bytes(), 0);
+        assert_eq!(client.rtt(), Some(Duration::from_millis(100)));
+        // srtt + 4 * rttvar = 100 ms + 4 * 50 ms
+        assert_eq!(client.resend_timeout(), Duration::from_millis(300));
+
+        // Lose the next chunk, it's resent after the resend timeout and the
+        // resend timeout is backed off.
+        client.send(cb, b"\x44\x45", true).unwrap();
+        client.flush(cb).void_unwrap();
+        cb.packets.pop_front().unwrap();
+        cb.advance(299);
+        client.tick(cb, &mut Panic).1.void_unwrap();
+        assert!(cb.packets.is_empty());
+        cb.advance(1);
+        client.tick(cb, &mut Panic).1.void_unwrap();
+        assert!(cb.packets.pop_front().is_some());
+        assert!(cb.packets.is_empty());
+        assert_eq!(client.resends(), 1);
+        assert_eq!(client.queued_vital_bytes(), 2);
+        assert_eq!(client.resend_timeout(), Duration::from_millis(600));
+    }
+
+    #[test]
+    fn queue_limit() {
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let token = protocol::Token([0x12, 0x34, 0x56, 0x78]);
+        let mut client = Connection::new_accept_token(cb, token);
+        client.set_queue_limit(Some(QueueLimit {
+            max_vital_bytes: 2,
+            action: QueueLimitAction::Warn,
+        }));
+        client.send(cb, b"\x42\x43\x44", true).unwrap();
+        client.flush(cb).void_unwrap();
+        cb.packets.clear();
+
+        // The limit is checked when the peer's acknowledgements are
+        // processed, warn only once.
+        let keepalive = b"\x10\x00\x00\x00\x12\x34\x56\x78";
+        let mut warnings = vec![];
+        for _ in 0..2 {
+            assert!(client
+                .feed(cb, &mut warnings, keepalive, &mut buffer[..])
+                .0
+                .next()
+                .is_none());
+        }
+        assert!(matches!(warnings[..], [Warning::QueueLimitExceeded(3)]));
+
+        client.set_queue_limit(Some(QueueLimit {
+            max_vital_bytes: 2,
+            action: QueueLimitAction::Disconnect,
+        }));
+        client.send(cb, b"\x45", true).unwrap();
+        client.flush(cb).void_unwrap();
+        cb.packets.clear();
+        // Go below the limit again by acknowledging the first chunk.
+        let ack = b"\x10\x01\x00\x00\x12\x34\x56\x78";
+        assert!(client
+            .feed(cb, &mut warnings, ack, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        client.send(cb, b"\x46\x47", true).unwrap();
+        client.flush(cb).void_unwrap();
+        cb.packets.clear();
+        assert!(
+            client
+                .feed(cb, &mut warnings, ack, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ReceiveChunk::Disconnect(QUEUE_LIMIT_REASON)]
+        );
+        assert!(matches!(
+            warnings[..],
+            [
+                Warning::QueueLimitExceeded(3),
+                Warning::QueueLimitExceeded(3)
+            ]
+        ));
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(packet.starts_with(b"\x10\x00\x00\x04Too weak connection"));
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn queue_limit_without_acks() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let token = protocol::Token([0x12, 0x34, 0x56, 0x78]);
+        let mut client = Connection::new_accept_token(cb, token);
+        client.set_queue_limit(Some(QueueLimit {
+            max_vital_bytes: 2,
+            action: QueueLimitAction::Disconnect,
+        }));
+        client.send(cb, b"\x42", true).unwrap();
+        client.flush(cb).void_unwrap();
+        cb.packets.clear();
+        assert!(client.needs_tick() > Timeout::active(cb.time()));
+
+        // The peer never acknowledges anything, the limit is checked on the
+        // next tick after the send exceeding it.
+        client.send(cb, b"\x43\x44", true).unwrap();
+        assert_eq!(client.needs_tick(), Timeout::active(cb.time()));
+        let mut warnings = vec![];
+        let (packet, result) = client.tick(cb, &mut warnings);
+        result.void_unwrap();
+        assert!(packet.collect_vec() == &[ReceiveChunk::Disconnect(QUEUE_LIMIT_REASON)]);
+        assert!(matches!(warnings[..], [Warning::QueueLimitExceeded(3)]));
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(packet.starts_with(b"\x10\x00\x00\x04Too weak connection"));
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn rtt_from_flush() {
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let token = protocol::Token([0x12, 0x34, 0x56, 0x78]);
+        let mut client = Connection::new_accept_token(cb, token);
+
+        // The chunk is queued for a while before being flushed, the
+        // round-trip time is measured from the flush.
+        client.send(cb, b"\x42", true).unwrap();
+        cb.advance(500);
+        client.flush(cb).void_unwrap();
+        cb.packets.clear();
+        cb.advance(100);
+        let ack = b"\x10\x01\x00\x00\x12\x34\x56\x78";
+        assert!(client
+            .feed(cb, &mut Panic, ack, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        assert_eq!(client.queued_vital_bytes(), 0);
+        assert_eq!(client.rtt(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn connect_fallback_without_token() {
+        struct Cb {
+            packets: VecDeque<Vec<u8>>,
+            time: Timestamp,
+        }
+        impl Callback for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                let _ = buffer;
+                unimplemented!();
+            }
+            fn send(&mut self, data: &[u8]) -> Result<(), Void> {
+                self.packets.push_back(data.to_owned());
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                self.time
+            }
+        }
+        let mut cb = Cb {
+            packets: VecDeque::new(),
+            time: Timestamp::from_secs_since_epoch(0),
+        };
+        let cb = &mut cb;
+
+        let mut client = Connection::new();
+        client.connect(cb).void_unwrap();
+        for _ in 1..super::CONNECT_TOKEN_ATTEMPTS {
+            cb.time = cb.time + Duration::from_millis(500);
+            client.tick(cb, &mut Panic).1.void_unwrap();
+        }
+        assert!(cb.packets.len() == super::CONNECT_TOKEN_ATTEMPTS as usize);
+        assert!(cb
+            .packets
+            .drain(..)
+            .all(|p| p == b"\x10\x00\x00\x01TKEN\xff\xff\xff\xff"));
+
+        // The server doesn't seem to understand tokens, try without.
+        cb.time = cb.time + Duration::from_millis(500);
+        client.tick(cb, &mut Panic).1.void_unwrap();
+        assert!(cb.packets.pop_front().unwrap() == b"\x10\x00\x00\x01");
+        assert!(cb.packets.is_empty());
+    }
+
     #[test]
     fn establish_connection() {
         struct Cb(VecDeque<Vec<u8>>);
@@ -0,0 +1,890 @@
+use crate::connection::Callback;
+use crate::connection::Error;
+use crate::connection::PacketBuilder;
+use crate::connection::PacketContents;
+use crate::connection::QueueLimit;
+use crate::connection::QueueLimitAction;
+use crate::connection::ReceiveChunk;
+use crate::connection::ReceivePacket;
+use crate::connection::ResendQueue;
+use crate::connection::Sequence;
+use crate::connection::SequenceOrdering;
+use crate::connection::Stats;
+use crate::connection::TimeoutExt;
+use crate::connection::Warning;
+use crate::connection::INITIAL_RESEND_TIMEOUT;
+use crate::connection::QUEUE_LIMIT_REASON;
+use crate::protocol7;
+use crate::protocol7::ChunksIter;
+use crate::protocol7::ConnectedPacket;
+use crate::protocol7::ConnectedPacketType;
+use crate::protocol7::ControlPacket;
+use crate::protocol7::Packet;
+use crate::protocol7::Token;
+use crate::protocol7::MAX_PAYLOAD;
+use crate::protocol7::TOKEN_NONE;
+use crate::Timeout;
+use buffer::with_buffer;
+use buffer::Buffer;
+use buffer::BufferRef;
+use std::cmp;
+use std::time::Duration;
+use warn::Warn;
+
+/// A Teeworlds 0.7 connection.
+///
+/// Unlike the 0.6 protocol, both sides choose a token which the other side
+/// has to include in every packet. The client requests the server's token
+/// with a `Token` control packet before sending `Connect`, the server
+/// answers a `Connect` with `Accept`. There's no third packet in the
+/// handshake.
+pub struct Connection7 {
+    state: State,
+    send: Timeout,
+    builder: PacketBuilder,
+    queue_limit: Option<QueueLimit>,
+    // Set by `send` when the queue limit is exceeded, `tick` reports it.
+    queue_limit_check: Timeout,
+    stats: Stats,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Tokens {
+    // `token` is chosen by us, the peer includes it in every packet.
+    token: Token,
+    // `peer_token` is chosen by the peer, we include it in every packet.
+    peer_token: Token,
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    Unconnected,
+    // Client side, waiting for the server's token. Contains our token.
+    Token(Token),
+    // Client side, waiting for the server to accept the connection.
+    Connecting(Tokens),
+    // Server side, waiting for the application to accept the connection.
+    Pending(Tokens),
+    Online(OnlineState),
+    Disconnected,
+}
+
+impl State {
+    fn assert_online(&mut self) -> &mut OnlineState {
+        match *self {
+            State::Online(ref mut s) => s,
+            _ => panic!("state not online"),
+        }
+    }
+    /// The token the peer has to include in its packets.
+    fn token(&self) -> Option<Token> {
+        match *self {
+            State::Unconnected => None,
+            State::Token(token) => Some(token),
+            State::Connecting(ref tokens) => Some(tokens.token),
+            State::Pending(ref tokens) => Some(tokens.token),
+            State::Online(ref online) => Some(online.tokens.token),
+            State::Disconnected => None,
+        }
+    }
+    /// The token we have to include in our packets.
+    fn peer_token(&self) -> Option<Token> {
+        match *self {
+            State::Unconnected => None,
+            State::Token(_) => None,
+            State::Connecting(ref tokens) => Some(tokens.peer_token),
+            State::Pending(ref tokens) => Some(tokens.peer_token),
+            State::Online(ref online) => Some(online.tokens.peer_token),
+            State::Disconnected => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ReceiveChunks7<'a> {
+    ack: Sequence,
+    chunks: ChunksIter<'a>,
+}
+
+impl<'a> Iterator for ReceiveChunks7<'a> {
+    type Item = ReceiveChunk<'a>;
+    fn next(&mut self) -> Option<ReceiveChunk<'a>> {
+        self.chunks.next().and_then(|c| {
+            if let Some((sequence, resend)) = c.vital {
+                let _ = resend;
+                if self.ack.update(Sequence::from_u16(sequence)) != SequenceOrdering::Current {
+                    return self.next();
+                }
+            }
+            Some(ReceiveChunk::Connected(c.data, c.vital.is_some()))
+        })
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.clone().count();
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for ReceiveChunks7<'a> {}
+
+fn receive_connected<'a, W>(
+    warn: &mut W,
+    online: &mut OnlineState,
+    stats: &mut Stats,
+    num_chunks: u8,
+    data: &'a [u8],
+) -> ReceivePacket<'a>
+where
+    W: Warn<Warning>,
+{
+    let chunks_iter = ChunksIter::new(data, num_chunks);
+    let ack = online.ack.clone();
+    let mut iter = chunks_iter.clone();
+    while let Some(c) = iter.next_warn(&mut w(warn)) {
+        if let Some((sequence, resend)) = c.vital {
+            let _ = resend;
+            match online.ack.update(Sequence::from_u16(sequence)) {
+                SequenceOrdering::Current => stats.vital_chunks_received += 1,
+                SequenceOrdering::Past => {
+                    online.request_resend = true;
+                    stats.dropped_duplicates += 1;
+                }
+                SequenceOrdering::Future => {
+                    online.request_resend = true;
+                    stats.dropped_out_of_order += 1;
+                }
+            }
+        } else {
+            stats.nonvital_chunks_received += 1;
+        }
+    }
+    ReceivePacket::connected7(ReceiveChunks7 {
+        ack: ack,
+        chunks: chunks_iter,
+    })
+}
+
+#[derive(Clone, Debug)]
+struct OnlineState {
+    tokens: Tokens,
+    // `ack` is the vital chunk from the peer we want to acknowledge.
+    ack: Sequence,
+    // `sequence` is the vital chunk from us that the peer acknowledged.
+    sequence: Sequence,
+    request_resend: bool,
+    // `packet` contains all the queued chunks, `packet_nonvital` only the
+    // non-vital ones. This is important for resending.
+    packet: PacketContents,
+    packet_nonvital: PacketContents,
+    resend_queue: ResendQueue,
+}
+
+impl OnlineState {
+    fn new(tokens: Tokens) -> OnlineState {
+        OnlineState {
+            tokens: tokens,
+            ack: Sequence::new(),
+            sequence: Sequence::new(),
+            request_resend: false,
+            packet: PacketContents::new(protocol7::write_chunk_impl),
+            packet_nonvital: PacketContents::new(protocol7::write_chunk_impl),
+            resend_queue: ResendQueue::new(),
+        }
+    }
+    fn can_send(&self) -> bool {
+        self.packet.num_chunks != 0 || self.request_resend
+    }
+    fn flush<CB: Callback>(
+        &mut self,
+        cb: &mut CB,
+        builder: &mut PacketBuilder,
+    ) -> Result<(), CB::Error> {
+        if !self.can_send() {
+            return Ok(());
+        }
+        let packet = ConnectedPacket {
+            token: self.tokens.peer_token,
+            ack: self.ack.to_u16(),
+            type_: ConnectedPacketType::Chunks(
+                self.request_resend,
+                self.packet.num_chunks,
+                &self.packet.data,
+            ),
+        };
+        let result = builder
+            .send_with(cb, |buffer| written(packet.write(buffer)))
+            .map_err(|e| e.unwrap_callback());
+        if result.is_ok() {
+            self.resend_queue.mark_sent(cb);
+        }
+        self.request_resend = false;
+        self.packet.clear();
+        self.packet_nonvital.clear();
+        result
+    }
+}
+
+/// Converts the result of writing a packet into the packet buffer of
+/// `PacketBuilder::send_with`.
+fn written<CE>(result: Result<&[u8], protocol7::Error>) -> Result<&[u8], Error<CE>> {
+    match result {
+        Ok(d) => Ok(d),
+        Err(protocol7::Error::Capacity(_)) => unreachable!("too short buffer provided"),
+        Err(protocol7::Error::TooLongData) => Err(Error::TooLongData),
+    }
+}
+
+struct WarnCallback<'a, W: Warn<Warning> + 'a> {
+    warn: &'a mut W,
+}
+
+fn w<W: Warn<Warning>>(warn: &mut W) -> WarnCallback<W> {
+    WarnCallback { warn: warn }
+}
+
+impl<'a, W: Warn<Warning>> Warn<protocol7::Warning> for WarnCallback<'a, W> {
+    fn warn(&mut self, warning: protocol7::Warning) {
+        self.warn.warn(Warning::Packet7(warning))
+    }
+}
+
+impl Connection7 {
+    pub fn new() -> Connection7 {
+        Connection7 {
+            state: State::Unconnected,
+            send: Timeout::inactive(),
+            builder: PacketBuilder::new(),
+            queue_limit: None,
+            queue_limit_check: Timeout::inactive(),
+            stats: Stats::default(),
+        }
+    }
+    /// Creates the server side of a connection after receiving a `Connect`
+    /// packet carrying our `token` in the header and the client's
+    /// `peer_token` in the payload.
+    ///
+    /// The connection stays pending until `accept` or `disconnect` is called.
+    pub fn new_pending(token: Token, peer_token: Token) -> Connection7 {
+        Connection7 {
+            state: State::Pending(Tokens {
+                token: token,
+                peer_token: peer_token,
+            }),
+            send: Timeout::inactive(),
+            builder: PacketBuilder::new(),
+            queue_limit: None,
+            queue_limit_check: Timeout::inactive(),
+            stats: Stats::default(),
+        }
+    }
+    pub fn reset(&mut self) {
+        assert!(matches!(self.state, State::Disconnected));
+        let queue_limit = self.queue_limit;
+        *self = Connection7::new();
+        self.queue_limit = queue_limit;
+    }
+    /// Sets the limit on the amount of unacknowledged vital data.
+    ///
+    /// The limit is checked in `tick` and whenever a packet from the peer is
+    /// processed. Queueing vital data beyond the limit makes `needs_tick`
+    /// expire immediately, so the `QueueLimitAction` is taken on the next
+    /// `tick` even if the peer stays silent.
+    pub fn set_queue_limit(&mut self, limit: Option<QueueLimit>) {
+        self.queue_limit = limit;
+    }
+    fn resend_queue(&self) -> Option<&ResendQueue> {
+        match self.state {
+            State::Online(ref online) => Some(&online.resend_queue),
+            _ => None,
+        }
+    }
+    /// Number of bytes of vital chunks that haven't been acknowledged by the
+    /// peer yet.
+    pub fn queued_vital_bytes(&self) -> usize {
+        self.resend_queue().map(|q| q.vital_bytes()).unwrap_or(0)
+    }
+    /// Total number of vital chunks that were resent.
+    pub fn resends(&self) -> u64 {
+        self.resend_queue().map(|q| q.resends()).unwrap_or(0)
+    }
+    /// Smoothed round-trip time, if it has been measured yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.resend_queue().and_then(|q| q.rtt())
+    }
+    /// Current timeout after which unacknowledged vital chunks are resent.
+    pub fn resend_timeout(&self) -> Duration {
+        self.resend_queue()
+            .map(|q| q.resend_timeout())
+            .unwrap_or(INITIAL_RESEND_TIMEOUT)
+    }
+    /// Returns the traffic statistics of this connection.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+        stats.packets_sent = self.builder.packets_sent;
+        stats.bytes_sent = self.builder.bytes_sent;
+        if let State::Online(ref online) = self.state {
+            stats.resends = online.resend_queue.resends();
+            stats.ack = online.ack.to_u16();
+            stats.sequence = online.sequence.to_u16();
+            stats.rtt = online.resend_queue.rtt();
+        }
+        stats
+    }
+    fn check_queue_limit<CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+    ) -> Option<(ReceivePacket<'static>, Result<(), CB::Error>)>
+    where
+        CB: Callback,
+        W: Warn<Warning>,
+    {
+        let online = match self.state {
+            State::Online(ref mut online) => online,
+            _ => return None,
+        };
+        let action = online.resend_queue.check_limit(self.queue_limit)?;
+        warn.warn(Warning::QueueLimitExceeded(
+            online.resend_queue.vital_bytes(),
+        ));
+        match action {
+            QueueLimitAction::Warn => None,
+            QueueLimitAction::Disconnect => {
+                let result = self.disconnect(cb, QUEUE_LIMIT_REASON);
+                Some((ReceivePacket::disconnect(QUEUE_LIMIT_REASON), result))
+            }
+        }
+    }
+    pub fn is_unconnected(&self) -> bool {
+        matches!(self.state, State::Unconnected)
+    }
+    pub fn is_pending(&self) -> bool {
+        matches!(self.state, State::Pending(_))
+    }
+    pub fn is_online(&self) -> bool {
+        matches!(self.state, State::Online(_))
+    }
+    pub fn needs_tick(&self) -> Timeout {
+        match self.state {
+            State::Unconnected | State::Pending(_) | State::Disconnected => {
+                return Timeout::inactive()
+            }
+            _ => {}
+        }
+        let resends = match self.state {
+            State::Online(ref online) => online.resend_queue.next_send(),
+            _ => Timeout::inactive(),
+        };
+        cmp::min(cmp::min(self.send, resends), self.queue_limit_check)
+    }
+    pub fn connect<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        assert!(matches!(self.state, State::Unconnected));
+        let token = Token::random(|b| cb.secure_random(b));
+        self.state = State::Token(token);
+        self.tick_action(cb)?;
+        Ok(())
+    }
+    pub fn accept<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        let tokens = match self.state {
+            State::Pending(tokens) => tokens,
+            _ => panic!("state not pending"),
+        };
+        self.state = State::Online(OnlineState::new(tokens));
+        self.send.set(cb, Duration::from_millis(500));
+        self.send_control(cb, ControlPacket::Accept)
+    }
+    pub fn disconnect<CB: Callback>(
+        &mut self,
+        cb: &mut CB,
+        reason: &[u8],
+    ) -> Result<(), CB::Error> {
+        assert!(
+            !matches!(self.state, State::Disconnected),
+            "Can't call disconnect on an already disconnected connection"
+        );
+        assert!(
+            reason.iter().all(|&b| b != 0),
+            "reason must not contain NULs"
+        );
+        // Without the peer's token, the peer would drop the packet anyway.
+        let result = if self.state.peer_token().is_some() {
+            self.send_control(cb, ControlPacket::Close(reason))
+        } else {
+            Ok(())
+        };
+        self.state = State::Disconnected;
+        result
+    }
+    fn resend<CB: Callback>(&mut self, cb: &mut CB, timed_out: bool) -> Result<(), CB::Error> {
+        let online = self.state.assert_online();
+        if online.resend_queue.is_empty() {
+            return Ok(());
+        }
+        online.packet = online.packet_nonvital.clone();
+        let mut i = 0;
+        online.resend_queue.start_resend(cb, timed_out);
+        while i < online.resend_queue.len() {
+            let can_fit;
+            {
+                let chunk = online.resend_queue.oldest(i);
+                can_fit = online.packet.can_fit_chunk(&chunk.data, true);
+                if can_fit {
+                    let vital = (chunk.sequence.to_u16(), true);
+                    online.packet.write_chunk(&chunk.data, Some(vital));
+                    i += 1;
+                }
+            }
+            if !can_fit {
+                self.send.set(cb, Duration::from_millis(500));
+                online.flush(cb, &mut self.builder)?;
+            }
+        }
+        if timed_out {
+            // Nobody else is going to flush the resent chunks soon.
+            self.send.set(cb, Duration::from_millis(500));
+            online.flush(cb, &mut self.builder)?;
+        }
+        Ok(())
+    }
+    pub fn flush<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        self.send.set(cb, Duration::from_millis(500));
+        self.state.assert_online().flush(cb, &mut self.builder)
+    }
+    fn queue<CB: Callback>(&mut self, cb: &mut CB, buffer: &[u8], vital: bool) {
+        let online = self.state.assert_online();
+        if vital {
+            self.stats.vital_chunks_sent += 1;
+        } else {
+            self.stats.nonvital_chunks_sent += 1;
+        }
+        let vital = if vital {
+            let sequence = online.sequence.next();
+            online.resend_queue.push(cb, sequence, buffer);
+            if online.resend_queue.exceeds_limit(self.queue_limit) {
+                self.queue_limit_check.set(cb, Duration::from_secs(0));
+            }
+            Some((sequence.to_u16(), false))
+        } else {
+            None
+        };
+        if vital.is_none() {
+            online.packet_nonvital.write_chunk(buffer, vital);
+        }
+        online.packet.write_chunk(buffer, vital)
+    }
+    pub fn send<CB: Callback>(
+        &mut self,
+        cb: &mut CB,
+        buffer: &[u8],
+        vital: bool,
+    ) -> Result<(), Error<CB::Error>> {
+        let result;
+        {
+            let online = self.state.assert_online();
+            if buffer.len() > MAX_PAYLOAD {
+                return Err(Error::TooLongData);
+            }
+            if !online.packet.can_fit_chunk(buffer, vital) {
+                result = online.flush(cb, &mut self.builder).map_err(Error::from);
+            } else {
+                result = Ok(());
+            }
+        }
+        self.queue(cb, buffer, vital);
+        result
+    }
+    pub fn send_connless<CB: Callback>(
+        &mut self,
+        cb: &mut CB,
+        data: &[u8],
+    ) -> Result<(), Error<CB::Error>> {
+        let tokens = self.state.assert_online().tokens;
+        self.send.set(cb, Duration::from_millis(500));
+        self.builder.send_with(cb, |buffer| {
+            written(protocol7::write_connless_packet(
+                tokens.peer_token,
+                tokens.token,
+                data,
+                buffer,
+            ))
+        })
+    }
+    fn send_control<CB: Callback>(
+        &mut self,
+        cb: &mut CB,
+        control: ControlPacket,
+    ) -> Result<(), CB::Error> {
+        let ack = match self.state {
+            State::Online(ref mut online) => online.ack.to_u16(),
+            _ => 0,
+        };
+        let token = match self.state {
+            // Token requests are sent without knowing the peer's token.
+            State::Token(_) => TOKEN_NONE,
+            ref s => s.peer_token().expect("control packet without peer token"),
+        };
+        let packet = ConnectedPacket {
+            token: token,
+            ack: ack,
+            type_: ConnectedPacketType::Control(control),
+        };
+        self.builder
+            .send_with(cb, |buffer| written(packet.write(buffer)))
+            .map_err(|e| e.unwrap_callback())
+    }
+    /// Resends unacknowledged chunks and sends keepalives as necessary.
+    ///
+    /// Also checks the queue limit, which can't be done by `send`.
+    pub fn tick<CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+    ) -> (ReceivePacket<'static>, Result<(), CB::Error>)
+    where
+        CB: Callback,
+        W: Warn<Warning>,
+    {
+        self.queue_limit_check = Timeout::inactive();
+        if let Some(result) = self.check_queue_limit(cb, warn) {
+            return result;
+        }
+        let do_resend = match self.state {
+            State::Online(ref online) => online.resend_queue.next_send().has_triggered_level(cb),
+            _ => false,
+        };
+        let result = if do_resend {
+            self.resend(cb, true)
+        } else if self.send.has_triggered_edge(cb) {
+            self.tick_action(cb)
+        } else {
+            Ok(())
+        };
+        (ReceivePacket::none(), result)
+    }
+    fn tick_action<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        let control = match self.state {
+            State::Token(token) => ControlPacket::Token(token),
+            State::Connecting(ref tokens) => ControlPacket::Connect(tokens.token),
+            State::Online(ref mut online) => {
+                if online.can_send() {
+                    self.send.set(cb, Duration::from_millis(500));
+                    return online.flush(cb, &mut self.builder);
+                }
+                ControlPacket::KeepAlive
+            }
+            _ => return Ok(()),
+        };
+        self.send.set(cb, Duration::from_millis(500));
+        self.send_control(cb, control)
+    }
+    /// Notifies the connection of incoming data.
+    ///
+    /// `buffer` must have at least size `MAX_PAYLOAD`.
+    pub fn feed<'a, B, CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        data: &'a [u8],
+        buf: B,
+    ) -> (ReceivePacket<'a>, Result<(), CB::Error>)
+    where
+        B: Buffer<'a>,
+        CB: Callback,
+        W: Warn<Warning>,
+    {
+        with_buffer(buf, |b| self.feed_impl(cb, warn, data, b))
+    }
+
+    pub fn feed_impl<'d, 's, CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        data: &'d [u8],
+        mut buffer: BufferRef<'d, 's>,
+    ) -> (ReceivePacket<'d>, Result<(), CB::Error>)
+    where
+        CB: Callback,
+        W: Warn<Warning>,
+    {
+        let none = (ReceivePacket::none(), Ok(()));
+        {
+            use protocol7::ConnectedPacketType::*;
+            use protocol7::ControlPacket::*;
+
+            let packet = match Packet::read(&mut w(warn), data, &mut buffer) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn.warn(Warning::Read7(e));
+                    return none;
+                }
+            };
+
+            let connected = match packet {
+                Packet::Connless(payload) => {
+                    let token = Packet::connless_header(data).map(|h| h.token);
+                    if token.is_none() || token != self.state.token() {
+                        warn.warn(Warning::TokenMismatch);
+                        return none;
+                    }
+                    return (ReceivePacket::connless(payload), Ok(()));
+                }
+                Packet::Connected(c) => c,
+            };
+            let ConnectedPacket { token, ack, type_ } = connected;
+
+            match self.state.token() {
+                Some(expected_token) => {
+                    if token != expected_token {
+                        warn.warn(Warning::TokenMismatch);
+                        return none;
+                    }
+                }
+                None => {
+                    warn.warn(Warning::Unexpected);
+                    return none;
+                }
+            }
+            self.stats.packets_received += 1;
+            self.stats.bytes_received += data.len() as u64;
+            self.stats.last_received = Some(cb.time());
+
+            // TODO: Check ack for sanity.
+            if let State::Online(ref mut online) = self.state {
+                online.resend_queue.ack(cb, Sequence::from_u16(ack));
+            }
+            if let Some(result) = self.check_queue_limit(cb, warn) {
+                return result;
+            }
+
+            match type_ {
+                Chunks(request_resend, num_chunks, chunks) => {
+                    let result;
+                    if request_resend {
+                        if let State::Online(_) = self.state {
+                            result = self.resend(cb, false);
+                        } else {
+                            result = Ok(());
+                        }
+                    } else {
+                        result = Ok(())
+                    }
+                    match self.state {
+                        State::Online(ref mut online) => {
+                            return (
+                                receive_connected(
+                                    warn,
+                                    online,
+                                    &mut self.stats,
+                                    num_chunks,
+                                    chunks,
+                                ),
+                                result,
+                            );
+                        }
+                        // WARN: packet received while not online.
+                        _ => return none,
+                    }
+                }
+                Control(KeepAlive) => return none,
+                Control(Token(peer_token)) => {
+                    if let State::Token(token) = self.state {
+                        self.state = State::Connecting(Tokens {
+                            token: token,
+                            peer_token: peer_token,
+                        });
+                        // Fall through to tick.
+                    } else {
+                        return none;
+                    }
+                }
+                Control(Connect(_)) => {
+                    // Our `Accept` got lost, send it again.
+                    if let State::Online(_) = self.state {
+                        return (ReceivePacket::none(), self.send_control(cb, Accept));
+                    } else {
+                        return none;
+                    }
+                }
+                Control(Accept) => {
+                    if let State::Connecting(tokens) = self.state {
+                        self.state = State::Online(OnlineState::new(tokens));
+                        self.send.set(cb, Duration::from_millis(500));
+                        return (ReceivePacket::ready(), Ok(()));
+                    } else {
+                        return none;
+                    }
+                }
+                Control(Close(reason)) => {
+                    self.state = State::Disconnected;
+                    return (ReceivePacket::disconnect(reason), Ok(()));
+                }
+            }
+        }
+        // Fall-through from `Control(Token)`
+        (ReceivePacket::none(), self.tick_action(cb))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Connection7;
+    use crate::connection::Callback;
+    use crate::connection::ReceiveChunk;
+    use crate::protocol7;
+    use crate::protocol7::Token;
+    use crate::Timestamp;
+    use hexdump::hexdump;
+    use itertools::Itertools;
+    use std::collections::VecDeque;
+    use void::ResultVoidExt;
+    use void::Void;
+    use warn::Panic;
+
+    #[test]
+    fn establish_connection() {
+        struct Cb(VecDeque<Vec<u8>>);
+        impl Cb {
+            fn new() -> Cb {
+                Cb(VecDeque::new())
+            }
+        }
+        impl Callback for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                if buffer.len() != 4 {
+                    unimplemented!();
+                }
+                buffer[0] = 0x12;
+                buffer[1] = 0x34;
+                buffer[2] = 0x56;
+                buffer[3] = 0x78;
+            }
+            fn send(&mut self, data: &[u8]) -> Result<(), Void> {
+                self.0.push_back(data.to_owned());
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let mut buffer = [0; protocol7::MAX_PACKETSIZE];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+
+        let server_token = Token([0x9a, 0xbc, 0xde, 0xf0]);
+        let mut client = Connection7::new();
+
+        // Token request
+        client.connect(cb).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        assert!(packet.len() == 8 + protocol7::TOKEN_REQUEST_DATA_SIZE);
+        assert!(&packet[..12] == b"\x04\x00\x00\xff\xff\xff\xff\x05\x12\x34\x56\x78");
+
+        // Token response, normally sent by `Net7`.
+        let packet = b"\x04\x00\x00\x12\x34\x56\x78\x05\x9a\xbc\xde\xf0";
+        assert!(client
+            .feed(cb, &mut Panic, packet, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+
+        // Connect
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        assert!(packet.len() == 8 + protocol7::TOKEN_REQUEST_DATA_SIZE);
+        assert!(&packet[..12] == b"\x04\x00\x00\x9a\xbc\xde\xf0\x01\x12\x34\x56\x78");
+
+        // Accept
+        let mut server = Connection7::new_pending(server_token, Token([0x12, 0x34, 0x56, 0x78]));
+        server.accept(cb).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        hexdump(&packet);
+        assert!(&packet == b"\x04\x00\x00\x12\x34\x56\x78\x02");
+
+        assert!(
+            client
+                .feed(cb, &mut Panic, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ReceiveChunk::Ready]
+        );
+        assert!(cb.0.is_empty());
+
+        // Send
+        client.send(cb, b"\x42", true).unwrap();
+        assert!(cb.0.is_empty());
+
+        // Flush
+        client.flush(cb).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        hexdump(&packet);
+        assert!(&packet == b"\x00\x00\x01\x9a\xbc\xde\xf0\x40\x01\x01\x42");
+
+        // Receive
+        assert!(
+            server
+                .feed(cb, &mut Panic, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ReceiveChunk::Connected(b"\x42", true)]
+        );
+        assert!(cb.0.is_empty());
+
+        // Disconnect
+        server.disconnect(cb, b"42").void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        hexdump(&packet);
+        assert!(&packet == b"\x04\x01\x00\x12\x34\x56\x78\x0442\0");
+
+        assert!(
+            client
+                .feed(cb, &mut Panic, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ReceiveChunk::Disconnect(b"42")]
+        );
+
+        client.reset();
+        server.reset();
+    }
+
+    #[test]
+    fn token_mismatch() {
+        struct Cb;
+        impl Callback for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                let _ = buffer;
+                unimplemented!();
+            }
+            fn send(&mut self, data: &[u8]) -> Result<(), Void> {
+                let _ = data;
+                unreachable!();
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let mut buffer = [0; protocol7::MAX_PACKETSIZE];
+        let mut warnings = vec![];
+        let mut server = Connection7::new_pending(
+            Token([0x9a, 0xbc, 0xde, 0xf0]),
+            Token([0x12, 0x34, 0x56, 0x78]),
+        );
+        // Close with the wrong token.
+        let packet = b"\x04\x00\x00\x9a\xbc\xde\xf1\x04\0";
+        assert!(server
+            .feed(&mut Cb, &mut warnings, packet, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        assert!(server.is_pending());
+        assert!(matches!(
+            &warnings[..],
+            [crate::connection::Warning::TokenMismatch]
+        ));
+    }
+}
@@ -1,11 +1,17 @@
+pub mod admission;
 pub mod collections;
 pub mod connection;
+pub mod connection7;
 pub mod net;
+pub mod net7;
 pub mod protocol;
 pub mod protocol7;
+pub mod sim;
 pub mod time;
 
 pub use self::connection::Connection;
+pub use self::connection7::Connection7;
 pub use self::net::Net;
+pub use self::net7::Net7;
 pub use self::time::Timeout;
 pub use self::time::Timestamp;
@@ -1,3 +1,5 @@
+use crate::admission::Admission;
+use crate::admission::Dropped;
 use crate::collections::peer_map;
 use crate::collections::PeerMap;
 use crate::connection;
@@ -7,21 +9,35 @@ use crate::protocol::ConnectedPacket;
 use crate::protocol::ConnectedPacketType;
 use crate::protocol::ControlPacket;
 use crate::protocol::Packet;
+use crate::protocol7;
+use crate::protocol7::Token;
+use crate::protocol7::TOKEN_NONE;
 use crate::Connection;
+use crate::Connection7;
 use crate::Timeout;
 use crate::Timestamp;
 use arrayvec::ArrayVec;
 use buffer::with_buffer;
 use buffer::Buffer;
 use buffer::BufferRef;
+use std::any::Any;
+use std::collections::hash_map;
+use std::collections::HashMap;
 use std::fmt;
 use std::hash::Hash;
+use std::hash::Hasher;
+#[allow(deprecated)]
+use std::hash::SipHasher;
 use std::iter;
 use std::ops;
+use std::time::Duration;
+use std::vec;
 use warn::Panic;
 use warn::Warn;
 
 pub use crate::connection::Error;
+pub use crate::connection::QueueLimit;
+pub use crate::connection::QueueLimitAction;
 
 pub trait Callback<A: Address> {
     type Error;
@@ -34,6 +50,9 @@ pub trait Callback<A: Address> {
 pub enum Warning<A: Address> {
     Peer(A, PeerId, connection::Warning),
     Connless(A, connection::Warning),
+    /// A packet was dropped by the admission policy, see
+    /// `Net::set_admission`.
+    Dropped(A, Dropped),
 }
 
 impl<A: Address> Warning<A> {
@@ -41,6 +60,7 @@ impl<A: Address> Warning<A> {
         match *self {
             Warning::Peer(addr, _, _) => addr,
             Warning::Connless(addr, _) => addr,
+            Warning::Dropped(addr, _) => addr,
         }
     }
 }
@@ -71,23 +91,156 @@ impl fmt::Display for PeerId {
     }
 }
 
-const CONNECT_PACKET: &'static [u8; 12] = b"\x10\x00\x00\x01TKEN\xff\xff\xff\xff";
+/// The wire protocol spoken with a peer.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Protocol {
+    /// Teeworlds 0.6 and DDNet.
+    V6,
+    /// Teeworlds 0.7.
+    V7,
+}
+
+/// Statistics about the connection to a peer, see `Net::peer_stats`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeerStats {
+    pub protocol: Protocol,
+    pub connection: connection::Stats,
+    /// Time since the last packet from the peer was received, `None` if
+    /// none was received yet.
+    pub since_last_received: Option<Duration>,
+}
+
+/// Allows getting the concrete admission policy back, see
+/// `Net::admission_mut`.
+trait AnyAdmission<A: Address>: Admission<A> {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<A: Address, P: Admission<A> + 'static> AnyAdmission<A> for P {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
 const CONNECT_PACKET_NO_TOKEN: &'static [u8; 4] = b"\x10\x00\x00\x01";
 
+enum PeerConnection {
+    V6(Connection),
+    V7(Connection7),
+}
+
+impl PeerConnection {
+    fn protocol(&self) -> Protocol {
+        match *self {
+            PeerConnection::V6(_) => Protocol::V6,
+            PeerConnection::V7(_) => Protocol::V7,
+        }
+    }
+    /// Whether the connection is waiting for `Net::accept` or `Net::reject`.
+    fn is_pending(&self) -> bool {
+        match *self {
+            PeerConnection::V6(ref c) => c.is_unconnected(),
+            PeerConnection::V7(ref c) => c.is_pending(),
+        }
+    }
+    fn needs_tick(&self) -> Timeout {
+        match *self {
+            PeerConnection::V6(ref c) => c.needs_tick(),
+            PeerConnection::V7(ref c) => c.needs_tick(),
+        }
+    }
+    fn connect<CB: connection::Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.connect(cb),
+            PeerConnection::V7(ref mut c) => c.connect(cb),
+        }
+    }
+    fn disconnect<CB: connection::Callback>(
+        &mut self,
+        cb: &mut CB,
+        reason: &[u8],
+    ) -> Result<(), CB::Error> {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.disconnect(cb, reason),
+            PeerConnection::V7(ref mut c) => c.disconnect(cb, reason),
+        }
+    }
+    fn send<CB: connection::Callback>(
+        &mut self,
+        cb: &mut CB,
+        buffer: &[u8],
+        vital: bool,
+    ) -> Result<(), Error<CB::Error>> {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.send(cb, buffer, vital),
+            PeerConnection::V7(ref mut c) => c.send(cb, buffer, vital),
+        }
+    }
+    fn flush<CB: connection::Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.flush(cb),
+            PeerConnection::V7(ref mut c) => c.flush(cb),
+        }
+    }
+    fn stats(&self) -> connection::Stats {
+        match *self {
+            PeerConnection::V6(ref c) => c.stats(),
+            PeerConnection::V7(ref c) => c.stats(),
+        }
+    }
+    fn set_queue_limit(&mut self, limit: Option<QueueLimit>) {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.set_queue_limit(limit),
+            PeerConnection::V7(ref mut c) => c.set_queue_limit(limit),
+        }
+    }
+    fn tick<CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+    ) -> (connection::ReceivePacket<'static>, Result<(), CB::Error>)
+    where
+        CB: connection::Callback,
+        W: Warn<connection::Warning>,
+    {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.tick(cb, warn),
+            PeerConnection::V7(ref mut c) => c.tick(cb, warn),
+        }
+    }
+    fn feed<'a, CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        data: &'a [u8],
+        buf: &mut BufferRef<'a, '_>,
+    ) -> (connection::ReceivePacket<'a>, Result<(), CB::Error>)
+    where
+        CB: connection::Callback,
+        W: Warn<connection::Warning>,
+    {
+        match *self {
+            PeerConnection::V6(ref mut c) => c.feed(cb, warn, data, buf),
+            PeerConnection::V7(ref mut c) => c.feed(cb, warn, data, buf),
+        }
+    }
+}
+
 struct Peer<A: Address> {
-    conn: Connection,
+    conn: PeerConnection,
     addr: A,
-    /// This flag says whether the incoming connection had indicated token
-    /// support.
+    /// DDNet token the peer echoed back during the handshake.
     ///
-    /// DDNet token support is unfortunately kind of hacked in.
-    token: bool,
+    /// Only meaningful for incoming 0.6 connections that haven't been
+    /// accepted yet, the connection itself takes over the token on
+    /// `Net::accept`.
+    token: Option<protocol::Token>,
 }
 
 impl<A: Address> Peer<A> {
-    fn new(addr: A, token: bool) -> Peer<A> {
+    fn new(addr: A, conn: PeerConnection, token: Option<protocol::Token>) -> Peer<A> {
         Peer {
-            conn: Connection::new(),
+            conn: conn,
             addr: addr,
             token: token,
         }
@@ -106,14 +259,19 @@ impl<A: Address> Peers<A> {
             next_peer_id: PeerId(0),
         }
     }
-    fn new_peer(&mut self, addr: A, token: bool) -> (PeerId, &mut Peer<A>) {
+    fn new_peer(
+        &mut self,
+        addr: A,
+        conn: PeerConnection,
+        token: Option<protocol::Token>,
+    ) -> (PeerId, &mut Peer<A>) {
         // FIXME(rust-lang/rfcs#811): Work around missing non-lexical borrows.
         let raw_self: *mut Peers<A> = self;
         unsafe {
             loop {
                 let peer_id = self.next_peer_id.get_and_increment();
                 if let peer_map::Entry::Vacant(v) = (*raw_self).peers.entry(peer_id) {
-                    return (peer_id, v.insert(Peer::new(addr, token)));
+                    return (peer_id, v.insert(Peer::new(addr, conn, token)));
                 }
             }
         }
@@ -162,7 +320,7 @@ impl<A: Address> ops::IndexMut<PeerId> for Peers<A> {
 pub enum ChunkOrEvent<'a, A: Address> {
     Chunk(Chunk<'a>),
     Connless(ConnlessChunk<'a, A>),
-    Connect(PeerId),
+    Connect(PeerId, Protocol),
     Ready(PeerId),
     Disconnect(PeerId, &'a [u8]),
 }
@@ -178,6 +336,9 @@ pub struct Chunk<'a> {
 pub struct ConnlessChunk<'a, A: Address> {
     pub addr: A,
     pub pid: Option<PeerId>,
+    /// The protocol the packet was received with, replies should be sent
+    /// with `Net::send_connless` or `Net::send_connless7` accordingly.
+    pub protocol: Protocol,
     pub data: &'a [u8],
 }
 
@@ -207,6 +368,81 @@ impl ConnlessBuilder {
     }
 }
 
+struct Builder7 {
+    buffer: [u8; protocol7::MAX_PACKETSIZE],
+}
+
+impl Builder7 {
+    fn new() -> Builder7 {
+        Builder7 {
+            buffer: [0; protocol7::MAX_PACKETSIZE],
+        }
+    }
+    fn send<A: Address, CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+        packet: protocol7::ConnectedPacket,
+    ) -> Result<(), CB::Error> {
+        let send_data = match packet.write(&mut self.buffer[..]) {
+            Ok(d) => d,
+            Err(protocol7::Error::Capacity(_)) => unreachable!("too short buffer provided"),
+            Err(protocol7::Error::TooLongData) => unreachable!("control packet too long"),
+        };
+        cb.send(addr, send_data)
+    }
+    fn send_connless<A: Address, CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+        token: Token,
+        response_token: Token,
+        data: &[u8],
+    ) -> Result<(), Error<CB::Error>> {
+        let write_result =
+            protocol7::write_connless_packet(token, response_token, data, &mut self.buffer[..]);
+        let send_data = match write_result {
+            Ok(d) => d,
+            Err(protocol7::Error::Capacity(_)) => unreachable!("too short buffer provided"),
+            Err(protocol7::Error::TooLongData) => return Err(Error::TooLongData),
+        };
+        cb.send(addr, send_data)?;
+        Ok(())
+    }
+}
+
+/// How long learned 0.7 connless tokens and connless packets waiting for a
+/// token are kept, like in the reference implementation.
+const CONNLESS_TOKEN_EXPIRY: Duration = Duration::from_secs(16);
+
+/// State of the 0.7 connless token exchange.
+///
+/// Before sending a connless packet, a token is requested from the peer
+/// with a `Token` control packet. The peer echoes our token and includes
+/// its own, which is then included in the connless packets.
+struct ConnlessTokens<A: Address> {
+    /// Tokens the peers expect from us, with the time they were last seen.
+    tokens: HashMap<A, (Token, Timestamp)>,
+    /// Packets waiting for the token of the peer, with the time of the
+    /// token request.
+    pending: HashMap<A, (Timestamp, Vec<Vec<u8>>)>,
+}
+
+impl<A: Address> ConnlessTokens<A> {
+    fn new() -> ConnlessTokens<A> {
+        ConnlessTokens {
+            tokens: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+    fn expire(&mut self, time: Timestamp) {
+        self.tokens
+            .retain(|_, &mut (_, seen)| time < seen + CONNLESS_TOKEN_EXPIRY);
+        self.pending
+            .retain(|_, &mut (requested, _)| time < requested + CONNLESS_TOKEN_EXPIRY);
+    }
+}
+
 #[derive(Clone)]
 pub struct ReceivePacket<'a, A: Address> {
     type_: ReceivePacketType<'a, A>,
@@ -220,12 +456,15 @@ impl<'a, A: Address> Iterator for ReceivePacket<'a, A> {
         use self::ReceivePacketType::Connless;
         match self.type_ {
             ReceivePacketType::None => None,
-            Connect(ref mut once) => once.next().map(|pid| ChunkOrEvent::Connect(pid)),
-            Connected(addr, pid, ref mut receive_packet) => {
+            Connect(ref mut once) => once
+                .next()
+                .map(|(pid, protocol)| ChunkOrEvent::Connect(pid, protocol)),
+            Connected(addr, pid, protocol, ref mut receive_packet) => {
                 receive_packet.next().map(|chunk| match chunk {
                     ReceiveChunk::Connless(d) => ChunkOrEvent::Connless(ConnlessChunk {
                         addr: addr,
                         pid: Some(pid),
+                        protocol: protocol,
                         data: d,
                     }),
                     ReceiveChunk::Connected(d, vital) => ChunkOrEvent::Chunk(Chunk {
@@ -237,10 +476,11 @@ impl<'a, A: Address> Iterator for ReceivePacket<'a, A> {
                     ReceiveChunk::Disconnect(r) => ChunkOrEvent::Disconnect(pid, r),
                 })
             }
-            Connless(addr, ref mut once) => once.next().map(|data| {
+            Connless(addr, protocol, ref mut once) => once.next().map(|data| {
                 ChunkOrEvent::Connless(ConnlessChunk {
                     addr: addr,
                     pid: None,
+                    protocol: protocol,
                     data: data,
                 })
             }),
@@ -260,9 +500,9 @@ impl<'a, A: Address> ReceivePacket<'a, A> {
             type_: ReceivePacketType::None,
         }
     }
-    fn connect(pid: PeerId) -> ReceivePacket<'a, A> {
+    fn connect(pid: PeerId, protocol: Protocol) -> ReceivePacket<'a, A> {
         ReceivePacket {
-            type_: ReceivePacketType::Connect(iter::once(pid)),
+            type_: ReceivePacketType::Connect(iter::once((pid, protocol))),
         }
     }
     fn connected(
@@ -271,19 +511,29 @@ impl<'a, A: Address> ReceivePacket<'a, A> {
         receive_packet: connection::ReceivePacket<'a>,
         net: &mut Net<A>,
     ) -> ReceivePacket<'a, A> {
+        let protocol = net.peers[pid].conn.protocol();
         for chunk in receive_packet.clone() {
             if let ReceiveChunk::Disconnect(..) = chunk {
                 net.peers.remove_peer(pid);
             }
         }
         ReceivePacket {
-            type_: ReceivePacketType::Connected(addr, pid, receive_packet),
+            type_: ReceivePacketType::Connected(addr, pid, protocol, receive_packet),
         }
     }
 
-    fn connless(addr: A, data: &'a [u8]) -> ReceivePacket<'a, A> {
+    fn connless(addr: A, protocol: Protocol, data: &'a [u8]) -> ReceivePacket<'a, A> {
         ReceivePacket {
-            type_: ReceivePacketType::Connless(addr, iter::once(data)),
+            type_: ReceivePacketType::Connless(addr, protocol, iter::once(data)),
+        }
+    }
+    fn is_connless(&self) -> bool {
+        match self.type_ {
+            ReceivePacketType::Connless(..) => true,
+            ReceivePacketType::Connected(_, _, _, ref packet) => packet
+                .clone()
+                .any(|c| matches!(c, ReceiveChunk::Connless(..))),
+            _ => false,
         }
     }
 }
@@ -291,15 +541,27 @@ impl<'a, A: Address> ReceivePacket<'a, A> {
 #[derive(Clone)]
 enum ReceivePacketType<'a, A: Address> {
     None,
-    Connect(iter::Once<PeerId>),
-    Connected(A, PeerId, connection::ReceivePacket<'a>),
-    Connless(A, iter::Once<&'a [u8]>),
+    Connect(iter::Once<(PeerId, Protocol)>),
+    Connected(A, PeerId, Protocol, connection::ReceivePacket<'a>),
+    Connless(A, Protocol, iter::Once<&'a [u8]>),
 }
 
 pub struct Net<A: Address> {
     peers: Peers<A>,
     builder: ConnlessBuilder,
+    builder7: Builder7,
     accept_connections: bool,
+    /// If set, only connections using this protocol are accepted.
+    only: Option<Protocol>,
+    /// Secret key used to derive the tokens handed out to connecting
+    /// clients.
+    secret: Option<(u64, u64)>,
+    /// Tokens handed out instead of the derived ones, see
+    /// [`Net::set_server_tokens`].
+    server_tokens: HashMap<A, [u8; 4]>,
+    connless7: ConnlessTokens<A>,
+    queue_limit: Option<QueueLimit>,
+    admission: Option<Box<dyn AnyAdmission<A> + Send>>,
 }
 
 struct ConnectionCallback<'a, A: Address, CB: Callback<A> + 'a> {
@@ -327,6 +589,15 @@ impl<'a, A: Address, W: Warn<Warning<A>>> Warn<protocol::Warning> for WarnCallba
     }
 }
 
+impl<'a, A: Address, W: Warn<Warning<A>>> Warn<protocol7::Warning> for WarnCallback<'a, A, W> {
+    fn warn(&mut self, warning: protocol7::Warning) {
+        self.warn.warn(Warning::Connless(
+            self.addr,
+            connection::Warning::Packet7(warning),
+        ))
+    }
+}
+
 struct WarnCallback<'a, A: Address, W: Warn<Warning<A>> + 'a> {
     warn: &'a mut W,
     addr: A,
@@ -377,18 +648,128 @@ impl<'a, A: Address, CB: Callback<A>> connection::Callback for ConnectionCallbac
 }
 
 impl<A: Address> Net<A> {
-    fn new(accept_connections: bool) -> Net<A> {
+    fn new(accept_connections: bool, only: Option<Protocol>) -> Net<A> {
         Net {
             peers: Peers::new(),
             builder: ConnlessBuilder::new(),
+            builder7: Builder7::new(),
             accept_connections: accept_connections,
+            only: only,
+            secret: None,
+            server_tokens: HashMap::new(),
+            connless7: ConnlessTokens::new(),
+            queue_limit: None,
+            admission: None,
         }
     }
+    /// Creates a server accepting both 0.6/DDNet and 0.7 connections.
     pub fn server() -> Net<A> {
-        Net::new(true)
+        Net::new(true, None)
     }
     pub fn client() -> Net<A> {
-        Net::new(false)
+        Net::new(false, None)
+    }
+    pub(crate) fn server7() -> Net<A> {
+        Net::new(true, Some(Protocol::V7))
+    }
+    pub(crate) fn client7() -> Net<A> {
+        Net::new(false, Some(Protocol::V7))
+    }
+    /// Sets the limit on the amount of unacknowledged vital data for all
+    /// current and future connections.
+    pub fn set_queue_limit(&mut self, limit: Option<QueueLimit>) {
+        self.queue_limit = limit;
+        for (_, peer) in self.peers.iter_mut() {
+            peer.conn.set_queue_limit(limit);
+        }
+    }
+    /// Makes the server hand out the given tokens to clients at the
+    /// respective addresses instead of the ones derived from its secret,
+    /// replacing the previously set tokens.
+    ///
+    /// This is meant for replaying captures, in which the clients echo the
+    /// tokens of the recorded server.
+    pub fn set_server_tokens(&mut self, tokens: HashMap<A, [u8; 4]>) {
+        self.server_tokens = tokens;
+    }
+    /// Installs an admission policy deciding which received packets and
+    /// connection attempts are processed, replacing the previous one.
+    pub fn set_admission<P: Admission<A> + Send + 'static>(&mut self, policy: P) {
+        self.admission = Some(Box::new(policy));
+    }
+    pub fn remove_admission(&mut self) {
+        self.admission = None;
+    }
+    /// Returns the installed admission policy if it is of type `P`.
+    pub fn admission_mut<P: Admission<A> + 'static>(&mut self) -> Option<&mut P> {
+        self.admission
+            .as_mut()
+            .and_then(|a| a.as_any_mut().downcast_mut())
+    }
+    fn admit_connect<W: Warn<Warning<A>>>(
+        &mut self,
+        time: Timestamp,
+        warn: &mut W,
+        addr: A,
+    ) -> bool {
+        if let Some(ref mut admission) = self.admission {
+            let mut peers = self.peers.iter().map(|(_, p)| p.addr);
+            if let Err(dropped) = admission.allow_connect(time, addr, &mut peers) {
+                warn.warn(Warning::Dropped(addr, dropped));
+                return false;
+            }
+        }
+        true
+    }
+    fn new_peer(
+        &mut self,
+        addr: A,
+        mut conn: PeerConnection,
+        token: Option<protocol::Token>,
+    ) -> (PeerId, &mut Peer<A>) {
+        conn.set_queue_limit(self.queue_limit);
+        self.peers.new_peer(addr, conn, token)
+    }
+    fn accepts(&self, protocol: Protocol) -> bool {
+        self.only.map(|p| p == protocol).unwrap_or(true)
+    }
+    /// Derives the token a client at `addr` has to echo back in order to
+    /// connect.
+    ///
+    /// This way, no state has to be kept for connection attempts until the
+    /// client has proven that it can receive packets at `addr`. Also used as
+    /// our token in the 0.7 connless token exchange.
+    fn server_token<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A) -> [u8; 4] {
+        if let Some(&token) = self.server_tokens.get(&addr) {
+            return token;
+        }
+        let (k0, k1) = *self.secret.get_or_insert_with(|| {
+            let mut secret = [0; 16];
+            cb.secure_random(&mut secret);
+            let (k0, k1) = secret.split_at(8);
+            (
+                u64::from_le_bytes(k0.try_into().unwrap()),
+                u64::from_le_bytes(k1.try_into().unwrap()),
+            )
+        });
+        // SipHash-2-4 keyed with the secret, so that the tokens can't be
+        // predicted without knowing it.
+        #[allow(deprecated)]
+        let mut hasher = SipHasher::new_with_keys(k0, k1);
+        addr.hash(&mut hasher);
+        let token = (hasher.finish() as u32).to_be_bytes();
+        // Avoid the values that are reserved in either protocol.
+        if token == protocol::TOKEN_NONE.0 || token == protocol::TOKEN_RESERVED.0 {
+            [0, 0, 0, 1]
+        } else {
+            token
+        }
+    }
+    fn server_token6<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A) -> protocol::Token {
+        protocol::Token(self.server_token(cb, addr))
+    }
+    fn server_token7<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A) -> Token {
+        Token(self.server_token(cb, addr))
     }
     pub fn needs_tick(&self) -> Timeout {
         self.peers
@@ -404,12 +785,41 @@ impl<A: Address> Net<A> {
             true
         }
     }
+    /// Returns the wire protocol used for the connection to `pid`.
+    pub fn protocol(&self, pid: PeerId) -> Protocol {
+        self.peers[pid].conn.protocol()
+    }
+    /// Returns statistics about the connection to `pid`.
+    pub fn peer_stats<CB: Callback<A>>(&self, cb: &mut CB, pid: PeerId) -> PeerStats {
+        let connection = self.peers[pid].conn.stats();
+        PeerStats {
+            protocol: self.protocol(pid),
+            connection: connection,
+            since_last_received: connection
+                .last_received
+                .map(|t| cb.time().duration_since(t)),
+        }
+    }
+    /// Connects to a Teeworlds 0.6 or DDNet server.
     pub fn connect<CB: Callback<A>>(
         &mut self,
         cb: &mut CB,
         addr: A,
     ) -> (PeerId, Result<(), CB::Error>) {
-        let (pid, peer) = self.peers.new_peer(addr, false);
+        assert!(self.accepts(Protocol::V6));
+        let conn = PeerConnection::V6(Connection::new());
+        let (pid, peer) = self.new_peer(addr, conn, None);
+        (pid, peer.conn.connect(&mut cc(cb, peer.addr)))
+    }
+    /// Connects to a Teeworlds 0.7 server.
+    pub fn connect7<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+    ) -> (PeerId, Result<(), CB::Error>) {
+        assert!(self.accepts(Protocol::V7));
+        let conn = PeerConnection::V7(Connection7::new());
+        let (pid, peer) = self.new_peer(addr, conn, None);
         (pid, peer.conn.connect(&mut cc(cb, peer.addr)))
     }
     pub fn disconnect<CB: Callback<A>>(
@@ -421,7 +831,7 @@ impl<A: Address> Net<A> {
         let result;
         {
             let peer = &mut self.peers[pid];
-            assert!(!peer.conn.is_unconnected());
+            assert!(!peer.conn.is_pending());
             result = peer.conn.disconnect(&mut cc(cb, peer.addr), reason);
         }
         self.peers.remove_peer(pid);
@@ -435,6 +845,97 @@ impl<A: Address> Net<A> {
     ) -> Result<(), Error<CB::Error>> {
         self.builder.send(cb, addr, Packet::Connless(data))
     }
+    /// Sends a Teeworlds 0.7 connectionless packet.
+    ///
+    /// 0.7 connectionless packets carry the token of the receiver. If it
+    /// isn't known yet, it is requested first and `data` is sent once it
+    /// arrives.
+    pub fn send_connless7<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+        data: &[u8],
+    ) -> Result<(), Error<CB::Error>> {
+        use crate::protocol7::ConnectedPacket;
+        use crate::protocol7::ConnectedPacketType;
+        use crate::protocol7::ControlPacket;
+
+        if data.len() > protocol7::MAX_PACKETSIZE - protocol7::HEADER_SIZE_CONNLESS {
+            return Err(Error::TooLongData);
+        }
+        if let Some(pid) = self.peers.pid_from_addr(addr) {
+            if let PeerConnection::V7(ref mut conn) = self.peers[pid].conn {
+                if conn.is_online() {
+                    return conn.send_connless(&mut cc(cb, addr), data);
+                }
+            }
+        }
+        let time = cb.time();
+        let own_token = self.server_token7(cb, addr);
+        if let Some(&(token, _)) = self.connless7.tokens.get(&addr) {
+            return self
+                .builder7
+                .send_connless(cb, addr, token, own_token, data);
+        }
+        match self.connless7.pending.entry(addr) {
+            hash_map::Entry::Occupied(mut o) => {
+                o.get_mut().1.push(data.to_owned());
+                Ok(())
+            }
+            hash_map::Entry::Vacant(v) => {
+                v.insert((time, vec![data.to_owned()]));
+                let request = ConnectedPacket {
+                    token: TOKEN_NONE,
+                    ack: 0,
+                    type_: ConnectedPacketType::Control(ControlPacket::Token(own_token)),
+                };
+                Ok(self.builder7.send(cb, addr, request)?)
+            }
+        }
+    }
+    /// Checks the token of a received 0.7 connless packet and remembers the
+    /// token of the sender for replies.
+    fn check_connless_token7<CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        addr: A,
+        data: &[u8],
+    ) -> bool
+    where
+        CB: Callback<A>,
+        W: Warn<Warning<A>>,
+    {
+        let header = protocol7::Packet::connless_header(data).unwrap();
+        if header.token != self.server_token7(cb, addr) {
+            w(warn, addr).warn(connection::Warning::TokenMismatch);
+            return false;
+        }
+        let time = cb.time();
+        self.connless7
+            .tokens
+            .insert(addr, (header.response_token, time));
+        true
+    }
+    /// Sends the connless packets that were waiting for the token of the
+    /// peer at `addr`.
+    fn receive_connless_token7<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+        token: Token,
+    ) -> Result<(), CB::Error> {
+        let (_, packets) = self.connless7.pending.remove(&addr).unwrap();
+        let time = cb.time();
+        let own_token = self.server_token7(cb, addr);
+        self.connless7.tokens.insert(addr, (token, time));
+        for data in packets {
+            self.builder7
+                .send_connless(cb, addr, token, own_token, &data)
+                .map_err(|e| e.unwrap_callback())?;
+        }
+        Ok(())
+    }
     pub fn send<CB: Callback<A>>(
         &mut self,
         cb: &mut CB,
@@ -453,18 +954,28 @@ impl<A: Address> Net<A> {
     }
     pub fn accept<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId) -> Result<(), CB::Error> {
         let peer = &mut self.peers[pid];
-        assert!(peer.conn.is_unconnected());
-        let mut buf: ArrayVec<[u8; 2048]> = ArrayVec::new();
-        let connect_packet: &[u8] = if peer.token {
-            CONNECT_PACKET
-        } else {
-            CONNECT_PACKET_NO_TOKEN
-        };
-        let (mut none, res) =
-            peer.conn
-                .feed(&mut cc(cb, peer.addr), &mut Panic, connect_packet, &mut buf);
-        assert!(none.next().is_none());
-        res
+        assert!(peer.conn.is_pending());
+        match peer.conn {
+            PeerConnection::V6(ref mut conn) => {
+                if let Some(token) = peer.token {
+                    // The client already considers itself connected, it
+                    // echoed our token in its `Accept`.
+                    *conn = Connection::new_accept_token(&mut cc(cb, peer.addr), token);
+                    conn.set_queue_limit(self.queue_limit);
+                    return Ok(());
+                }
+                let mut buf: ArrayVec<[u8; 2048]> = ArrayVec::new();
+                let (mut none, res) = conn.feed(
+                    &mut cc(cb, peer.addr),
+                    &mut Panic,
+                    CONNECT_PACKET_NO_TOKEN,
+                    &mut buf,
+                );
+                assert!(none.next().is_none());
+                res
+            }
+            PeerConnection::V7(ref mut conn) => conn.accept(&mut cc(cb, peer.addr)),
+        }
     }
     pub fn reject<CB: Callback<A>>(
         &mut self,
@@ -475,16 +986,56 @@ impl<A: Address> Net<A> {
         let result;
         {
             let peer = &mut self.peers[pid];
-            assert!(peer.conn.is_unconnected());
-            result = peer.conn.disconnect(&mut cc(cb, peer.addr), reason);
+            assert!(peer.conn.is_pending());
+            result = match peer.conn {
+                // The connection hasn't been set up yet, send the `Close`
+                // ourselves.
+                PeerConnection::V6(_) => {
+                    let close = Packet::Connected(ConnectedPacket {
+                        token: peer.token,
+                        ack: 0,
+                        type_: ConnectedPacketType::Control(ControlPacket::Close(reason)),
+                    });
+                    self.builder
+                        .send(cb, peer.addr, close)
+                        .map_err(|e| e.unwrap_callback())
+                }
+                PeerConnection::V7(ref mut conn) => conn.disconnect(&mut cc(cb, peer.addr), reason),
+            };
         }
         self.peers.remove_peer(pid);
         result
     }
-    pub fn tick<'a, CB: Callback<A>>(&'a mut self, cb: &'a mut CB) -> Tick<A, CB> {
+    /// Resends data and sends keepalives to all peers.
+    ///
+    /// Peers exceeding their queue limit are disconnected, this is reported
+    /// as `ChunkOrEvent::Disconnect`.
+    pub fn tick<CB, W>(&mut self, cb: &mut CB, warn: &mut W) -> Tick<A, CB::Error>
+    where
+        CB: Callback<A>,
+        W: Warn<Warning<A>>,
+    {
+        let time = cb.time();
+        self.connless7.expire(time);
+        let mut events = Vec::new();
+        let mut disconnected = Vec::new();
+        for (pid, p) in self.peers.iter_mut() {
+            let (packet, result) = p.conn.tick(&mut cc(cb, p.addr), &mut wp(warn, p.addr, pid));
+            for chunk in packet {
+                if let ReceiveChunk::Disconnect(reason) = chunk {
+                    events.push(Ok(ChunkOrEvent::Disconnect(pid, reason)));
+                    disconnected.push(pid);
+                }
+            }
+            if let Err(e) = result {
+                events.push(Err(e));
+            }
+        }
+        for pid in disconnected {
+            self.peers.remove_peer(pid);
+        }
         Tick {
-            iter_mut: self.peers.iter_mut(),
-            cb: cb,
+            events: events.into_iter(),
         }
     }
     pub fn feed<'a, CB, B, W>(
@@ -503,6 +1054,35 @@ impl<A: Address> Net<A> {
         with_buffer(buf, |b| self.feed_impl(cb, warn, addr, data, b))
     }
     fn feed_impl<'d, 's, CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        addr: A,
+        data: &'d [u8],
+        buf: BufferRef<'d, 's>,
+    ) -> (ReceivePacket<'d, A>, Result<(), CB::Error>)
+    where
+        CB: Callback<A>,
+        W: Warn<Warning<A>>,
+    {
+        if let Some(ref mut admission) = self.admission {
+            if let Err(dropped) = admission.allow_packet(cb.time(), addr) {
+                warn.warn(Warning::Dropped(addr, dropped));
+                return (ReceivePacket::none(), Ok(()));
+            }
+        }
+        let (packet, result) = self.feed_admitted(cb, warn, addr, data, buf);
+        if let Some(ref mut admission) = self.admission {
+            if packet.is_connless() {
+                if let Err(dropped) = admission.allow_connless(cb.time(), addr) {
+                    warn.warn(Warning::Dropped(addr, dropped));
+                    return (ReceivePacket::none(), result);
+                }
+            }
+        }
+        (packet, result)
+    }
+    fn feed_admitted<'d, 's, CB, W>(
         &mut self,
         cb: &mut CB,
         warn: &mut W,
@@ -522,31 +1102,164 @@ impl<A: Address> Net<A> {
                 &mut buf,
             );
             (ReceivePacket::connected(addr, pid, packet, self), e)
+        } else if !self.accepts(Protocol::V6)
+            || (self.accepts(Protocol::V7)
+                && (protocol7::Packet::is_connless(data) || !Packet::is_initial(data)))
+        {
+            self.feed_unknown7(cb, warn, addr, data, buf)
         } else {
-            let packet = match Packet::read(&mut w(warn, addr), data, None, &mut buf) {
-                Ok(p) => p,
-                Err(e) => {
-                    w(warn, addr).warn(connection::Warning::Read(e));
+            self.feed_unknown6(cb, warn, addr, data, buf)
+        }
+    }
+    fn feed_unknown6<'d, 's, CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        addr: A,
+        data: &'d [u8],
+        mut buf: BufferRef<'d, 's>,
+    ) -> (ReceivePacket<'d, A>, Result<(), CB::Error>)
+    where
+        CB: Callback<A>,
+        W: Warn<Warning<A>>,
+    {
+        let packet = match Packet::read(&mut w(warn, addr), data, None, &mut buf) {
+            Ok(p) => p,
+            Err(e) => {
+                w(warn, addr).warn(connection::Warning::Read(e));
+                return (ReceivePacket::none(), Ok(()));
+            }
+        };
+        let ConnectedPacket { token, type_, .. } = match packet {
+            Packet::Connless(d) => return (ReceivePacket::connless(addr, Protocol::V6, d), Ok(())),
+            Packet::Connected(c) => c,
+        };
+        if !self.accept_connections {
+            w(warn, addr).warn(connection::Warning::Unexpected);
+            return (ReceivePacket::none(), Ok(()));
+        }
+        match (type_, token) {
+            // DDNet client supporting tokens. Reply statelessly with the token
+            // it has to echo back.
+            (ConnectedPacketType::Control(ControlPacket::Connect), Some(protocol::TOKEN_NONE)) => {
+                let server_token = self.server_token6(cb, addr);
+                let response = Packet::Connected(ConnectedPacket {
+                    token: Some(server_token),
+                    ack: 0,
+                    type_: ConnectedPacketType::Control(ControlPacket::ConnectAccept),
+                });
+                let result = self
+                    .builder
+                    .send(cb, addr, response)
+                    .map_err(|e| e.unwrap_callback());
+                (ReceivePacket::none(), result)
+            }
+            // Legacy client without token support.
+            //
+            // TODO: This is vulnerable to IP spoofing.
+            (ConnectedPacketType::Control(ControlPacket::Connect), None) => {
+                if !self.admit_connect(cb.time(), warn, addr) {
                     return (ReceivePacket::none(), Ok(()));
                 }
-            };
-            if let Packet::Connless(d) = packet {
-                (ReceivePacket::connless(addr, d), Ok(()))
-            } else if let Packet::Connected(ConnectedPacket {
-                token,
-                type_: ConnectedPacketType::Control(ControlPacket::Connect),
-                ..
-            }) = packet
+                let conn = PeerConnection::V6(Connection::new());
+                let (pid, _) = self.new_peer(addr, conn, None);
+                (ReceivePacket::connect(pid, Protocol::V6), Ok(()))
+            }
+            (ConnectedPacketType::Control(ControlPacket::Accept), Some(token)) => {
+                if token != self.server_token6(cb, addr) {
+                    w(warn, addr).warn(connection::Warning::TokenMismatch);
+                    return (ReceivePacket::none(), Ok(()));
+                }
+                if !self.admit_connect(cb.time(), warn, addr) {
+                    return (ReceivePacket::none(), Ok(()));
+                }
+                let conn = PeerConnection::V6(Connection::new());
+                let (pid, _) = self.new_peer(addr, conn, Some(token));
+                (ReceivePacket::connect(pid, Protocol::V6), Ok(()))
+            }
+            _ => {
+                w(warn, addr).warn(connection::Warning::Unexpected);
+                (ReceivePacket::none(), Ok(()))
+            }
+        }
+    }
+    fn feed_unknown7<'d, 's, CB, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        addr: A,
+        data: &'d [u8],
+        mut buf: BufferRef<'d, 's>,
+    ) -> (ReceivePacket<'d, A>, Result<(), CB::Error>)
+    where
+        CB: Callback<A>,
+        W: Warn<Warning<A>>,
+    {
+        use crate::protocol7::ConnectedPacket;
+        use crate::protocol7::ConnectedPacketType;
+        use crate::protocol7::ControlPacket;
+        use crate::protocol7::Packet;
+
+        let packet = match Packet::read(&mut w(warn, addr), data, &mut buf) {
+            Ok(p) => p,
+            Err(e) => {
+                w(warn, addr).warn(connection::Warning::Read7(e));
+                return (ReceivePacket::none(), Ok(()));
+            }
+        };
+        let ConnectedPacket { token, type_, .. } = match packet {
+            Packet::Connless(d) => {
+                if !self.check_connless_token7(cb, warn, addr, data) {
+                    return (ReceivePacket::none(), Ok(()));
+                }
+                return (ReceivePacket::connless(addr, Protocol::V7, d), Ok(()));
+            }
+            Packet::Connected(c) => c,
+        };
+        // Answer to our token request for connless packets.
+        if let ConnectedPacketType::Control(ControlPacket::Token(peer_token)) = type_ {
+            if token != TOKEN_NONE && self.connless7.pending.contains_key(&addr) {
+                if token != self.server_token7(cb, addr) {
+                    w(warn, addr).warn(connection::Warning::TokenMismatch);
+                    return (ReceivePacket::none(), Ok(()));
+                }
+                let result = self.receive_connless_token7(cb, addr, peer_token);
+                return (ReceivePacket::none(), result);
+            }
+        }
+        if !self.accept_connections {
+            w(warn, addr).warn(connection::Warning::Unexpected);
+            return (ReceivePacket::none(), Ok(()));
+        }
+        match type_ {
+            ConnectedPacketType::Control(ControlPacket::Token(peer_token))
+                if token == TOKEN_NONE =>
             {
-                if self.accept_connections {
-                    // TODO: This is vulnerable to IP spoofing.
-                    let (pid, _) = self.peers.new_peer(addr, token.is_some());
-                    (ReceivePacket::connect(pid), Ok(()))
-                } else {
-                    w(warn, addr).warn(connection::Warning::Unexpected);
-                    (ReceivePacket::none(), Ok(()))
+                let server_token = self.server_token7(cb, addr);
+                let response = ConnectedPacket {
+                    token: peer_token,
+                    ack: 0,
+                    type_: ConnectedPacketType::Control(ControlPacket::Token(server_token)),
+                };
+                (
+                    ReceivePacket::none(),
+                    self.builder7.send(cb, addr, response),
+                )
+            }
+            ConnectedPacketType::Control(ControlPacket::Connect(peer_token)) => {
+                let server_token = self.server_token7(cb, addr);
+                if token != server_token {
+                    w(warn, addr).warn(connection::Warning::TokenMismatch);
+                    return (ReceivePacket::none(), Ok(()));
                 }
-            } else {
+                if !self.admit_connect(cb.time(), warn, addr) {
+                    return (ReceivePacket::none(), Ok(()));
+                }
+                let conn = PeerConnection::V7(Connection7::new_pending(server_token, peer_token));
+                let (pid, _) = self.new_peer(addr, conn, None);
+                (ReceivePacket::connect(pid, Protocol::V7), Ok(()))
+            }
+            _ => {
                 w(warn, addr).warn(connection::Warning::Unexpected);
                 (ReceivePacket::none(), Ok(()))
             }
@@ -554,33 +1267,38 @@ impl<A: Address> Net<A> {
     }
 }
 
-pub struct Tick<'a, A: Address + 'a, CB: Callback<A> + 'a> {
-    iter_mut: peer_map::IterMut<'a, Peer<A>>,
-    cb: &'a mut CB,
+pub struct Tick<A: Address, E> {
+    events: vec::IntoIter<Result<ChunkOrEvent<'static, A>, E>>,
 }
 
-impl<'a, A: Address + 'a, CB: Callback<A> + 'a> Iterator for Tick<'a, A, CB> {
-    type Item = CB::Error;
-    fn next(&mut self) -> Option<CB::Error> {
-        while let Some((_, p)) = self.iter_mut.next() {
-            match p.conn.tick(&mut cc(self.cb, p.addr)) {
-                Ok(()) => {}
-                Err(e) => return Some(e),
-            }
-        }
-        None
+impl<A: Address, E> Iterator for Tick<A, E> {
+    type Item = Result<ChunkOrEvent<'static, A>, E>;
+    fn next(&mut self) -> Option<Result<ChunkOrEvent<'static, A>, E>> {
+        self.events.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.events.size_hint()
     }
 }
 
+impl<A: Address, E> ExactSizeIterator for Tick<A, E> {}
+
 #[cfg(test)]
 mod test {
     use super::Callback;
+    use super::Chunk;
     use super::ChunkOrEvent;
     use super::Net;
+    use super::PeerId;
+    use super::Protocol;
+    use super::Warning;
+    use crate::connection;
     use crate::protocol;
+    use crate::protocol7;
     use crate::Timestamp;
     use itertools::Itertools;
     use std::collections::VecDeque;
+    use std::time::Duration;
     use void::ResultVoidExt;
     use void::Void;
     use warn::Panic;
@@ -607,13 +1325,9 @@ mod test {
         impl Callback<Address> for Cb {
             type Error = Void;
             fn secure_random(&mut self, buffer: &mut [u8]) {
-                if buffer.len() != 4 {
-                    unimplemented!();
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = 0x12 + i as u8;
                 }
-                buffer[0] = 0x12;
-                buffer[1] = 0x34;
-                buffer[2] = 0x56;
-                buffer[3] = 0x78;
             }
             fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
                 assert!(self.recipient == addr);
@@ -636,8 +1350,32 @@ mod test {
         res.void_unwrap();
         let packet = cb.packets.pop_front().unwrap();
         assert!(cb.packets.is_empty());
+        assert!(&packet == b"\x10\x00\x00\x01TKEN\xff\xff\xff\xff");
+
+        // ConnectAccept, no peer is created yet.
+        cb.recipient = Address::Client;
+        assert!(net
+            .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+        assert!(packet.starts_with(b"\x10\x00\x00\x02TKEN"));
+        let token = packet[8..12].to_owned();
+
+        // Accept
+        cb.recipient = Address::Server;
+        assert!(
+            net.feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ChunkOrEvent::Ready(c_pid)]
+        );
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+        assert!(packet[..4] == b"\x10\x00\x00\x03"[..] && packet[4..] == token[..]);
 
-        // ConnectAccept
         cb.recipient = Address::Client;
         let s_pid;
         {
@@ -646,51 +1384,560 @@ mod test {
                 .0
                 .collect_vec();
             assert!(p.len() == 1);
-            if let ChunkOrEvent::Connect(s) = p[0] {
+            if let ChunkOrEvent::Connect(s, Protocol::V6) = p[0] {
                 s_pid = s;
             } else {
                 panic!();
             }
         }
-        // No packets sent out until we accept the client.
+        // The client is already online, accepting doesn't send anything.
+        net.accept(cb, s_pid).void_unwrap();
         assert!(cb.packets.is_empty());
 
-        net.accept(cb, s_pid).void_unwrap();
+        // Disconnect
+        cb.recipient = Address::Server;
+        net.disconnect(cb, c_pid, b"foobar").void_unwrap();
         let packet = cb.packets.pop_front().unwrap();
         assert!(cb.packets.is_empty());
 
-        // Accept
-        cb.recipient = Address::Server;
+        cb.recipient = Address::Client;
         assert!(
-            net.feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..])
+            net.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
                 .0
                 .collect_vec()
-                == &[ChunkOrEvent::Ready(c_pid)]
+                == &[ChunkOrEvent::Disconnect(s_pid, b"foobar")]
         );
-        let packet = cb.packets.pop_front().unwrap();
         assert!(cb.packets.is_empty());
+    }
 
-        cb.recipient = Address::Client;
-        assert!(net
-            .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+    #[test]
+    fn token_mismatch() {
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        struct Address;
+        struct Cb(VecDeque<Vec<u8>>);
+        impl Callback<Address> for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = 0x12 + i as u8;
+                }
+            }
+            fn send(&mut self, _: Address, data: &[u8]) -> Result<(), Void> {
+                self.0.push_back(data.to_owned());
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let cb = &mut Cb(VecDeque::new());
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+        let mut warnings = vec![];
+
+        let mut server = Net::server();
+
+        // A spoofed `Accept` without a preceding `ConnectAccept` doesn't
+        // create a peer.
+        let accept = b"\x10\x00\x00\x03\x12\x34\x56\x78";
+        assert!(server
+            .feed(cb, &mut warnings, Address, accept, &mut buffer[..])
             .0
             .next()
             .is_none());
-        assert!(cb.packets.is_empty());
+        assert!(cb.0.is_empty());
+        assert!(matches!(
+            warnings[..],
+            [Warning::Connless(
+                Address,
+                connection::Warning::TokenMismatch
+            )]
+        ));
+        assert!(server.peers.iter().next().is_none());
 
-        // Disconnect
-        cb.recipient = Address::Server;
-        net.disconnect(cb, c_pid, b"foobar").void_unwrap();
-        let packet = cb.packets.pop_front().unwrap();
-        assert!(cb.packets.is_empty());
+        // Neither are 0.7 connless packets without our token, to prevent
+        // reflection attacks.
+        warnings.clear();
+        let mut connless = [0; protocol7::MAX_PACKETSIZE];
+        let connless = protocol7::write_connless_packet(
+            protocol7::TOKEN_NONE,
+            protocol7::TOKEN_NONE,
+            b"info",
+            &mut connless[..],
+        )
+        .unwrap();
+        assert!(server
+            .feed(cb, &mut warnings, Address, connless, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        assert!(cb.0.is_empty());
+        assert!(matches!(
+            warnings[..],
+            [Warning::Connless(
+                Address,
+                connection::Warning::TokenMismatch
+            )]
+        ));
+    }
 
-        cb.recipient = Address::Client;
+    #[test]
+    fn admission() {
+        use crate::admission::Dropped;
+        use crate::admission::Limiter;
+        use crate::admission::Limits;
+        use crate::admission::Rate;
+        use crate::sim::SimNetwork;
+        use std::net::SocketAddr;
+        use warn::Ignore;
+
+        /// Delivers all packets in flight, returns the number of events and
+        /// the drop reasons reported by the server.
+        fn deliver(
+            sim: &mut SimNetwork<SocketAddr>,
+            server: (SocketAddr, &mut Net<SocketAddr>),
+            clients: &mut [(SocketAddr, Net<SocketAddr>)],
+        ) -> (usize, Vec<Dropped>) {
+            let mut buffer = [0; protocol::MAX_PACKETSIZE];
+            let mut events = 0;
+            let mut warnings = Vec::new();
+            while let Some(packet) = sim.receive() {
+                if packet.to == server.0 {
+                    let (received, res) = server.1.feed(
+                        &mut sim.endpoint(server.0),
+                        &mut warnings,
+                        packet.from,
+                        &packet.data,
+                        &mut buffer[..],
+                    );
+                    events += received.count();
+                    res.void_unwrap();
+                } else {
+                    let (addr, net) = clients.iter_mut().find(|(a, _)| *a == packet.to).unwrap();
+                    let (received, res) = net.feed(
+                        &mut sim.endpoint(*addr),
+                        &mut Ignore,
+                        packet.from,
+                        &packet.data,
+                        &mut buffer[..],
+                    );
+                    received.for_each(drop);
+                    res.void_unwrap();
+                }
+            }
+            let warnings = warnings
+                .into_iter()
+                .map(|w| match w {
+                    Warning::Dropped(_, d) => d,
+                    w => panic!("unexpected warning {:?}", w),
+                })
+                .collect();
+            (events, warnings)
+        }
+
+        let mut sim = SimNetwork::new(0);
+        let s: SocketAddr = "203.0.113.1:8303".parse().unwrap();
+        let a1: SocketAddr = "192.0.2.1:1".parse().unwrap();
+        let a2: SocketAddr = "192.0.2.1:2".parse().unwrap();
+        let b: SocketAddr = "198.51.100.1:1".parse().unwrap();
+
+        let mut server = Net::server();
+        server.set_admission(Limiter::new(Limits {
+            connections_per_ip: Some(1),
+            connless: Some(Rate {
+                per_second: 1,
+                burst: 1,
+            }),
+            ..Limits::default()
+        }));
+        let mut clients = [(a1, Net::client()), (a2, Net::client()), (b, Net::client())];
+
+        let mut connect = |sim: &mut SimNetwork<SocketAddr>, i: usize| {
+            let (addr, ref mut client) = clients[i];
+            client.connect(&mut sim.endpoint(addr), s).1.void_unwrap();
+            deliver(sim, (s, &mut server), &mut clients)
+        };
+        assert_eq!(connect(&mut sim, 0), (1, vec![]));
+        assert_eq!(connect(&mut sim, 1), (0, vec![Dropped::TooManyConnections]));
+        assert_eq!(connect(&mut sim, 2), (1, vec![]));
+
+        let mut info = |sim: &mut SimNetwork<SocketAddr>, server: &mut Net<SocketAddr>| {
+            let (addr, ref mut client) = clients[2];
+            client
+                .send_connless(&mut sim.endpoint(addr), s, b"info")
+                .unwrap();
+            deliver(sim, (s, server), &mut clients)
+        };
+        assert_eq!(info(&mut sim, &mut server), (1, vec![]));
+        assert_eq!(
+            info(&mut sim, &mut server),
+            (0, vec![Dropped::ConnlessRateLimited])
+        );
+
+        server.admission_mut::<Limiter>().unwrap().ban(b.ip(), None);
+        assert_eq!(info(&mut sim, &mut server), (0, vec![Dropped::Banned]));
+    }
+
+    #[test]
+    fn legacy_client_reject() {
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        struct Address;
+        struct Cb(VecDeque<Vec<u8>>);
+        impl Callback<Address> for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                let _ = buffer;
+                unimplemented!();
+            }
+            fn send(&mut self, _: Address, data: &[u8]) -> Result<(), Void> {
+                self.0.push_back(data.to_owned());
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let cb = &mut Cb(VecDeque::new());
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+
+        let mut server = Net::server();
+
+        // Clients without token support get a peer right away.
+        let connect = b"\x10\x00\x00\x01";
+        let p = server
+            .feed(cb, &mut Panic, Address, connect, &mut buffer[..])
+            .0
+            .collect_vec();
+        let pid = match p[..] {
+            [ChunkOrEvent::Connect(pid, Protocol::V6)] => pid,
+            _ => panic!(),
+        };
+        assert!(cb.0.is_empty());
+
+        server.reject(cb, pid, b"full").void_unwrap();
+        assert!(cb.0.pop_front().unwrap() == b"\x10\x00\x00\x04full\0");
+        assert!(cb.0.is_empty());
+        assert!(server.peers.iter().next().is_none());
+    }
+
+    #[test]
+    fn peer_stats() {
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        struct Address;
+        struct Cb {
+            packets: VecDeque<Vec<u8>>,
+            time: Timestamp,
+        }
+        impl Callback<Address> for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = 0x12 + i as u8;
+                }
+            }
+            fn send(&mut self, _: Address, data: &[u8]) -> Result<(), Void> {
+                self.packets.push_back(data.to_owned());
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                self.time
+            }
+        }
+        let mut cb = Cb {
+            packets: VecDeque::new(),
+            time: Timestamp::from_secs_since_epoch(0),
+        };
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+
+        let mut server = Net::server();
+        let mut client = Net::client();
+
+        let (c_pid, res) = client.connect(cb, Address);
+        res.void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(server
+            .feed(cb, &mut Panic, Address, &packet, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        let packet = cb.packets.pop_front().unwrap();
         assert!(
-            net.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+            client
+                .feed(cb, &mut Panic, Address, &packet, &mut buffer[..])
                 .0
                 .collect_vec()
-                == &[ChunkOrEvent::Disconnect(s_pid, b"foobar")]
+                == &[ChunkOrEvent::Ready(c_pid)]
         );
+        let packet = cb.packets.pop_front().unwrap();
+        let s_pid = match server
+            .feed(cb, &mut Panic, Address, &packet, &mut buffer[..])
+            .0
+            .collect_vec()[..]
+        {
+            [ChunkOrEvent::Connect(pid, Protocol::V6)] => pid,
+            _ => panic!(),
+        };
+        server.accept(cb, s_pid).void_unwrap();
         assert!(cb.packets.is_empty());
+
+        let send = |client: &mut Net<Address>, cb: &mut Cb, data: &[u8], vital| {
+            let chunk = Chunk {
+                pid: c_pid,
+                vital: vital,
+                data: data,
+            };
+            client.send(cb, chunk).unwrap();
+            client.flush(cb, c_pid).void_unwrap();
+            cb.packets.pop_front().unwrap()
+        };
+        let packet = send(&mut client, cb, b"\x42", true);
+        let packet2 = send(&mut client, cb, b"\x43", false);
+        let packet3 = send(&mut client, cb, b"\x44", true);
+        let packet4 = send(&mut client, cb, b"\x45", true);
+        for p in &[&packet, &packet2, &packet4, &packet3, &packet] {
+            server
+                .feed(cb, &mut Panic, Address, p, &mut buffer[..])
+                .0
+                .for_each(drop);
+        }
+        // Packets with the wrong token aren't counted.
+        let mut wrong_token = packet2.clone();
+        *wrong_token.last_mut().unwrap() ^= 0xff;
+        let mut warnings = Vec::new();
+        server
+            .feed(cb, &mut warnings, Address, &wrong_token, &mut buffer[..])
+            .0
+            .for_each(drop);
+        assert!(matches!(
+            warnings[..],
+            [Warning::Peer(
+                Address,
+                pid,
+                connection::Warning::TokenMismatch
+            )] if pid == s_pid
+        ));
+
+        cb.time = cb.time + Duration::from_millis(250);
+        let stats = client.peer_stats(cb, c_pid);
+        assert_eq!(stats.protocol, Protocol::V6);
+        assert_eq!(stats.connection.packets_sent, 6);
+        assert_eq!(stats.connection.packets_received, 1);
+        assert_eq!(stats.connection.vital_chunks_sent, 3);
+        assert_eq!(stats.connection.nonvital_chunks_sent, 1);
+        assert_eq!(stats.connection.sequence, 3);
+        assert_eq!(stats.since_last_received, Some(Duration::from_millis(250)));
+
+        let stats = server.peer_stats(cb, s_pid);
+        let bytes: usize = [&packet, &packet2, &packet3, &packet4, &packet]
+            .iter()
+            .map(|p| p.len())
+            .sum();
+        assert_eq!(stats.connection.packets_received, 5);
+        assert_eq!(stats.connection.bytes_received, bytes as u64);
+        assert_eq!(stats.connection.vital_chunks_received, 2);
+        assert_eq!(stats.connection.nonvital_chunks_received, 1);
+        // The third vital chunk arrived before the second one.
+        assert_eq!(stats.connection.dropped_out_of_order, 1);
+        // The first vital chunk arrived twice.
+        assert_eq!(stats.connection.dropped_duplicates, 1);
+        assert_eq!(stats.connection.ack, 2);
+        assert_eq!(stats.since_last_received, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn dual_stack() {
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        enum Address {
+            Client6,
+            Client7,
+            Server,
+        }
+        struct Cb {
+            packets: VecDeque<(Address, Address, Vec<u8>)>,
+            sender: Address,
+        }
+        enum Event {
+            Connect(PeerId, Protocol),
+            Ready(PeerId),
+            Chunk(Vec<u8>),
+        }
+        impl Callback<Address> for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = 0x12 + i as u8;
+                }
+            }
+            fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
+                self.packets.push_back((self.sender, addr, data.to_owned()));
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let mut cb = Cb {
+            packets: VecDeque::new(),
+            sender: Address::Client6,
+        };
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+
+        let mut server = Net::server();
+        let mut client6 = Net::client();
+        let mut client7 = Net::client();
+
+        cb.sender = Address::Client6;
+        let (c6_pid, res) = client6.connect(cb, Address::Server);
+        res.void_unwrap();
+        cb.sender = Address::Client7;
+        let (c7_pid, res) = client7.connect7(cb, Address::Server);
+        res.void_unwrap();
+
+        let mut connects = vec![];
+        let mut ready = vec![];
+        let mut received = vec![];
+        while let Some((from, to, packet)) = cb.packets.pop_front() {
+            cb.sender = to;
+            let net = match to {
+                Address::Server => &mut server,
+                Address::Client6 => &mut client6,
+                Address::Client7 => &mut client7,
+            };
+            let events: Vec<_> = net
+                .feed(cb, &mut Panic, from, &packet, &mut buffer[..])
+                .0
+                .map(|e| match e {
+                    ChunkOrEvent::Connect(pid, protocol) => Event::Connect(pid, protocol),
+                    ChunkOrEvent::Ready(pid) => Event::Ready(pid),
+                    ChunkOrEvent::Chunk(c) => Event::Chunk(c.data.to_owned()),
+                    e => panic!("unexpected event {:?}", e),
+                })
+                .collect();
+            for event in events {
+                match event {
+                    Event::Connect(pid, protocol) => {
+                        connects.push((from, protocol));
+                        net.accept(cb, pid).void_unwrap();
+                    }
+                    Event::Ready(pid) => {
+                        ready.push((to, pid));
+                        let data: &[u8] = match to {
+                            Address::Client6 => b"six",
+                            _ => b"seven",
+                        };
+                        let chunk = Chunk {
+                            pid: pid,
+                            vital: true,
+                            data: data,
+                        };
+                        net.send(cb, chunk).unwrap();
+                        net.flush(cb, pid).void_unwrap();
+                    }
+                    Event::Chunk(data) => received.push((from, data)),
+                }
+            }
+        }
+        connects.sort();
+        assert_eq!(
+            connects,
+            [
+                (Address::Client6, Protocol::V6),
+                (Address::Client7, Protocol::V7)
+            ]
+        );
+        ready.sort();
+        assert_eq!(
+            ready,
+            [(Address::Client6, c6_pid), (Address::Client7, c7_pid)]
+        );
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                (Address::Client6, b"six".to_vec()),
+                (Address::Client7, b"seven".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dual_stack_connless() {
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        enum Address {
+            Client,
+            Server,
+        }
+        struct Cb {
+            packets: VecDeque<(Address, Address, Vec<u8>)>,
+            sender: Address,
+        }
+        impl Callback<Address> for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                for b in buffer {
+                    *b = 0x34;
+                }
+            }
+            fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
+                self.packets.push_back((self.sender, addr, data.to_owned()));
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let mut cb = Cb {
+            packets: VecDeque::new(),
+            sender: Address::Client,
+        };
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+
+        let mut server = Net::server();
+        let mut client = Net::client();
+        client.send_connless(cb, Address::Server, b"info6").unwrap();
+        client
+            .send_connless7(cb, Address::Server, b"info7")
+            .unwrap();
+
+        let mut received = vec![];
+        while let Some((from, to, packet)) = cb.packets.pop_front() {
+            cb.sender = to;
+            let net = match to {
+                Address::Server => &mut server,
+                Address::Client => &mut client,
+            };
+            let chunks: Vec<_> = net
+                .feed(cb, &mut Panic, from, &packet, &mut buffer[..])
+                .0
+                .map(|e| match e {
+                    ChunkOrEvent::Connless(c) => (c.protocol, c.data.to_owned()),
+                    e => panic!("unexpected event {:?}", e),
+                })
+                .collect();
+            for (protocol, data) in chunks {
+                received.push((to, protocol, data.clone()));
+                if to == Address::Server {
+                    // Reply using the protocol of the request.
+                    let reply = [&b"re:"[..], &data].concat();
+                    match protocol {
+                        Protocol::V6 => net.send_connless(cb, from, &reply).unwrap(),
+                        Protocol::V7 => net.send_connless7(cb, from, &reply).unwrap(),
+                    }
+                }
+            }
+        }
+        // The 0.7 packet is only sent after the token exchange, the reply
+        // reuses the token of the request.
+        assert_eq!(
+            received,
+            [
+                (Address::Server, Protocol::V6, b"info6".to_vec()),
+                (Address::Client, Protocol::V6, b"re:info6".to_vec()),
+                (Address::Server, Protocol::V7, b"info7".to_vec()),
+                (Address::Client, Protocol::V7, b"re:info7".to_vec()),
+            ]
+        );
     }
 }
@@ -0,0 +1,307 @@
+use crate::net::Address;
+use crate::net::Callback;
+use crate::net::Chunk;
+use crate::net::ChunkOrEvent;
+use crate::net::Error;
+use crate::net::PeerId;
+use crate::net::PeerStats;
+use crate::net::QueueLimit;
+use crate::net::ReceivePacket;
+use crate::net::Tick;
+use crate::net::Warning;
+use crate::Net;
+use crate::Timeout;
+use buffer::Buffer;
+use warn::Warn;
+
+/// A Teeworlds 0.7-only network endpoint.
+///
+/// This is a `Net` that only speaks the 0.7 protocol, see there for the
+/// documentation of the individual methods. Use `Net` directly to accept
+/// both 0.6/DDNet and 0.7 connections.
+pub struct Net7<A: Address> {
+    net: Net<A>,
+}
+
+impl<A: Address> Net7<A> {
+    pub fn server() -> Net7<A> {
+        Net7 {
+            net: Net::server7(),
+        }
+    }
+    pub fn client() -> Net7<A> {
+        Net7 {
+            net: Net::client7(),
+        }
+    }
+    pub fn set_queue_limit(&mut self, limit: Option<QueueLimit>) {
+        self.net.set_queue_limit(limit)
+    }
+    pub fn needs_tick(&self) -> Timeout {
+        self.net.needs_tick()
+    }
+    pub fn is_receive_chunk_still_valid(&self, chunk: &mut ChunkOrEvent<A>) -> bool {
+        self.net.is_receive_chunk_still_valid(chunk)
+    }
+    pub fn peer_stats<CB: Callback<A>>(&self, cb: &mut CB, pid: PeerId) -> PeerStats {
+        self.net.peer_stats(cb, pid)
+    }
+    pub fn connect<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+    ) -> (PeerId, Result<(), CB::Error>) {
+        self.net.connect7(cb, addr)
+    }
+    pub fn disconnect<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        pid: PeerId,
+        reason: &[u8],
+    ) -> Result<(), CB::Error> {
+        self.net.disconnect(cb, pid, reason)
+    }
+    pub fn send_connless<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        addr: A,
+        data: &[u8],
+    ) -> Result<(), Error<CB::Error>> {
+        self.net.send_connless7(cb, addr, data)
+    }
+    pub fn send<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        chunk: Chunk,
+    ) -> Result<(), Error<CB::Error>> {
+        self.net.send(cb, chunk)
+    }
+    pub fn flush<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId) -> Result<(), CB::Error> {
+        self.net.flush(cb, pid)
+    }
+    pub fn ignore(&mut self, pid: PeerId) {
+        self.net.ignore(pid)
+    }
+    pub fn accept<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId) -> Result<(), CB::Error> {
+        self.net.accept(cb, pid)
+    }
+    pub fn reject<CB: Callback<A>>(
+        &mut self,
+        cb: &mut CB,
+        pid: PeerId,
+        reason: &[u8],
+    ) -> Result<(), CB::Error> {
+        self.net.reject(cb, pid, reason)
+    }
+    pub fn tick<CB, W>(&mut self, cb: &mut CB, warn: &mut W) -> Tick<A, CB::Error>
+    where
+        CB: Callback<A>,
+        W: Warn<Warning<A>>,
+    {
+        self.net.tick(cb, warn)
+    }
+    pub fn feed<'a, CB, B, W>(
+        &mut self,
+        cb: &mut CB,
+        warn: &mut W,
+        addr: A,
+        data: &'a [u8],
+        buf: B,
+    ) -> (ReceivePacket<'a, A>, Result<(), CB::Error>)
+    where
+        CB: Callback<A>,
+        B: Buffer<'a>,
+        W: Warn<Warning<A>>,
+    {
+        self.net.feed(cb, warn, addr, data, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Net7;
+    use crate::net::Callback;
+    use crate::net::Chunk;
+    use crate::net::ChunkOrEvent;
+    use crate::net::ConnlessChunk;
+    use crate::net::Protocol;
+    use crate::protocol7;
+    use crate::Timestamp;
+    use itertools::Itertools;
+    use std::collections::VecDeque;
+    use void::ResultVoidExt;
+    use void::Void;
+    use warn::Panic;
+
+    #[test]
+    fn establish_connection() {
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        enum Address {
+            Client,
+            Server,
+        }
+        struct Cb {
+            packets: VecDeque<Vec<u8>>,
+            recipient: Address,
+        }
+        impl Cb {
+            fn new() -> Cb {
+                Cb {
+                    packets: VecDeque::new(),
+                    recipient: Address::Server,
+                }
+            }
+        }
+        impl Callback<Address> for Cb {
+            type Error = Void;
+            fn secure_random(&mut self, buffer: &mut [u8]) {
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = 0x12 + i as u8;
+                }
+            }
+            fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
+                assert!(self.recipient == addr);
+                self.packets.push_back(data.to_owned());
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol7::MAX_PACKETSIZE];
+
+        let mut client = Net7::client();
+        let mut server = Net7::server();
+
+        // Token request
+        cb.recipient = Address::Server;
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        // Token response
+        cb.recipient = Address::Client;
+        assert!(server
+            .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        // Connect
+        cb.recipient = Address::Server;
+        assert!(client
+            .feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..])
+            .0
+            .next()
+            .is_none());
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        cb.recipient = Address::Client;
+        let s_pid;
+        {
+            let p = server
+                .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+                .0
+                .collect_vec();
+            assert!(p.len() == 1);
+            if let ChunkOrEvent::Connect(s, Protocol::V7) = p[0] {
+                s_pid = s;
+            } else {
+                panic!();
+            }
+        }
+        // No packets sent out until we accept the client.
+        assert!(cb.packets.is_empty());
+
+        // Accept
+        server.accept(cb, s_pid).void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        cb.recipient = Address::Server;
+        assert!(
+            client
+                .feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ChunkOrEvent::Ready(c_pid)]
+        );
+        assert!(cb.packets.is_empty());
+
+        // Send
+        let chunk = Chunk {
+            pid: c_pid,
+            vital: true,
+            data: b"\x42",
+        };
+        client.send(cb, chunk).unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        cb.recipient = Address::Client;
+        assert!(
+            server
+                .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ChunkOrEvent::Chunk(Chunk {
+                    pid: s_pid,
+                    vital: true,
+                    data: b"\x42",
+                })]
+        );
+        assert!(cb.packets.is_empty());
+
+        // Connless packets use the tokens of the connection, no token
+        // exchange is necessary.
+        cb.recipient = Address::Server;
+        client.send_connless(cb, Address::Server, b"info").unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        cb.recipient = Address::Client;
+        assert!(
+            server
+                .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ChunkOrEvent::Connless(ConnlessChunk {
+                    addr: Address::Client,
+                    pid: Some(s_pid),
+                    protocol: Protocol::V7,
+                    data: b"info",
+                })]
+        );
+        assert!(cb.packets.is_empty());
+
+        // Disconnect
+        cb.recipient = Address::Server;
+        client.disconnect(cb, c_pid, b"foobar").void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        cb.recipient = Address::Client;
+        assert!(
+            server
+                .feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..])
+                .0
+                .collect_vec()
+                == &[ChunkOrEvent::Disconnect(s_pid, b"foobar")]
+        );
+        assert!(cb.packets.is_empty());
+        assert!(
+            !server.is_receive_chunk_still_valid(&mut ChunkOrEvent::Chunk(Chunk {
+                pid: s_pid,
+                vital: true,
+                data: b"",
+            }))
+        );
+    }
+}
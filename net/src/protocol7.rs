@@ -1,3 +1,4 @@
+use arrayvec::ArrayVec;
 use buffer::with_buffer;
 use buffer::Buffer;
 use buffer::BufferRef;
@@ -45,6 +46,7 @@ pub const CTRLMSG_TOKEN: u8 = 5;
 pub const CONNLESS_VERSION: u8 = 1;
 pub const CTRLMSG_CLOSE_REASON_LENGTH: usize = 127;
 pub const TOKEN_REQUEST_PACKET_SIZE: usize = 519;
+pub const TOKEN_REQUEST_DATA_SIZE: usize = 512;
 pub const TOKEN_NONE: Token = Token([0xff, 0xff, 0xff, 0xff]);
 
 pub const CHUNK_FLAGS_BITS: u32 = 2;
@@ -62,6 +64,18 @@ pub fn chunk_header_size(vital: bool) -> usize {
     }
 }
 
+#[derive(Debug)]
+pub enum Error {
+    Capacity(buffer::CapacityError),
+    TooLongData,
+}
+
+impl From<buffer::CapacityError> for Error {
+    fn from(e: buffer::CapacityError) -> Error {
+        Error::Capacity(e)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum Warning {
     ChunkHeaderPadding,
@@ -130,7 +144,40 @@ impl fmt::Display for Token {
     }
 }
 
+impl Token {
+    pub fn random<F: FnMut(&mut [u8])>(mut f: F) -> Token {
+        loop {
+            let mut token = TOKEN_NONE;
+            f(&mut token.0);
+            if token != TOKEN_NONE {
+                return token;
+            }
+        }
+    }
+}
+
 impl<'a> Packet<'a> {
+    /// Returns whether `packet` has a 0.7 connless header.
+    ///
+    /// The 0.6 parser also takes these packets for connless ones, so this
+    /// needs to be checked first when accepting both protocols.
+    pub fn is_connless(packet: &[u8]) -> bool {
+        if packet.len() > MAX_PACKETSIZE {
+            return false;
+        }
+        let (header, _) =
+            unwrap_or_return!(PacketHeaderConnlessPacked::ref_and_rest_from(packet), false);
+        let header = header.unpack_warn(&mut Ignore);
+        header.flags & PACKETFLAG_CONNLESS != 0 && header.version == CONNLESS_VERSION
+    }
+    /// Returns the header of a 0.7 connless packet, containing its tokens.
+    pub fn connless_header(packet: &[u8]) -> Option<PacketHeaderConnless> {
+        if !Packet::is_connless(packet) {
+            return None;
+        }
+        let (header, _) = PacketHeaderConnlessPacked::ref_and_rest_from(packet)?;
+        Some(header.unpack())
+    }
     fn needs_decompression(packet: &[u8]) -> bool {
         if packet.len() > MAX_PACKETSIZE {
             return false;
@@ -249,7 +296,8 @@ impl<'a> Packet<'a> {
                     empty(warn);
                     ControlPacket::KeepAlive
                 }
-                CTRLMSG_CONNECT => ControlPacket::Connect(token(warn, true)?),
+                // Connect packets are padded like token requests.
+                CTRLMSG_CONNECT => ControlPacket::Connect(token(warn, false)?),
                 CTRLMSG_ACCEPT => {
                     empty(warn);
                     ControlPacket::Accept
@@ -291,6 +339,7 @@ impl<'a> Packet<'a> {
         };
 
         Ok(Packet::Connected(ConnectedPacket {
+            token: header.token,
             ack: ack,
             type_: type_,
         }))
@@ -345,8 +394,108 @@ impl<'a> Packet<'a> {
     }
 }
 
+impl<'a> ConnectedPacket<'a> {
+    pub fn write<'b, B: Buffer<'b>>(&self, buffer: B) -> Result<&'b [u8], Error> {
+        with_buffer(buffer, |b| self.write_impl(b))
+    }
+
+    fn write_impl<'d, 's>(&self, mut buffer: BufferRef<'d, 's>) -> Result<&'d [u8], Error> {
+        match self.type_ {
+            ConnectedPacketType::Chunks(request_resend, num_chunks, payload) => {
+                if payload.len() > MAX_PACKETSIZE - HEADER_SIZE {
+                    return Err(Error::TooLongData);
+                }
+                let mut compression_buffer: ArrayVec<[u8; 2048]> = ArrayVec::new();
+                let mut compression = 0;
+                let comp_result = HUFFMAN.compress(payload, &mut compression_buffer);
+                if comp_result
+                    .map(|s| s.len() < payload.len())
+                    .unwrap_or(false)
+                {
+                    compression = PACKETFLAG_COMPRESSION;
+                }
+                let request_resend = if request_resend {
+                    PACKETFLAG_REQUEST_RESEND
+                } else {
+                    0
+                };
+                buffer.write(
+                    PacketHeader {
+                        flags: request_resend | compression,
+                        ack: self.ack,
+                        num_chunks: num_chunks,
+                        token: self.token,
+                    }
+                    .pack()
+                    .as_bytes(),
+                )?;
+                buffer.write(if compression != 0 {
+                    &compression_buffer
+                } else {
+                    payload
+                })?;
+                Ok(buffer.initialized())
+            }
+            ConnectedPacketType::Control(c) => c.write(self.token, self.ack, buffer),
+        }
+    }
+}
+
+impl<'a> ControlPacket<'a> {
+    fn write<'d, 's>(
+        &self,
+        token: Token,
+        ack: u16,
+        mut buffer: BufferRef<'d, 's>,
+    ) -> Result<&'d [u8], Error> {
+        buffer.write(
+            PacketHeader {
+                flags: PACKETFLAG_CONTROL,
+                ack: ack,
+                num_chunks: 0,
+                token: token,
+            }
+            .pack()
+            .as_bytes(),
+        )?;
+        let magic = match *self {
+            ControlPacket::KeepAlive => CTRLMSG_KEEPALIVE,
+            ControlPacket::Connect(..) => CTRLMSG_CONNECT,
+            ControlPacket::Accept => CTRLMSG_ACCEPT,
+            ControlPacket::Close(..) => CTRLMSG_CLOSE,
+            ControlPacket::Token(..) => CTRLMSG_TOKEN,
+        };
+        buffer.write(&[magic])?;
+        match *self {
+            ControlPacket::KeepAlive | ControlPacket::Accept => {}
+            ControlPacket::Connect(response_token) | ControlPacket::Token(response_token) => {
+                buffer.write(&response_token.0)?;
+                // Token requests and connect packets are padded so that they
+                // can't be used for traffic amplification.
+                let is_request = match *self {
+                    ControlPacket::Token(_) => token == TOKEN_NONE,
+                    _ => true,
+                };
+                if is_request {
+                    let padding = [0; TOKEN_REQUEST_DATA_SIZE - 4];
+                    buffer.write(&padding)?;
+                }
+            }
+            ControlPacket::Close(m) => {
+                assert!(m.iter().all(|&b| b != 0));
+                buffer.write(m)?;
+                buffer.write(&[0])?;
+            }
+        }
+        let result = buffer.initialized();
+        assert!(result.len() <= MAX_PACKETSIZE);
+        Ok(result)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct ConnectedPacket<'a> {
+    pub token: Token,
     pub ack: u16, // u10
     pub type_: ConnectedPacketType<'a>,
 }
@@ -439,6 +588,80 @@ impl<'a> Iterator for ChunksIter<'a> {
 
 impl<'a> ExactSizeIterator for ChunksIter<'a> {}
 
+// vital: Some((sequence, resend))
+pub fn write_chunk<'a, B: Buffer<'a>>(
+    bytes: &[u8],
+    vital: Option<(u16, bool)>,
+    buffer: B,
+) -> Result<&'a [u8], buffer::CapacityError> {
+    with_buffer(buffer, |b| write_chunk_impl(bytes, vital, b))
+}
+
+pub fn write_chunk_impl<'d, 's>(
+    bytes: &[u8],
+    vital: Option<(u16, bool)>,
+    mut buffer: BufferRef<'d, 's>,
+) -> Result<&'d [u8], buffer::CapacityError> {
+    assert!(bytes.len() >> CHUNK_SIZE_BITS == 0);
+    let size = bytes.len().assert_u16();
+
+    let (sequence, resend) = vital.unwrap_or((0, false));
+    let resend_flag = if resend { CHUNKFLAG_RESEND } else { 0 };
+    let vital_flag = if vital.is_some() { CHUNKFLAG_VITAL } else { 0 };
+    let flags = vital_flag | resend_flag;
+
+    let header_nonvital = ChunkHeader {
+        flags: flags,
+        size: size,
+    };
+
+    let header1;
+    let header2;
+    let header: &[u8] = if vital.is_some() {
+        header1 = ChunkHeaderVital {
+            h: header_nonvital,
+            sequence: sequence,
+        }
+        .pack();
+        header1.as_bytes()
+    } else {
+        header2 = header_nonvital.pack();
+        header2.as_bytes()
+    };
+    buffer.write(header)?;
+    buffer.write(bytes)?;
+    Ok(buffer.initialized())
+}
+
+pub fn write_connless_packet<'a, B: Buffer<'a>>(
+    token: Token,
+    response_token: Token,
+    bytes: &[u8],
+    buffer: B,
+) -> Result<&'a [u8], Error> {
+    fn inner<'d, 's>(
+        token: Token,
+        response_token: Token,
+        bytes: &[u8],
+        mut buffer: BufferRef<'d, 's>,
+    ) -> Result<&'d [u8], Error> {
+        if bytes.len() > MAX_PACKETSIZE - HEADER_SIZE_CONNLESS {
+            return Err(Error::TooLongData);
+        }
+        let header = PacketHeaderConnless {
+            flags: PACKETFLAG_CONNLESS,
+            version: CONNLESS_VERSION,
+            token: token,
+            response_token: response_token,
+        };
+        buffer.write(header.pack().as_bytes())?;
+        buffer.write(bytes)?;
+        Ok(buffer.initialized())
+    }
+
+    with_buffer(buffer, |b| inner(token, response_token, bytes, b))
+}
+
 #[repr(C, packed)]
 #[derive(AsBytes, Clone, Copy, FromBytes, FromZeroes)]
 pub struct PacketHeaderPacked {
@@ -0,0 +1,429 @@
+//! Deterministic in-memory network for testing `Net` instances without
+//! sockets.
+//!
+//! All endpoints share one `SimNetwork` which keeps a virtual clock and the
+//! packets in flight. Each `Net` is driven through an `Endpoint`, which
+//! implements `net::Callback` for its address. Delivered packets are taken out
+//! of the network with `SimNetwork::receive` and fed to the `Net` of the
+//! recipient, the clock only advances when asked to.
+
+use crate::net::Address;
+use crate::net::Callback;
+use crate::Timeout;
+use crate::Timestamp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::time::Duration;
+use void::Void;
+
+/// Properties of the link between two endpoints, in one direction.
+///
+/// The default link delivers every packet instantly.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Link {
+    /// Delay of every packet.
+    pub latency: Duration,
+    /// Maximum additional, uniformly distributed delay of every packet.
+    pub jitter: Duration,
+    /// Probability of a packet getting lost.
+    pub loss: f64,
+    /// Probability of a packet getting delivered twice.
+    pub duplication: f64,
+    /// Probability of a packet getting held back for an additional
+    /// `reorder_delay`, so that packets sent after it can overtake it.
+    pub reordering: f64,
+    pub reorder_delay: Duration,
+}
+
+/// A packet delivered by the `SimNetwork`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Packet<A: Address> {
+    pub from: A,
+    pub to: A,
+    pub data: Vec<u8>,
+}
+
+/// SplitMix64, chosen for being simple and stable across versions.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    /// Returns `true` with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        // Always draw a number, even for `p` of 0 or 1. The random sequence
+        // still depends on the outcomes though: a duplicated packet draws
+        // the numbers for its second copy as well.
+        let x = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        x < p
+    }
+    /// Returns a uniformly distributed duration in `[0, max]`.
+    fn duration(&mut self, max: Duration) -> Duration {
+        let max_us = max.as_micros() as u64;
+        Duration::from_micros(self.next_u64() % (max_us + 1))
+    }
+}
+
+pub struct SimNetwork<A: Address> {
+    time: Timestamp,
+    rng: Rng,
+    default_link: Link,
+    links: HashMap<(A, A), Link>,
+    // Packets in flight, ordered by delivery time and then by the order in
+    // which they were sent.
+    in_flight: BTreeMap<(Timestamp, u64), Packet<A>>,
+    next_id: u64,
+}
+
+/// The view of a single endpoint onto the `SimNetwork`.
+pub struct Endpoint<'a, A: Address> {
+    sim: &'a mut SimNetwork<A>,
+    addr: A,
+}
+
+impl<A: Address> SimNetwork<A> {
+    /// Creates a network whose randomness is completely determined by
+    /// `seed`.
+    pub fn new(seed: u64) -> SimNetwork<A> {
+        SimNetwork {
+            time: Timestamp::from_secs_since_epoch(0),
+            rng: Rng::new(seed),
+            default_link: Link::default(),
+            links: HashMap::new(),
+            in_flight: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+    pub fn time(&self) -> Timestamp {
+        self.time
+    }
+    /// Sets the link used between endpoints that don't have a link set
+    /// explicitly.
+    pub fn set_default_link(&mut self, link: Link) {
+        self.default_link = link;
+    }
+    /// Sets the link for packets from `from` to `to`.
+    pub fn set_link(&mut self, from: A, to: A, link: Link) {
+        self.links.insert((from, to), link);
+    }
+    pub fn link(&self, from: A, to: A) -> Link {
+        self.links
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(self.default_link)
+    }
+    /// Returns the callback for the endpoint at `addr`.
+    pub fn endpoint(&mut self, addr: A) -> Endpoint<A> {
+        Endpoint {
+            sim: self,
+            addr: addr,
+        }
+    }
+    /// Number of packets that haven't been delivered yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+    /// Returns the time at which the next packet is delivered.
+    pub fn next_delivery(&self) -> Timeout {
+        self.in_flight
+            .keys()
+            .next()
+            .map(|&(time, _)| Timeout::active(time))
+            .unwrap_or_default()
+    }
+    /// Advances the virtual clock to `time`.
+    pub fn advance_to(&mut self, time: Timestamp) {
+        assert!(time >= self.time, "time must not go backwards");
+        self.time = time;
+    }
+    pub fn advance(&mut self, duration: Duration) {
+        let time = self.time + duration;
+        self.advance_to(time);
+    }
+    /// Returns the next packet that has arrived by the current time.
+    pub fn receive(&mut self) -> Option<Packet<A>> {
+        let key = *self.in_flight.keys().next()?;
+        if key.0 > self.time {
+            return None;
+        }
+        self.in_flight.remove(&key)
+    }
+    fn send(&mut self, from: A, to: A, data: &[u8]) {
+        let link = self.link(from, to);
+        let lost = self.rng.chance(link.loss);
+        let duplicated = self.rng.chance(link.duplication);
+        for _ in 0..(if duplicated { 2 } else { 1 }) {
+            let mut delay = link.latency + self.rng.duration(link.jitter);
+            if self.rng.chance(link.reordering) {
+                delay += link.reorder_delay;
+            }
+            if lost {
+                continue;
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            let packet = Packet {
+                from: from,
+                to: to,
+                data: data.to_owned(),
+            };
+            self.in_flight.insert((self.time + delay, id), packet);
+        }
+    }
+}
+
+impl<'a, A: Address> Endpoint<'a, A> {
+    pub fn addr(&self) -> A {
+        self.addr
+    }
+}
+
+impl<'a, A: Address> Callback<A> for Endpoint<'a, A> {
+    type Error = Void;
+    fn secure_random(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let random = self.sim.rng.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+    fn send(&mut self, addr: A, data: &[u8]) -> Result<(), Void> {
+        self.sim.send(self.addr, addr, data);
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        self.sim.time
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Link;
+    use super::SimNetwork;
+    use crate::net::Chunk;
+    use crate::net::ChunkOrEvent;
+    use crate::net::PeerId;
+    use crate::net::Protocol;
+    use crate::protocol;
+    use crate::Net;
+    use crate::Timestamp;
+    use std::time::Duration;
+    use void::ResultVoidExt;
+    use warn::Ignore;
+
+    const SERVER: u32 = 0;
+    const CLIENT: u32 = 1;
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    enum Event {
+        Connect(PeerId, Protocol),
+        Ready(PeerId),
+        Chunk(PeerId, Vec<u8>),
+        Disconnect(PeerId, Vec<u8>),
+    }
+
+    /// Advances to the next point in time where something happens, ticks
+    /// all nets and delivers the packets that arrived.
+    ///
+    /// Returns `false` if nothing is going to happen anymore.
+    fn step(
+        sim: &mut SimNetwork<u32>,
+        nets: &mut [(u32, &mut Net<u32>)],
+        events: &mut Vec<(u32, Event)>,
+    ) -> bool {
+        let next = nets
+            .iter()
+            .map(|(_, net)| net.needs_tick())
+            .chain(Some(sim.next_delivery()))
+            .min()
+            .unwrap();
+        let next = match next.to_opt() {
+            Some(t) => t,
+            None => return false,
+        };
+        if next > sim.time() {
+            sim.advance_to(next);
+        }
+        for (addr, net) in nets.iter_mut() {
+            for event in net.tick(&mut sim.endpoint(*addr), &mut Ignore) {
+                match event.void_unwrap() {
+                    ChunkOrEvent::Disconnect(pid, reason) => {
+                        events.push((*addr, Event::Disconnect(pid, reason.to_owned())))
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        let mut buffer = [0; protocol::MAX_PACKETSIZE];
+        while let Some(packet) = sim.receive() {
+            let (addr, net) = nets.iter_mut().find(|(a, _)| *a == packet.to).unwrap();
+            let (received, res) = net.feed(
+                &mut sim.endpoint(*addr),
+                &mut Ignore,
+                packet.from,
+                &packet.data,
+                &mut buffer[..],
+            );
+            for chunk in received {
+                let event = match chunk {
+                    ChunkOrEvent::Connect(pid, protocol) => Event::Connect(pid, protocol),
+                    ChunkOrEvent::Ready(pid) => Event::Ready(pid),
+                    ChunkOrEvent::Chunk(c) => Event::Chunk(c.pid, c.data.to_owned()),
+                    ChunkOrEvent::Disconnect(pid, reason) => {
+                        Event::Disconnect(pid, reason.to_owned())
+                    }
+                    ChunkOrEvent::Connless(_) => continue,
+                };
+                events.push((*addr, event));
+            }
+            res.void_unwrap();
+        }
+        true
+    }
+
+    /// Connects a client, sends `num_chunks` vital chunks to the server,
+    /// disconnects and returns the events seen by both sides.
+    fn scenario(seed: u64, link: Link, num_chunks: u8) -> Vec<(Timestamp, u32, Event)> {
+        let mut sim = SimNetwork::new(seed);
+        sim.set_default_link(link);
+        let mut server = Net::server();
+        let mut client = Net::client();
+
+        let mut trace = vec![];
+        let mut events = vec![];
+        let (c_pid, res) = client.connect(&mut sim.endpoint(CLIENT), SERVER);
+        res.void_unwrap();
+        let mut s_pid = None;
+        let mut ready = false;
+        let mut sent = 0;
+        let mut received = 0;
+        let mut disconnect_sent = false;
+        let mut disconnected = false;
+        while !disconnected {
+            assert!(sim.time() < Timestamp::from_secs_since_epoch(60));
+            {
+                let mut nets = [(SERVER, &mut server), (CLIENT, &mut client)];
+                assert!(step(&mut sim, &mut nets, &mut events));
+            }
+            for (addr, event) in events.drain(..) {
+                trace.push((sim.time(), addr, event.clone()));
+                match (addr, event) {
+                    (SERVER, Event::Connect(pid, Protocol::V6)) => {
+                        server.accept(&mut sim.endpoint(SERVER), pid).void_unwrap();
+                        s_pid = Some(pid);
+                    }
+                    (CLIENT, Event::Ready(pid)) => {
+                        assert_eq!(pid, c_pid);
+                        ready = true;
+                    }
+                    (SERVER, Event::Chunk(pid, data)) => {
+                        assert_eq!(Some(pid), s_pid);
+                        assert_eq!(data, [received]);
+                        received += 1;
+                    }
+                    (SERVER, Event::Disconnect(pid, reason)) => {
+                        assert_eq!(Some(pid), s_pid);
+                        assert_eq!(reason, b"bye");
+                        disconnected = true;
+                    }
+                    e => panic!("unexpected event {:?}", e),
+                }
+            }
+            if ready && sent < num_chunks {
+                let mut endpoint = sim.endpoint(CLIENT);
+                while sent < num_chunks {
+                    let chunk = Chunk {
+                        pid: c_pid,
+                        vital: true,
+                        data: &[sent],
+                    };
+                    client.send(&mut endpoint, chunk).unwrap();
+                    client.flush(&mut endpoint, c_pid).void_unwrap();
+                    sent += 1;
+                }
+            }
+            if received == num_chunks && !disconnect_sent {
+                // The `Close` isn't resent, make sure it arrives.
+                disconnect_sent = true;
+                sim.set_default_link(Link::default());
+                client
+                    .disconnect(&mut sim.endpoint(CLIENT), c_pid, b"bye")
+                    .void_unwrap();
+            }
+        }
+        trace
+    }
+
+    fn lossy() -> Link {
+        Link {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(20),
+            loss: 0.2,
+            duplication: 0.1,
+            reordering: 0.1,
+            reorder_delay: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn perfect_link() {
+        let trace = scenario(0, Link::default(), 10);
+        assert!(trace
+            .iter()
+            .all(|&(t, _, _)| t == Timestamp::from_secs_since_epoch(0)));
+    }
+
+    #[test]
+    fn lossy_link() {
+        for seed in 0..10 {
+            scenario(seed, lossy(), 30);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(scenario(42, lossy(), 30), scenario(42, lossy(), 30));
+    }
+
+    #[test]
+    fn link_properties() {
+        let mut sim = SimNetwork::new(0);
+        sim.set_link(
+            CLIENT,
+            SERVER,
+            Link {
+                latency: Duration::from_millis(100),
+                ..Link::default()
+            },
+        );
+        sim.set_link(
+            SERVER,
+            CLIENT,
+            Link {
+                loss: 1.0,
+                ..Link::default()
+            },
+        );
+        use crate::net::Callback;
+        sim.endpoint(CLIENT).send(SERVER, b"ping").void_unwrap();
+        sim.endpoint(SERVER).send(CLIENT, b"pong").void_unwrap();
+        assert_eq!(sim.in_flight(), 1);
+        assert!(sim.receive().is_none());
+        sim.advance(Duration::from_millis(99));
+        assert!(sim.receive().is_none());
+        sim.advance(Duration::from_millis(1));
+        let packet = sim.receive().unwrap();
+        assert_eq!((packet.from, packet.to), (CLIENT, SERVER));
+        assert_eq!(packet.data, b"ping");
+        assert_eq!(sim.in_flight(), 0);
+    }
+}
@@ -30,6 +30,11 @@ impl Timestamp {
     pub fn as_usecs_since_epoch(&self) -> u64 {
         self.usec
     }
+    /// Returns the time elapsed from `earlier` to `self`, or zero if
+    /// `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        Duration::from_micros(self.usec.saturating_sub(earlier.usec))
+    }
 }
 
 impl ops::Add<Duration> for Timestamp {
@@ -280,9 +280,14 @@ where
                         match image.data {
                             Some(d) => {
                                 let data = map.image_data(d)?;
-                                if data.len() % mem::size_of::<Color>() != 0 {
-                                    return Err(OwnError::ImageShape.into());
-                                }
+                                let data = libtw2_map::image::RgbaImage::from_raw(
+                                    image.width,
+                                    image.height,
+                                    image.format,
+                                    &data,
+                                )
+                                .map_err(|_| OwnError::ImageShape)?
+                                .data;
                                 let data: Vec<Color> = unsafe { vec::transmute(data) };
                                 Array2::from_shape_vec((height, width), data)
                                     .map_err(|_| OwnError::ImageShape)?
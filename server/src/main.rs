@@ -57,7 +57,9 @@ use std::cell::Cell;
 use std::fmt;
 use std::fmt::Write;
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::process;
 use std::time::Duration;
 
 const TICKS_PER_SECOND: u32 = 50;
@@ -391,8 +393,9 @@ impl<L: Loop> Application<L> for Server {
 }
 
 impl Server {
-    fn run<L: Loop>() {
-        L::accept_connections_on_port(8303).run(Server::default());
+    fn run<L: Loop>() -> io::Result<()> {
+        L::accept_connections_on_port(8303)?.run(Server::default());
+        Ok(())
     }
     fn loop_<'a, L: Loop + 'a>(&'a mut self, loop_: &'a mut L) -> ServerLoop<'a, L> {
         ServerLoop {
@@ -816,5 +819,8 @@ impl<'a, L: Loop> ServerLoop<'a, L> {
 
 fn main() {
     libtw2_logger::init();
-    Server::run::<SocketLoop>();
+    if let Err(e) = Server::run::<SocketLoop>() {
+        error!("{}", e);
+        process::exit(1);
+    }
 }
@@ -7,6 +7,7 @@ use buffer::BufferRef;
 use hexdump::hexdump_iter;
 use itertools::Itertools;
 use libtw2_common::unwrap_or_return;
+use libtw2_net::admission::IpAddress;
 use libtw2_net::net::Callback;
 use libtw2_net::Timestamp;
 use log::LogLevel;
@@ -16,9 +17,11 @@ use mio::Token;
 use net2::UdpBuilder;
 use rand::thread_rng;
 use rand::RngCore as _;
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
 use std::io;
+use std::io::Write;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
@@ -27,6 +30,10 @@ use std::str;
 use std::str::FromStr;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub mod pcap;
 
 #[derive(Debug)]
 enum Direction {
@@ -82,6 +89,12 @@ impl From<SocketAddr> for Addr {
     }
 }
 
+impl IpAddress for Addr {
+    fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
 impl FromStr for Addr {
     type Err = std::net::AddrParseError;
     fn from_str(s: &str) -> Result<Addr, std::net::AddrParseError> {
@@ -112,19 +125,98 @@ impl fmt::Display for AddressFamilyNotSupported {
     }
 }
 
+#[derive(Debug)]
+pub struct BindError {
+    addr: Option<SocketAddr>,
+    error: io::Error,
+}
+
+impl BindError {
+    fn new(addr: SocketAddr, error: io::Error) -> BindError {
+        BindError {
+            addr: Some(addr),
+            error: error,
+        }
+    }
+    /// The address that couldn't be bound, `None` if no addresses were
+    /// given or the error occurred while setting up polling.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+    pub fn io_error(&self) -> &io::Error {
+        &self.error
+    }
+}
+
+impl error::Error for BindError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "couldn't bind to {}: {}", addr, self.error),
+            None => self.error.fmt(f),
+        }
+    }
+}
+
+impl From<BindError> for io::Error {
+    fn from(e: BindError) -> io::Error {
+        io::Error::new(e.error.kind(), e)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BindOptions {
+    /// Sets `IPV6_V6ONLY` on IPv6 sockets. If disabled, IPv6 sockets also
+    /// send and receive IPv4 traffic using IPv4-mapped addresses.
+    pub only_v6: bool,
+    pub loss_rate: f32,
+}
+
+impl Default for BindOptions {
+    fn default() -> BindOptions {
+        BindOptions {
+            only_v6: true,
+            loss_rate: 0.0,
+        }
+    }
+}
+
+struct BoundSocket {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    dual_stack: bool,
+    readable: bool,
+}
+
+/// Maximum number of remote addresses for which the receiving socket is
+/// remembered, see `Socket::reply_via`.
+const MAX_REPLY_ROUTES: usize = 65536;
+
 pub struct Socket {
     start: Instant,
     time_cached: Timestamp,
     poll: mio::Poll,
     events: mio::Events,
-    v4: Option<UdpSocket>,
-    v6: Option<UdpSocket>,
-    check_v4: bool,
-    check_v6: bool,
+    sockets: Vec<BoundSocket>,
+    /// Socket used for IPv4 destinations without a known route.
+    default_v4: Option<usize>,
+    /// Socket used for IPv6 destinations without a known route.
+    default_v6: Option<usize>,
+    /// Remote addresses whose packets arrived on a socket other than the
+    /// default one for their address family. Replies are sent from the
+    /// same socket so that they originate from the address the remote
+    /// talked to, which matters on multi-homed hosts.
+    reply_via: HashMap<Addr, usize>,
+    capture: Option<pcap::Writer<Box<dyn Write + Send>>>,
     loss_rate: f32,
 }
 
-fn udp_socket(bindaddr: &SocketAddr) -> io::Result<Option<UdpSocket>> {
+fn udp_socket(bindaddr: &SocketAddr, only_v6: bool) -> io::Result<Option<UdpSocket>> {
     debug!("binding to {}", bindaddr);
     let builder;
     match *bindaddr {
@@ -136,7 +228,7 @@ fn udp_socket(bindaddr: &SocketAddr) -> io::Result<Option<UdpSocket>> {
         b => b?,
     };
     if let SocketAddr::V6(..) = *bindaddr {
-        builder.only_v6(true)?;
+        builder.only_v6(only_v6)?;
     }
     Ok(Some(UdpSocket::from_socket(builder.bind(bindaddr)?)?))
 }
@@ -148,6 +240,18 @@ fn non_block<T>(res: io::Result<T>) -> Option<io::Result<T>> {
     }
 }
 
+/// Turns IPv4-mapped IPv6 addresses received on dual-stack sockets back
+/// into IPv4 addresses.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(..) => addr,
+    }
+}
+
 impl Socket {
     pub fn new() -> io::Result<Socket> {
         Socket::construct(None, 0.0)
@@ -161,48 +265,149 @@ impl Socket {
     pub fn bound_with_loss_rate(port: u16, loss_rate: f32) -> io::Result<Socket> {
         Socket::construct(Some(port), loss_rate)
     }
+    /// Binds the IPv4 and IPv6 wildcard addresses, skipping address
+    /// families that aren't supported by the system.
     pub fn construct(port: Option<u16>, loss_rate: f32) -> io::Result<Socket> {
         assert!(port != Some(0));
         let port = port.unwrap_or(0);
-        assert!(0.0 <= loss_rate && loss_rate <= 1.0);
-
-        fn register(poll: &mut mio::Poll, token: usize, socket: &UdpSocket) -> io::Result<()> {
-            use mio::PollOpt;
-            poll.register(socket, Token(token), Ready::readable(), PollOpt::level())
-        }
 
         let addr_v4 = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
         let addr_v6 = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
 
-        let v4 = udp_socket(&SocketAddr::new(addr_v4, port))?;
-        let v6 = udp_socket(&SocketAddr::new(addr_v6, port))?;
+        let mut sockets = Vec::new();
+        for &ip in &[addr_v4, addr_v6] {
+            let addr = SocketAddr::new(ip, port);
+            if let Some(socket) = udp_socket(&addr, true)? {
+                sockets.push((socket, false));
+            }
+        }
 
-        if v4.is_none() && v6.is_none() {
+        if sockets.is_empty() {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
                 NoAddressFamiliesSupported(()),
             ));
         }
+        Ok(Socket::from_sockets(sockets, loss_rate)?)
+    }
+    /// Binds each of the given addresses with a separate socket.
+    pub fn bind(addrs: &[SocketAddr]) -> Result<Socket, BindError> {
+        Socket::bind_with_options(addrs, BindOptions::default())
+    }
+    pub fn bind_with_options(
+        addrs: &[SocketAddr],
+        options: BindOptions,
+    ) -> Result<Socket, BindError> {
+        if addrs.is_empty() {
+            return Err(BindError {
+                addr: None,
+                error: io::Error::new(io::ErrorKind::InvalidInput, "no addresses to bind to"),
+            });
+        }
+        let mut sockets = Vec::new();
+        for addr in addrs {
+            let socket = udp_socket(addr, options.only_v6)
+                .map_err(|e| BindError::new(*addr, e))?
+                .ok_or_else(|| {
+                    BindError::new(
+                        *addr,
+                        io::Error::new(io::ErrorKind::Other, AddressFamilyNotSupported(())),
+                    )
+                })?;
+            sockets.push((socket, addr.is_ipv6() && !options.only_v6));
+        }
+        Socket::from_sockets(sockets, options.loss_rate)
+    }
+    fn from_sockets(sockets: Vec<(UdpSocket, bool)>, loss_rate: f32) -> Result<Socket, BindError> {
+        use mio::PollOpt;
+
+        assert!(0.0 <= loss_rate && loss_rate <= 1.0);
+        let poll_error = |e| BindError {
+            addr: None,
+            error: e,
+        };
 
-        let mut poll = mio::Poll::new()?;
-        v4.as_ref()
-            .map(|v4| register(&mut poll, 4, &v4))
-            .unwrap_or(Ok(()))?;
-        v6.as_ref()
-            .map(|v6| register(&mut poll, 6, &v6))
-            .unwrap_or(Ok(()))?;
+        let poll = mio::Poll::new().map_err(poll_error)?;
+        let mut bound = Vec::new();
+        for (i, (socket, dual_stack)) in sockets.into_iter().enumerate() {
+            let local_addr = socket.local_addr().map_err(poll_error)?;
+            poll.register(&socket, Token(i), Ready::readable(), PollOpt::level())
+                .map_err(|e| BindError::new(local_addr, e))?;
+            bound.push(BoundSocket {
+                socket: socket,
+                local_addr: local_addr,
+                dual_stack: dual_stack,
+                readable: false,
+            });
+        }
+        let default_v6 = bound.iter().position(|s| s.local_addr.is_ipv6());
+        let default_v4 = bound
+            .iter()
+            .position(|s| s.local_addr.is_ipv4())
+            .or_else(|| bound.iter().position(|s| s.dual_stack));
         Ok(Socket {
             start: Instant::now(),
             time_cached: Timestamp::from_secs_since_epoch(0),
             poll: poll,
-            events: mio::Events::with_capacity(2),
-            v4: v4,
-            v6: v6,
-            check_v4: false,
-            check_v6: false,
+            events: mio::Events::with_capacity(bound.len()),
+            sockets: bound,
+            default_v4: default_v4,
+            default_v6: default_v6,
+            reply_via: HashMap::new(),
+            capture: None,
             loss_rate: loss_rate,
         })
     }
+    /// Returns the local addresses of the bound sockets.
+    pub fn local_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sockets.iter().map(|s| s.local_addr)
+    }
+    /// Writes every datagram sent or received from now on to `writer` in
+    /// the pcap format, see [`pcap`].
+    ///
+    /// Write errors stop the capture, they don't affect the socket.
+    pub fn capture_to<W: Write + Send + 'static>(&mut self, writer: W) -> io::Result<()> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        self.capture = Some(pcap::Writer::new(writer)?);
+        Ok(())
+    }
+    /// Stops the capture started by [`Socket::capture_to`], flushing the
+    /// writer.
+    pub fn stop_capture(&mut self) -> io::Result<()> {
+        match self.capture.take() {
+            Some(mut capture) => capture.flush(),
+            None => Ok(()),
+        }
+    }
+    fn capture(&mut self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        if let Some(ref mut capture) = self.capture {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            if let Err(e) = capture.write_packet(time, src, dst, data) {
+                warn!("stopping packet capture: {}", e);
+                self.capture = None;
+            }
+        }
+    }
+    fn default_socket(&self, addr: Addr) -> Option<usize> {
+        match addr.ip {
+            IpAddr::V4(..) => self.default_v4,
+            IpAddr::V6(..) => self.default_v6,
+        }
+    }
+    fn remember_route(&mut self, addr: Addr, index: usize) {
+        if self.default_socket(addr) == Some(index) {
+            if !self.reply_via.is_empty() {
+                self.reply_via.remove(&addr);
+            }
+            return;
+        }
+        if self.reply_via.len() >= MAX_REPLY_ROUTES && !self.reply_via.contains_key(&addr) {
+            self.reply_via.clear();
+        }
+        self.reply_via.insert(addr, index);
+    }
     fn loss(&self) -> bool {
         self.loss_rate != 0.0 && rand::random::<f32>() < self.loss_rate
     }
@@ -219,27 +424,30 @@ impl Socket {
         let mut result = None;
         {
             let buf_slice = unsafe { buf.uninitialized_mut() };
-            if result.is_none() && self.check_v6 {
-                if let Some(r) = non_block(self.v6.as_ref().unwrap().recv_from(buf_slice)) {
-                    result = Some(r);
-                    self.check_v6 = false;
+            for (i, s) in self.sockets.iter_mut().enumerate() {
+                if !s.readable {
+                    continue;
                 }
-            }
-            if result.is_none() && self.check_v4 {
-                if let Some(r) = non_block(self.v4.as_ref().unwrap().recv_from(buf_slice)) {
-                    result = Some(r);
-                    self.check_v4 = false;
+                if let Some(r) = non_block(s.socket.recv_from(buf_slice)) {
+                    result = Some(r.map(|(len, addr)| (len, addr, i)));
+                    s.readable = false;
+                    break;
                 }
+                s.readable = false;
             }
         }
         let result = unwrap_or_return!(result);
         if self.loss() {
             return self.receive_impl(buf);
         }
-        Some(result.map(|(len, addr)| unsafe {
-            let addr = Addr::from(addr);
+        Some(result.map(|(len, addr, i)| unsafe {
+            let addr = unmap(addr);
+            let local_addr = self.sockets[i].local_addr;
+            self.remember_route(Addr::from(addr), i);
             buf.advance(len);
             let initialized = buf.initialized();
+            self.capture(addr, local_addr, initialized);
+            let addr = Addr::from(addr);
             dump(Direction::Receive, addr, initialized);
             (addr, initialized)
         }))
@@ -253,11 +461,8 @@ impl Socket {
         // on loss-free networks.
         for ev in &self.events {
             assert!(ev.readiness() == Ready::readable());
-            match ev.token() {
-                Token(4) => self.check_v4 = true,
-                Token(6) => self.check_v6 = true,
-                _ => unreachable!(),
-            }
+            let Token(i) = ev.token();
+            self.sockets[i].readable = true;
         }
         self.update_time_cached();
         Ok(())
@@ -277,36 +482,128 @@ impl Callback<Addr> for Socket {
             return Ok(());
         }
         dump(Direction::Send, addr, data);
-        let sock_addr = SocketAddr::new(addr.ip, addr.port);
-        let maybe_socket = if let IpAddr::V4(..) = addr.ip {
-            &self.v4
-        } else {
-            &self.v6
-        };
+        let index = self
+            .reply_via
+            .get(&addr)
+            .cloned()
+            .or_else(|| self.default_socket(addr));
         let socket;
-        if let Some(ref s) = *maybe_socket {
-            socket = s;
+        if let Some(i) = index {
+            socket = &self.sockets[i];
         } else {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
                 AddressFamilyNotSupported(()),
             ));
         }
-        non_block(socket.send_to(data, &sock_addr))
+        let sock_addr = match addr.ip {
+            IpAddr::V4(ip) if socket.local_addr.is_ipv6() => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port)
+            }
+            ip => SocketAddr::new(ip, addr.port),
+        };
+        // TODO: Check for these errors and decide what to do with them
+        // EHOSTUNREACH
+        // ENETDOWN
+        // ENTUNREACH
+        // EAGAIN EWOULDBLOCK
+        let local_addr = socket.local_addr;
+        let result = non_block(socket.socket.send_to(data, &sock_addr))
             .unwrap_or_else(|| {
                 Err(io::Error::new(
                     io::ErrorKind::WouldBlock,
                     "write would block",
                 ))
             })
-            .map(|s| assert!(data.len() == s))
-        // TODO: Check for these errors and decide what to do with them
-        // EHOSTUNREACH
-        // ENETDOWN
-        // ENTUNREACH
-        // EAGAIN EWOULDBLOCK
+            .map(|s| assert!(data.len() == s));
+        if result.is_ok() {
+            self.capture(local_addr, SocketAddr::new(addr.ip, addr.port), data);
+        }
+        result
     }
     fn time(&mut self) -> Timestamp {
         self.time_cached
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Addr;
+    use super::Socket;
+    use libtw2_net::net::Callback;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn receive(socket: &mut Socket) -> (Addr, Vec<u8>) {
+        let mut buf = Vec::with_capacity(4096);
+        loop {
+            socket.sleep(Some(Duration::from_secs(1))).unwrap();
+            if let Some(res) = socket.receive(&mut buf) {
+                let (addr, data) = res.unwrap();
+                return (addr, data.to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn multiple_addresses() {
+        let v4: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let v6: SocketAddr = "[::1]:0".parse().unwrap();
+        let mut server = Socket::bind(&[v4, v6]).unwrap();
+        let mut client4 = Socket::bind(&[v4]).unwrap();
+        let mut client6 = Socket::bind(&[v6]).unwrap();
+        let server_addrs: Vec<_> = server.local_addrs().collect();
+        let client4_addr = Addr::from(client4.local_addrs().next().unwrap());
+        let client6_addr = Addr::from(client6.local_addrs().next().unwrap());
+
+        client4.send(Addr::from(server_addrs[0]), b"v4").unwrap();
+        assert_eq!(receive(&mut server), (client4_addr, b"v4".to_vec()));
+        client6.send(Addr::from(server_addrs[1]), b"v6").unwrap();
+        assert_eq!(receive(&mut server), (client6_addr, b"v6".to_vec()));
+
+        server.send(client4_addr, b"reply4").unwrap();
+        assert_eq!(
+            receive(&mut client4),
+            (Addr::from(server_addrs[0]), b"reply4".to_vec())
+        );
+        server.send(client6_addr, b"reply6").unwrap();
+        assert_eq!(
+            receive(&mut client6),
+            (Addr::from(server_addrs[1]), b"reply6".to_vec())
+        );
+    }
+
+    // Only Linux routes all of 127.0.0.0/8 to the loopback interface by
+    // default.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reply_from_receiving_address() {
+        let first: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let second: SocketAddr = "127.0.0.2:0".parse().unwrap();
+        let mut server = Socket::bind(&[first, second]).unwrap();
+        let mut client = Socket::bind(&[first]).unwrap();
+        let server_second = Addr::from(server.local_addrs().nth(1).unwrap());
+        let client_addr = Addr::from(client.local_addrs().next().unwrap());
+
+        client.send(server_second, b"hello").unwrap();
+        assert_eq!(receive(&mut server), (client_addr, b"hello".to_vec()));
+        server.send(client_addr, b"reply").unwrap();
+        assert_eq!(receive(&mut client), (server_second, b"reply".to_vec()));
+    }
+
+    #[test]
+    fn bind_error() {
+        let socket = Socket::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let addr = socket.local_addrs().next().unwrap();
+        let err = Socket::bind(&[addr]).err().unwrap();
+        assert_eq!(err.addr(), Some(addr));
+        assert_eq!(err.io_error().kind(), std::io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn bind_no_addresses() {
+        let err = Socket::bind(&[]).err().unwrap();
+        assert_eq!(err.addr(), None);
+        assert_eq!(err.io_error().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,411 @@
+//! Reading and writing UDP datagrams in the classic pcap file format.
+//!
+//! Written captures use `LINKTYPE_RAW`, i.e. every datagram is stored with
+//! synthesized IP and UDP headers so that the addresses are preserved and
+//! the file can be inspected using Wireshark. The reader additionally
+//! understands Ethernet and Linux cooked captures as written by `tcpdump`.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const SNAPLEN: u32 = 65535;
+const MAX_RECORD_LEN: u32 = 262144;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Packet {
+    /// Time since the Unix epoch.
+    pub time: Duration,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Converts both addresses to IPv6 if only one of them is, which happens
+/// for IPv4 peers of dual-stack sockets.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    fn to_v6(addr: SocketAddr) -> SocketAddr {
+        match addr.ip() {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+            IpAddr::V6(..) => addr,
+        }
+    }
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
+
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [a, b] => u16::from_be_bytes([a, b]),
+            [a] => u16::from_be_bytes([a, 0]),
+            _ => unreachable!(),
+        };
+        sum += u32::from(word);
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub struct Writer<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the pcap file header.
+    pub fn new(mut inner: W) -> io::Result<Writer<W>> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        inner.write_all(&header)?;
+        Ok(Writer {
+            inner: inner,
+            buf: Vec::new(),
+        })
+    }
+    pub fn write_packet(
+        &mut self,
+        time: Duration,
+        src: SocketAddr,
+        dst: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (src, dst) = same_family(src, dst);
+        let udp_len = 8 + data.len();
+        if udp_len > 0xffff - 40 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram too large",
+            ));
+        }
+
+        let buf = &mut self.buf;
+        buf.clear();
+        // The UDP checksum's pseudo header.
+        let mut sum;
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                let total_len = (20 + udp_len) as u16;
+                buf.extend_from_slice(&[0x45, 0]);
+                buf.extend_from_slice(&total_len.to_be_bytes());
+                buf.extend_from_slice(&[0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0]);
+                buf.extend_from_slice(&s.octets());
+                buf.extend_from_slice(&d.octets());
+                let header_checksum = checksum_finish(checksum_add(0, &buf[..20]));
+                buf[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+                sum = checksum_add(0, &buf[12..20]);
+                sum += u32::from(IPPROTO_UDP) + udp_len as u32;
+            }
+            (IpAddr::V6(s), IpAddr::V6(d)) => {
+                buf.extend_from_slice(&[0x60, 0, 0, 0]);
+                buf.extend_from_slice(&(udp_len as u16).to_be_bytes());
+                buf.extend_from_slice(&[IPPROTO_UDP, 64]);
+                buf.extend_from_slice(&s.octets());
+                buf.extend_from_slice(&d.octets());
+                sum = checksum_add(0, &buf[8..40]);
+                sum += u32::from(IPPROTO_UDP) + udp_len as u32;
+            }
+            _ => unreachable!(),
+        }
+        let udp_start = buf.len();
+        buf.extend_from_slice(&src.port().to_be_bytes());
+        buf.extend_from_slice(&dst.port().to_be_bytes());
+        buf.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(data);
+        let udp_checksum = match checksum_finish(checksum_add(sum, &buf[udp_start..])) {
+            0 => 0xffff,
+            c => c,
+        };
+        buf[udp_start + 6..udp_start + 8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&time.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(buf)
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+pub struct Reader<R: Read> {
+    inner: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the pcap file header.
+    ///
+    /// Fails with `io::ErrorKind::InvalidData` if the file isn't a pcap file
+    /// or uses an unsupported link type.
+    pub fn new(mut inner: R) -> io::Result<Reader<R>> {
+        let mut header = [0; 24];
+        inner.read_exact(&mut header)?;
+        let magic_bytes = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = match (
+            u32::from_le_bytes(magic_bytes),
+            u32::from_be_bytes(magic_bytes),
+        ) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => return Err(invalid_data("not a pcap file")),
+        };
+        let mut result = Reader {
+            inner: inner,
+            big_endian: big_endian,
+            nanos: nanos,
+            link_type: 0,
+        };
+        result.link_type = result.u32(&header[20..24]) & 0x0fff_ffff;
+        match result.link_type {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL
+            | LINKTYPE_LINUX_SLL2 => {}
+            _ => return Err(invalid_data("unsupported pcap link type")),
+        }
+        Ok(result)
+    }
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+    /// Returns the next UDP datagram, skipping all other records.
+    pub fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        let mut data = Vec::new();
+        loop {
+            let mut header = [0; 16];
+            match self.inner.read_exact(&mut header[..1]) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            self.inner.read_exact(&mut header[1..])?;
+            let secs = self.u32(&header[0..4]);
+            let fraction = self.u32(&header[4..8]);
+            let len = self.u32(&header[8..12]);
+            if len > MAX_RECORD_LEN {
+                return Err(invalid_data("pcap record too large"));
+            }
+            data.resize(len as usize, 0);
+            self.inner.read_exact(&mut data)?;
+            let nanos = if self.nanos {
+                fraction
+            } else {
+                fraction.saturating_mul(1000)
+            };
+            let time = Duration::new(u64::from(secs), 0) + Duration::from_nanos(nanos.into());
+            if let Some((src, dst, payload)) = self.parse_link(&data) {
+                return Ok(Some(Packet {
+                    time: time,
+                    src: src,
+                    dst: dst,
+                    data: payload.to_vec(),
+                }));
+            }
+        }
+    }
+    fn parse_link<'a>(&self, data: &'a [u8]) -> Option<(SocketAddr, SocketAddr, &'a [u8])> {
+        let (ethertype, ip) = match self.link_type {
+            LINKTYPE_RAW => (None, data),
+            LINKTYPE_NULL => {
+                let family = self.u32(data.get(..4)?);
+                let ethertype = match family {
+                    2 => ETHERTYPE_IPV4,
+                    24 | 28 | 30 => ETHERTYPE_IPV6,
+                    _ => return None,
+                };
+                (Some(ethertype), &data[4..])
+            }
+            LINKTYPE_ETHERNET => {
+                let mut ethertype = u16::from_be_bytes([*data.get(12)?, *data.get(13)?]);
+                let mut rest = data.get(14..)?;
+                if ethertype == ETHERTYPE_VLAN {
+                    ethertype = u16::from_be_bytes([*rest.get(2)?, *rest.get(3)?]);
+                    rest = rest.get(4..)?;
+                }
+                (Some(ethertype), rest)
+            }
+            LINKTYPE_LINUX_SLL => (
+                Some(u16::from_be_bytes([*data.get(14)?, *data.get(15)?])),
+                data.get(16..)?,
+            ),
+            LINKTYPE_LINUX_SLL2 => (
+                Some(u16::from_be_bytes([*data.first()?, *data.get(1)?])),
+                data.get(20..)?,
+            ),
+            _ => unreachable!(),
+        };
+        let version = *ip.first()? >> 4;
+        match (ethertype, version) {
+            (None, 4) | (Some(ETHERTYPE_IPV4), 4) => parse_ipv4(ip),
+            (None, 6) | (Some(ETHERTYPE_IPV6), 6) => parse_ipv6(ip),
+            _ => None,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Packet>;
+    fn next(&mut self) -> Option<io::Result<Packet>> {
+        self.read_packet().transpose()
+    }
+}
+
+fn parse_ipv4(ip: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let header_len = usize::from(ip[0] & 0x0f) * 4;
+    let total_len = usize::from(u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]));
+    let fragment = u16::from_be_bytes([*ip.get(6)?, *ip.get(7)?]);
+    // Skip fragments, only complete datagrams are of interest.
+    if fragment & 0x3fff != 0 || *ip.get(9)? != IPPROTO_UDP {
+        return None;
+    }
+    let src: [u8; 4] = ip.get(12..16)?.try_into().unwrap();
+    let dst: [u8; 4] = ip.get(16..20)?.try_into().unwrap();
+    let udp = ip.get(header_len..total_len)?;
+    parse_udp(IpAddr::from(src), IpAddr::from(dst), udp)
+}
+
+fn parse_ipv6(ip: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let payload_len = usize::from(u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]));
+    // Extension headers aren't supported.
+    if *ip.get(6)? != IPPROTO_UDP {
+        return None;
+    }
+    let src: [u8; 16] = ip.get(8..24)?.try_into().unwrap();
+    let dst: [u8; 16] = ip.get(24..40)?.try_into().unwrap();
+    let udp = ip.get(40..40 + payload_len)?;
+    parse_udp(
+        IpAddr::V6(Ipv6Addr::from(src)),
+        IpAddr::V6(Ipv6Addr::from(dst)),
+        udp,
+    )
+}
+
+fn parse_udp(src: IpAddr, dst: IpAddr, udp: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let src_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let dst_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let len = usize::from(u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]));
+    let payload = udp.get(8..len)?;
+    Some((
+        SocketAddr::new(src, src_port),
+        SocketAddr::new(dst, dst_port),
+        payload,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Packet;
+    use super::Reader;
+    use super::Writer;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn roundtrip() {
+        let v4_a: SocketAddr = "192.0.2.1:8303".parse().unwrap();
+        let v4_b: SocketAddr = "198.51.100.7:51234".parse().unwrap();
+        let v6_a: SocketAddr = "[2001:db8::1]:8303".parse().unwrap();
+        let packets = vec![
+            Packet {
+                time: Duration::new(1_600_000_000, 123_000),
+                src: v4_a,
+                dst: v4_b,
+                data: b"\x10\x00\x00\x01".to_vec(),
+            },
+            Packet {
+                time: Duration::new(1_600_000_001, 999_999_000),
+                src: v6_a,
+                dst: "[2001:db8::2]:1234".parse().unwrap(),
+                data: b"odd".to_vec(),
+            },
+            Packet {
+                time: Duration::new(1_600_000_002, 0),
+                src: v4_b,
+                dst: v4_a,
+                data: Vec::new(),
+            },
+        ];
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for p in &packets {
+            writer.write_packet(p.time, p.src, p.dst, &p.data).unwrap();
+        }
+        let file = writer.into_inner();
+        let read: Vec<_> = Reader::new(&file[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read, packets);
+    }
+
+    #[test]
+    fn mixed_families() {
+        let v6: SocketAddr = "[::]:8303".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer
+            .write_packet(Duration::from_secs(1), v6, v4, b"data")
+            .unwrap();
+        let file = writer.into_inner();
+        let packet = Reader::new(&file[..]).unwrap().next().unwrap().unwrap();
+        assert_eq!(packet.src, v6);
+        assert_eq!(packet.dst, "[::ffff:192.0.2.1]:1234".parse().unwrap());
+        assert_eq!(packet.data, b"data");
+    }
+
+    #[test]
+    fn not_pcap() {
+        assert!(Reader::new(&[0u8; 24][..]).is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use clap::App;
 use clap::Arg;
 use libtw2_demo::ddnet;
+use libtw2_gamenet_common::traits::Protocol;
 use libtw2_gamenet_ddnet::Protocol as DDNet;
+use libtw2_gamenet_teeworlds_0_7::Protocol as Teeworlds07;
 use std::error::Error;
 use std::fs;
 use std::process;
@@ -26,7 +28,7 @@ fn main() {
         .arg(
             Arg::with_name("DDNET")
                 .long("ddnet")
-                .help("Interpret the demo as a DDNet demo"),
+                .help("Interpret the demo as a DDNet or Teeworlds 0.7 demo"),
         )
         .get_matches();
 
@@ -58,17 +60,33 @@ fn read_write(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
         reader.timestamp(),
         reader.map_data(),
     )?;
+    for &marker in reader.timeline_markers() {
+        writer.add_timeline_marker(marker, &mut warn::Ignore);
+    }
     while let Some(chunk) = reader.read_chunk(&mut warn::Ignore)? {
         writer.write_chunk(chunk)?;
     }
+    writer.finish()?;
     Ok(())
 }
 
 fn ddnet_read_write(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    let reader = libtw2_demo::Reader::new(fs::File::open(input)?, &mut warn::Log)?;
+    if reader.is_teeworlds_0_7() {
+        typed_read_write::<Teeworlds07>(input, output)
+    } else {
+        typed_read_write::<DDNet>(input, output)
+    }
+}
+
+fn typed_read_write<P: for<'p> Protocol<'p>>(
+    input: &str,
+    output: &str,
+) -> Result<(), Box<dyn Error>> {
     let input_file = fs::File::open(input)?;
     let output_file = fs::File::create(output)?;
-    let mut reader = ddnet::DemoReader::<DDNet>::new(input_file, &mut warn::Log)?;
-    let mut writer = ddnet::DemoWriter::<DDNet>::new(
+    let mut reader = ddnet::DemoReader::<P>::new(input_file, &mut warn::Log)?;
+    let mut writer = ddnet::DemoWriter::<P>::new(
         output_file,
         reader.inner().net_version(),
         reader.inner().map_name(),
@@ -79,6 +97,9 @@ fn ddnet_read_write(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
         reader.inner().timestamp(),
         reader.inner().map_data(),
     )?;
+    for &marker in reader.inner().timeline_markers() {
+        writer.add_timeline_marker(marker, &mut warn::Log);
+    }
     let mut last_tick = None;
     while let Some(chunk) = reader.next_chunk(&mut warn::Log)? {
         match chunk {
@@ -91,5 +112,6 @@ fn ddnet_read_write(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
             ddnet::Chunk::Invalid => eprintln!("Invalid chunk!"),
         }
     }
+    writer.finish()?;
     Ok(())
 }
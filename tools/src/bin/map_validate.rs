@@ -0,0 +1,72 @@
+use libtw2_map::validate;
+use libtw2_map::validate::Diagnostic;
+use libtw2_map::validate::Severity;
+use serde_derive::Serialize;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+#[derive(Serialize)]
+struct Output<'a> {
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open_error: Option<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Returns whether the map has errors.
+fn process(path: &Path) -> bool {
+    let path_str = path.to_string_lossy();
+    let output = match libtw2_map::Reader::open(path) {
+        Ok(mut map) => Output {
+            path: &path_str,
+            open_error: None,
+            diagnostics: validate::validate(&mut map),
+        },
+        Err(err) => Output {
+            path: &path_str,
+            open_error: Some(format!("{:?}", err)),
+            diagnostics: Vec::new(),
+        },
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer(&mut stdout, &output).unwrap();
+    stdout.write_all(b"\n").unwrap();
+    output.open_error.is_some()
+        || output
+            .diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+}
+
+fn main() {
+    use clap::App;
+    use clap::Arg;
+
+    libtw2_logger::init();
+
+    let matches = App::new("Map validator")
+        .about(
+            "Checks map files for problems, printing one line of JSON per map. \
+             Exits with an error if any map has errors.",
+        )
+        .arg(
+            Arg::with_name("MAP")
+                .help("Sets the map files to check")
+                .multiple(true)
+                .required(true),
+        )
+        .get_matches();
+
+    let maps = matches.values_of_os("MAP").unwrap();
+
+    let mut error = false;
+    for map in maps {
+        error |= process(Path::new(map));
+    }
+    if error {
+        process::exit(1);
+    }
+}
@@ -294,6 +294,7 @@ fn process(in_: &Path, out: &Path) -> Result<(), String> {
             last_snap = Some(snap);
         }
     }
+    demo.finish().map_err(|err| err.to_string())?;
     Ok(())
 }
 
@@ -53,6 +53,7 @@ struct ErrorStats {
     map_errors: HashMap<libtw2_map::format::Error, u64>,
     df_errors: HashMap<df::format::Error, u64>,
     io_errors: Vec<io::Error>,
+    external_image_errors: Vec<libtw2_map::image::ExternalImageError>,
     ok: u64,
 }
 
@@ -67,6 +68,9 @@ fn update_error_stats(stats: &mut ErrorStats, err: libtw2_map::Error) {
         libtw2_map::Error::Df(df::Error::Io(e)) => {
             stats.io_errors.push(e);
         }
+        libtw2_map::Error::ExternalImage(e) => {
+            stats.external_image_errors.push(e);
+        }
     }
 }
 
@@ -80,6 +84,9 @@ fn print_error_stats(error_stats: &ErrorStats) {
     for e in &error_stats.io_errors {
         println!("{:?}", e);
     }
+    for e in &error_stats.external_image_errors {
+        println!("{:?}", e);
+    }
     println!("ok: {}", error_stats.ok);
 }
 